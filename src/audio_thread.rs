@@ -1,26 +1,1016 @@
-use std::sync::mpsc;
+use std::any::Any;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use sdl2::audio::{AudioCallback, AudioSpecDesired};
 
-use crate::filters::{Filter, StringSynth, SynthBuilder, FIR, SAMPLING_FREQ};
-use crate::midi::{MidiEvent, MidiEventInner};
-use crate::note;
+use crate::clock::{Clock, EngineClock, MidiClock, Stamp, TICKS_PER_BEAT};
+use crate::console::Console;
+use crate::delay::{DelayTime, FeedbackDelay, PingPongDelay};
+use crate::distortion::{Curve, Waveshaper};
+use crate::dynamics::{Compressor, Key};
+use crate::event_log::{EventLog, Field};
+use crate::expression::{Expression, ExpressionConfig, EXPRESSION_CC};
+use crate::filters::{
+    Articulation, Bus, DcBlocker, Effect, Filter, Ladder, Named, Noise, Rack, Synth, SynthBuilder,
+    FIR, SAMPLING_FREQ,
+};
+use crate::guard::{catch_stereo, EngineError, Guarded, Guards, Reporter};
+use crate::harmonizer::{Chord, Harmonizer, Scale};
+use crate::metronome::{Beats, Metronome};
+use crate::midi::{self, CcMap, MidiEvent, MidiEventInner};
+use crate::modulation::{LfoConfig, ModConfig, Modulated, Source, MOD_WHEEL_CC};
+use crate::mpe::{Route, Zones};
+use crate::note::Pitch;
+use crate::outputs::OutputMap;
+use crate::params::{ParamInfo, Params, Smoothed};
+use crate::patch::{Diagnostic, Entry, Patch};
+use crate::pressure::{Pressure, PressureConfig};
+use crate::queue::{self, Coalesce};
+use crate::random::Explorer;
+use crate::recording::{self, Recording, Tap};
+use crate::reload::{Reload, Watcher};
+use crate::reverb::{ConvolutionReverb, Reverb};
+use crate::sequencer::{Control, Pattern, Sequencer, Song, Step};
+use crate::session::{Saver, Session, AUTOSAVE_PERIOD};
+use crate::siggen::Siggen;
+use crate::smf::{Recorder, TimeChange};
+use crate::snapshot::{Snapshot, Snapshots};
+use crate::snoop;
+use crate::sources::{SynthKind, DEFAULT_SEED};
+use crate::spsc::{self, Consumer, Producer};
+use crate::stereo::{DualMono, Haas, Panner, Stereo, StereoFilter, Width};
+use crate::strum::{StrumConfig, StrumEvent, Strummer};
+use crate::tone::ToneStack;
+use crate::tuning::Tuning;
+use crate::voice::{DynVoice, KeyPressure, PerNote, VoiceManager};
+use crate::watchdog::Watchdog;
 
-#[derive(Clone, Copy, Debug)]
+/// Events for the audio thread. Notes carry when they happened on the
+/// engine clock, and are played that long after the start of the block they
+/// happened during, so their timing comes out exact (a block late) rather
+/// than jittering by up to a block.
+#[derive(Clone, Debug)]
 pub enum AudioEvent {
-    // FIXME: timestamping?
     Midi(MidiEvent),
-    PlayNote(f32),
+    /// A note from the console: frequency, velocity in 0..=1, and when. It
+    /// rings out with no note off.
+    PlayNote(f32, f32, Stamp),
+    /// A computer keyboard key going down: frequency, velocity in 0..=1,
+    /// and when. It plays the nearest MIDI note until the key comes up.
+    KeyDown(f32, f32, Stamp),
+    /// The key playing this frequency coming up, and when.
+    KeyUp(f32, Stamp),
+    /// A MIDI file being played changing its tempo or time signature, and
+    /// when.
+    Time(TimeChange, Stamp),
+    ToggleLatch,
+    ReleaseAll,
+    /// Starts or stops learning the expression pedal's range.
+    LearnExpression,
+    /// Writes out what has been recorded so far.
+    SaveRecording,
+    /// Starts or stops recording the output to a WAV file.
+    ToggleWavRecording,
+    /// Starts every snoop capturing, or saves them all.
+    ToggleSnoops,
+    /// Fades the effects out to hear the voices dry, or back in.
+    ToggleDry,
+    /// Starts or stops the metronome.
+    ToggleClick,
+    Sequencer(Control),
+    /// A console command that the engine has to answer, typed in or sent
+    /// over OSC.
+    Console(Console),
     Terminate,
 }
 
-struct SDLShim<T: Filter>(T);
+/// A controller moving on again leaves where it was on its way of no use,
+/// and losing a note off would leave the note hanging.
+impl Coalesce for AudioEvent {
+    fn replaces(&self, older: &Self) -> bool {
+        let (AudioEvent::Midi(new), AudioEvent::Midi(old)) = (self, older) else {
+            return false;
+        };
+        match (&new.inner, &old.inner) {
+            (
+                MidiEventInner::ControlChange { controller, .. },
+                MidiEventInner::ControlChange {
+                    controller: was, ..
+                },
+            ) => new.channel == old.channel && controller == was && !SWITCHES.contains(controller),
+            (
+                MidiEventInner::KeyPressure { key, .. },
+                MidiEventInner::KeyPressure { key: was, .. },
+            ) => new.channel == old.channel && key == was,
+            _ => false,
+        }
+    }
+
+    fn expendable(&self) -> bool {
+        let AudioEvent::Midi(event) = self else {
+            return false;
+        };
+        match event.inner {
+            MidiEventInner::ControlChange { controller, .. } => !SWITCHES.contains(&controller),
+            MidiEventInner::KeyPressure { .. }
+            | MidiEventInner::ChannelPressure(_)
+            | MidiEventInner::PitchBend(_)
+            | MidiEventInner::Clock => true,
+            _ => false,
+        }
+    }
+
+    fn essential(&self) -> bool {
+        match self {
+            AudioEvent::Midi(event) => matches!(
+                event.inner,
+                MidiEventInner::Up { .. } | MidiEventInner::Down { velocity: 0, .. }
+            ),
+            AudioEvent::ReleaseAll | AudioEvent::Terminate => true,
+            _ => false,
+        }
+    }
+}
+
+/// Controllers that are on or off, like the sustain pedal, where every
+/// change counts and not just the last.
+const SWITCHES: RangeInclusive<u8> = 64..=69;
+
+/// Smaller changes than this to the tempo of MIDI clock coming in are just
+/// the ticks coming in a little unevenly.
+const TEMPO_JITTER: f32 = 0.5;
+
+/// How many commands can be waiting for the audio callback.
+const COMMAND_QUEUE_LEN: usize = 1024;
+
+/// How long swapping in an engine built from a changed patch takes: 50ms.
+const CROSSFADE_SAMPLES: u32 = SAMPLING_FREQ as u32 / 20;
+
+/// Samples of room for an outgoing engine's output, more than a block of
+/// any size SDL or JACK usually asks for.
+const SCRATCH_LEN: usize = 4096;
+
+/// Extra time to wait for the fade out, for the blocks already queued up in
+/// the device.
+const FADE_MARGIN: Duration = Duration::from_millis(30);
+
+type Voices = Guarded<VoiceManager<Box<dyn DynVoice>>>;
+
+/// The voices and mono effects `F`, then the stereo effects `O`, with the
+/// modulation matrix on any of their parameters.
+pub(crate) type Engine<F, O> = Modulated<Stereo<Synth<Voices, F>, O>>;
+
+/// A change to the engine, which the audio callback makes between blocks.
+#[derive(Debug)]
+enum Command {
+    NoteOn {
+        note: Option<u8>,
+        freq: f32,
+        velocity: f32,
+    },
+    NoteOff(u8),
+    Bend(f32),
+    /// A note from an MPE member channel, which follows that channel's
+    /// expression.
+    ChannelNoteOn {
+        channel: u8,
+        note: u8,
+        freq: f32,
+        velocity: f32,
+    },
+    ChannelNoteOff(u8, u8),
+    PerNote(u8, PerNote),
+    /// Polyphonic aftertouch on a note, 0..=1.
+    KeyPressure(u8, f32),
+    /// Moves a modulation source that comes from outside, 0..=1.
+    ModSource(Source, f32),
+    SetParam(Arc<str>, f32),
+    /// Lines tempo synced LFOs up with the song being this many beats in.
+    SyncToBeat(f64),
+    ToggleLatch,
+    ReleaseAll,
+    /// Starts or stops copying the output somewhere to be recorded.
+    SetTap(Option<Tap>),
+    /// Fades the output out, ready to stop.
+    FadeOut,
+    /// Crossfades to a [`Generation`] built from a changed patch.
+    Swap(Box<dyn Any + Send>),
+}
+
+/// Everything that gets built again when the patch changes, ready to be
+/// swapped in by the audio callback without it allocating.
+struct Generation<F: Filter, O: StereoFilter> {
+    engine: Engine<F, O>,
+    watchdog: Option<Watchdog>,
+    /// room for the outgoing engine's output while they crossfade
+    scratch: [Vec<f32>; 2],
+}
+
+impl<F: Filter, O: StereoFilter> Generation<F, O> {
+    fn new(engine: Engine<F, O>, watchdog: Option<Watchdog>) -> Self {
+        Self {
+            engine,
+            watchdog,
+            scratch: [vec![0.; SCRATCH_LEN], vec![0.; SCRATCH_LEN]],
+        }
+    }
+}
+
+/// Brings the modulation sources that follow the keys up to date with the
+/// voices, after anything that might start notes or let them go: key
+/// pressure, and whether the envelope is held.
+pub(crate) fn follow_keys<F: Filter + Params, O: StereoFilter + Params>(engine: &mut Engine<F, O>) {
+    let synth = &engine.mono.synth;
+    let (touch, held) = (synth.key_pressure(), synth.holding());
+    engine.set_source(Source::KeyPressure, touch);
+    engine.set_held(held);
+}
+
+impl Command {
+    fn apply<F: Filter + Params, O: StereoFilter + Params>(self, shim: &mut SDLShim<F, O>) {
+        let engine = &mut shim.engine;
+        match self {
+            Command::NoteOn {
+                note,
+                freq,
+                velocity,
+            } => {
+                engine.note_on(velocity);
+                engine.mono.synth.note_on(note, freq, velocity);
+                follow_keys(engine);
+            }
+            Command::NoteOff(note) => {
+                engine.mono.synth.note_off(note);
+                follow_keys(engine);
+            }
+            Command::Bend(semitones) => engine.mono.synth.set_bend(semitones),
+            Command::ChannelNoteOn {
+                channel,
+                note,
+                freq,
+                velocity,
+            } => {
+                engine.note_on(velocity);
+                engine
+                    .mono
+                    .synth
+                    .channel_note_on(channel, note, freq, velocity);
+                follow_keys(engine);
+            }
+            Command::ChannelNoteOff(channel, note) => {
+                engine.mono.synth.channel_note_off(channel, note);
+                follow_keys(engine);
+            }
+            Command::PerNote(channel, change) => engine.mono.synth.set_per_note(channel, change),
+            Command::KeyPressure(note, pressure) => {
+                engine.mono.synth.set_key_pressure(note, pressure);
+                follow_keys(engine);
+            }
+            Command::ModSource(source, value) => engine.set_source(source, value),
+            Command::SetParam(name, value) => {
+                engine.set_param(&name, value);
+                // which might be the latch or the sustain pedal letting go
+                follow_keys(engine);
+            }
+            Command::SyncToBeat(beat) => engine.sync_to_beat(beat),
+            Command::ToggleLatch => {
+                let latch = !engine.mono.synth.latch();
+                engine.mono.synth.set_latch(latch);
+                follow_keys(engine);
+            }
+            Command::ReleaseAll => {
+                engine.release_all();
+                engine.mono.synth.release_all();
+            }
+            // the old one's queue stays alive on the other end, so this
+            // doesn't free anything
+            Command::SetTap(new) => shim.tap = new,
+            Command::FadeOut => shim.fade.set_over(0., shim.fade_samples),
+            Command::Swap(new) => match new.downcast::<Generation<F, O>>() {
+                Ok(new) => shim.swap(new),
+                Err(_) => unreachable!("an engine of another type"),
+            },
+        }
+    }
+
+    /// Writes down the command as sent at `at`.
+    fn log(&self, at: Stamp, log: &EventLog) {
+        let number = |n: u8| Field::Number(n as f32);
+        match *self {
+            Command::NoteOn {
+                note,
+                freq,
+                velocity,
+            } => log.log(
+                at,
+                "note_on",
+                &[
+                    ("note", note.map_or(Field::Null, number)),
+                    ("freq", Field::Number(freq)),
+                    ("velocity", Field::Number(velocity)),
+                ],
+            ),
+            Command::NoteOff(note) => log.log(at, "note_off", &[("note", number(note))]),
+            Command::Bend(semitones) => {
+                log.log(at, "bend", &[("semitones", Field::Number(semitones))])
+            }
+            Command::ChannelNoteOn {
+                channel,
+                note,
+                freq,
+                velocity,
+            } => log.log(
+                at,
+                "note_on",
+                &[
+                    ("channel", number(channel)),
+                    ("note", number(note)),
+                    ("freq", Field::Number(freq)),
+                    ("velocity", Field::Number(velocity)),
+                ],
+            ),
+            Command::ChannelNoteOff(channel, note) => log.log(
+                at,
+                "note_off",
+                &[("channel", number(channel)), ("note", number(note))],
+            ),
+            Command::PerNote(channel, change) => {
+                let (name, value) = match change {
+                    PerNote::Bend(v) => ("bend", v),
+                    PerNote::Pressure(v) => ("pressure", v),
+                    PerNote::Timbre(v) => ("timbre", v),
+                };
+                log.log(
+                    at,
+                    "per_note",
+                    &[("channel", number(channel)), (name, Field::Number(value))],
+                );
+            }
+            Command::KeyPressure(note, pressure) => log.log(
+                at,
+                "key_pressure",
+                &[
+                    ("note", number(note)),
+                    ("pressure", Field::Number(pressure)),
+                ],
+            ),
+            Command::ModSource(source, value) => log.log(
+                at,
+                "mod_source",
+                &[
+                    ("source", Field::Text(&source.to_string())),
+                    ("value", Field::Number(value)),
+                ],
+            ),
+            Command::SetParam(ref name, value) => log.log(
+                at,
+                "param",
+                &[("name", Field::Text(name)), ("value", Field::Number(value))],
+            ),
+            Command::SyncToBeat(beat) => {
+                log.log(at, "sync", &[("beat", Field::Number(beat as f32))])
+            }
+            Command::ToggleLatch => log.log(at, "latch", &[]),
+            Command::ReleaseAll => log.log(at, "release_all", &[]),
+            Command::SetTap(ref tap) => {
+                log.log(at, "recording", &[("on", Field::Flag(tap.is_some()))])
+            }
+            Command::FadeOut => log.log(at, "fade_out", &[]),
+            Command::Swap(_) => log.log(at, "rebuild", &[]),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Timed {
+    at: Stamp,
+    command: Command,
+}
+
+struct SDLShim<F: Filter, O: StereoFilter> {
+    engine: Engine<F, O>,
+    commands: Consumer<Timed>,
+    snapshots: Arc<Snapshots>,
+    report: Reporter,
+    /// what blocks are stamped with when they start
+    clock: EngineClock,
+    /// when the previous callback started
+    last_block: Option<Stamp>,
+    /// each channel of the block, before they're interleaved for SDL
+    left: Vec<f32>,
+    right: Vec<f32>,
+    /// where they go in the device's channels, of which there are
+    /// `channels`
+    outputs: OutputMap,
+    channels: usize,
+    /// where the click comes from when it has an output of its own, and
+    /// its block
+    click_out: Option<Bus>,
+    click: Vec<f32>,
+    /// where the output is being recorded, if it is
+    tap: Option<Tap>,
+    watchdog: Option<Watchdog>,
+    /// gain on the output, which ramps up at the start and down at the
+    /// end so the speakers don't pop
+    fade: Smoothed,
+    fade_samples: u32,
+    /// what this system was calibrated to, after anything recorded
+    output_gain: f32,
+    /// the engine being faded out after a patch change, which holds what
+    /// `engine` replaced
+    outgoing: Option<Box<Generation<F, O>>>,
+    crossfade: Smoothed,
+    /// where finished generations go to be freed off the audio thread
+    retired: Option<Producer<Box<dyn Any + Send>>>,
+}
+
+impl<F: Filter + Params, O: StereoFilter + Params> SDLShim<F, O> {
+    fn new(
+        engine: Engine<F, O>,
+        commands: Consumer<Timed>,
+        snapshots: Arc<Snapshots>,
+        report: Reporter,
+        clock: EngineClock,
+    ) -> Self {
+        Self {
+            engine,
+            commands,
+            snapshots,
+            report,
+            clock,
+            last_block: None,
+            left: Vec::new(),
+            right: Vec::new(),
+            outputs: OutputMap::default(),
+            channels: 2,
+            click_out: None,
+            click: Vec::new(),
+            tap: None,
+            watchdog: None,
+            fade: Smoothed::new(1.),
+            fade_samples: 0,
+            output_gain: 1.,
+            outgoing: None,
+            crossfade: Smoothed::new(1.),
+            retired: None,
+        }
+    }
+
+    /// Puts `new` in place of the engine, crossfading from the old one.
+    fn swap(&mut self, mut new: Box<Generation<F, O>>) {
+        // one that's still fading out from the last swap just stops
+        self.retire();
+        std::mem::swap(&mut self.engine, &mut new.engine);
+        std::mem::swap(&mut self.watchdog, &mut new.watchdog);
+        self.outgoing = Some(new);
+        self.crossfade.reset(0.);
+        self.crossfade.set_over(1., CROSSFADE_SAMPLES);
+    }
+
+    fn retire(&mut self) {
+        let Some(old) = self.outgoing.take() else {
+            return;
+        };
+        if let Some(retired) = &mut self.retired {
+            if let Err(old) = retired.push(old) {
+                // freeing it here beats keeping it running
+                drop(old);
+            }
+        }
+    }
+
+    /// Mixes the outgoing engine's take on the block into it, if there is
+    /// one.
+    fn crossfade(&mut self, left: &mut [f32], right: &mut [f32]) {
+        let Some(old) = &mut self.outgoing else {
+            return;
+        };
+        let Generation {
+            engine,
+            scratch: [old_left, old_right],
+            ..
+        } = &mut **old;
+        // only when the callback is asking for more than usual
+        old_left.resize(old_left.len().max(left.len()), 0.);
+        old_right.resize(old_right.len().max(right.len()), 0.);
+        let (ol, or) = (&mut old_left[..left.len()], &mut old_right[..right.len()]);
+        ol.fill(0.);
+        or.fill(0.);
+        if catch_stereo(ol, or, |l, r| engine.process_stereo(l, r)).is_err() {
+            ol.fill(0.);
+            or.fill(0.);
+        }
+        for i in 0..left.len() {
+            let mix = self.crossfade.next_value();
+            left[i] = left[i] * mix + ol[i] * (1. - mix);
+            right[i] = right[i] * mix + or[i] * (1. - mix);
+        }
+        if !self.crossfade.is_ramping() {
+            self.retire();
+        }
+    }
+
+    /// Starts silent, fading in over `samples`, which fading out takes too.
+    fn fade_in(&mut self, samples: u32) {
+        self.fade_samples = samples;
+        self.fade.reset(0.);
+        self.fade.set_over(1., samples);
+    }
 
-impl<T: Filter + Send> AudioCallback for SDLShim<T> {
+    /// Renders the block the audio device is asking for now.
+    fn block(&mut self, left: &mut [f32], right: &mut [f32]) {
+        let started = Instant::now();
+        let now = self.clock.stamp(started);
+        let block_start = self.last_block.replace(now).unwrap_or(now);
+        if self.click_out.is_some() {
+            self.click.resize(left.len(), 0.);
+        }
+        self.render(left, right, block_start);
+        if let Some(watchdog) = &mut self.watchdog {
+            watchdog.check(left.len(), started.elapsed());
+        }
+        if self.fade.is_ramping() || self.fade.value() != 1. {
+            for (i, (l, r)) in left.iter_mut().zip(right.iter_mut()).enumerate() {
+                let gain = self.fade.next_value();
+                *l *= gain;
+                *r *= gain;
+                if let Some(click) = self.click.get_mut(i) {
+                    *click *= gain;
+                }
+            }
+        }
+        if let Some(tap) = &mut self.tap {
+            tap.write(left, right);
+        }
+        if self.output_gain != 1. {
+            for s in left
+                .iter_mut()
+                .chain(right.iter_mut())
+                .chain(&mut self.click)
+            {
+                *s *= self.output_gain;
+            }
+        }
+        self.snapshots
+            .publish_if_requested(|| Snapshot::of(&self.engine));
+    }
+
+    /// Renders a block, applying each command as many samples in as it
+    /// happened after `block_start`. Ones from after the block wait for the
+    /// next.
+    fn render(&mut self, left: &mut [f32], right: &mut [f32], block_start: Stamp) {
+        let len = left.len();
+        let mut done = 0;
+        loop {
+            let due = self
+                .commands
+                .peek()
+                .map(|timed| (timed.at.samples_since(block_start) as usize).max(done));
+            let until = due.unwrap_or(len).min(len);
+            if until > done {
+                // the nodes guard themselves, this is the last line of defense
+                let engine = &mut self.engine;
+                let (l, r) = (&mut left[done..until], &mut right[done..until]);
+                if let Err(message) = catch_stereo(l, r, |l, r| engine.process_stereo(l, r)) {
+                    (self.report)(EngineError {
+                        node: "engine".to_string(),
+                        message,
+                        bypassed: false,
+                    });
+                }
+                // before the outgoing engine's click can get in
+                if let Some(bus) = &self.click_out {
+                    bus.take(&mut self.click[done..until]);
+                }
+                self.crossfade(&mut left[done..until], &mut right[done..until]);
+                done = until;
+            }
+            if done == len {
+                break;
+            }
+            if let Some(timed) = self.commands.pop() {
+                timed.command.apply(self);
+            }
+        }
+    }
+}
+
+impl<F: Filter + Params, O: StereoFilter + Params> AudioCallback for SDLShim<F, O> {
     type Channel = f32;
 
     fn callback(&mut self, samples: &mut [Self::Channel]) {
-        self.0.process(samples);
+        // only allocates if SDL hands us a bigger block than before
+        let frames = samples.len() / self.channels;
+        let (mut left, mut right) = (
+            std::mem::take(&mut self.left),
+            std::mem::take(&mut self.right),
+        );
+        left.resize(frames, 0.);
+        right.resize(frames, 0.);
+        self.block(&mut left, &mut right);
+        self.outputs
+            .interleave(samples, self.channels, &left, &right, &self.click);
+        (self.left, self.right) = (left, right);
+    }
+}
+
+/// The control side of the engine, which sends it [`Command`]s rather than
+/// locking the audio device. Its parameters are the engine's, so CC maps
+/// and the like can set them, but their values are only known to the engine
+/// itself; take a snapshot for those.
+struct EngineHandle {
+    commands: Producer<Timed>,
+    params: Vec<ParamInfo>,
+    /// shared with the commands, so the callback never frees a name
+    names: HashMap<String, Arc<str>>,
+    /// when parameter changes made through [`Params`] happened
+    at: Stamp,
+    /// what commands that happen here and now are stamped with
+    clock: EngineClock,
+    /// where every command gets written down, if anywhere
+    log: Option<EventLog>,
+}
+
+impl EngineHandle {
+    fn new(commands: Producer<Timed>, engine: &impl Params, clock: EngineClock) -> Self {
+        let mut handle = Self {
+            commands,
+            params: Vec::new(),
+            names: HashMap::new(),
+            at: clock.now(),
+            clock,
+            log: None,
+        };
+        handle.rebuilt(engine);
+        handle
+    }
+
+    fn now(&self) -> Stamp {
+        self.clock.now()
+    }
+
+    /// Takes on the parameters of an engine built to replace the old one.
+    fn rebuilt(&mut self, engine: &impl Params) {
+        self.params = engine.params();
+        self.names = self
+            .params
+            .iter()
+            .map(|p| (p.name.clone(), Arc::from(p.name.as_str())))
+            .collect();
+    }
+
+    fn send(&mut self, at: Stamp, command: Command) {
+        if let Some(log) = &self.log {
+            command.log(at, log);
+        }
+        if let Err(timed) = self.commands.push(Timed { at, command }) {
+            println!("audio queue full, dropping {:?}", timed.command);
+        }
+    }
+}
+
+impl Params for EngineHandle {
+    fn params(&self) -> Vec<ParamInfo> {
+        self.params.clone()
+    }
+
+    fn get_param(&self, _name: &str) -> Option<f32> {
+        None
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        let Some(name) = self.names.get(name).cloned() else {
+            return false;
+        };
+        self.send(self.at, Command::SetParam(name, value));
+        true
+    }
+}
+
+/// The effects between the voices and the stereo end of the chain, which
+/// can go in any order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    Distortion,
+    Ladder,
+    Fir,
+    Delay,
+    Reverb,
+    Ir,
+}
+
+impl Stage {
+    /// In the order they go in without being told otherwise.
+    pub const ALL: [Stage; 6] = [
+        Stage::Distortion,
+        Stage::Ladder,
+        Stage::Fir,
+        Stage::Delay,
+        Stage::Reverb,
+        Stage::Ir,
+    ];
+
+    /// Also what their parameters start with.
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Distortion => "distortion",
+            Stage::Ladder => "ladder",
+            Stage::Fir => "fir",
+            Stage::Delay => "delay",
+            Stage::Reverb => "reverb",
+            Stage::Ir => "ir",
+        }
+    }
+}
+
+/// Another effect in the chain besides the one of each [`Stage`], set up
+/// from its own text rather than from options, so there can be any number
+/// of them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Extra {
+    Distortion(Curve),
+    /// at this cutoff
+    Ladder(f32),
+    Delay(DelayTime),
+    Reverb,
+    Tone,
+    Compressor,
+}
+
+impl Extra {
+    /// Also what their parameters start with, after a number saying which
+    /// of that kind it is.
+    pub fn name(self) -> &'static str {
+        match self {
+            Extra::Distortion(_) => "distortion",
+            Extra::Ladder(_) => "ladder",
+            Extra::Delay(_) => "delay",
+            Extra::Reverb => "reverb",
+            Extra::Tone => "tone",
+            Extra::Compressor => "compressor",
+        }
+    }
+
+    fn build(self, bpm: f32) -> Box<dyn Effect> {
+        match self {
+            Extra::Distortion(curve) => Box::new(Waveshaper::new(curve, 4)),
+            Extra::Ladder(cutoff) => Box::new(Ladder::new(cutoff, 0.)),
+            Extra::Delay(time) => Box::new(FeedbackDelay::new(time, bpm, 0.4, 0.3)),
+            Extra::Reverb => Box::new(Reverb::default()),
+            Extra::Tone => Box::new(ToneStack::default()),
+            Extra::Compressor => Box::new(Compressor::default()),
+        }
+    }
+}
+
+/// "distortion:<curve>", "ladder:<cutoff>", "delay:<time>", "reverb",
+/// "tone" or "compressor". The ones that take something take it the way
+/// their options do.
+impl std::str::FromStr for Extra {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        let (kind, arg) = match value.split_once(':') {
+            Some((kind, arg)) => (kind.trim(), Some(arg.trim())),
+            None => (value.trim(), None),
+        };
+        Ok(match (kind, arg) {
+            ("distortion", Some(curve)) => Extra::Distortion(curve.parse()?),
+            ("ladder", Some(cutoff)) => Extra::Ladder(
+                cutoff
+                    .parse()
+                    .ok()
+                    .filter(|hz: &f32| *hz > 0.)
+                    .ok_or_else(|| format!("invalid cutoff {cutoff:?}"))?,
+            ),
+            ("delay", Some(time)) => Extra::Delay(time.parse()?),
+            ("reverb", None) => Extra::Reverb,
+            ("tone", None) => Extra::Tone,
+            ("compressor", None) => Extra::Compressor,
+            ("distortion" | "ladder" | "delay", None) => {
+                return Err(format!("{kind} needs setting up, like +{kind}:<what>"))
+            }
+            ("reverb" | "tone" | "compressor", Some(_)) => {
+                return Err(format!("{kind} doesn't take anything"))
+            }
+            _ => {
+                return Err(format!(
+                    "can't add another {kind:?}, expected distortion, ladder, delay, reverb, tone or compressor"
+                ))
+            }
+        })
+    }
+}
+
+/// One place in the chain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Slot {
+    Stage(Stage),
+    Extra(Extra),
+}
+
+/// The order the effects after the voices run in. Every [`Stage`] is in it
+/// once, with any [`Extra`]s between them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Order(pub Vec<Slot>);
+
+impl Default for Order {
+    fn default() -> Self {
+        Self(Stage::ALL.map(Slot::Stage).to_vec())
+    }
+}
+
+/// Stage names separated by commas, like "ladder, distortion", and extras
+/// as "+" and how [`Extra`] reads them, like "ladder, +ladder:400". Any
+/// stages left out go after, in their usual order.
+impl std::str::FromStr for Order {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        let mut order = Vec::new();
+        for name in value.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            if let Some(extra) = name.strip_prefix('+') {
+                order.push(Slot::Extra(extra.parse()?));
+                continue;
+            }
+            let stage = Stage::ALL
+                .into_iter()
+                .find(|s| s.name() == name)
+                .ok_or_else(|| {
+                    let names: Vec<_> = Stage::ALL.iter().map(|s| s.name()).collect();
+                    format!(
+                        "unknown effect {name:?}, expected one of {}, or +<effect> for another",
+                        names.join(", ")
+                    )
+                })?;
+            if order.contains(&Slot::Stage(stage)) {
+                return Err(format!("{name} is in there twice, +{name} adds another"));
+            }
+            order.push(Slot::Stage(stage));
+        }
+        for stage in Stage::ALL {
+            if !order.contains(&Slot::Stage(stage)) {
+                order.push(Slot::Stage(stage));
+            }
+        }
+        Ok(Self(order))
+    }
+}
+
+/// Settings for the audio thread that come from the command line.
+pub struct AudioConfig {
+    /// Pitch bend range in semitones each way.
+    pub bend_range: f32,
+    /// The voices to play notes on, which are all the same kind.
+    pub voices: Vec<Box<dyn DynVoice>>,
+    pub cc_map: CcMap,
+    /// Lowest of the MIDI notes that switch articulations instead of
+    /// playing, one per entry of [`Articulation::ALL`].
+    pub key_switch_base: Option<u8>,
+    /// MPE member channels to start with, below channel 1 as the master.
+    /// Controllers can set up zones of their own either way.
+    pub mpe: u8,
+    /// What each MIDI note plays.
+    pub tuning: Tuning,
+    pub strum: Option<StrumConfig>,
+    /// A test signal to add to the voices, if there is one.
+    pub siggen: Option<Siggen>,
+    pub distortion: Option<Waveshaper>,
+    /// A compressor after the effects rack, listening to something other
+    /// than what it turns down.
+    pub sidechain: Option<Key>,
+    /// Cutoff of the ladder filter after the voices, if there is one.
+    pub ladder: Option<f32>,
+    /// Notes to play along with each one played.
+    pub chord: Option<Chord>,
+    /// The key to pull notes played into.
+    pub scale: Option<Scale>,
+    /// Where channel aftertouch goes.
+    pub pressure: Option<PressureConfig>,
+    /// Where polyphonic aftertouch goes.
+    pub key_pressure: KeyPressure,
+    /// The only voice to hear, by index, when listening to one at a time.
+    pub solo: Option<usize>,
+    /// Whether each voice gets a snoop tap of its own.
+    pub tap_voices: bool,
+    /// How the expression pedal reads, and where it goes besides the mod
+    /// matrix.
+    pub expression: ExpressionConfig,
+    /// A filter designed outside the program, if there is one.
+    pub fir: Option<FIR>,
+    /// Echo time, if there is an echo.
+    pub delay: Option<DelayTime>,
+    /// echoes that bounce between the sides, after the panner
+    pub ping_pong: Option<DelayTime>,
+    /// Tempo for anything synced to it, in beats per minute.
+    pub bpm: f32,
+    /// LFOs, and the parameters they move.
+    pub lfos: Vec<LfoConfig>,
+    /// The rest of the modulation matrix.
+    pub mods: Vec<ModConfig>,
+    pub reverb: bool,
+    pub convolution: Option<ConvolutionReverb>,
+    /// The order the effects after the voices go in.
+    pub order: Order,
+    /// Whether the compressor on the output is a brickwall limiter.
+    pub limiter: bool,
+    /// Parameter values to start with, from a saved session or a patch.
+    pub restore: Vec<(String, f32)>,
+    /// Where to save everything played, as a MIDI file.
+    pub record: Option<PathBuf>,
+    /// Where to record the output to. Without it, each recording gets a
+    /// new name in the current directory.
+    pub record_wav: Option<PathBuf>,
+    /// Whether a recording of the output is going, once it's started
+    /// properly, for the window to show.
+    pub recording: Arc<AtomicBool>,
+    /// Whether the watchdog takes out the slowest effect when the callback
+    /// can't keep up, rather than only saying so.
+    pub shed: bool,
+    /// How long the output takes to fade in at the start and out at the
+    /// end.
+    pub fade: Duration,
+    /// What the output is multiplied by on its way to the speakers, from
+    /// `synthtoy calibrate`. Recordings are made before it.
+    pub output_gain: f32,
+    /// Where in the session it is, to stamp recordings with.
+    pub clock: Clock,
+    /// Where to write down everything the engine does, if anywhere.
+    pub log: Option<EventLog>,
+    /// The step sequencer's patterns to start with.
+    pub patterns: Vec<Pattern>,
+    /// Where each pattern came from, in the same order, to save it back to.
+    pub sequences: Vec<PathBuf>,
+    /// The patterns chained into a song.
+    pub song: Song,
+    /// Where to save the session every [`AUTOSAVE_PERIOD`] and on the way
+    /// out, if anywhere.
+    pub session: Option<PathBuf>,
+    /// The patch file to pick up changes to while playing, if there is
+    /// one.
+    pub watch: Option<Watcher>,
+    /// The sources and effects as patch entries, for the console to change
+    /// and build again from.
+    pub nodes: Vec<Entry>,
+    /// Where the click goes instead of the mix, when it has an output of
+    /// its own.
+    pub click_out: Option<Bus>,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self {
+            bend_range: 2.,
+            voices: SynthKind::String
+                .build_voices(8, &Noise::default(), DEFAULT_SEED)
+                .unwrap(),
+            cc_map: CcMap::general_midi(),
+            key_switch_base: None,
+            mpe: 0,
+            tuning: Tuning::default(),
+            strum: None,
+            chord: None,
+            scale: None,
+            siggen: None,
+            sidechain: None,
+            distortion: None,
+            ladder: None,
+            pressure: None,
+            key_pressure: KeyPressure::default(),
+            solo: None,
+            tap_voices: false,
+            expression: ExpressionConfig::default(),
+            fir: None,
+            delay: None,
+            ping_pong: None,
+            bpm: 120.,
+            lfos: Vec::new(),
+            mods: Vec::new(),
+            reverb: false,
+            convolution: None,
+            order: Order::default(),
+            limiter: false,
+            restore: Vec::new(),
+            record: None,
+            record_wav: None,
+            recording: Arc::default(),
+            shed: false,
+            fade: Duration::from_millis(10),
+            watch: None,
+            nodes: Vec::new(),
+            click_out: None,
+            output_gain: 1.,
+            clock: Clock::new(Instant::now(), 120.),
+            log: None,
+            patterns: Vec::new(),
+            sequences: Vec::new(),
+            song: Song::default(),
+            session: None,
+        }
     }
 }
 
@@ -29,54 +1019,1261 @@ pub struct AudioSubsystemCrimesWrapper(pub sdl2::AudioSubsystem);
 // SAFETY: crimes!
 unsafe impl Send for AudioSubsystemCrimesWrapper {}
 
-pub fn audio_thread(audio: AudioSubsystemCrimesWrapper, audio_recv: mpsc::Receiver<AudioEvent>) {
-    let audio = audio.0;
+/// Where the sound goes.
+pub enum Backend {
+    /// An output device, or the default one without a name.
+    Sdl {
+        audio: AudioSubsystemCrimesWrapper,
+        device: Option<String>,
+        outputs: OutputMap,
+    },
+    /// Ports on a JACK server, plus a MIDI input port if there's somewhere
+    /// to send what comes in on it.
+    #[cfg(feature = "jack")]
+    Jack {
+        midi: Option<queue::Sender<AudioEvent>>,
+    },
+}
+
+pub(crate) fn key_switch(base: Option<u8>, note: u8) -> Option<Articulation> {
+    let idx = note.checked_sub(base?)?;
+    Articulation::ALL.get(idx as usize).copied()
+}
+
+/// An optional effect for the chain, with its parameters under `name.`.
+fn effect<F>(
+    name: &'static str,
+    filter: Option<F>,
+    guards: &mut Guards,
+) -> Guarded<Named<Option<F>>> {
+    guards.guard(name, Named::new(name, filter))
+}
 
-    let freq_curve = move |x: f32| {
-        if x <= 1000. {
-            1.
-        } else {
-            0.
+/// Puts together the voices and effects `config` asks for, taking them out
+/// of it, and sets the parameters it starts with.
+pub(crate) fn build_engine(
+    config: &mut AudioConfig,
+    guards: &mut Guards,
+) -> Engine<impl Filter + Params, impl StereoFilter + Params> {
+    let mut ladder = config.ladder.map(|cutoff| Ladder::new(cutoff, 0.));
+    let mut delay = config
+        .delay
+        .map(|time| FeedbackDelay::new(time, config.bpm, 0.4, 0.3));
+    let mut reverb = config.reverb.then(Reverb::default);
+    // keeps mashing lots of keys from clipping
+    let compressor = if config.limiter {
+        Compressor::limiter(-0.3)
+    } else {
+        Compressor::default()
+    };
+    // not one for the watchdog, since without them there's nothing to hear
+    let mut voices = VoiceManager::new(std::mem::take(&mut config.voices));
+    voices.set_key_pressure_target(config.key_pressure);
+    voices.set_solo(config.solo);
+    if config.tap_voices {
+        voices.tap_voices();
+    }
+    let key_bus = Bus::default();
+    if let Some(Key::Below(note)) = config.sidechain {
+        voices.set_key_bus(note, key_bus.clone());
+    }
+    let voices = Guarded::new(voices, guards.report.clone());
+    let mut rack = Rack::default();
+    // the second of a kind is "<kind>2", counting the one that's always
+    // there, used or not
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for &slot in &config.order.0 {
+        let stage = match slot {
+            Slot::Stage(stage) => stage,
+            Slot::Extra(extra) => {
+                let count = counts.entry(extra.name()).or_insert(1);
+                *count += 1;
+                let name = format!("{}{count}", extra.name());
+                let named = Named::new(name.clone(), extra.build(config.bpm));
+                rack.add(guards.guard(&name, named));
+                continue;
+            }
+        };
+        let name = stage.name();
+        match stage {
+            Stage::Distortion => rack.add(effect(name, config.distortion.take(), guards)),
+            Stage::Ladder => rack.add(effect(name, ladder.take(), guards)),
+            Stage::Fir => rack.add(effect(name, config.fir.take(), guards)),
+            Stage::Delay => rack.add(effect(name, delay.take(), guards)),
+            Stage::Reverb => rack.add(effect(name, reverb.take(), guards)),
+            Stage::Ir => rack.add(effect(name, config.convolution.take(), guards)),
         }
+    }
+    // in the rack too, so `dry` goes round them with the rest
+    rack.add(effect(
+        "sidechain",
+        config
+            .sidechain
+            .map(|key| key.sidechain(config.bpm, &key_bus)),
+        guards,
+    ));
+    rack.add(effect("dc", Some(DcBlocker::default()), guards));
+    let synth = SynthBuilder::new(voices)
+        .chain(effect("siggen", config.siggen.take(), guards))
+        .chain(rack)
+        .build();
+    let mut metronome = Metronome::default();
+    metronome.out = config.click_out.clone();
+    let engine = Stereo::new(synth)
+        .chain(effect("haas", Some(Haas::new(0., 0.)), guards))
+        .chain(effect("width", Some(Width::default()), guards))
+        .chain(effect("pan", Some(Panner::default()), guards))
+        .chain(effect(
+            "ping_pong",
+            config
+                .ping_pong
+                .map(|time| PingPongDelay::new(time, config.bpm, 0.4, 0.3)),
+            guards,
+        ))
+        .chain(effect(
+            "tone",
+            Some(DualMono::new(ToneStack::default(), ToneStack::default())),
+            guards,
+        ))
+        .chain(effect("compressor", Some(compressor), guards))
+        .dry_switch()
+        .chain(effect("metronome", Some(metronome), guards));
+    let mut engine = Modulated::new(engine, &config.lfos, &config.mods, config.bpm);
+    for (name, value) in &config.restore {
+        if !engine.set_param(name, *value) {
+            println!("no parameter {name} to set");
+        }
+    }
+    engine
+}
+
+pub fn audio_thread(
+    backend: Backend,
+    mut config: AudioConfig,
+    snapshots: Arc<Snapshots>,
+    report: Reporter,
+    audio_recv: queue::Receiver<AudioEvent>,
+) {
+    // things going wrong get written down along with everything else
+    let report: Reporter = match config.log.clone() {
+        Some(log) => Arc::new(move |e| {
+            log.error(&e);
+            report(e);
+        }),
+        None => report,
+    };
+    // JACK only has the two ports, so the click stays in the mix there
+    config.click_out = match &backend {
+        Backend::Sdl { outputs, .. } => outputs.click.map(|_| Bus::default()),
+        #[cfg(feature = "jack")]
+        Backend::Jack { .. } => None,
     };
+    let mut guards = Guards::new(report.clone());
+    let synth = build_engine(&mut config, &mut guards);
 
-    let spec = AudioSpecDesired {
-        freq: Some(SAMPLING_FREQ as i32),
-        channels: Some(1),
-        samples: Some(256),
+    let (commands, consumer) = spsc::channel(COMMAND_QUEUE_LEN);
+    let clock = config.clock.engine();
+    let mut engine = EngineHandle::new(commands, &synth, clock);
+    engine.log = config.log.clone();
+    let mut shim = SDLShim::new(synth, consumer, snapshots.clone(), report.clone(), clock);
+    shim.click_out = config.click_out.clone();
+    let mut watchdog = Watchdog::new(guards.loads, config.shed, report.clone());
+    watchdog.log = config.log.clone();
+    shim.watchdog = Some(watchdog);
+    let (retired, mut graveyard) = spsc::channel(4);
+    shim.retired = Some(retired);
+    shim.fade_in((config.fade.as_secs_f32() * SAMPLING_FREQ as f32) as u32);
+    shim.output_gain = config.output_gain;
+    // keeps the output going until we return
+    let _output: Box<dyn Any> = match backend {
+        Backend::Sdl {
+            audio,
+            device,
+            outputs,
+        } => {
+            let spec = AudioSpecDesired {
+                freq: Some(SAMPLING_FREQ as i32),
+                channels: Some(outputs.channels() as u8),
+                samples: Some(256),
+            };
+            let mut shim = shim;
+            let dev = audio
+                .0
+                .open_playback(device.as_deref(), &spec, |spec| {
+                    shim.outputs = outputs;
+                    shim.channels = spec.channels as usize;
+                    shim
+                })
+                .unwrap();
+            dev.resume();
+            Box::new(dev)
+        }
+        #[cfg(feature = "jack")]
+        Backend::Jack { midi } => {
+            let mut shim = shim;
+            match crate::jack::open(move |left, right| shim.block(left, right), midi, clock) {
+                Ok(client) => Box::new(client),
+                Err(e) => {
+                    println!("jack: {e}");
+                    return;
+                }
+            }
+        }
     };
 
-    let synth = SynthBuilder::new(StringSynth::new(500))
-        // .chain(NoopFilter)
-        // FIXME: why does this make a bump on startup?
-        // .chain(FIR::new(25, freq_curve))
-        .build();
+    let mut harmonizer = Harmonizer::new(config.chord.clone(), config.scale.clone());
+    let mut strummer = config.strum.clone().map(Strummer::new);
+    let mut strummed = Vec::new();
+    let mut pressure = config.pressure.clone().map(Pressure::new);
+    let mut expression = Expression::new(config.expression.clone());
+    let mut recorder = config
+        .record
+        .clone()
+        .map(|path| Recorder::new(path, config.clock.clone()));
+    let mut recording: Option<Recording> = None;
+    let mut sequencer = Sequencer::new(
+        std::mem::take(&mut config.patterns),
+        std::mem::take(&mut config.song),
+        config.clock.clone(),
+    );
+    let mut sequenced = Vec::new();
+    let mut midi_clock = MidiClock::default();
+    let mut beats = Beats::default();
+    let mut watch = config.watch.take();
+    let mut toggles = Toggles::default();
+    let mut zones = Zones::new(config.mpe);
+    let mut explorer = Explorer::new(unix_secs() as u32);
+    let saver = config
+        .session
+        .clone()
+        .map(|path| Saver::spawn(path, snapshots.clone()));
+    let mut next_save = Instant::now() + AUTOSAVE_PERIOD;
+    // the parameters come from a snapshot when it's written
+    let session = |config: &AudioConfig, sequencer: &Sequencer| Session {
+        nodes: config.nodes.clone(),
+        params: Vec::new(),
+        lfos: config.lfos.clone(),
+        mods: config.mods.clone(),
+        patterns: sequencer.patterns.clone(),
+        song: sequencer.song.clone(),
+    };
 
-    let mut dev = audio
-        .open_playback(None, &spec, |_spec| SDLShim(synth))
-        .unwrap();
+    loop {
+        let deadline = [
+            strummer.as_ref().and_then(Strummer::next_deadline),
+            pressure.as_ref().and_then(Pressure::next_deadline),
+            sequencer.next_deadline(),
+            beats.next_deadline(),
+            watch.as_ref().map(|w| clock.stamp(w.next_deadline())),
+            saver.as_ref().map(|_| clock.stamp(next_save)),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let event = match deadline {
+            Some(deadline) => {
+                let timeout = clock
+                    .instant(deadline)
+                    .saturating_duration_since(Instant::now());
+                match audio_recv.recv_timeout(timeout) {
+                    Ok(event) => Some(event),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            None => Some(audio_recv.recv().unwrap()),
+        };
+        let overflowed = audio_recv.take_overflowed();
+        if !overflowed.is_empty() {
+            println!(
+                "queue: full, {} events dropped and {} controller moves merged",
+                overflowed.dropped, overflowed.coalesced
+            );
+            if let Some(log) = &config.log {
+                log.log(
+                    clock.now(),
+                    "overflow",
+                    &[
+                        ("dropped", Field::Number(overflowed.dropped as f32)),
+                        ("coalesced", Field::Number(overflowed.coalesced as f32)),
+                    ],
+                );
+            }
+        }
 
-    dev.resume();
+        if let (Some(recorder), Some(event)) = (&mut recorder, &event) {
+            record(recorder, event);
+        }
 
-    loop {
-        match audio_recv.recv().unwrap() {
-            AudioEvent::Midi(MidiEvent { inner, .. }) => match inner {
-                MidiEventInner::Down { velocity: _, note } => {
-                    let freq = note::midi_note_to_freq(note);
-                    let mut lock = dev.lock();
-                    let synth = &mut lock.0.synth;
-                    synth.tune(freq);
-                    synth.trigger_count = 50;
+        // notes from MPE member channels are played here and go no further
+        let event = match event {
+            Some(AudioEvent::Midi(ref midi)) => {
+                let (channel, at) = (midi.channel & 15, midi.at);
+                match zones.route(midi) {
+                    Route::Normal => event,
+                    Route::Configured => None,
+                    Route::NoteOn { velocity, note } => {
+                        // keys the tuning leaves out don't play
+                        if let Some(freq) = config.tuning.freq(note) {
+                            let command = Command::ChannelNoteOn {
+                                channel,
+                                note,
+                                freq,
+                                velocity: velocity as f32 / 127.,
+                            };
+                            engine.send(at, command);
+                        }
+                        None
+                    }
+                    Route::NoteOff(note) => {
+                        engine.send(at, Command::ChannelNoteOff(channel, note));
+                        None
+                    }
+                    Route::PerNote(change) => {
+                        engine.send(at, Command::PerNote(channel, change));
+                        None
+                    }
+                }
+            }
+            event => event,
+        };
+
+        match event {
+            Some(AudioEvent::Midi(MidiEvent { inner, at, .. })) => match inner {
+                MidiEventInner::Down { velocity: 0, note } | MidiEventInner::Up { note, .. } => {
+                    for note in harmonizer.note_off(note) {
+                        let deferred = strummer.as_mut().is_some_and(|s| s.note_off(note));
+                        if !deferred {
+                            engine.send(at, Command::NoteOff(note));
+                        }
+                    }
+                }
+                MidiEventInner::Down { velocity, note } => {
+                    enter_step(&mut sequencer, note, velocity);
+                    if let Some(articulation) = key_switch(config.key_switch_base, note) {
+                        println!("articulation: {articulation:?}");
+                        engine.at = at;
+                        engine.set_param("articulation", articulation.index() as f32);
+                    } else {
+                        for note in harmonizer.note_on(note) {
+                            if let Some(strummer) = &mut strummer {
+                                strummer.note_on(at, note, velocity);
+                            } else if let Some(freq) = config.tuning.freq(note) {
+                                engine.send(
+                                    at,
+                                    Command::NoteOn {
+                                        note: Some(note),
+                                        freq,
+                                        velocity: velocity as f32 / 127.,
+                                    },
+                                );
+                            }
+                        }
+                    }
+                }
+                MidiEventInner::PitchBend(bend) => {
+                    let semitones = midi::pitch_bend_semitones(bend, config.bend_range);
+                    engine.send(at, Command::Bend(semitones));
+                }
+                MidiEventInner::ControlChange { controller, value } => {
+                    engine.at = at;
+                    if controller == MOD_WHEEL_CC {
+                        let wheel = value as f32 / 127.;
+                        engine.send(at, Command::ModSource(Source::Wheel, wheel));
+                    }
+                    let pedal = (controller == EXPRESSION_CC).then(|| expression.set(value));
+                    if let Some(position) = pedal {
+                        engine.send(at, Command::ModSource(Source::Expression, position));
+                    }
+                    match (pedal, &expression.config.target) {
+                        (Some(position), Some(target)) => {
+                            target.apply(position, &mut engine);
+                        }
+                        _ => {
+                            config.cc_map.apply(controller, value, &mut engine);
+                        }
+                    }
+                }
+                MidiEventInner::ChannelPressure(value) => {
+                    let aftertouch = value as f32 / 127.;
+                    engine.send(at, Command::ModSource(Source::Aftertouch, aftertouch));
+                    if let Some(pressure) = &mut pressure {
+                        pressure.set(at, value);
+                    }
+                }
+                MidiEventInner::KeyPressure { key, pressure } => {
+                    for note in harmonizer.held(key) {
+                        engine.send(at, Command::KeyPressure(note, pressure as f32 / 127.));
+                    }
+                }
+                // notes already playing keep the pitch they started at
+                MidiEventInner::Tuning(retuning) => {
+                    config.tuning.apply(&retuning);
+                    println!("mts: retuned {} notes", retuning.0.len());
+                }
+                MidiEventInner::Clock => {
+                    if let Some(position) = midi_clock.tick(at) {
+                        sequencer.follow(position, at, &mut sequenced);
+                        if toggles.click && position.is_multiple_of(TICKS_PER_BEAT) {
+                            engine.at = at;
+                            let beat = position / TICKS_PER_BEAT;
+                            engine.set_param("metronome.click", beat as f32);
+                        }
+                    }
+                    let changed = midi_clock
+                        .bpm()
+                        .filter(|bpm| (bpm - config.clock.bpm()).abs() >= TEMPO_JITTER);
+                    if let Some(bpm) = changed {
+                        engine.at = at;
+                        follow_tempo(bpm, &mut engine, &mut config);
+                    }
+                }
+                // the click follows MIDI clock while it plays
+                MidiEventInner::Start => {
+                    midi_clock.start();
+                    beats.stop();
+                    engine.send(at, Command::SyncToBeat(0.));
+                }
+                MidiEventInner::Continue => {
+                    midi_clock.resume();
+                    beats.stop();
+                }
+                MidiEventInner::SongPosition(sixteenths) => {
+                    midi_clock.seek(sixteenths);
+                    engine.send(at, Command::SyncToBeat(sixteenths as f64 / 4.));
+                }
+                MidiEventInner::Stop => {
+                    midi_clock.stop();
+                    sequencer.stop(&mut sequenced);
+                    if toggles.click {
+                        beats.start(&config.clock, at);
+                    }
                 }
-                _ => {}
             },
-            AudioEvent::PlayNote(freq) => {
-                let mut lock = dev.lock();
-                let synth = &mut lock.0.synth;
-                synth.tune(freq);
-                synth.trigger_count = 50;
+            Some(AudioEvent::Time(change, at)) => {
+                engine.at = at;
+                match change {
+                    TimeChange::Tempo(bpm) => follow_tempo(bpm, &mut engine, &mut config),
+                    TimeChange::Meter(meter) => {
+                        config.clock.set_meter(at, meter);
+                        engine.set_param("metronome.meter", meter as f32);
+                    }
+                }
+                // the beats still to come have moved
+                if toggles.click && !midi_clock.is_playing() {
+                    beats.start(&config.clock, at);
+                }
+            }
+            Some(AudioEvent::PlayNote(freq, velocity, at)) => {
+                let (note, cents) = Pitch::from_freq(freq).nearest();
+                let midi_velocity = (velocity * 127.).round().clamp(1., 127.) as u8;
+                enter_step(&mut sequencer, note.clamp(0, 127) as u8, midi_velocity);
+                // they ring out with no note off, so nothing is held
+                let freqs = if harmonizer.is_active() {
+                    harmonizer
+                        .notes(note)
+                        .into_iter()
+                        .map(|n| Pitch(n as f32 + cents / 100.).freq())
+                        .collect()
+                } else {
+                    vec![freq]
+                };
+                for freq in freqs {
+                    if let Some(freq) = config.tuning.retune(freq) {
+                        engine.send(
+                            at,
+                            Command::NoteOn {
+                                note: None,
+                                freq,
+                                velocity,
+                            },
+                        );
+                    }
+                }
+            }
+            Some(AudioEvent::KeyDown(freq, velocity, at)) => {
+                let (note, cents) = Pitch::from_freq(freq).nearest();
+                let note = note.clamp(0, 127) as u8;
+                let midi_velocity = (velocity * 127.).round().clamp(1., 127.) as u8;
+                enter_step(&mut sequencer, note, midi_velocity);
+                for note in harmonizer.note_on(note) {
+                    let freq = Pitch(note as f32 + cents / 100.).freq();
+                    if let Some(freq) = config.tuning.retune(freq) {
+                        engine.send(
+                            at,
+                            Command::NoteOn {
+                                note: Some(note),
+                                freq,
+                                velocity,
+                            },
+                        );
+                    }
+                }
+            }
+            Some(AudioEvent::KeyUp(freq, at)) => {
+                let (note, _) = Pitch::from_freq(freq).nearest();
+                for note in harmonizer.note_off(note.clamp(0, 127) as u8) {
+                    engine.send(at, Command::NoteOff(note));
+                }
+            }
+            Some(AudioEvent::ToggleLatch) => engine.send(engine.now(), Command::ToggleLatch),
+            Some(AudioEvent::ReleaseAll) => engine.send(engine.now(), Command::ReleaseAll),
+            Some(AudioEvent::LearnExpression) => match expression.toggle_learning() {
+                _ if expression.is_learning() => {
+                    println!("expression: sweep the pedal all the way, then press E again")
+                }
+                Some(learned) => {
+                    println!("expression: learned {learned}, --expression-range {learned} keeps it")
+                }
+                None => println!(
+                    "expression: the pedal didn't move, keeping {}",
+                    expression.config.calibration
+                ),
+            },
+            Some(AudioEvent::SaveRecording) => match &recorder {
+                Some(recorder) => save_recording(recorder),
+                None => println!("record: nowhere to save it without --record"),
+            },
+            Some(AudioEvent::ToggleWavRecording) => match recording.take() {
+                Some(recording) => {
+                    engine.send(engine.now(), Command::SetTap(None));
+                    config.recording.store(false, Ordering::Relaxed);
+                    println!("recording: {}", recording.finish());
+                }
+                None => {
+                    let path = config
+                        .record_wav
+                        .clone()
+                        .unwrap_or_else(|| PathBuf::from(format!("synthtoy-{}.wav", unix_secs())));
+                    match recording::start(path, Some(format!("synthtoy {}", config.clock.now()))) {
+                        Ok((started, tap)) => {
+                            println!("recording: to {}", started.path.display());
+                            engine.send(engine.now(), Command::SetTap(Some(tap)));
+                            config.recording.store(true, Ordering::Relaxed);
+                            recording = Some(started);
+                        }
+                        Err(e) => println!("recording: {e}"),
+                    }
+                }
+            },
+            Some(AudioEvent::ToggleSnoops) => {
+                let messages = if snoop::any_capturing() {
+                    snoop::save_all()
+                } else {
+                    snoop::start_all(unix_secs())
+                };
+                for message in messages {
+                    println!("snoop {message}");
+                }
+            }
+            Some(AudioEvent::ToggleDry) => {
+                toggles.dry = !toggles.dry;
+                engine.at = engine.now();
+                engine.set_param("dry", toggles.dry as u8 as f32);
+                println!(
+                    "{}",
+                    if toggles.dry {
+                        "dry: effects off"
+                    } else {
+                        "dry: effects back on"
+                    }
+                );
+            }
+            Some(AudioEvent::ToggleClick) => {
+                toggles.click = !toggles.click;
+                engine.at = engine.now();
+                engine.set_param("metronome.on", toggles.click as u8 as f32);
+                if toggles.click && !midi_clock.is_playing() {
+                    beats.start(&config.clock, engine.at);
+                } else {
+                    beats.stop();
+                }
+                if toggles.click {
+                    println!("metronome: on at {}bpm", config.clock.bpm());
+                } else {
+                    println!("metronome: off");
+                }
+            }
+            Some(AudioEvent::Sequencer(control)) => match control {
+                Control::PlayStop => {
+                    sequencer.play_stop(engine.now(), &mut sequenced);
+                    if sequencer.is_running() {
+                        println!("sequencer: playing");
+                    } else {
+                        println!("sequencer: stopped");
+                    }
+                }
+                Control::StepEntry => {
+                    if sequencer.entry.take().is_none() {
+                        sequencer.entry = Some(0);
+                        println!("sequencer: play notes into the pattern from step 1, A for a rest, I when done");
+                    } else {
+                        println!("sequencer: done entering steps");
+                    }
+                }
+                Control::Rest => {
+                    if let Some(at) = sequencer.enter(Step::default()) {
+                        println!("sequencer: step {} rests", at + 1);
+                    }
+                }
+                Control::Save => {
+                    let path = config
+                        .sequences
+                        .get(sequencer.selected)
+                        .cloned()
+                        .unwrap_or_else(|| PathBuf::from(format!("synthtoy-{}.seq", unix_secs())));
+                    match std::fs::write(&path, sequencer.pattern().to_string()) {
+                        Ok(()) => println!("sequencer: saved to {}", path.display()),
+                        Err(e) => println!("sequencer: {}: {e}", path.display()),
+                    }
+                }
+                Control::Select(idx) => {
+                    sequencer.select(idx);
+                    println!("sequencer: on pattern {}", idx + 1);
+                }
+                Control::SongMode => {
+                    if sequencer.song_mode {
+                        sequencer.song_mode = false;
+                        println!("sequencer: playing pattern {}", sequencer.selected + 1);
+                    } else if sequencer.song.is_empty() {
+                        println!("sequencer: no song to play yet, J chains patterns into one");
+                    } else {
+                        sequencer.song_mode = true;
+                        println!("sequencer: playing the song, {}", sequencer.song);
+                    }
+                }
+                Control::Chain => {
+                    sequencer.song.chain(sequencer.selected);
+                    println!("sequencer: the song is {}", sequencer.song);
+                }
+                Control::ClearSong => {
+                    sequencer.song = Song::default();
+                    sequencer.song_mode = false;
+                    println!("sequencer: song cleared");
+                }
+            },
+            Some(AudioEvent::Console(command)) => console(
+                command,
+                &mut engine,
+                &mut config,
+                &mut explorer,
+                toggles,
+                &snapshots,
+                &report,
+            ),
+            Some(AudioEvent::Terminate) => {
+                // while there's still an engine playing to snapshot
+                if let Some(saver) = saver {
+                    saver.save(session(&config, &sequencer));
+                    saver.finish();
+                }
+                break;
+            }
+            None => {}
+        }
+
+        if let Some(pressure) = &mut pressure {
+            let now = engine.now();
+            if let Some(value) = pressure.update(now) {
+                engine.at = now;
+                pressure.config.target.apply(value, &mut engine);
+            }
+        }
+
+        if let Some(strummer) = &mut strummer {
+            let now = engine.now();
+            strummer.poll(now, &mut strummed);
+            for event in strummed.drain(..) {
+                let command = match event {
+                    StrumEvent::On { note, velocity } => {
+                        let Some(freq) = config.tuning.freq(note) else {
+                            continue;
+                        };
+                        Command::NoteOn {
+                            note: Some(note),
+                            freq,
+                            velocity: velocity as f32 / 127.,
+                        }
+                    }
+                    StrumEvent::Off { note } => Command::NoteOff(note),
+                };
+                engine.send(now, command);
+            }
+        }
+
+        let now = engine.now();
+        sequencer.poll(now, &mut sequenced);
+        for event in sequenced.drain(..) {
+            match event {
+                MidiEventInner::Down { velocity, note } => {
+                    if let Some(freq) = config.tuning.freq(note) {
+                        let command = Command::NoteOn {
+                            note: Some(note),
+                            freq,
+                            velocity: velocity as f32 / 127.,
+                        };
+                        engine.send(now, command);
+                    }
+                }
+                MidiEventInner::Up { note, .. } => engine.send(now, Command::NoteOff(note)),
+                _ => {}
+            }
+        }
+
+        while let Some((at, beat)) = beats.poll(&config.clock, now) {
+            engine.at = at;
+            engine.set_param("metronome.click", beat as f32);
+        }
+
+        if let Some(reload) = watch.as_mut().and_then(|w| w.poll(Instant::now())) {
+            match reload {
+                Reload::Params(params) => {
+                    engine.at = engine.now();
+                    for (name, value) in params {
+                        engine.set_param(&name, value);
+                    }
+                    println!("patch: set the parameters that changed");
+                }
+                Reload::Rebuild(patch) => {
+                    match rebuild(
+                        &patch,
+                        &mut engine,
+                        &mut config,
+                        toggles,
+                        &snapshots,
+                        &report,
+                    ) {
+                        Ok(()) => {
+                            config.nodes = patch.nodes;
+                            println!("patch: rebuilt it");
+                        }
+                        Err(errors) => {
+                            for error in errors {
+                                println!("patch: {error}");
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(saver) = &saver {
+            if Instant::now() >= next_save {
+                saver.save(session(&config, &sequencer));
+                next_save = Instant::now() + AUTOSAVE_PERIOD;
+            }
+        }
+
+        // engines the callback is done with, freed here instead
+        while graveyard.pop().is_some() {}
+    }
+
+    // give the fade out time to be heard before the device goes away with
+    // the rest of this
+    engine.send(engine.now(), Command::FadeOut);
+    std::thread::sleep(config.fade + FADE_MARGIN);
+
+    if let Some(recording) = recording {
+        engine.send(engine.now(), Command::SetTap(None));
+        println!("recording: {}", recording.finish());
+    }
+    for message in snoop::save_all() {
+        println!("snoop {message}");
+    }
+    // an empty recording isn't worth writing over an older one with
+    if let Some(recorder) = recorder.filter(|r| !r.events.is_empty()) {
+        save_recording(&recorder);
+    }
+}
+
+/// What building `patch` again while playing starts from: the patch, with
+/// what went into the engine from the command line rather than the patch
+/// carried over from `config`, and the tempo wherever it's got to unless
+/// the patch says otherwise.
+fn rebuilt_config(patch: &Patch, config: &AudioConfig) -> Result<AudioConfig, Vec<Diagnostic>> {
+    let mut fresh = patch.audio_config()?;
+    fresh.restore = patch.param_values();
+    fresh.lfos = config.lfos.clone();
+    fresh.mods = config.mods.clone();
+    fresh.key_pressure = config.key_pressure;
+    fresh.solo = config.solo;
+    fresh.tap_voices = config.tap_voices;
+    fresh.click_out = config.click_out.clone();
+    if !patch.nodes.iter().any(|e| e.key == "bpm") {
+        fresh.bpm = config.clock.bpm();
+    }
+    Ok(fresh)
+}
+
+/// The parameters in `current` that `patch` doesn't set itself.
+fn carried(patch: &Patch, current: Vec<(String, f32)>) -> Vec<(String, f32)> {
+    current
+        .into_iter()
+        .filter(|(name, _)| !patch.params.iter().any(|e| e.key == *name))
+        .collect()
+}
+
+/// The switches on the keyboard that aren't parameters of the patch, which
+/// a rebuilt engine gets back.
+#[derive(Clone, Copy, Debug, Default)]
+struct Toggles {
+    dry: bool,
+    click: bool,
+}
+
+impl Toggles {
+    /// Sets them on an engine that was built with its effects on and no
+    /// click.
+    fn restore(self, engine: &mut impl Params) {
+        if self.dry {
+            engine.set_param("dry", 1.);
+        }
+        if self.click {
+            engine.set_param("metronome.on", 1.);
+        }
+    }
+}
+
+/// Builds an engine from `patch` and crossfades over to it, playing in
+/// its tuning from then on.
+///
+/// Whatever has been set while playing carries over where the patch
+/// doesn't say otherwise: the parameters, as a snapshot of the engine
+/// playing now has them, and everything in [`rebuilt_config`]. What's
+/// going on in the engine itself starts over, the way it would starting
+/// the program on the patch: notes playing, the latch and sustain pedal,
+/// bends, the modulation sources and where the LFOs are in their cycles.
+/// `dry` and the click are kept out of snapshots, so they come from
+/// `toggles`.
+fn rebuild(
+    patch: &Patch,
+    engine: &mut EngineHandle,
+    config: &mut AudioConfig,
+    toggles: Toggles,
+    snapshots: &Snapshots,
+    report: &Reporter,
+) -> Result<(), Vec<Diagnostic>> {
+    let mut fresh = rebuilt_config(patch, config)?;
+    let current = match snapshots.take(Duration::from_millis(500)) {
+        Some(snapshot) => snapshot.params,
+        None => {
+            println!("patch: timed out waiting for a snapshot, so parameters start over");
+            Vec::new()
+        }
+    };
+    let mut guards = Guards::new(report.clone());
+    let mut new = build_engine(&mut fresh, &mut guards);
+    // quietly, since some of them may have gone with the old engine
+    for (name, value) in carried(patch, current) {
+        new.set_param(&name, value);
+    }
+    toggles.restore(&mut new);
+    engine.rebuilt(&new);
+    let watchdog = Watchdog::new(guards.loads, config.shed, report.clone());
+    let new = Generation::new(new, Some(watchdog));
+    engine.send(engine.now(), Command::Swap(Box::new(new)));
+    if fresh.bpm != config.clock.bpm() {
+        config.clock.set_bpm(engine.now(), fresh.bpm);
+    }
+    config.tuning = fresh.tuning;
+    Ok(())
+}
+
+/// Carries out a console command that needs the engine, changing the
+/// sources and effects by building it again from `config.nodes`.
+fn console(
+    command: Console,
+    engine: &mut EngineHandle,
+    config: &mut AudioConfig,
+    explorer: &mut Explorer,
+    toggles: Toggles,
+    snapshots: &Snapshots,
+    report: &Reporter,
+) {
+    let snapshot = || snapshots.take(Duration::from_millis(500));
+    let mut nodes = config.nodes.clone();
+    match command {
+        Console::Set(name, value) => {
+            engine.at = engine.now();
+            if !engine.set_param(&name, value) {
+                println!("no parameter {name} to set, `params` lists them");
+            }
+            return;
+        }
+        Console::Params => {
+            let values = snapshot().map_or(Vec::new(), |s| s.params);
+            for info in engine.params() {
+                let value = values.iter().find(|(name, _)| *name == info.name);
+                let value = value.map_or("?".to_string(), |(_, v)| v.to_string());
+                println!("{} = {value} ({}..{})", info.name, info.min, info.max);
+            }
+            return;
+        }
+        Console::Chain => {
+            for entry in &config.nodes {
+                println!("{entry}");
+            }
+            if let Some(snapshot) = snapshot() {
+                print!("{}", snapshot.graph);
+            }
+            return;
+        }
+        Console::ChainAdd(entry) => match nodes.iter_mut().find(|e| e.key == entry.key) {
+            Some(existing) => *existing = entry,
+            None => nodes.push(entry),
+        },
+        Console::ChainRemove(key) => {
+            if !nodes.iter().any(|e| e.key == key) {
+                println!("chain: there's no {key} to remove");
+                return;
+            }
+            nodes.retain(|e| e.key != key);
+        }
+        Console::Patch(patch) => {
+            let errors = patch.validate();
+            for error in &errors {
+                println!("patch: {}", error.message);
+            }
+            if !errors.is_empty() {
+                return;
+            }
+            let playing = Patch::from_state(nodes, &[]);
+            match Reload::between(&playing, &patch) {
+                Some(Reload::Rebuild(patch)) => {
+                    match rebuild(&patch, engine, config, toggles, snapshots, report) {
+                        Ok(()) => {
+                            config.nodes = patch.nodes;
+                            println!("patch: rebuilt it");
+                        }
+                        Err(errors) => {
+                            for error in errors {
+                                println!("patch: {}", error.message);
+                            }
+                        }
+                    }
+                }
+                Some(Reload::Params(params)) => {
+                    engine.at = engine.now();
+                    for (name, value) in params {
+                        engine.set_param(&name, value);
+                    }
+                }
+                None => {}
+            }
+            return;
+        }
+        Console::Randomize(..) | Console::Mutate(..) | Console::Undo => {
+            let current = || match snapshot() {
+                Some(snapshot) => Some(snapshot.params),
+                None => {
+                    println!("timed out waiting for a snapshot");
+                    None
+                }
+            };
+            let (changes, done) = match command {
+                Console::Randomize(group) => {
+                    let Some(current) = current() else { return };
+                    let changes = explorer.randomize(&engine.params(), &current, group.as_deref());
+                    (changes, "randomized")
+                }
+                Console::Mutate(group, amount) => {
+                    let Some(current) = current() else { return };
+                    let changes =
+                        explorer.mutate(&engine.params(), &current, group.as_deref(), amount);
+                    (changes, "mutated")
+                }
+                _ => match explorer.undo() {
+                    Some(changes) => (changes, "put back"),
+                    None => {
+                        println!("nothing to undo");
+                        return;
+                    }
+                },
+            };
+            if changes.is_empty() {
+                println!("no parameters there that can be randomized");
+                return;
+            }
+            engine.at = engine.now();
+            for (name, value) in &changes {
+                engine.set_param(name, *value);
+                println!("{name} = {value}");
             }
-            AudioEvent::Terminate => break,
+            println!("{done} {} parameters", changes.len());
+            return;
         }
+        // answered by the console itself
+        Console::Help | Console::Trigger(..) => return,
+    }
+    let patch = Patch::from_state(nodes, &[]);
+    match rebuild(&patch, engine, config, toggles, snapshots, report) {
+        Ok(()) => config.nodes = patch.nodes,
+        Err(errors) => {
+            for error in errors {
+                println!("chain: {error}");
+            }
+        }
+    }
+}
+
+fn record(recorder: &mut Recorder, event: &AudioEvent) {
+    match *event {
+        // the transport isn't part of what was played
+        AudioEvent::Midi(MidiEvent {
+            inner:
+                MidiEventInner::Clock
+                | MidiEventInner::Start
+                | MidiEventInner::Continue
+                | MidiEventInner::Stop
+                | MidiEventInner::SongPosition(_),
+            ..
+        }) => {}
+        AudioEvent::Midi(ref midi) => recorder.record(midi.clone()),
+        // computer keyboard and console notes go down as the nearest MIDI
+        // note, and console ones ring out with no note off just like when
+        // they were played
+        AudioEvent::PlayNote(freq, velocity, at) | AudioEvent::KeyDown(freq, velocity, at) => {
+            let (note, _) = Pitch::from_freq(freq).nearest();
+            let midi = MidiEvent {
+                at,
+                channel: 0,
+                inner: MidiEventInner::Down {
+                    note: note.clamp(0, 127) as u8,
+                    velocity: (velocity * 127.).round().clamp(1., 127.) as u8,
+                },
+            };
+            recorder.record(midi);
+        }
+        AudioEvent::KeyUp(freq, at) => {
+            let (note, _) = Pitch::from_freq(freq).nearest();
+            let midi = MidiEvent {
+                at,
+                channel: 0,
+                inner: MidiEventInner::Up {
+                    note: note.clamp(0, 127) as u8,
+                    velocity: 0,
+                },
+            };
+            recorder.record(midi);
+        }
+        _ => {}
+    }
+}
+
+/// Moves everything that keeps time to `bpm` from when `engine` is at: the
+/// session clock, which the sequencer and the window title share, and any
+/// parameter called `bpm`, like the delay's.
+fn follow_tempo(bpm: f32, engine: &mut EngineHandle, config: &mut AudioConfig) {
+    config.bpm = bpm;
+    config.clock.set_bpm(engine.at, bpm);
+    for param in engine.params() {
+        if param.name == "bpm" || param.name.ends_with(".bpm") {
+            engine.set_param(&param.name, bpm);
+        }
+    }
+    println!("midi clock: {bpm:.1}bpm");
+}
+
+/// Puts a note played into the pattern, if notes are being entered.
+fn enter_step(sequencer: &mut Sequencer, note: u8, velocity: u8) {
+    let step = Step {
+        note,
+        gate: true,
+        velocity,
+    };
+    if let Some(at) = sequencer.enter(step) {
+        println!("sequencer: step {} is {step}", at + 1);
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |t| t.as_secs())
+}
+
+fn save_recording(recorder: &Recorder) {
+    match recorder.save() {
+        Ok(()) => println!(
+            "record: saved {} events to {}",
+            recorder.events.len(),
+            recorder.path.display()
+        ),
+        Err(e) => println!("record: {}: {e}", recorder.path.display()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sources::FmVoice;
+
+    #[test]
+    fn test_sample_accurate_commands() {
+        let report: Reporter = Arc::new(|_| {});
+        let voices: Vec<Box<dyn DynVoice>> = vec![Box::<FmVoice>::default()];
+        let (mut commands, consumer) = spsc::channel(4);
+        let synth =
+            SynthBuilder::new(Guarded::new(VoiceManager::new(voices), report.clone())).build();
+        let engine = Modulated::new(Stereo::new(synth), &[], &[], 120.);
+        let clock = EngineClock::new(Instant::now());
+        let mut shim = SDLShim::new(engine, consumer, Arc::default(), report, clock);
+
+        let block_start = Stamp(1000);
+        let at = |samples: u64| Stamp(block_start.0 + samples);
+        let note_on = Command::NoteOn {
+            note: Some(69),
+            freq: 440.,
+            velocity: 1.,
+        };
+        commands
+            .push(Timed {
+                at: at(100),
+                command: note_on,
+            })
+            .unwrap();
+        commands
+            .push(Timed {
+                at: at(1000),
+                command: Command::NoteOff(69),
+            })
+            .unwrap();
+
+        let (mut out, mut right) = ([0.; 256], [0.; 256]);
+        shim.render(&mut out, &mut right, block_start);
+        assert!(out[..=100].iter().all(|&s| s == 0.), "{out:?}");
+        assert!(out[101..120].iter().any(|&s| s != 0.));
+        assert_eq!(out, right);
+        // the note off is for a later block
+        assert!(matches!(
+            shim.commands.peek(),
+            Some(Timed {
+                command: Command::NoteOff(69),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_dry() {
+        let report: Reporter = Arc::new(|_| {});
+        let engine = |dry: f32| {
+            let mut config = AudioConfig {
+                siggen: Some(Siggen::new("dc:0.5".parse().unwrap())),
+                ..AudioConfig::default()
+            };
+            let mut engine = build_engine(&mut config, &mut Guards::new(report.clone()));
+            assert!(engine.set_param("dry", dry));
+            // the rack's and the stereo one's are the one switch
+            let dries = engine.params().iter().filter(|p| p.name == "dry").count();
+            assert_eq!(dries, 1);
+            let (mut left, mut right) = ([0.; 256], [0.; 256]);
+            for _ in 0..SAMPLING_FREQ / 256 {
+                engine.process_stereo(&mut left, &mut right);
+            }
+            (left, right)
+        };
+        // the DC blocker takes the signal away, and the stereo effects
+        // after it don't bring it back
+        let (left, right) = engine(0.);
+        assert!(left.iter().chain(&right).all(|s| s.abs() < 0.05));
+        // dry goes round the lot, so it's what the signal generator made
+        assert_eq!(engine(1.), ([0.5; 256], [0.5; 256]));
+    }
+
+    #[test]
+    fn test_rebuild_carries_over() {
+        let config = AudioConfig {
+            mods: vec!["wheel:ladder.cutoff=0.5".parse().unwrap()],
+            key_pressure: KeyPressure::Timbre,
+            solo: Some(1),
+            ..AudioConfig::default()
+        };
+        config.clock.set_bpm(Stamp(0), 90.);
+        let patch = Patch::parse("ladder = 1000\n[params]\nladder.resonance = 0.2\n").unwrap();
+        let fresh = rebuilt_config(&patch, &config).unwrap();
+        assert_eq!(fresh.mods, config.mods);
+        assert_eq!(fresh.key_pressure, KeyPressure::Timbre);
+        assert_eq!(fresh.solo, Some(1));
+        assert_eq!(fresh.bpm, 90.);
+        assert_eq!(fresh.restore, [("ladder.resonance".to_string(), 0.2)]);
+        // unless the patch has a tempo of its own
+        let patch = Patch::parse("bpm = 100\n").unwrap();
+        assert_eq!(rebuilt_config(&patch, &config).unwrap().bpm, 100.);
+
+        // what's been turned while playing stays, but not over the patch
+        let patch = Patch::parse("[params]\nladder.resonance = 0.2\n").unwrap();
+        let current = vec![
+            ("ladder.resonance".to_string(), 0.5),
+            ("tone.bass".to_string(), 3.),
+        ];
+        assert_eq!(carried(&patch, current), [("tone.bass".to_string(), 3.)]);
+    }
+
+    #[test]
+    fn test_fade() {
+        let report: Reporter = Arc::new(|_| {});
+        let shim = || {
+            let voices: Vec<Box<dyn DynVoice>> = vec![Box::<FmVoice>::default()];
+            let synth =
+                SynthBuilder::new(Guarded::new(VoiceManager::new(voices), report.clone())).build();
+            let (commands, consumer) = spsc::channel(4);
+            let engine = Modulated::new(Stereo::new(synth), &[], &[], 120.);
+            let clock = EngineClock::new(Instant::now());
+            let mut shim = SDLShim::new(engine, consumer, Arc::default(), report.clone(), clock);
+            shim.engine.mono.synth.note_on(Some(69), 440., 1.);
+            (shim, commands)
+        };
+        let (mut plain, _) = shim();
+        let (mut faded, mut commands) = shim();
+        faded.fade_in(64);
+
+        let (mut expected, mut right) = ([0.; 128], [0.; 128]);
+        plain.block(&mut expected, &mut right);
+        let (mut out, mut right) = ([0.; 128], [0.; 128]);
+        faded.block(&mut out, &mut right);
+        for i in 0..128 {
+            let gain = ((i + 1) as f32 / 64.).min(1.);
+            assert!((out[i] - expected[i] * gain).abs() < 1e-6, "{i}");
+        }
+
+        commands
+            .push(Timed {
+                // due at the very start of the next block
+                at: Stamp::default(),
+                command: Command::FadeOut,
+            })
+            .unwrap();
+        faded.block(&mut out, &mut right);
+        assert!(out[64..].iter().all(|&s| s == 0.));
+    }
+
+    #[test]
+    fn test_swap() {
+        let report: Reporter = Arc::new(|_| {});
+        let engine = || {
+            let voices: Vec<Box<dyn DynVoice>> = vec![Box::<FmVoice>::default()];
+            let synth =
+                SynthBuilder::new(Guarded::new(VoiceManager::new(voices), report.clone())).build();
+            Modulated::new(Stereo::new(synth), &[], &[], 120.)
+        };
+        let (mut commands, consumer) = spsc::channel(4);
+        let clock = EngineClock::new(Instant::now());
+        let mut shim = SDLShim::new(engine(), consumer, Arc::default(), report.clone(), clock);
+        shim.engine.mono.synth.note_on(Some(69), 440., 1.);
+
+        // swapped for one with nothing playing, which fades the note out
+        let new = Generation::new(engine(), None);
+        commands
+            .push(Timed {
+                at: Stamp::default(),
+                command: Command::Swap(Box::new(new)),
+            })
+            .unwrap();
+        let (mut left, mut right) = ([0.; 4096], [0.; 4096]);
+        shim.block(&mut left, &mut right);
+        let len = CROSSFADE_SAMPLES as usize;
+        assert!(left[..len / 2].iter().any(|&s| s.abs() > 0.01));
+        assert!(left[len..].iter().all(|&s| s == 0.));
+        assert!(shim.outgoing.is_none());
     }
 }
@@ -0,0 +1,235 @@
+//! Setting how loud this system plays, so a patch comes out as loud here as
+//! anywhere else: `synthtoy calibrate` plays pink noise at a known level,
+//! the gain gets turned until that's as loud as it should be, and every
+//! run after plays through that gain.
+
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use sdl2::audio::{AudioCallback, AudioSpecDesired};
+use sdl2::AudioSubsystem;
+
+use crate::filters::{Biquad, BiquadKind, Filter, SAMPLING_FREQ};
+use crate::outputs::OutputMap;
+use crate::siggen::{Siggen, Signal};
+
+/// RMS level of the noise, in dB below full scale.
+pub const REFERENCE_DBFS: f32 = -20.;
+/// The band the noise is limited to, as for calibrating film and mixing
+/// rooms, so the speakers' lows and highs don't sway it.
+const BAND: (f32, f32) = (500., 2000.);
+/// Seconds of noise, played over and over.
+const LOOP_SECS: usize = 10;
+const MIN_GAIN: f32 = -60.;
+const MAX_GAIN: f32 = 12.;
+const KEY: &str = "output_gain";
+
+const HELP: &str = "\
+<db>      set the gain, like `-6`
++ or -    turn it up or down by 1dB
+save      keep it for every run from now on, and stop
+quit      stop without keeping it";
+
+fn gain(db: f32) -> f32 {
+    10f32.powf(db / 20.)
+}
+
+/// `$XDG_CONFIG_HOME/synthtoy/calibration`, falling back to `~/.config`.
+pub fn config_path() -> Option<PathBuf> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(std::env::var_os("HOME")?).join(".config")))?;
+    Some(config.join("synthtoy").join("calibration"))
+}
+
+/// Reads the gain in dB out of a calibration file, which has one line,
+/// `output_gain = <db>`.
+fn parse(text: &str) -> Result<f32, String> {
+    text.lines()
+        .find_map(|line| {
+            let (key, value) = line.split_once('=')?;
+            (key.trim() == KEY).then_some(value.trim())
+        })
+        .ok_or_else(|| format!("no {KEY} in it"))?
+        .parse::<f32>()
+        .ok()
+        .filter(|db| db.is_finite())
+        .ok_or_else(|| format!("bad {KEY}"))
+}
+
+/// The gain this system was calibrated to, as a factor, or none at all if
+/// it hasn't been.
+pub fn load_gain() -> f32 {
+    let Some(path) = config_path() else { return 1. };
+    match fs::read_to_string(&path) {
+        Ok(text) => match parse(&text) {
+            Ok(db) => gain(db.clamp(MIN_GAIN, MAX_GAIN)),
+            Err(e) => {
+                println!("calibration: {}: {e}", path.display());
+                1.
+            }
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 1.,
+        Err(e) => {
+            println!("calibration: {}: {e}", path.display());
+            1.
+        }
+    }
+}
+
+fn save(path: &Path, db: f32) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, format!("{KEY} = {db}\n"))
+}
+
+/// Pink noise limited to [`BAND`], at exactly [`REFERENCE_DBFS`] RMS.
+pub fn pink_noise(len: usize) -> Vec<f32> {
+    let mut siggen = Siggen::new(Signal::Pink);
+    let q = std::f32::consts::FRAC_1_SQRT_2;
+    let mut filters = [
+        Biquad::new(BiquadKind::HighPass, BAND.0, q, 0.),
+        Biquad::new(BiquadKind::HighPass, BAND.0, q, 0.),
+        Biquad::new(BiquadKind::LowPass, BAND.1, q, 0.),
+        Biquad::new(BiquadKind::LowPass, BAND.1, q, 0.),
+    ];
+    // a second to let the filters settle, which is thrown away
+    let settle = SAMPLING_FREQ;
+    let mut noise = vec![0.; settle + len];
+    siggen.process(&mut noise);
+    for filter in &mut filters {
+        filter.process(&mut noise);
+    }
+    noise.drain(..settle);
+    let rms = (noise.iter().map(|s| s * s).sum::<f32>() / len.max(1) as f32).sqrt();
+    let scale = gain(REFERENCE_DBFS) / rms.max(1e-9);
+    for s in &mut noise {
+        *s *= scale;
+    }
+    noise
+}
+
+/// What's typed while calibrating.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Adjust {
+    Set(f32),
+    Nudge(f32),
+    Save,
+    Quit,
+}
+
+impl FromStr for Adjust {
+    type Err = String;
+    fn from_str(line: &str) -> Result<Self, String> {
+        Ok(match line.trim() {
+            "+" => Adjust::Nudge(1.),
+            "-" => Adjust::Nudge(-1.),
+            "save" => Adjust::Save,
+            "quit" | "q" => Adjust::Quit,
+            db => Adjust::Set(
+                db.strip_suffix("dB")
+                    .unwrap_or(db)
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|db| db.is_finite())
+                    .ok_or_else(|| format!("don't know {db:?}\n{HELP}"))?,
+            ),
+        })
+    }
+}
+
+/// Plays the noise round and round.
+struct Player {
+    noise: Vec<f32>,
+    position: usize,
+    gain: f32,
+    outputs: OutputMap,
+    channels: usize,
+}
+
+impl AudioCallback for Player {
+    type Channel = f32;
+    fn callback(&mut self, out: &mut [f32]) {
+        for frame in out.chunks_exact_mut(self.channels) {
+            let s = self.noise[self.position] * self.gain;
+            self.position = (self.position + 1) % self.noise.len();
+            frame.fill(0.);
+            frame[self.outputs.left] = s;
+            frame[self.outputs.right] = s;
+        }
+    }
+}
+
+/// Plays the noise through `output` and takes gains on stdin until one is
+/// saved or it's told to stop.
+pub fn run(audio: &AudioSubsystem, output: Option<&str>, outputs: OutputMap) -> Result<(), String> {
+    let path = config_path().ok_or("nowhere to keep the calibration, HOME isn't set")?;
+    let mut db = 20. * load_gain().log10();
+    let spec = AudioSpecDesired {
+        freq: Some(SAMPLING_FREQ as i32),
+        channels: Some(outputs.channels() as u8),
+        samples: Some(256),
+    };
+    let noise = pink_noise(LOOP_SECS * SAMPLING_FREQ);
+    let mut player = audio.open_playback(output, &spec, |spec| Player {
+        noise,
+        position: 0,
+        gain: gain(db),
+        outputs,
+        channels: spec.channels as usize,
+    })?;
+    player.resume();
+
+    println!(
+        "calibration: playing pink noise from {}Hz to {}Hz at {REFERENCE_DBFS}dBFS RMS",
+        BAND.0, BAND.1
+    );
+    println!("turn the gain until it's as loud as a patch at full tilt should be");
+    println!("{HELP}");
+    println!("gain: {db:+.1}dB");
+    for line in io::stdin().lock().lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        match line.parse() {
+            Ok(Adjust::Set(to)) => db = to,
+            Ok(Adjust::Nudge(by)) => db += by,
+            Ok(Adjust::Save) => {
+                save(&path, db).map_err(|e| format!("{}: {e}", path.display()))?;
+                println!("calibration: saved {db:+.1}dB to {}", path.display());
+                return Ok(());
+            }
+            Ok(Adjust::Quit) => break,
+            Err(e) => {
+                println!("{e}");
+                continue;
+            }
+        }
+        db = db.clamp(MIN_GAIN, MAX_GAIN);
+        player.lock().gain = gain(db);
+        println!("gain: {db:+.1}dB");
+    }
+    println!("calibration: left as it was");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calibration() {
+        let noise = pink_noise(SAMPLING_FREQ);
+        let rms = (noise.iter().map(|s| s * s).sum::<f32>() / noise.len() as f32).sqrt();
+        assert!((20. * rms.log10() - REFERENCE_DBFS).abs() < 0.01);
+
+        assert_eq!(parse("output_gain = -4.5\n"), Ok(-4.5));
+        assert!(parse("gain = 3").is_err());
+        assert_eq!("-6".parse(), Ok(Adjust::Set(-6.)));
+        assert_eq!("+".parse(), Ok(Adjust::Nudge(1.)));
+        assert_eq!(" save ".parse(), Ok(Adjust::Save));
+        assert!("louder".parse::<Adjust>().is_err());
+    }
+}
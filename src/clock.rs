@@ -0,0 +1,495 @@
+//! The session clock: how long synthtoy has been playing, in minutes and
+//! seconds and in bars and beats at the tempo, for the window title and for
+//! stamping recordings with when in the session they were made.
+//!
+//! Everything that happens gets a [`Stamp`] on the [`EngineClock`], in
+//! samples since it started, whether it came from a MIDI driver with
+//! timestamps of its own, the computer keyboard, or the sequencer, so
+//! they're all scheduled and recorded against the same time.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::{Add, AddAssign, Sub};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::filters::SAMPLING_FREQ;
+
+/// Beats to the bar until something says otherwise.
+pub const BEATS_PER_BAR: u64 = 4;
+/// Ticks of MIDI clock to the beat.
+pub const TICKS_PER_BEAT: u64 = 24;
+
+/// When something happens, in samples since the engine clock started. It
+/// works like [`Instant`] does, with durations added to it and taken from
+/// another, which round to the nearest sample.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Stamp(pub u64);
+
+fn samples(duration: Duration) -> u64 {
+    (duration.as_secs_f64() * SAMPLING_FREQ as f64).round() as u64
+}
+
+impl Stamp {
+    /// How long after `earlier` it is, or nothing if it's before.
+    pub fn saturating_duration_since(self, earlier: Stamp) -> Duration {
+        Duration::from_secs_f64(self.0.saturating_sub(earlier.0) as f64 / SAMPLING_FREQ as f64)
+    }
+
+    /// Samples after `earlier`, or none if it's before.
+    pub fn samples_since(self, earlier: Stamp) -> u64 {
+        self.0.saturating_sub(earlier.0)
+    }
+
+    pub fn as_secs_f64(self) -> f64 {
+        self.0 as f64 / SAMPLING_FREQ as f64
+    }
+}
+
+impl Add<Duration> for Stamp {
+    type Output = Stamp;
+    fn add(self, duration: Duration) -> Stamp {
+        Stamp(self.0 + samples(duration))
+    }
+}
+
+impl AddAssign<Duration> for Stamp {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+/// Stops at the start of the clock.
+impl Sub<Duration> for Stamp {
+    type Output = Stamp;
+    fn sub(self, duration: Duration) -> Stamp {
+        Stamp(self.0.saturating_sub(samples(duration)))
+    }
+}
+
+/// Like [`Stamp::saturating_duration_since`].
+impl Sub for Stamp {
+    type Output = Duration;
+    fn sub(self, earlier: Stamp) -> Duration {
+        self.saturating_duration_since(earlier)
+    }
+}
+
+/// Puts moments on the wall clock onto the engine's. Every thread that
+/// stamps things gets a copy, made from the one the audio thread has.
+#[derive(Clone, Copy, Debug)]
+pub struct EngineClock {
+    start: Instant,
+}
+
+impl EngineClock {
+    pub fn new(start: Instant) -> Self {
+        Self { start }
+    }
+
+    pub fn stamp(&self, at: Instant) -> Stamp {
+        Stamp::default() + at.saturating_duration_since(self.start)
+    }
+
+    pub fn now(&self) -> Stamp {
+        self.stamp(Instant::now())
+    }
+
+    /// Back on the wall clock, for waiting until then.
+    pub fn instant(&self, stamp: Stamp) -> Instant {
+        self.start + Duration::from_secs_f64(stamp.as_secs_f64())
+    }
+}
+
+/// Later than this behind the engine clock, a driver's timestamps have
+/// drifted, and get lined up again.
+const MAX_DRIFT: Duration = Duration::from_millis(20);
+
+/// Lines up a driver's own timestamps, in microseconds from whenever it
+/// likes, with the engine clock. Events keep the spacing the driver gave
+/// them rather than the one they happened to be read with, and the
+/// quickest any has come through sets how far behind they are. Drivers
+/// that give no timestamps, as zero, get stamped when they're read.
+#[derive(Clone, Copy, Debug)]
+pub struct DriverClock {
+    engine: EngineClock,
+    /// engine samples at a driver time of zero
+    offset: Option<i64>,
+}
+
+impl DriverClock {
+    pub fn new(engine: EngineClock) -> Self {
+        Self {
+            engine,
+            offset: None,
+        }
+    }
+
+    /// Stamps an event the driver says came at `micros`, read at `now`.
+    pub fn stamp(&mut self, micros: u64, now: Instant) -> Stamp {
+        let now = self.engine.stamp(now);
+        if micros == 0 {
+            return now;
+        }
+        let driver = samples(Duration::from_micros(micros)) as i64;
+        let oldest = now.0 as i64 - samples(MAX_DRIFT) as i64;
+        match self.offset.map(|offset| driver + offset) {
+            Some(at) if (oldest..=now.0 as i64).contains(&at) => Stamp(at as u64),
+            _ => {
+                self.offset = Some(now.0 as i64 - driver);
+                now
+            }
+        }
+    }
+}
+
+/// The tempo, and where it took over from the one before, and the meter,
+/// counting from the bar line where it took over.
+#[derive(Clone, Copy, Debug)]
+struct Tempo {
+    /// beats per minute
+    bpm: f32,
+    since: Stamp,
+    /// beats played by `since`
+    beats: f64,
+    /// beats to the bar
+    meter: u64,
+    /// beats played by the bar line the meter started at
+    downbeat: f64,
+    /// bars played by then
+    bars: u64,
+}
+
+impl Tempo {
+    fn beats(&self, at: Stamp) -> f64 {
+        let secs = at.as_secs_f64() - self.since.as_secs_f64();
+        self.beats + secs * self.bpm as f64 / 60.
+    }
+
+    /// Whole beats from the bar line the meter started at to `beats`.
+    fn since_downbeat(&self, beats: f64) -> u64 {
+        // a hair on, so a beat rounded to the sample still counts
+        (beats - self.downbeat + 1e-6).max(0.) as u64
+    }
+
+    /// Whole bars played by `beats`, and whole beats into the one after.
+    fn bar(&self, beats: f64) -> (u64, u64) {
+        let into = self.since_downbeat(beats);
+        (self.bars + into / self.meter, into % self.meter)
+    }
+
+    fn stamp(&self, beats: f64) -> Stamp {
+        let secs = self.since.as_secs_f64() + (beats - self.beats) * 60. / self.bpm as f64;
+        Stamp((secs * SAMPLING_FREQ as f64).round().max(0.) as u64)
+    }
+}
+
+/// Bars and beats on the engine clock. Copies share the tempo, so a change
+/// made through any of them moves them all, and the beats already played
+/// stay played: the tempo only counts from when it changed.
+#[derive(Clone, Debug)]
+pub struct Clock {
+    engine: EngineClock,
+    tempo: Arc<Mutex<Tempo>>,
+}
+
+impl Clock {
+    pub fn new(start: Instant, bpm: f32) -> Self {
+        Self {
+            engine: EngineClock::new(start),
+            tempo: Arc::new(Mutex::new(Tempo {
+                bpm: if bpm > 0. { bpm } else { 120. },
+                since: Stamp::default(),
+                beats: 0.,
+                meter: BEATS_PER_BAR,
+                downbeat: 0.,
+                bars: 0,
+            })),
+        }
+    }
+
+    fn tempo(&self) -> Tempo {
+        *self.tempo.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The engine clock, which starts when this does.
+    pub fn engine(&self) -> EngineClock {
+        self.engine
+    }
+
+    /// Beats per minute.
+    pub fn bpm(&self) -> f32 {
+        self.tempo().bpm
+    }
+
+    /// Changes the tempo from `at` on.
+    pub fn set_bpm(&self, at: Stamp, bpm: f32) {
+        if bpm <= 0. {
+            return;
+        }
+        let mut tempo = self.tempo.lock().unwrap_or_else(PoisonError::into_inner);
+        *tempo = Tempo {
+            bpm,
+            since: at,
+            beats: tempo.beats(at),
+            ..*tempo
+        };
+    }
+
+    /// Whole beats by `at` since the bar line the meter last started at.
+    pub fn since_downbeat(&self, at: Stamp) -> u64 {
+        let tempo = self.tempo();
+        tempo.since_downbeat(tempo.beats(at))
+    }
+
+    /// Beats to the bar.
+    pub fn meter(&self) -> u64 {
+        self.tempo().meter
+    }
+
+    /// Starts a bar of `meter` beats at `at`, and more after it. Unless
+    /// `at` is on a bar line already, the bar before is cut short, and
+    /// unless it's on a beat, the beats move up to start there, so a song
+    /// starting partway through a beat gets its bars and beats from the
+    /// moment it starts.
+    pub fn set_meter(&self, at: Stamp, meter: u64) {
+        let mut tempo = self.tempo.lock().unwrap_or_else(PoisonError::into_inner);
+        let beats = tempo.beats(at);
+        let (bars, into) = tempo.bar(beats);
+        let on_beat = (beats - beats.round()).abs() < 1e-6;
+        let on_bar = on_beat && into == 0;
+        let beats = if on_beat { beats.round() } else { beats.ceil() };
+        *tempo = Tempo {
+            since: at,
+            beats,
+            meter: meter.max(1),
+            downbeat: beats,
+            bars: if on_bar { bars } else { bars + 1 },
+            ..*tempo
+        };
+    }
+
+    /// Beats played by `at`, and how far into the one after.
+    pub fn beats(&self, at: Stamp) -> f64 {
+        self.tempo().beats(at)
+    }
+
+    pub fn at(&self, at: Stamp) -> Position {
+        let tempo = self.tempo();
+        let (bars, beat) = tempo.bar(tempo.beats(at));
+        Position {
+            elapsed: at.saturating_duration_since(Stamp::default()),
+            bars,
+            beat,
+        }
+    }
+
+    pub fn now(&self) -> Position {
+        self.at(self.engine.now())
+    }
+
+    /// How long a beat lasts.
+    pub fn beat(&self) -> Duration {
+        Duration::from_secs_f64(60. / self.bpm() as f64)
+    }
+
+    /// The first time at or after `now` that lands on a grid of `per_beat`
+    /// to the beat, so things started at different times play in time.
+    pub fn next(&self, now: Stamp, per_beat: u32) -> Stamp {
+        let tempo = self.tempo();
+        let per_beat = per_beat.max(1) as f64;
+        // a hair back, so one rounded to the sample is still on the grid
+        let steps = ((tempo.beats(now) - 1e-9) * per_beat).ceil();
+        tempo.stamp(steps / per_beat)
+    }
+}
+
+/// Follows MIDI clock from a DAW or drum machine: the tempo from how fast
+/// the ticks come, and where the song is from how many there have been
+/// since it started.
+#[derive(Debug, Default)]
+pub struct MidiClock {
+    /// when the last beat's worth of ticks came
+    ticks: VecDeque<Stamp>,
+    /// the tick coming next, while the song is playing
+    position: Option<u64>,
+    /// where it stopped, to carry on from
+    stopped_at: u64,
+}
+
+impl MidiClock {
+    /// Takes a tick, returning how many ticks into the song it is if the
+    /// song is playing.
+    pub fn tick(&mut self, at: Stamp) -> Option<u64> {
+        if self.ticks.len() > TICKS_PER_BEAT as usize {
+            self.ticks.pop_front();
+        }
+        self.ticks.push_back(at);
+        let position = self.position?;
+        self.position = Some(position + 1);
+        Some(position)
+    }
+
+    /// The tempo over the last beat, once a beat's worth of ticks has come.
+    pub fn bpm(&self) -> Option<f32> {
+        if self.ticks.len() <= TICKS_PER_BEAT as usize {
+            return None;
+        }
+        let beat = *self.ticks.back()? - *self.ticks.front()?;
+        Some(60. / beat.as_secs_f32().max(1e-3))
+    }
+
+    pub fn start(&mut self) {
+        self.position = Some(0);
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.position.is_some()
+    }
+
+    /// Moves to `sixteenths` into the song, as Song Position Pointer says:
+    /// where it carries on from if it's stopped, or where the next tick is
+    /// if it's playing.
+    pub fn seek(&mut self, sixteenths: u16) {
+        let position = sixteenths as u64 * (TICKS_PER_BEAT / 4);
+        match &mut self.position {
+            Some(next) => *next = position,
+            None => self.stopped_at = position,
+        }
+    }
+
+    pub fn resume(&mut self) {
+        self.position.get_or_insert(self.stopped_at);
+    }
+
+    pub fn stop(&mut self) {
+        if let Some(position) = self.position.take() {
+            self.stopped_at = position;
+        }
+    }
+}
+
+/// Where the clock is, shown as `<bar>:<beat> <minutes>:<seconds>`, with
+/// bars and beats counting from 1 like a sequencer's do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Position {
+    pub elapsed: Duration,
+    /// whole bars since the start
+    bars: u64,
+    /// whole beats into the bar
+    beat: u64,
+}
+
+impl Position {
+    pub fn bar(self) -> u64 {
+        self.bars + 1
+    }
+
+    pub fn beat(self) -> u64 {
+        self.beat + 1
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.elapsed.as_secs();
+        write!(
+            f,
+            "{}:{} {}:{:02}",
+            self.bar(),
+            self.beat(),
+            secs / 60,
+            secs % 60
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock() {
+        let start = Stamp::default();
+        let clock = Clock::new(Instant::now(), 120.);
+        assert_eq!(clock.at(start).to_string(), "1:1 0:00");
+        let position = clock.at(start + Duration::from_secs_f32(5.25));
+        assert_eq!((position.bar(), position.beat()), (3, 3));
+        assert_eq!(position.to_string(), "3:3 0:05");
+        assert_eq!(
+            Clock::new(Instant::now(), 90.)
+                .at(start + Duration::from_secs(75))
+                .to_string(),
+            "29:1 1:15"
+        );
+        let mut midi = MidiClock::default();
+        let tick = Duration::from_secs_f32(0.5 / TICKS_PER_BEAT as f32);
+        assert_eq!(midi.tick(start), None);
+        assert_eq!(midi.bpm(), None);
+        midi.start();
+        for n in 1..=TICKS_PER_BEAT {
+            assert_eq!(midi.tick(start + tick * n as u32), Some(n - 1));
+        }
+        assert!((midi.bpm().unwrap() - 120.).abs() < 0.01);
+        midi.stop();
+        assert_eq!(midi.tick(start), None);
+        midi.resume();
+        assert_eq!(midi.tick(start), Some(TICKS_PER_BEAT));
+        // a bar in, stopped and playing
+        midi.stop();
+        midi.seek(16);
+        midi.resume();
+        assert_eq!(midi.tick(start), Some(4 * TICKS_PER_BEAT));
+        midi.seek(4);
+        assert_eq!(midi.tick(start), Some(TICKS_PER_BEAT));
+
+        assert_eq!(clock.next(start, 4), start);
+        assert_eq!(
+            clock.next(start + Duration::from_millis(130), 4),
+            start + Duration::from_millis(250)
+        );
+
+        // halving the tempo 2 bars in carries on from there, on every copy
+        let copy = clock.clone();
+        clock.set_bpm(start + Duration::from_secs(4), 60.);
+        assert_eq!(copy.bpm(), 60.);
+        let position = copy.at(start + Duration::from_secs(6));
+        assert_eq!(position.to_string(), "3:3 0:06");
+        assert_eq!(
+            copy.next(start + Duration::from_millis(4100), 4),
+            start + Duration::from_millis(4250)
+        );
+
+        // a song in 3/4 starting partway through a beat starts a bar
+        copy.set_meter(start + Duration::from_millis(6500), 3);
+        assert_eq!(copy.meter(), 3);
+        let at = |ms| clock.at(start + Duration::from_millis(ms)).to_string();
+        assert_eq!(at(6500), "4:1 0:06");
+        assert_eq!(at(8499), "4:2 0:08");
+        assert_eq!(at(9500), "5:1 0:09");
+        // and a change on the bar line after doesn't skip one
+        clock.set_meter(start + Duration::from_millis(9500), 2);
+        assert_eq!(at(9500), "5:1 0:09");
+        assert_eq!(at(11500), "6:1 0:11");
+    }
+
+    #[test]
+    fn test_driver_clock() {
+        let t0 = Instant::now();
+        let engine = EngineClock::new(t0);
+        let ms = |n| Duration::from_millis(n);
+        assert_eq!(engine.stamp(t0 + ms(10)), Stamp(441));
+        assert_eq!(engine.instant(Stamp(441)), t0 + ms(10));
+        assert_eq!(Stamp(441) - ms(20), Stamp(0));
+
+        // two read together keep the driver's spacing
+        let mut driver = DriverClock::new(engine);
+        assert_eq!(driver.stamp(5_000_000, t0 + ms(100)), Stamp(4410));
+        assert_eq!(driver.stamp(5_005_000, t0 + ms(110)), Stamp(4410 + 221));
+        // quicker than the first lines them up again
+        assert_eq!(driver.stamp(5_010_000, t0 + ms(108)), Stamp(4763));
+        // as does drifting too far behind
+        assert_eq!(driver.stamp(5_011_000, t0 + ms(200)), Stamp(8820));
+        assert_eq!(driver.stamp(0, t0 + ms(300)), Stamp(13230));
+    }
+}
@@ -0,0 +1,201 @@
+//! Commands typed on stdin while playing, for poking at the engine without
+//! a controller: setting and listing parameters, playing notes, and adding
+//! or taking out sources and effects.
+
+use std::io::BufRead;
+use std::str::FromStr;
+
+use crate::audio_thread::AudioEvent;
+use crate::clock::EngineClock;
+use crate::live;
+use crate::note::Pitch;
+use crate::patch::{self, Entry, Patch};
+use crate::queue::Sender;
+use crate::random::DEFAULT_MUTATION;
+
+pub const HELP: &str = "\
+set <param> <value>       set a parameter, like `set ladder.cutoff 800`
+params                    list the parameters, their values and ranges
+trigger <pitch> [vel]     play a note, like `trigger A4` or `trigger 440Hz 0.5`
+chain                     show the sources and effects, and the mono fold-down
+chain add <node> [value]  add or change one, like `chain add reverb`
+chain remove <node>       take one out again
+patch <chain>             play a chain, like `patch fm |> lpf(1200) |> reverb(0.3)`
+randomize [group]         set parameters at random, like `randomize ladder`
+mutate [group] [amount]   nudge them, by a tenth of their range unless told
+undo                      put back what the last randomize or mutate changed
+help                      this";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Console {
+    Help,
+    Set(String, f32),
+    Params,
+    /// A note at a frequency with a velocity in 0..=1, which rings out with
+    /// no note off. Sources that hold their notes play it until it's stolen.
+    Trigger(f32, f32),
+    Chain,
+    /// Adds a node to the patch the engine was built from, or replaces the
+    /// one with the same key.
+    ChainAdd(Entry),
+    ChainRemove(String),
+    /// Swaps to a patch written as a chain, rebuilding only if its sources
+    /// or effects changed.
+    Patch(Patch),
+    /// Sets the parameters in a group, or all of them, at random.
+    Randomize(Option<String>),
+    /// Nudges the parameters in a group by up to this much of their range.
+    Mutate(Option<String>, f32),
+    Undo,
+}
+
+impl FromStr for Console {
+    type Err = String;
+    fn from_str(line: &str) -> Result<Self, String> {
+        let words: Vec<_> = line.split_whitespace().collect();
+        let number = |word: &str| {
+            word.parse::<f32>()
+                .ok()
+                .filter(|n| n.is_finite())
+                .ok_or_else(|| format!("expected a number, got {word:?}"))
+        };
+        let node = |key: &str| {
+            if patch::NODES.contains(&key) {
+                Ok(key.to_string())
+            } else {
+                Err(format!(
+                    "unknown node {key:?}, expected one of {}",
+                    patch::NODES.join(", ")
+                ))
+            }
+        };
+        if let Some(chain) = line.trim().strip_prefix("patch ") {
+            return live::parse(chain).map(Console::Patch).map_err(|errors| {
+                let errors: Vec<_> = errors
+                    .iter()
+                    .map(|e| match &e.field {
+                        Some(field) => format!("{field}: {}", e.message),
+                        None => e.message.clone(),
+                    })
+                    .collect();
+                errors.join(", ")
+            });
+        }
+        Ok(match words[..] {
+            ["help"] => Console::Help,
+            ["set", name, value] => Console::Set(name.to_string(), number(value)?),
+            ["params"] => Console::Params,
+            ["trigger", pitch] | ["trigger", pitch, _] => {
+                let velocity = match words.get(2) {
+                    Some(v) => number(v)?.clamp(0., 1.),
+                    None => 0.8,
+                };
+                Console::Trigger(pitch.parse::<Pitch>()?.freq(), velocity)
+            }
+            ["chain"] => Console::Chain,
+            ["chain", "add", key] | ["chain", "add", key, _] => {
+                let key = node(key)?;
+                // switches are the only nodes that need nothing more
+                let raw = words.get(3).copied().unwrap_or("true");
+                Console::ChainAdd(
+                    patch::node_entry(&key, raw)
+                        .ok_or_else(|| format!("bad value {raw:?} for {key}"))?,
+                )
+            }
+            ["chain", "remove", key] => Console::ChainRemove(node(key)?),
+            ["randomize"] => Console::Randomize(None),
+            ["randomize", group] => Console::Randomize(Some(group.to_string())),
+            ["mutate", ref rest @ ..] if rest.len() <= 2 => {
+                let (group, amount) = match rest {
+                    [] => (None, DEFAULT_MUTATION),
+                    [only] => match only.parse() {
+                        Ok(amount) => (None, amount),
+                        Err(_) => (Some(only.to_string()), DEFAULT_MUTATION),
+                    },
+                    [group, amount] => (Some(group.to_string()), number(amount)?),
+                    _ => unreachable!(),
+                };
+                Console::Mutate(group, amount.clamp(0., 1.))
+            }
+            ["undo"] => Console::Undo,
+            _ => return Err(format!("don't know {:?}, try `help`", line.trim())),
+        })
+    }
+}
+
+/// Reads commands from stdin until it closes, answering `help` and
+/// mistakes itself and sending the rest to the audio thread. Notes are
+/// stamped on `clock` as they're read.
+pub fn spawn(send: Sender<AudioEvent>, clock: EngineClock) {
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event = match line.parse() {
+                Ok(Console::Help) => {
+                    println!("{HELP}");
+                    continue;
+                }
+                Ok(Console::Trigger(freq, velocity)) => {
+                    AudioEvent::PlayNote(freq, velocity, clock.now())
+                }
+                Ok(command) => AudioEvent::Console(command),
+                Err(e) => {
+                    println!("console: {e}");
+                    continue;
+                }
+            };
+            if send.send(event).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch::Value;
+
+    #[test]
+    fn test_console() {
+        assert_eq!(
+            "set ladder.cutoff 800".parse(),
+            Ok(Console::Set("ladder.cutoff".to_string(), 800.))
+        );
+        let Ok(Console::Trigger(freq, velocity)) = " trigger  A4 ".parse() else {
+            panic!()
+        };
+        assert_eq!((freq, velocity), (440., 0.8));
+        assert_eq!(
+            "chain add reverb".parse(),
+            Ok(Console::ChainAdd(Entry::new("reverb", Value::Bool(true))))
+        );
+        assert_eq!(
+            "chain add ladder 1200".parse(),
+            Ok(Console::ChainAdd(Entry::new(
+                "ladder",
+                Value::Number(1200.)
+            )))
+        );
+        assert!("chain add ladder".parse::<Console>().is_err());
+        assert!("chain add phaser".parse::<Console>().is_err());
+        assert!("set ladder.cutoff loud".parse::<Console>().is_err());
+        assert_eq!("mutate 0.3".parse(), Ok(Console::Mutate(None, 0.3)));
+        assert_eq!(
+            "mutate ladder".parse(),
+            Ok(Console::Mutate(
+                Some("ladder".to_string()),
+                DEFAULT_MUTATION
+            ))
+        );
+        assert!(matches!(
+            "patch string(4) |> reverb".parse(),
+            Ok(Console::Patch(patch)) if patch.nodes.len() == 4
+        ));
+        assert!("patch string |> phaser".parse::<Console>().is_err());
+        assert!("".parse::<Console>().is_err());
+    }
+}
@@ -0,0 +1,368 @@
+//! Echo effects.
+
+use crate::filters::{Filter, SAMPLING_FREQ};
+use crate::params::{ParamInfo, Params, Smoothed};
+use crate::stereo::StereoFilter;
+
+/// Longest echo a [`FeedbackDelay`] can make.
+pub const MAX_DELAY_SECS: f32 = 4.;
+
+/// How long the read head takes to glide to a new delay time.
+const GLIDE_SECS: f32 = 0.05;
+
+/// A delay time as written on the command line: milliseconds ("350ms", or
+/// just "350") or a note length relative to the tempo ("1/8"), optionally
+/// dotted ("1/8.") or a triplet ("1/8t").
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DelayTime {
+    Ms(f32),
+    Beats(f32),
+}
+
+impl std::str::FromStr for DelayTime {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        let err = || format!("invalid delay time {value:?}");
+        let Some((num, den)) = value.split_once('/') else {
+            let ms = value.strip_suffix("ms").unwrap_or(value);
+            return ms.trim().parse().map(DelayTime::Ms).map_err(|_| err());
+        };
+
+        let (den, scale) = if let Some(den) = den.strip_suffix('.') {
+            (den, 1.5)
+        } else if let Some(den) = den.strip_suffix('t') {
+            (den, 2. / 3.)
+        } else {
+            (den, 1.)
+        };
+        let num: f32 = num.trim().parse().map_err(|_| err())?;
+        let den: f32 = den.trim().parse().map_err(|_| err())?;
+        if den <= 0. {
+            return Err(err());
+        }
+        // a quarter note is a beat
+        Ok(DelayTime::Beats(4. * num / den * scale))
+    }
+}
+
+/// A ring buffer of [`MAX_DELAY_SECS`], read between samples.
+struct Line {
+    buf: Vec<f32>,
+    pos: usize,
+}
+
+impl Line {
+    fn new() -> Self {
+        Self {
+            buf: vec![0.; (MAX_DELAY_SECS * SAMPLING_FREQ as f32) as usize + 2],
+            pos: 0,
+        }
+    }
+
+    /// The longest delay it can read back.
+    fn max_delay(&self) -> f32 {
+        (self.buf.len() - 2) as f32
+    }
+
+    /// What went in `delay` samples ago.
+    fn read(&self, delay: f32) -> f32 {
+        let len = self.buf.len();
+        let read = self.pos as f32 + len as f32 - delay;
+        let idx = read as usize;
+        let frac = read - idx as f32;
+        let a = self.buf[idx % len];
+        let b = self.buf[(idx + 1) % len];
+        a + (b - a) * frac
+    }
+
+    fn write(&mut self, s: f32) {
+        self.buf[self.pos] = s;
+        self.pos = (self.pos + 1) % self.buf.len();
+    }
+}
+
+/// The delay `time` milliseconds make, or `beats` at `bpm` if that's
+/// nonzero, in samples, as far as `line` goes.
+fn delay_samples(time: f32, beats: f32, bpm: f32, line: &Line) -> f32 {
+    let secs = if beats > 0. {
+        beats * 60. / bpm.max(1.)
+    } else {
+        time / 1000.
+    };
+    (secs * SAMPLING_FREQ as f32).clamp(1., line.max_delay())
+}
+
+fn glide_coeff() -> f32 {
+    1. - (-1. / (GLIDE_SECS * SAMPLING_FREQ as f32)).exp()
+}
+
+/// Echo with feedback. The time is either `time` in milliseconds, or if
+/// `beats` is nonzero, that many beats at `bpm`. Changing it makes the read
+/// head glide over rather than jump, so it bends the pitch a little instead
+/// of clicking.
+pub struct FeedbackDelay {
+    pub time: f32,
+    pub beats: f32,
+    pub bpm: f32,
+    pub feedback: f32,
+    pub mix: f32,
+
+    line: Line,
+    /// current delay in samples, which chases the target
+    delay: f32,
+    glide: f32,
+    smoothed_feedback: Smoothed,
+    smoothed_mix: Smoothed,
+}
+
+impl FeedbackDelay {
+    pub fn new(time: DelayTime, bpm: f32, feedback: f32, mix: f32) -> Self {
+        let (time, beats) = match time {
+            DelayTime::Ms(ms) => (ms, 0.),
+            DelayTime::Beats(beats) => (0., beats),
+        };
+        let mut this = Self {
+            time,
+            beats,
+            bpm,
+            feedback,
+            mix,
+            line: Line::new(),
+            delay: 0.,
+            glide: glide_coeff(),
+            smoothed_feedback: Smoothed::new(feedback.clamp(0., 0.99)),
+            smoothed_mix: Smoothed::new(mix.clamp(0., 1.)),
+        };
+        this.delay = this.target();
+        this
+    }
+
+    /// The delay being glided towards, in samples.
+    fn target(&self) -> f32 {
+        delay_samples(self.time, self.beats, self.bpm, &self.line)
+    }
+}
+
+impl Filter for FeedbackDelay {
+    fn process(&mut self, samples: &mut [f32]) {
+        let target = self.target();
+        self.smoothed_feedback.set(self.feedback.clamp(0., 0.99));
+        self.smoothed_mix.set(self.mix.clamp(0., 1.));
+        for s in samples.iter_mut() {
+            let feedback = self.smoothed_feedback.next_value();
+            let mix = self.smoothed_mix.next_value();
+            self.delay += (target - self.delay) * self.glide;
+
+            let echo = self.line.read(self.delay);
+            self.line.write(*s + echo * feedback);
+            *s = *s * (1. - mix) + echo * mix;
+        }
+    }
+}
+
+impl Params for FeedbackDelay {
+    fn params(&self) -> Vec<ParamInfo> {
+        vec![
+            ParamInfo::new("time", 1., MAX_DELAY_SECS * 1000.),
+            ParamInfo::new("beats", 0., 4.),
+            ParamInfo::new("bpm", 20., 300.).not_random(),
+            ParamInfo::new("feedback", 0., 0.95).random_range(0., 0.8),
+            ParamInfo::new("mix", 0., 1.),
+        ]
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        Some(match name {
+            "time" => self.time,
+            "beats" => self.beats,
+            "bpm" => self.bpm,
+            "feedback" => self.feedback,
+            "mix" => self.mix,
+            _ => return None,
+        })
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name {
+            "time" => self.time = value,
+            "beats" => self.beats = value,
+            "bpm" => self.bpm = value,
+            "feedback" => self.feedback = value,
+            "mix" => self.mix = value,
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Echoes that go back and forth between the sides, starting on the
+/// left. Both sides go in, and each echo is the one before it from the
+/// other side, turned down by that side's feedback. The time works as for
+/// [`FeedbackDelay`], and is how far apart the echoes are.
+pub struct PingPongDelay {
+    pub time: f32,
+    pub beats: f32,
+    pub bpm: f32,
+    /// how much of each echo on the left comes back on the right
+    pub feedback_left: f32,
+    /// and from the right back to the left
+    pub feedback_right: f32,
+    pub mix: f32,
+
+    lines: [Line; 2],
+    delay: f32,
+    glide: f32,
+    smoothed_feedback: [Smoothed; 2],
+    smoothed_mix: Smoothed,
+}
+
+impl PingPongDelay {
+    pub fn new(time: DelayTime, bpm: f32, feedback: f32, mix: f32) -> Self {
+        let (time, beats) = match time {
+            DelayTime::Ms(ms) => (ms, 0.),
+            DelayTime::Beats(beats) => (0., beats),
+        };
+        let feedback = feedback.clamp(0., 0.99);
+        let mut this = Self {
+            time,
+            beats,
+            bpm,
+            feedback_left: feedback,
+            feedback_right: feedback,
+            mix,
+            lines: [Line::new(), Line::new()],
+            delay: 0.,
+            glide: glide_coeff(),
+            smoothed_feedback: [Smoothed::new(feedback), Smoothed::new(feedback)],
+            smoothed_mix: Smoothed::new(mix.clamp(0., 1.)),
+        };
+        this.delay = this.target();
+        this
+    }
+
+    fn target(&self) -> f32 {
+        delay_samples(self.time, self.beats, self.bpm, &self.lines[0])
+    }
+}
+
+impl StereoFilter for PingPongDelay {
+    fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) {
+        let target = self.target();
+        self.smoothed_feedback[0].set(self.feedback_left.clamp(0., 0.99));
+        self.smoothed_feedback[1].set(self.feedback_right.clamp(0., 0.99));
+        self.smoothed_mix.set(self.mix.clamp(0., 1.));
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            let feedback_left = self.smoothed_feedback[0].next_value();
+            let feedback_right = self.smoothed_feedback[1].next_value();
+            let mix = self.smoothed_mix.next_value();
+            self.delay += (target - self.delay) * self.glide;
+
+            let echo_left = self.lines[0].read(self.delay);
+            let echo_right = self.lines[1].read(self.delay);
+            self.lines[0].write(0.5 * (*l + *r) + echo_right * feedback_right);
+            self.lines[1].write(echo_left * feedback_left);
+            *l = *l * (1. - mix) + echo_left * mix;
+            *r = *r * (1. - mix) + echo_right * mix;
+        }
+    }
+}
+
+impl Params for PingPongDelay {
+    fn params(&self) -> Vec<ParamInfo> {
+        vec![
+            ParamInfo::new("time", 1., MAX_DELAY_SECS * 1000.),
+            ParamInfo::new("beats", 0., 4.),
+            ParamInfo::new("bpm", 20., 300.).not_random(),
+            ParamInfo::new("feedback_left", 0., 0.95).random_range(0., 0.8),
+            ParamInfo::new("feedback_right", 0., 0.95).random_range(0., 0.8),
+            ParamInfo::new("mix", 0., 1.),
+        ]
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        Some(match name {
+            "time" => self.time,
+            "beats" => self.beats,
+            "bpm" => self.bpm,
+            "feedback_left" => self.feedback_left,
+            "feedback_right" => self.feedback_right,
+            "mix" => self.mix,
+            _ => return None,
+        })
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name {
+            "time" => self.time = value,
+            "beats" => self.beats = value,
+            "bpm" => self.bpm = value,
+            "feedback_left" => self.feedback_left = value,
+            "feedback_right" => self.feedback_right = value,
+            "mix" => self.mix = value,
+            _ => return false,
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_time() {
+        assert_eq!("350ms".parse(), Ok(DelayTime::Ms(350.)));
+        assert_eq!("350".parse(), Ok(DelayTime::Ms(350.)));
+        assert_eq!("1/4".parse(), Ok(DelayTime::Beats(1.)));
+        assert_eq!("1/8.".parse(), Ok(DelayTime::Beats(0.75)));
+        assert_eq!("3/4t".parse(), Ok(DelayTime::Beats(2.)));
+        assert!("1/0".parse::<DelayTime>().is_err());
+        assert!("fast".parse::<DelayTime>().is_err());
+    }
+
+    #[test]
+    fn test_feedback_delay() {
+        // an eighth note at 120bpm is a quarter second
+        let mut delay = FeedbackDelay::new(DelayTime::Beats(0.5), 120., 0.5, 1.);
+        let step = SAMPLING_FREQ / 4;
+        let mut out = vec![0.; step * 3 + 1];
+        out[0] = 1.;
+        delay.process(&mut out);
+        assert_eq!(out[0], 0.);
+        assert!((out[step] - 1.).abs() < 1e-6);
+        assert!((out[step * 2] - 0.5).abs() < 1e-6);
+        assert!((out[step * 3] - 0.25).abs() < 1e-6);
+
+        // halving the time glides rather than jumps
+        let mut delay = FeedbackDelay::new(DelayTime::Ms(100.), 120., 0., 1.);
+        let mut out: Vec<f32> = (0..SAMPLING_FREQ)
+            .map(|i| (i as f32 * 0.01).sin())
+            .collect();
+        delay.process(&mut out[..SAMPLING_FREQ / 2]);
+        delay.set_param("time", 50.);
+        delay.process(&mut out[SAMPLING_FREQ / 2..]);
+        let biggest_jump = out[SAMPLING_FREQ / 4..]
+            .windows(2)
+            .map(|w| (w[1] - w[0]).abs())
+            .fold(0f32, f32::max);
+        assert!(biggest_jump < 0.02, "{biggest_jump}");
+    }
+
+    #[test]
+    fn test_ping_pong_delay() {
+        let mut delay = PingPongDelay::new(DelayTime::Beats(0.5), 120., 0.5, 1.);
+        delay.set_param("feedback_right", 0.25);
+        let step = SAMPLING_FREQ / 4;
+        let mut left = vec![0.; step * 4 + 1];
+        let mut right = left.clone();
+        // from the right, which goes in as much as the left
+        right[0] = 2.;
+        delay.process_stereo(&mut left, &mut right);
+        let at = |side: &[f32]| [1, 2, 3, 4].map(|n| side[step * n]);
+        let close =
+            |got: [f32; 4], want: [f32; 4]| got.iter().zip(want).all(|(a, b)| (a - b).abs() < 1e-3);
+        // left, right, left, right, each the other side's feedback down
+        assert!(close(at(&left), [1., 0., 0.125, 0.]), "{:?}", at(&left));
+        assert!(close(at(&right), [0., 0.5, 0., 0.0625]), "{:?}", at(&right));
+    }
+}
@@ -0,0 +1,235 @@
+//! Overdrive and other ways of bending the waveform.
+
+use crate::filters::{Biquad, Filter, SAMPLING_FREQ};
+use crate::params::{ParamInfo, Params, Smoothed};
+
+/// Q of each section of an 8th order Butterworth low-pass.
+const BUTTERWORTH_8: [f32; 4] = [0.5098, 0.6013, 0.9000, 2.5629];
+
+/// The transfer curve of a [`Waveshaper`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Curve {
+    /// Smooth saturation.
+    Tanh,
+    HardClip,
+    /// Folds back down past full scale instead of flattening, which gets
+    /// very bright very quickly.
+    Foldback,
+    /// Saturates the negative half sooner than the positive half, adding
+    /// even harmonics (and some DC).
+    Asymmetric,
+}
+
+impl Curve {
+    pub const ALL: [Curve; 4] = [
+        Curve::Tanh,
+        Curve::HardClip,
+        Curve::Foldback,
+        Curve::Asymmetric,
+    ];
+
+    pub fn index(self) -> usize {
+        Self::ALL.iter().position(|&c| c == self).unwrap()
+    }
+
+    pub fn shape(self, x: f32) -> f32 {
+        match self {
+            Curve::Tanh => x.tanh(),
+            Curve::HardClip => x.clamp(-1., 1.),
+            Curve::Foldback => {
+                let t = (x + 1.).rem_euclid(4.);
+                if t < 2. {
+                    t - 1.
+                } else {
+                    3. - t
+                }
+            }
+            Curve::Asymmetric => {
+                if x >= 0. {
+                    x.tanh()
+                } else {
+                    0.5 * (2. * x).tanh()
+                }
+            }
+        }
+    }
+}
+
+/// `tanh`, `hard`, `fold` or `asym`.
+impl std::str::FromStr for Curve {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        Ok(match value {
+            "tanh" => Curve::Tanh,
+            "hard" => Curve::HardClip,
+            "fold" => Curve::Foldback,
+            "asym" => Curve::Asymmetric,
+            _ => return Err(format!("unknown curve {value:?}")),
+        })
+    }
+}
+
+/// Band limits the oversampled signal to our own Nyquist frequency.
+struct HalfBand([Biquad; 4]);
+
+impl HalfBand {
+    fn new(factor: usize) -> Self {
+        // biquads are designed at our sample rate, so scale the cutoff down to
+        // get the same fraction of the oversampled rate
+        let cutoff = 0.45 * SAMPLING_FREQ as f32 / factor as f32;
+        Self(BUTTERWORTH_8.map(|q| Biquad::low_pass(cutoff, q)))
+    }
+}
+
+impl Filter for HalfBand {
+    fn process(&mut self, samples: &mut [f32]) {
+        for section in &mut self.0 {
+            section.process(samples);
+        }
+    }
+}
+
+/// Multiplies the signal by `drive`, bends it with `curve`, and scales it by
+/// `level`. With `oversample` above 1 the curve runs at that many times the
+/// sample rate, so the harmonics it adds above Nyquist get filtered out
+/// rather than aliasing back down.
+pub struct Waveshaper {
+    pub curve: Curve,
+    pub drive: f32,
+    pub level: f32,
+
+    oversample: usize,
+    up: HalfBand,
+    down: HalfBand,
+    scratch: Vec<f32>,
+    smoothed_drive: Smoothed,
+    smoothed_level: Smoothed,
+}
+
+impl Waveshaper {
+    pub fn new(curve: Curve, oversample: usize) -> Self {
+        let oversample = oversample.max(1);
+        Self {
+            curve,
+            drive: 1.,
+            level: 1.,
+            oversample,
+            up: HalfBand::new(oversample),
+            down: HalfBand::new(oversample),
+            scratch: Vec::new(),
+            smoothed_drive: Smoothed::new(1.),
+            smoothed_level: Smoothed::new(1.),
+        }
+    }
+}
+
+impl Filter for Waveshaper {
+    fn process(&mut self, samples: &mut [f32]) {
+        let curve = self.curve;
+        self.smoothed_drive.set(self.drive);
+        self.smoothed_level.set(self.level);
+        let factor = self.oversample;
+        if factor == 1 {
+            for s in samples.iter_mut() {
+                let drive = self.smoothed_drive.next_value();
+                *s = curve.shape(*s * drive) * self.smoothed_level.next_value();
+            }
+            return;
+        }
+
+        // zero stuffing, with the gain made up so the images filter out to
+        // the original level
+        self.scratch.clear();
+        for &s in samples.iter() {
+            self.scratch.push(s * factor as f32);
+            self.scratch.extend(std::iter::repeat_n(0., factor - 1));
+        }
+        self.up.process(&mut self.scratch);
+        for chunk in self.scratch.chunks_mut(factor) {
+            let drive = self.smoothed_drive.next_value();
+            for s in chunk {
+                *s = curve.shape(*s * drive);
+            }
+        }
+        self.down.process(&mut self.scratch);
+        for (s, over) in samples.iter_mut().zip(self.scratch.iter().step_by(factor)) {
+            *s = over * self.smoothed_level.next_value();
+        }
+    }
+}
+
+impl Params for Waveshaper {
+    fn params(&self) -> Vec<ParamInfo> {
+        vec![
+            ParamInfo::new("curve", 0., (Curve::ALL.len() - 1) as f32),
+            ParamInfo::new("drive", 0.1, 50.).random_range(0.5, 10.),
+            ParamInfo::new("level", 0., 2.).not_random(),
+        ]
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        Some(match name {
+            "curve" => self.curve.index() as f32,
+            "drive" => self.drive,
+            "level" => self.level,
+            _ => return None,
+        })
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name {
+            "curve" => {
+                let idx = (value.round().max(0.) as usize).min(Curve::ALL.len() - 1);
+                self.curve = Curve::ALL[idx];
+            }
+            "drive" => self.drive = value,
+            "level" => self.level = value,
+            _ => return false,
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::TAU;
+
+    /// Magnitude of the `freq` component of a second of audio.
+    fn magnitude(samples: &[f32], freq: f32) -> f32 {
+        let (mut re, mut im) = (0., 0.);
+        for (i, s) in samples.iter().enumerate() {
+            let phase = TAU * freq * i as f32 / SAMPLING_FREQ as f32;
+            re += s * phase.cos();
+            im += s * phase.sin();
+        }
+        2. * (re * re + im * im).sqrt() / samples.len() as f32
+    }
+
+    #[test]
+    fn test_waveshaper() {
+        assert_eq!(Curve::Foldback.shape(1.5), 0.5);
+        assert_eq!(Curve::Foldback.shape(-3.5), 0.5);
+        assert_eq!(Curve::HardClip.shape(-3.), -1.);
+        assert!(Curve::Asymmetric.shape(-10.) > -0.6);
+        assert_eq!("fold".parse(), Ok(Curve::Foldback));
+        assert!("fuzz".parse::<Curve>().is_err());
+
+        // the 7th harmonic of a hard clipped 5kHz sine is at 35kHz, which
+        // aliases to 9.1kHz unless it's oversampled
+        let sine: Vec<f32> = (0..SAMPLING_FREQ)
+            .map(|i| (TAU * 5000. * i as f32 / SAMPLING_FREQ as f32).sin())
+            .collect();
+        let alias = |oversample| {
+            let mut shaper = Waveshaper::new(Curve::HardClip, oversample);
+            shaper.drive = 4.;
+            let mut out = sine.clone();
+            shaper.process(&mut out);
+            assert!(magnitude(&out, 5000.) > 0.9);
+            magnitude(&out, SAMPLING_FREQ as f32 - 35000.)
+        };
+        let (plain, oversampled) = (alias(1), alias(4));
+        assert!(plain > 0.05, "{plain}");
+        assert!(oversampled < plain / 10., "{oversampled} vs {plain}");
+    }
+}
@@ -0,0 +1,467 @@
+//! Keeping levels under control.
+
+use std::str::FromStr;
+
+use crate::filters::{Biquad, Bus, Effect, Filter, SAMPLING_FREQ};
+use crate::note::Pitch;
+use crate::params::{nested, ParamInfo, Params, Smoothed};
+use crate::snapshot::Node;
+use crate::stereo::StereoFilter;
+
+fn gain(db: f32) -> f32 {
+    10f32.powf(db / 20.)
+}
+
+/// One pole smoothing coefficient for a time constant in milliseconds.
+fn coeff(ms: f32) -> f32 {
+    if ms <= 0. {
+        return 1.;
+    }
+    1. - (-1000. / (ms * SAMPLING_FREQ as f32)).exp()
+}
+
+/// Feed-forward peak compressor. Levels above `threshold` (dB) are turned
+/// down to rise only 1/`ratio` as fast, with the gain reduction coming in
+/// over `attack` and going away over `release` (both ms), then everything is
+/// turned up by `makeup` (dB).
+///
+/// With `limit` set it's a brickwall limiter instead: the makeup gain goes
+/// first, and nothing gets out above `threshold`, however fast it arrives.
+///
+/// In stereo both sides get the same gain, set by whichever is louder, so
+/// the image doesn't lurch sideways when one side trips it.
+pub struct Compressor {
+    pub threshold: f32,
+    pub ratio: f32,
+    pub attack: f32,
+    pub release: f32,
+    pub makeup: f32,
+    pub limit: bool,
+
+    /// current gain reduction in dB
+    reduction: f32,
+    makeup_gain: Smoothed,
+}
+
+impl Default for Compressor {
+    fn default() -> Self {
+        Self {
+            threshold: -3.,
+            ratio: 4.,
+            attack: 5.,
+            release: 100.,
+            makeup: 0.,
+            limit: false,
+            reduction: 0.,
+            makeup_gain: Smoothed::new(1.),
+        }
+    }
+}
+
+impl Compressor {
+    /// A brickwall limiter at `ceiling` dB.
+    pub fn limiter(ceiling: f32) -> Self {
+        Self {
+            threshold: ceiling,
+            attack: 0.,
+            limit: true,
+            ..Self::default()
+        }
+    }
+}
+
+impl Compressor {
+    /// Compresses `samples` by how loud `key` is rather than by how loud
+    /// they are themselves.
+    pub fn process_keyed(&mut self, samples: &mut [f32], key: &[f32]) {
+        self.run(samples, None, Some(key));
+    }
+
+    fn run(&mut self, samples: &mut [f32], mut right: Option<&mut [f32]>, key: Option<&[f32]>) {
+        let slope = if self.limit {
+            1.
+        } else {
+            1. - 1. / self.ratio.max(1.)
+        };
+        let (attack, release) = (coeff(self.attack), coeff(self.release));
+        self.makeup_gain.set(gain(self.makeup));
+        for (i, s) in samples.iter_mut().enumerate() {
+            let makeup = self.makeup_gain.next_value();
+            let (pre, post) = if self.limit {
+                (makeup, 1.)
+            } else {
+                (1., makeup)
+            };
+            let x = *s * pre;
+            let y = right.as_ref().map_or(0., |right| right[i] * pre);
+            let detected = match key {
+                Some(key) => key.get(i).copied().unwrap_or(0.) * pre,
+                None => x.abs().max(y.abs()),
+            };
+            let level = 20. * detected.abs().max(1e-6).log10();
+            let target = (level - self.threshold).max(0.) * slope;
+            if self.limit && target > self.reduction {
+                self.reduction = target;
+            } else {
+                let coeff = if target > self.reduction {
+                    attack
+                } else {
+                    release
+                };
+                self.reduction += (target - self.reduction) * coeff;
+            }
+            let g = gain(-self.reduction) * post;
+            *s = x * g;
+            if let Some(right) = &mut right {
+                right[i] = y * g;
+            }
+        }
+    }
+}
+
+impl Filter for Compressor {
+    fn process(&mut self, samples: &mut [f32]) {
+        self.run(samples, None, None);
+    }
+}
+
+impl StereoFilter for Compressor {
+    fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) {
+        self.run(left, Some(right), None);
+    }
+}
+
+/// A compressor with a second input: `key` gets a copy of what comes in,
+/// and how loud its output is decides how much the signal itself gets
+/// turned down. A key that generates its own sound (a kick drum, say) and
+/// ignores its input makes the signal duck under it; a filter makes the
+/// compressor react more to some frequencies than others.
+pub struct Sidechain<K: Filter> {
+    pub key: K,
+    pub compressor: Compressor,
+    key_buf: Vec<f32>,
+}
+
+impl<K: Filter> Sidechain<K> {
+    pub fn new(key: K, compressor: Compressor) -> Self {
+        Self {
+            key,
+            compressor,
+            key_buf: Vec::new(),
+        }
+    }
+}
+
+impl<K: Filter> Filter for Sidechain<K> {
+    fn process(&mut self, samples: &mut [f32]) {
+        self.key_buf.clear();
+        self.key_buf.extend_from_slice(samples);
+        self.key.process(&mut self.key_buf);
+        self.compressor.process_keyed(samples, &self.key_buf);
+    }
+
+    fn describe(&self) -> Node {
+        Node::with_children("Sidechain", vec![self.key.describe()])
+    }
+}
+
+/// The compressor's parameters, and the key's under `key.`.
+impl<K: Filter + Params> Params for Sidechain<K> {
+    fn params(&self) -> Vec<ParamInfo> {
+        let mut params = self.compressor.params();
+        params.extend(nested("key", &self.key));
+        params
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name.strip_prefix("key.") {
+            Some(name) => self.key.get_param(name),
+            None => self.compressor.get_param(name),
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name.strip_prefix("key.") {
+            Some(name) => self.key.set_param(name, value),
+            None => self.compressor.set_param(name, value),
+        }
+    }
+}
+
+/// How long a [`Pulse`] takes to die away to -60dB.
+const PULSE_SECS: f32 = 0.2;
+/// The Q of a [`Key`]'s filter: no resonance.
+const KEY_Q: f32 = 0.707;
+
+/// What a [`Sidechain`] listens to: "beat" ducks everything on every beat,
+/// so it pumps in time, and "highpass:<hz>" or "lowpass:<hz>" make it react
+/// only to the top or the bottom of the sound. "below:<note>" listens to
+/// the voices playing that note or lower, through a [`Bus`], so a kick
+/// or a bass line down there ducks the pad above it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Key {
+    Beat,
+    HighPass(f32),
+    LowPass(f32),
+    Below(u8),
+}
+
+impl FromStr for Key {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        let (kind, hz) = value.split_once(':').unwrap_or((value, ""));
+        let cutoff = || {
+            hz.trim()
+                .parse::<f32>()
+                .ok()
+                .filter(|hz| *hz > 0. && *hz < SAMPLING_FREQ as f32 / 2.)
+                .ok_or_else(|| format!("bad cutoff {hz:?} in {value:?}"))
+        };
+        Ok(match kind {
+            "beat" => Key::Beat,
+            "highpass" => Key::HighPass(cutoff()?),
+            "lowpass" => Key::LowPass(cutoff()?),
+            "below" => {
+                let (note, _) = hz.parse::<Pitch>()?.nearest();
+                Key::Below(note.clamp(0, 127) as u8)
+            }
+            _ => {
+                return Err(format!(
+                    "expected beat, highpass:<hz>, lowpass:<hz> or below:<note>, got {value:?}"
+                ))
+            }
+        })
+    }
+}
+
+impl Key {
+    /// A sidechain compressor keyed this way, with beats at `bpm` and
+    /// voices coming in on `bus`. It's set to duck hard and let go quickly;
+    /// its parameters are there to tame it.
+    pub fn sidechain(self, bpm: f32, bus: &Bus) -> Sidechain<Box<dyn Effect>> {
+        let key: Box<dyn Effect> = match self {
+            Key::Beat => Box::new(Pulse::new(bpm)),
+            Key::HighPass(hz) => Box::new(Biquad::high_pass(hz, KEY_Q)),
+            Key::LowPass(hz) => Box::new(Biquad::low_pass(hz, KEY_Q)),
+            Key::Below(_) => Box::new(bus.clone()),
+        };
+        Sidechain::new(
+            key,
+            Compressor {
+                threshold: -20.,
+                ratio: 8.,
+                attack: 1.,
+                release: 150.,
+                ..Compressor::default()
+            },
+        )
+    }
+}
+
+/// A thump on every beat for a [`Sidechain`] to duck under, at full level
+/// on the beat and dying away over [`PULSE_SECS`]. What comes in is thrown
+/// away.
+pub struct Pulse {
+    pub bpm: f32,
+    /// samples into the current beat
+    elapsed: f64,
+}
+
+impl Pulse {
+    pub fn new(bpm: f32) -> Self {
+        Self { bpm, elapsed: 0. }
+    }
+}
+
+impl Filter for Pulse {
+    fn process(&mut self, samples: &mut [f32]) {
+        let beat_len = SAMPLING_FREQ as f64 * 60. / self.bpm.clamp(20., 300.) as f64;
+        for s in samples.iter_mut() {
+            if self.elapsed >= beat_len {
+                self.elapsed -= beat_len;
+            }
+            let t = self.elapsed as f32 / SAMPLING_FREQ as f32;
+            *s = (-6.9 * t / PULSE_SECS).exp();
+            self.elapsed += 1.;
+        }
+    }
+}
+
+impl Params for Pulse {
+    fn params(&self) -> Vec<ParamInfo> {
+        vec![ParamInfo::new("bpm", 20., 300.).not_random()]
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        (name == "bpm").then_some(self.bpm)
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        if name != "bpm" {
+            return false;
+        }
+        self.bpm = value;
+        true
+    }
+}
+
+impl Params for Compressor {
+    fn params(&self) -> Vec<ParamInfo> {
+        vec![
+            ParamInfo::new("threshold", -60., 0.).random_range(-30., 0.),
+            ParamInfo::new("ratio", 1., 20.),
+            ParamInfo::new("attack", 0., 100.),
+            ParamInfo::new("release", 10., 1000.),
+            ParamInfo::new("makeup", 0., 24.).not_random(),
+            ParamInfo::new("limit", 0., 1.).not_random(),
+        ]
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        Some(match name {
+            "threshold" => self.threshold,
+            "ratio" => self.ratio,
+            "attack" => self.attack,
+            "release" => self.release,
+            "makeup" => self.makeup,
+            "limit" => self.limit as u8 as f32,
+            _ => return None,
+        })
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name {
+            "threshold" => self.threshold = value,
+            "ratio" => self.ratio = value,
+            "attack" => self.attack = value,
+            "release" => self.release = value,
+            "makeup" => self.makeup = value,
+            "limit" => self.limit = value >= 0.5,
+            _ => return false,
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::TAU;
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0f32, |m, s| m.max(s.abs()))
+    }
+
+    #[test]
+    fn test_compressor() {
+        // a sine peaking at 0dB
+        let sine: Vec<f32> = (0..SAMPLING_FREQ)
+            .map(|i| (TAU * 100. * i as f32 / SAMPLING_FREQ as f32).sin())
+            .collect();
+
+        // 12dB over a 4:1 threshold comes out 3dB over, once it's settled
+        let mut comp = Compressor {
+            threshold: -12.,
+            ..Compressor::default()
+        };
+        let mut out = sine.clone();
+        comp.process(&mut out);
+        let settled = peak(&out[SAMPLING_FREQ / 2..]);
+        assert!((settled - gain(-9.)).abs() < 0.05, "{settled}");
+
+        let mut limiter = Compressor::limiter(-6.);
+        limiter.makeup = 6.;
+        let mut out = sine.clone();
+        limiter.process(&mut out);
+        assert!(peak(&out) <= gain(-6.) + 1e-5);
+        assert!(peak(&out[SAMPLING_FREQ / 2..]) > gain(-7.));
+
+        // in stereo the quiet side is turned down as much as the loud one
+        let mut comp = Compressor {
+            threshold: -12.,
+            ..Compressor::default()
+        };
+        let mut left = sine.clone();
+        let mut right: Vec<f32> = sine.iter().map(|s| s * 0.1).collect();
+        comp.process_stereo(&mut left, &mut right);
+        let settled = peak(&right[SAMPLING_FREQ / 2..]);
+        assert!((settled - 0.1 * gain(-9.)).abs() < 0.005, "{settled}");
+    }
+
+    /// A key that ignores its input and plays a full scale tone for the
+    /// first half second.
+    struct Burst(usize);
+
+    impl Filter for Burst {
+        fn process(&mut self, samples: &mut [f32]) {
+            for s in samples.iter_mut() {
+                *s = if self.0 < SAMPLING_FREQ / 2 { 1. } else { 0. };
+                self.0 += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn test_sidechain() {
+        let quiet: Vec<f32> = (0..SAMPLING_FREQ)
+            .map(|i| 0.1 * (TAU * 100. * i as f32 / SAMPLING_FREQ as f32).sin())
+            .collect();
+        let mut ducker = Sidechain::new(
+            Burst(0),
+            Compressor {
+                threshold: -20.,
+                ratio: 20.,
+                ..Compressor::default()
+            },
+        );
+        let mut out = quiet.clone();
+        ducker.process(&mut out);
+        // ducked by nearly 20dB while the key plays, then let back up
+        assert!(peak(&out[SAMPLING_FREQ / 4..SAMPLING_FREQ / 2]) < 0.015);
+        assert!(peak(&out[SAMPLING_FREQ * 9 / 10..]) > 0.095);
+    }
+
+    #[test]
+    fn test_beat_key() {
+        assert_eq!("lowpass:200".parse(), Ok(Key::LowPass(200.)));
+        assert!("lowpass".parse::<Key>().is_err());
+        assert!("kick".parse::<Key>().is_err());
+        assert_eq!("below:C2".parse(), Ok(Key::Below(36)));
+
+        // at 120bpm it ducks straight after each beat and is back up by the
+        // one after
+        let quiet: Vec<f32> = (0..SAMPLING_FREQ)
+            .map(|i| 0.1 * (TAU * 100. * i as f32 / SAMPLING_FREQ as f32).sin())
+            .collect();
+        let mut pump = Key::Beat.sidechain(120., &Bus::default());
+        assert_eq!(pump.get_param("key.bpm"), Some(120.));
+        let mut out = quiet.clone();
+        pump.process(&mut out);
+        let beat = SAMPLING_FREQ / 2;
+        assert!(peak(&out[beat + 100..beat + 1000]) < 0.02);
+        assert!(peak(&out[beat - 2000..beat]) > 0.09);
+    }
+
+    #[test]
+    fn test_key_bus() {
+        let quiet: Vec<f32> = (0..SAMPLING_FREQ / 10)
+            .map(|i| 0.1 * (TAU * 100. * i as f32 / SAMPLING_FREQ as f32).sin())
+            .collect();
+        let bus = Bus::default();
+        let mut ducker = Key::Below(36).sidechain(120., &bus);
+        let block = |ducker: &mut Sidechain<_>, loud: bool| {
+            let mut out = quiet.clone();
+            bus.start(out.len());
+            if loud {
+                bus.add(&vec![1.; out.len()]);
+            }
+            ducker.process(&mut out);
+            peak(&out[out.len() / 2..])
+        };
+        // ducks while something's routed in, and not for what's going
+        // through it, however loud
+        assert!(block(&mut ducker, true) < 0.02);
+        let mut ducker = Key::Below(36).sidechain(120., &bus);
+        assert!(block(&mut ducker, false) > 0.095);
+    }
+}
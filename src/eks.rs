@@ -0,0 +1,278 @@
+//! The Extended Karplus-Strong string from Jaffe and Smith, "Extensions of
+//! the Karplus-Strong Plucked-String Algorithm" (1983): the plain loop of
+//! [`StringSynth`](crate::filters::StringSynth) with a dynamics filter on
+//! the pluck, a loop filter that stretches or shortens the decay, and an
+//! allpass in the loop so high notes come out in tune.
+
+use std::f32::consts::{PI, TAU};
+
+use crate::filters::{Adsr, DelayLine, Exciter, Filter, Noise, SAMPLING_FREQ};
+use crate::params::{nested, ParamInfo, Params};
+use crate::snapshot::Node;
+use crate::voice::Voice;
+
+pub struct EksString {
+    /// Where the loop filter's weight sits, in 0..=1: 0.5 is the classic
+    /// two-point average, and further either way rings longer.
+    pub stretch: f32,
+    /// Loop gain, which shortens the decay at every pitch alike. Must stay
+    /// below 1 for the string to die out.
+    pub decay: f32,
+    /// How much softer notes are also darker, in 0..=1. At 0 every note is
+    /// plucked as hard as it goes.
+    pub dynamics: f32,
+    pub env: Adsr,
+    pub exciter: Box<dyn Exciter>,
+
+    /// the loop, less the loop filter's share, with its allpass tuning it
+    delay: DelayLine,
+    note_freq: f32,
+    bend: f32,
+    /// Jaffe and Smith's L, in 0..=1
+    level: f32,
+    /// the dynamics filter's coefficients and state
+    level_b: f32,
+    level_a: f32,
+    level_in: f32,
+    level_out: f32,
+    /// samples of pluck left
+    trigger_count: u32,
+    /// the loop filter's last input
+    last_in: f32,
+    last: f32,
+}
+
+impl Default for EksString {
+    fn default() -> Self {
+        Self {
+            stretch: 0.5,
+            decay: 0.998,
+            dynamics: 0.5,
+            env: Adsr::default(),
+            exciter: Box::<Noise>::default(),
+            delay: DelayLine::new(500),
+            note_freq: 440.,
+            bend: 0.,
+            level: 1.,
+            level_b: 1.,
+            level_a: 0.,
+            level_in: 0.,
+            level_out: 0.,
+            trigger_count: 0,
+            last_in: 0.,
+            last: 0.,
+        }
+    }
+}
+
+impl EksString {
+    fn freq(&self) -> f32 {
+        self.note_freq * 2f32.powf(self.bend / 12.)
+    }
+
+    /// Phase delay of the loop filter at `freq`, in samples.
+    fn stretch_delay(&self, freq: f32) -> f32 {
+        let omega = TAU * freq / SAMPLING_FREQ as f32;
+        let s = self.stretch.clamp(0., 1.);
+        // (1 - S) + S z^-1
+        let phase = (-s * omega.sin()).atan2(1. - s + s * omega.cos());
+        -phase / omega
+    }
+
+    fn tune(&mut self) {
+        let freq = self.freq();
+        // less the sample of feedback via `last`
+        let delay = SAMPLING_FREQ as f32 / freq - 1. - self.stretch_delay(freq);
+        self.delay.set_delay(delay.max(1.));
+    }
+
+    /// The pluck, through the dynamics filter: a one-pole low-pass at the
+    /// note, faded in as `level` comes down.
+    fn pluck(&mut self) -> f32 {
+        let mut burst = [0.];
+        self.exciter.process(&mut burst);
+        let x = burst[0];
+        self.level_out = self.level_b * (x + self.level_in) + self.level_a * self.level_out;
+        self.level_in = x;
+        self.level.powf(4. / 3.) * x + (1. - self.level) * self.level_out
+    }
+}
+
+impl Voice for EksString {
+    fn note_on(&mut self, freq: f32, velocity: f32) {
+        self.note_freq = freq;
+        self.tune();
+        self.level = 1. - self.dynamics.clamp(0., 1.) * (1. - velocity.clamp(0., 1.));
+        let w = (PI * freq.min(SAMPLING_FREQ as f32 * 0.45) / SAMPLING_FREQ as f32).tan();
+        self.level_b = w / (1. + w);
+        self.level_a = (1. - w) / (1. + w);
+        self.exciter.restart();
+        // a period of noise, the way the loop used to be filled
+        self.trigger_count =
+            self.exciter
+                .burst_len()
+                .unwrap_or((SAMPLING_FREQ as f32 / freq).round() as usize) as u32;
+        self.env.note_on();
+    }
+
+    fn note_off(&mut self) {
+        self.env.note_off();
+    }
+
+    fn set_bend(&mut self, semitones: f32) {
+        self.bend = semitones;
+        self.tune();
+    }
+
+    fn is_active(&self) -> bool {
+        !self.env.is_idle()
+    }
+}
+
+impl Filter for EksString {
+    fn process(&mut self, samples: &mut [f32]) {
+        let s = self.stretch.clamp(0., 1.);
+        for out in samples.iter_mut() {
+            let mut x = self.last;
+            if self.trigger_count > 0 {
+                self.trigger_count -= 1;
+                x += self.pluck();
+            }
+            let mut samp = [x];
+            self.delay.process(&mut samp);
+            let y = self.decay * ((1. - s) * samp[0] + s * self.last_in);
+            self.last_in = samp[0];
+            self.last = y;
+            *out = y * self.env.next_level();
+        }
+    }
+
+    fn describe(&self) -> Node {
+        let parts: [&dyn Filter; 2] = [&*self.exciter, &self.delay];
+        Node::with_children(
+            "EksString",
+            parts.into_iter().map(|p| p.describe()).collect(),
+        )
+    }
+}
+
+impl Params for EksString {
+    fn params(&self) -> Vec<ParamInfo> {
+        let mut out = vec![
+            ParamInfo::new("stretch", 0., 1.).random_range(0.2, 0.8),
+            ParamInfo::new("decay", 0.9, 0.999),
+            ParamInfo::new("dynamics", 0., 1.),
+        ];
+        out.extend(nested("env", &self.env));
+        out
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name {
+            "stretch" => Some(self.stretch),
+            "decay" => Some(self.decay),
+            "dynamics" => Some(self.dynamics),
+            _ => self.env.get_param(name.strip_prefix("env.")?),
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name {
+            "stretch" => {
+                self.stretch = value.clamp(0., 1.);
+                // the loop filter's delay is part of the tuning
+                self.tune();
+            }
+            "decay" => self.decay = value.min(0.999),
+            "dynamics" => self.dynamics = value.clamp(0., 1.),
+            _ => {
+                return name
+                    .strip_prefix("env.")
+                    .is_some_and(|rest| self.env.set_param(rest, value))
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pluck(string: &mut EksString, freq: f32, velocity: f32) -> Vec<f32> {
+        string.note_on(freq, velocity);
+        let mut out = vec![0.; SAMPLING_FREQ / 2];
+        string.process(&mut out);
+        out
+    }
+
+    /// The lag between 0.75 and 1.25 periods that lines up best, to a
+    /// fraction of a sample.
+    fn period(samples: &[f32], freq: f32) -> f32 {
+        let corr = |lag: usize| -> f32 {
+            samples
+                .iter()
+                .zip(&samples[lag..])
+                .map(|(a, b)| a * b)
+                .sum()
+        };
+        let period = SAMPLING_FREQ as f32 / freq;
+        let best = ((period * 0.75) as usize..(period * 1.25) as usize)
+            .max_by(|&a, &b| corr(a).total_cmp(&corr(b)))
+            .unwrap();
+        let (l, c, r) = (corr(best - 1), corr(best), corr(best + 1));
+        best as f32 + 0.5 * (l - r) / (l - 2. * c + r)
+    }
+
+    fn energy(samples: &[f32]) -> f32 {
+        samples.iter().map(|s| s * s).sum()
+    }
+
+    #[test]
+    fn test_eks_string() {
+        // in tune however the decay is stretched
+        for stretch in [0.1, 0.5, 0.9] {
+            for freq in [220., 1000., 1760.] {
+                let mut string = EksString::default();
+                assert!(string.set_param("stretch", stretch));
+                let out = pluck(&mut string, freq, 1.);
+                let got = SAMPLING_FREQ as f32 / period(&out[2000..], freq);
+                let cents = 1200. * (got / freq).log2();
+                assert!(
+                    cents.abs() < 3.,
+                    "{freq}Hz came out as {got}Hz at {stretch}"
+                );
+            }
+        }
+
+        // stretching it either way rings longer, and less loop gain shorter
+        let tail = |set: &[(&str, f32)]| {
+            let mut string = EksString::default();
+            string.set_param("decay", 0.999);
+            for &(name, value) in set {
+                string.set_param(name, value);
+            }
+            // differences, leaving out what's left of the pluck's offset
+            let out = pluck(&mut string, 2000., 1.);
+            let changes: Vec<f32> = out[SAMPLING_FREQ / 8..]
+                .windows(2)
+                .map(|w| w[1] - w[0])
+                .collect();
+            energy(&changes)
+        };
+        let plain = tail(&[]);
+        assert!(tail(&[("stretch", 0.1)]) > plain * 2.);
+        assert!(tail(&[("stretch", 0.9)]) > plain * 2.);
+        assert!(tail(&[("decay", 0.99)]) < plain / 2.);
+
+        // softer plucks are quieter, unless they aren't meant to be
+        let played = |dynamics, velocity| {
+            let mut string = EksString::default();
+            string.set_param("dynamics", dynamics);
+            energy(&pluck(&mut string, 440., velocity))
+        };
+        let loud = played(1., 1.);
+        assert!(played(1., 0.2) < loud / 2.);
+        assert_eq!(played(0., 0.2), loud);
+    }
+}
@@ -0,0 +1,194 @@
+//! A log of everything the engine is told to do and everything that goes
+//! wrong in it, a JSON object a line, for working out afterwards what
+//! happened in a performance.
+
+use std::fmt::{self, Write as _};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::clock::{EngineClock, Stamp};
+use crate::guard::EngineError;
+
+/// A value in a line of the log.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Field<'a> {
+    Number(f32),
+    Text(&'a str),
+    Flag(bool),
+    Null,
+}
+
+impl fmt::Display for Field<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            // JSON has no infinities or NaNs
+            Field::Number(n) if n.is_finite() => write!(f, "{n}"),
+            Field::Number(_) | Field::Null => f.write_str("null"),
+            Field::Text(text) => {
+                f.write_char('"')?;
+                for c in text.chars() {
+                    match c {
+                        '"' => f.write_str("\\\"")?,
+                        '\\' => f.write_str("\\\\")?,
+                        '\n' => f.write_str("\\n")?,
+                        c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                        c => f.write_char(c)?,
+                    }
+                }
+                f.write_char('"')
+            }
+            Field::Flag(flag) => write!(f, "{flag}"),
+        }
+    }
+}
+
+/// `{"t":<secs>,"event":<event>,<fields>...}`
+fn line(secs: f64, event: &str, fields: &[(&str, Field)]) -> String {
+    let mut line = format!("{{\"t\":{secs:.6},\"event\":{}", Field::Text(event));
+    for (name, value) in fields {
+        let _ = write!(line, ",{}:{value}", Field::Text(name));
+    }
+    line.push_str("}\n");
+    line
+}
+
+/// How often the writer thread looks for lines to write.
+const WRITE_EVERY: Duration = Duration::from_millis(10);
+
+/// Lines on their way to the file, which the last of them to go waits to
+/// have written.
+struct Writer {
+    lines: Option<Sender<String>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.lines = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Writes lines to `file` until every sender has gone. It never waits on
+/// the channel, only polls it, so senders never have a sleeping thread to
+/// wake up and never take a lock.
+fn write(lines: mpsc::Receiver<String>, mut file: File) {
+    loop {
+        match lines.try_recv() {
+            Ok(line) => {
+                if let Err(e) = file.write_all(line.as_bytes()) {
+                    println!("log: {e}");
+                }
+            }
+            Err(TryRecvError::Empty) => thread::sleep(WRITE_EVERY),
+            Err(TryRecvError::Disconnected) => return,
+        }
+    }
+}
+
+/// Shared between the control loop and the audio callback, which only
+/// writes to it when something has gone wrong anyway. Neither waits on the
+/// file: a thread of its own writes everything.
+#[derive(Clone)]
+pub struct EventLog {
+    writer: Arc<Writer>,
+    clock: EngineClock,
+}
+
+impl EventLog {
+    /// Times in it are seconds on `clock`.
+    pub fn create(path: &Path, clock: EngineClock) -> io::Result<Self> {
+        let file = File::create(path)?;
+        let (lines, recv) = mpsc::channel();
+        let thread = thread::spawn(move || write(recv, file));
+        Ok(Self {
+            writer: Arc::new(Writer {
+                lines: Some(lines),
+                thread: Some(thread),
+            }),
+            clock,
+        })
+    }
+
+    /// Writes down `event` as having happened `at`. Lines get to the file
+    /// within a few milliseconds, and all of them once the last clone of
+    /// the log has gone.
+    pub fn log(&self, at: Stamp, event: &str, fields: &[(&str, Field)]) {
+        let line = line(at.as_secs_f64(), event, fields);
+        if let Some(lines) = &self.writer.lines {
+            // the writer only goes once every sender has
+            let _ = lines.send(line);
+        }
+    }
+
+    /// What the time is now, as the log has it.
+    pub fn now(&self) -> Stamp {
+        self.clock.now()
+    }
+
+    pub fn error(&self, error: &EngineError) {
+        self.log(
+            self.now(),
+            "error",
+            &[
+                ("node", Field::Text(&error.node)),
+                ("message", Field::Text(&error.message)),
+                ("bypassed", Field::Flag(error.bypassed)),
+            ],
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_event_log() {
+        assert_eq!(
+            line(
+                1.5,
+                "param",
+                &[
+                    ("name", Field::Text("say \"hi\"\n")),
+                    ("value", Field::Number(0.3)),
+                    ("note", Field::Null),
+                    ("gain", Field::Number(f32::INFINITY)),
+                ]
+            ),
+            "{\"t\":1.500000,\"event\":\"param\",\"name\":\"say \\\"hi\\\"\\n\",\"value\":0.3,\"note\":null,\"gain\":null}\n"
+        );
+
+        let path = std::env::temp_dir().join(format!("synthtoy-log-{}.jsonl", std::process::id()));
+        let log = EventLog::create(&path, EngineClock::new(Instant::now())).unwrap();
+        log.log(
+            Stamp::default() + Duration::from_millis(250),
+            "note_off",
+            &[("note", Field::Number(60.))],
+        );
+        log.error(&EngineError {
+            node: "reverb".to_string(),
+            message: "took too long".to_string(),
+            bypassed: true,
+        });
+        drop(log);
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(
+            lines[0],
+            "{\"t\":0.250000,\"event\":\"note_off\",\"note\":60}"
+        );
+        assert!(lines[1].ends_with(
+            "\"event\":\"error\",\"node\":\"reverb\",\"message\":\"took too long\",\"bypassed\":true}"
+        ));
+    }
+}
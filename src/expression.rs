@@ -0,0 +1,215 @@
+//! The expression pedal (CC11): learning how far a particular pedal really
+//! travels, and shaping its response, before it goes wherever it's routed:
+//! the "expression" modulation source always, and a parameter of its own if
+//! it's given one. Few pedals sweep the whole 0..=127, so without this the
+//! ends of the travel do nothing.
+
+use std::fmt;
+
+use crate::midi::CcTarget;
+
+pub const EXPRESSION_CC: u8 = 11;
+
+/// How much the exponential and logarithmic responses bend.
+const BEND: f32 = 4.;
+
+/// How pedal travel maps onto the parameter.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Response {
+    #[default]
+    Linear,
+    /// Slow at the heel, fast at the toe, like a volume pedal.
+    Exponential,
+    /// The other way around.
+    Logarithmic,
+    /// Slow at both ends.
+    SCurve,
+}
+
+impl Response {
+    /// Shapes a position in 0..=1.
+    pub fn shape(self, x: f32) -> f32 {
+        match self {
+            Response::Linear => x,
+            Response::Exponential => (BEND * x).exp_m1() / BEND.exp_m1(),
+            Response::Logarithmic => (BEND.exp_m1() * x).ln_1p() / BEND,
+            Response::SCurve => x * x * (3. - 2. * x),
+        }
+    }
+}
+
+impl std::str::FromStr for Response {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        Ok(match value {
+            "linear" => Response::Linear,
+            "exp" => Response::Exponential,
+            "log" => Response::Logarithmic,
+            "s" => Response::SCurve,
+            _ => return Err(format!("unknown response {value:?}")),
+        })
+    }
+}
+
+/// The raw CC values a pedal sends at either end of its travel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Calibration {
+    pub low: u8,
+    pub high: u8,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self { low: 0, high: 127 }
+    }
+}
+
+impl Calibration {
+    /// Where `raw` is between the ends, in 0..=1.
+    pub fn normalize(self, raw: u8) -> f32 {
+        if self.high <= self.low {
+            return if raw >= self.high { 1. } else { 0. };
+        }
+        ((raw as f32 - self.low as f32) / (self.high - self.low) as f32).clamp(0., 1.)
+    }
+}
+
+/// `<low>..<high>`
+impl std::str::FromStr for Calibration {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        let parse = |v: &str| {
+            v.trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|&v| v < 128)
+                .ok_or_else(|| format!("bad CC value {v:?}"))
+        };
+        let (low, high) = value
+            .split_once("..")
+            .ok_or_else(|| format!("expected <low>..<high>, got {value:?}"))?;
+        let (low, high) = (parse(low)?, parse(high)?);
+        if low >= high {
+            return Err(format!("{low}..{high} is empty"));
+        }
+        Ok(Self { low, high })
+    }
+}
+
+impl fmt::Display for Calibration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}..{}", self.low, self.high)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ExpressionConfig {
+    /// a parameter it sets directly, taking CC11 from `cc_map`
+    pub target: Option<CcTarget>,
+    pub response: Response,
+    pub calibration: Calibration,
+}
+
+impl ExpressionConfig {
+    /// Where a raw CC value puts the target, in 0..=1.
+    pub fn position(&self, raw: u8) -> f32 {
+        self.response.shape(self.calibration.normalize(raw))
+    }
+}
+
+pub struct Expression {
+    pub config: ExpressionConfig,
+    /// lowest and highest values seen, while learning
+    learning: Option<(u8, u8)>,
+}
+
+impl Expression {
+    pub fn new(config: ExpressionConfig) -> Self {
+        Self {
+            config,
+            learning: None,
+        }
+    }
+
+    pub fn is_learning(&self) -> bool {
+        self.learning.is_some()
+    }
+
+    /// Starts learning the pedal's range from a sweep, or stops and uses
+    /// what was learned. Returns the new calibration when it stops, or
+    /// `None` if the pedal didn't move enough to tell, in which case the
+    /// old one stays.
+    pub fn toggle_learning(&mut self) -> Option<Calibration> {
+        match self.learning.take() {
+            None => {
+                self.learning = Some((u8::MAX, 0));
+                None
+            }
+            Some((low, high)) if low < high => {
+                self.config.calibration = Calibration { low, high };
+                Some(self.config.calibration)
+            }
+            Some(_) => None,
+        }
+    }
+
+    /// Takes a raw CC value, returning the position (0..=1) to set the
+    /// target to.
+    pub fn set(&mut self, raw: u8) -> f32 {
+        if let Some((low, high)) = &mut self.learning {
+            *low = (*low).min(raw);
+            *high = (*high).max(raw);
+        }
+        self.config.position(raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expression() {
+        for response in [
+            Response::Linear,
+            Response::Exponential,
+            Response::Logarithmic,
+            Response::SCurve,
+        ] {
+            assert!(response.shape(0.).abs() < 1e-6, "{response:?}");
+            assert!((response.shape(1.) - 1.).abs() < 1e-6, "{response:?}");
+        }
+        assert!(Response::Exponential.shape(0.5) < 0.5);
+        assert!(Response::Logarithmic.shape(0.5) > 0.5);
+
+        assert_eq!("10..120".parse(), Ok(Calibration { low: 10, high: 120 }));
+        assert!("120..10".parse::<Calibration>().is_err());
+        assert!("0..128".parse::<Calibration>().is_err());
+
+        let mut pedal = Expression::new(ExpressionConfig {
+            target: Some("volume".parse().unwrap()),
+            response: Response::Linear,
+            calibration: Calibration::default(),
+        });
+        assert_eq!(pedal.set(64), 64. / 127.);
+
+        // a pedal that only gets from 20 to 100
+        assert_eq!(pedal.toggle_learning(), None);
+        for raw in [60, 20, 45, 100, 80] {
+            pedal.set(raw);
+        }
+        assert_eq!(
+            pedal.toggle_learning(),
+            Some(Calibration { low: 20, high: 100 })
+        );
+        assert_eq!(pedal.set(20), 0.);
+        assert_eq!(pedal.set(60), 0.5);
+        assert_eq!(pedal.set(110), 1.);
+
+        // a sweep that went nowhere keeps the old calibration
+        pedal.toggle_learning();
+        pedal.set(70);
+        assert_eq!(pedal.toggle_learning(), None);
+        assert_eq!(pedal.set(100), 1.);
+    }
+}
@@ -1,12 +1,24 @@
 use std::{
-    cell::Cell,
-    f32::consts::PI,
-    fs::OpenOptions,
-    io::{self, BufWriter},
+    borrow::Cow,
+    collections::VecDeque,
+    f32::consts::TAU,
+    fs::File,
+    io::{self, BufReader},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
 };
 
 use wav::BitDepth;
 
+use crate::input::LiveInput;
+use crate::lfo::Lfo;
+use crate::params::{nested, ParamInfo, Params, Smoothed};
+use crate::snapshot::Node;
+use crate::snoop::Snoop;
+use crate::voice::Voice;
+use crate::window::Window;
+
 pub struct SynthBuilder<S: 'static + Filter + Send, T: Filter>(S, T);
 
 impl<S: 'static + Filter + Send> SynthBuilder<S, NoopFilter> {
@@ -23,6 +35,9 @@ impl<S: 'static + Filter + Send, T: Filter> SynthBuilder<S, T> {
         Synth {
             synth: self.0,
             filter: self.1,
+            volume: 1.,
+            gain: Smoothed::new(1.),
+            side: VecDeque::with_capacity(SIDE_LAG_LEN),
         }
     }
 }
@@ -31,23 +46,137 @@ pub const SAMPLING_FREQ: usize = 44100;
 
 pub trait Filter: 'static + Send {
     fn process(&mut self, samples: &mut [f32]);
+
+    /// How many samples late this filter's output is relative to its input
+    /// because of lookahead, so parallel paths can be lined back up.
+    fn latency(&self) -> usize {
+        0
+    }
+
+    /// This filter's place in the processing graph, for snapshots.
+    fn describe(&self) -> Node {
+        Node::leaf(Node::type_name::<Self>())
+    }
+
+    /// [`Filter::process`] for the start of a stereo chain, which sources
+    /// with a stereo image of their own, like voices, can fill both sides
+    /// of. The rest leave it be and play the same on both.
+    fn process_wide(&mut self, left: &mut [f32], right: &mut [f32]) {
+        self.process(left);
+        right.copy_from_slice(left);
+    }
+}
+
+impl<F: Filter + ?Sized> Filter for Box<F> {
+    fn process(&mut self, samples: &mut [f32]) {
+        (**self).process(samples);
+    }
+
+    fn process_wide(&mut self, left: &mut [f32], right: &mut [f32]) {
+        (**self).process_wide(left, right);
+    }
+
+    fn latency(&self) -> usize {
+        (**self).latency()
+    }
+
+    fn describe(&self) -> Node {
+        (**self).describe()
+    }
+}
+
+/// Describes filters run one after another, flattening nested chains.
+fn chain_node<'a>(parts: impl IntoIterator<Item = &'a dyn Filter>) -> Node {
+    chain_of(parts.into_iter().map(|part| part.describe()))
+}
+
+/// [`chain_node`] for filters that have already described themselves.
+pub(crate) fn chain_of(nodes: impl IntoIterator<Item = Node>) -> Node {
+    let mut children = Vec::new();
+    for node in nodes {
+        match node.name.as_str() {
+            "Chain" => children.extend(node.children),
+            "NoopFilter" => {}
+            _ => children.push(node),
+        }
+    }
+    Node::with_children("Chain", children)
+}
+
+/// First order allpass used to get delays of a fraction of a sample, which
+/// unlike linear interpolation has a flat magnitude response.
+pub struct AllpassInterp {
+    pub coeff: f32,
+    x1: f32,
+    y1: f32,
+}
+
+impl AllpassInterp {
+    /// Works best for `delay` in 0.5..1.5 samples.
+    pub fn new(delay: f32) -> Self {
+        let mut this = Self {
+            coeff: 0.,
+            x1: 0.,
+            y1: 0.,
+        };
+        this.set_delay(delay);
+        this
+    }
+
+    pub fn set_delay(&mut self, delay: f32) {
+        self.coeff = (1. - delay) / (1. + delay);
+    }
+}
+
+impl Filter for AllpassInterp {
+    fn process(&mut self, samples: &mut [f32]) {
+        for s in samples.iter_mut() {
+            let y = self.coeff * (*s - self.y1) + self.x1;
+            self.x1 = *s;
+            self.y1 = y;
+            *s = y;
+        }
+    }
 }
 
 pub struct DelayLine {
     pub samples: Vec<f32>,
     pub pos: usize,
+    /// handles the fractional part of the delay, if one has been set
+    pub frac: Option<AllpassInterp>,
 }
 
 impl DelayLine {
     pub fn new(len: usize) -> DelayLine {
         let samples = vec![0.; len];
-        DelayLine { samples, pos: 0 }
+        DelayLine {
+            samples,
+            pos: 0,
+            frac: None,
+        }
+    }
+
+    /// Sets the delay to `delay` samples, which needn't be a whole number.
+    pub fn set_delay(&mut self, delay: f32) {
+        // keep the fractional part in 0.5..1.5 where the allpass behaves
+        let whole = (delay - 0.5).floor().max(0.);
+        let frac = delay - whole;
+        // the buffer delays by one less than its length
+        self.set_len(whole as usize + 1);
+        match &mut self.frac {
+            Some(ap) => ap.set_delay(frac),
+            None => self.frac = Some(AllpassInterp::new(frac)),
+        }
     }
 
     pub fn len(&self) -> usize {
         self.samples.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
     pub fn set_len(&mut self, new_len: usize) {
         self.samples.resize(new_len, 0.);
         self.pos %= new_len.min(self.samples.len());
@@ -61,6 +190,9 @@ impl Filter for DelayLine {
             *s = self.samples[(self.pos + 1) % self.samples.len()];
             self.pos = (self.pos + 1) % self.samples.len();
         }
+        if let Some(frac) = &mut self.frac {
+            frac.process(inout_samples);
+        }
     }
 }
 
@@ -95,266 +227,2740 @@ impl Filter for LowPass {
     }
 }
 
-// group samples into a window of size n
-// y(n) = c1x(n) + c2x(n - 1) + ...
-// sum(cx | x <- [1..n]) <= 1
-// can express as a dot product of two matrices of coeffs to inputs
-// to achieve an FIR, take in a desired frequency response curve, perform an inverse FFT to get the individual coeffs
-// sample frequency response at given rate, e.g.
-// issue: how to implement phase delay at the fundamental frequency
+/// One-pole high-pass at a few Hz, to take out any DC offset that string
+/// loops and asymmetric distortion build up.
+pub struct DcBlocker {
+    pub cutoff: f32,
 
-// frequency sampling implementation of FIR
-pub struct FIR {
-    omegas: Vec<(f32, f32)>, // (initial freq resp(omega_k), omega_k)
-    freq0: f32,
-    coeff: f32,
+    last_in: f32,
+    last_out: f32,
 }
 
-impl FIR {
-    pub fn new(taps: usize, freq_resp_curve: impl Fn(f32) -> f32) -> Self {
-        debug_assert!(taps > 0);
-
-        // since freq response is symmetrical around the origin
-        let m = taps * 2 + 1;
-
-        // freq_resp_curve(omegak) = H(omegak)
-
-        let omegas = (1..=taps)
-            .map(|n| {
-                let omega_k = n as f32 * PI / m as f32;
-                let resp_out = freq_resp_curve(omega_k);
-
-                (resp_out, omega_k)
-            })
-            .collect();
-        let freq0 = freq_resp_curve(0.);
-
+impl Default for DcBlocker {
+    fn default() -> Self {
         Self {
-            omegas,
-            freq0,
-            coeff: 1.0 / m as f32,
+            cutoff: 10.,
+            last_in: 0.,
+            last_out: 0.,
         }
-        // todo!()
     }
 }
 
-impl Filter for FIR {
+impl Filter for DcBlocker {
     fn process(&mut self, samples: &mut [f32]) {
-        for sample in samples.iter_mut() {
-            let comp_sum = self
-                .omegas
-                .iter()
-                .map(|&(resp, omega_k)| resp * (omega_k * *sample).cos())
-                .sum::<f32>();
-            *sample = self.coeff * (self.freq0 + 2. * comp_sum);
+        let r = 1. - TAU * self.cutoff.max(0.) / SAMPLING_FREQ as f32;
+        for s in samples.iter_mut() {
+            let x = *s;
+            self.last_out = x - self.last_in + r * self.last_out;
+            self.last_in = x;
+            *s = self.last_out;
         }
     }
 }
 
-#[derive(Default)]
-pub struct Pipe {
-    pub components: Vec<Box<dyn Filter>>,
-}
-
-impl Pipe {
-    pub fn new(components: Vec<Box<dyn Filter>>) -> Pipe {
-        Self { components }
+impl Params for DcBlocker {
+    fn params(&self) -> Vec<ParamInfo> {
+        vec![ParamInfo::new("cutoff", 1., 50.)]
     }
 
-    pub fn add<T>(&mut self, comp: T)
-    where
-        T: Filter,
-    {
-        self.components.push(Box::new(comp));
+    fn get_param(&self, name: &str) -> Option<f32> {
+        (name == "cutoff").then_some(self.cutoff)
     }
-}
 
-impl Filter for Pipe {
-    fn process(&mut self, samples: &mut [f32]) {
-        for comp in self.components.iter_mut() {
-            comp.process(samples);
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        if name != "cutoff" {
+            return false;
         }
+        self.cutoff = value;
+        true
     }
 }
 
-pub struct Snoop {
-    pub name: String,
-    pub samples: Cell<Vec<f32>>,
+/// Tone control for the string loop: a one-pole low-pass whose cutoff comes
+/// from `brightness`, blended with the classic Karplus-Strong two-point
+/// average for extra high frequency damping.
+#[derive(Clone)]
+pub struct Damping {
+    /// 0..=1, where 1 leaves the one-pole wide open
+    pub brightness: f32,
+    /// 0..=1 blend of the two-point average; 1 is the classic loop filter
+    pub hf_damping: f32,
+    /// loop gain, must stay below 1 for the string to die out
+    pub decay: f32,
+    /// brightness added per octave above A4
+    pub key_tracking: f32,
+    /// brightness taken away at zero velocity
+    pub velocity_sens: f32,
+
+    /// brightness added for this note alone
+    timbre: f32,
+    note_freq: f32,
+    velocity: f32,
+    pole: f32,
+    last_in: f32,
+    last_out: f32,
 }
 
-impl Snoop {
-    pub fn new(name: String) -> Snoop {
-        Snoop {
-            name,
-            samples: Default::default(),
-        }
+impl Damping {
+    pub fn new(brightness: f32, hf_damping: f32, decay: f32) -> Self {
+        let mut this = Self {
+            brightness,
+            hf_damping,
+            decay: decay.min(0.999),
+            key_tracking: 0.,
+            velocity_sens: 0.,
+            timbre: 0.,
+            note_freq: 440.,
+            velocity: 1.,
+            pole: 0.,
+            last_in: 0.,
+            last_out: 0.,
+        };
+        this.update();
+        this
+    }
+
+    /// Sets the key position and velocity (0..=1) the tone is modulated by.
+    pub fn set_note(&mut self, freq: f32, velocity: f32) {
+        self.note_freq = freq;
+        self.velocity = velocity;
+        self.update();
+    }
+
+    /// Brightens or darkens this note alone, by -0.5..=0.5.
+    pub fn set_timbre(&mut self, timbre: f32) {
+        self.timbre = timbre;
+        self.update();
     }
 
-    pub fn save(&self) -> io::Result<()> {
-        let header = wav::Header::new(wav::header::WAV_FORMAT_IEEE_FLOAT, 1, 44100, 32);
-        let file = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(&self.name)?;
-        let mut writer = BufWriter::new(file);
+    /// Recomputes the filter after changing any of the public parameters.
+    pub fn update(&mut self) {
+        let brightness = self.brightness + self.key_tracking * (self.note_freq / 440.).log2()
+            - self.velocity_sens * (1. - self.velocity)
+            + self.timbre;
+        let brightness = brightness.clamp(0., 1.);
 
-        let fmt = BitDepth::ThirtyTwoFloat(self.samples.take());
-        wav::write(header, &fmt, &mut writer)
+        self.pole = if brightness >= 1. {
+            0.
+        } else {
+            // sweep exponentially from 50Hz up to nyquist
+            let nyquist = SAMPLING_FREQ as f32 / 2.;
+            let cutoff = 50. * (nyquist / 50.).powf(brightness);
+            (-TAU * cutoff / SAMPLING_FREQ as f32).exp()
+        };
     }
 }
 
-impl Filter for Snoop {
-    fn process(&mut self, samples: &mut [f32]) {
-        self.samples.get_mut().extend(samples.iter());
+impl Damping {
+    /// Phase delay in samples at `freq`, needed to tune the loop around it.
+    pub fn phase_delay(&self, freq: f32) -> f32 {
+        let omega = TAU * freq / SAMPLING_FREQ as f32;
+        let (h, a) = (self.hf_damping, self.pole);
+        // (1 - h/2) + h/2 z^-1
+        let avg_phase = (-h / 2. * omega.sin()).atan2(1. - h / 2. + h / 2. * omega.cos());
+        // (1 - a) / (1 - a z^-1)
+        let pole_phase = -(a * omega.sin()).atan2(1. - a * omega.cos());
+        -(avg_phase + pole_phase) / omega
     }
 }
 
-pub struct NoopFilter;
+impl Params for Damping {
+    fn params(&self) -> Vec<ParamInfo> {
+        vec![
+            ParamInfo::new("brightness", 0., 1.),
+            ParamInfo::new("hf_damping", 0., 1.),
+            ParamInfo::new("decay", 0.9, 0.999),
+            ParamInfo::new("key_tracking", -1., 1.),
+            ParamInfo::new("velocity_sens", 0., 1.),
+        ]
+    }
 
-impl Filter for NoopFilter {
-    fn process(&mut self, _samples: &mut [f32]) {}
+    fn get_param(&self, name: &str) -> Option<f32> {
+        Some(match name {
+            "brightness" => self.brightness,
+            "hf_damping" => self.hf_damping,
+            "decay" => self.decay,
+            "key_tracking" => self.key_tracking,
+            "velocity_sens" => self.velocity_sens,
+            _ => return None,
+        })
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name {
+            "brightness" => self.brightness = value,
+            "hf_damping" => self.hf_damping = value,
+            "decay" => self.decay = value.min(0.999),
+            "key_tracking" => self.key_tracking = value,
+            "velocity_sens" => self.velocity_sens = value,
+            _ => return false,
+        }
+        self.update();
+        true
+    }
 }
 
-pub struct Synth<S: 'static + Filter + Send, F: Filter = NoopFilter> {
-    pub synth: S,
-    pub filter: F,
+impl Default for Damping {
+    fn default() -> Self {
+        // matches the old fixed `LowPass` with gain 0.499
+        Self::new(1., 1., 0.998)
+    }
 }
 
-impl<S: 'static + Filter + Send, F: Filter> Filter for Synth<S, F> {
+impl Filter for Damping {
     fn process(&mut self, samples: &mut [f32]) {
-        self.synth.process(samples);
-        self.filter.process(samples);
+        for s in samples.iter_mut() {
+            let x = *s;
+            let averaged = (x + self.last_in) * 0.5;
+            self.last_in = x;
+            let x = x + (averaged - x) * self.hf_damping;
+
+            self.last_out = x + (self.last_out - x) * self.pole;
+            *s = self.last_out * self.decay;
+        }
     }
 }
 
-pub struct Chain<H: Filter, T: Filter>(pub H, pub T);
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BiquadKind {
+    LowPass,
+    HighPass,
+    /// constant 0dB peak gain
+    BandPass,
+    Notch,
+    Peaking,
+    LowShelf,
+    HighShelf,
+}
 
-impl<H: Filter, T: Filter> Filter for Chain<H, T> {
-    fn process(&mut self, samples: &mut [f32]) {
-        self.1.process(samples);
-        self.0.process(samples);
-    }
+/// Second order IIR filter with the coefficient formulas from Robert
+/// Bristow-Johnson's Audio EQ Cookbook. `gain` is in dB and only matters for
+/// the peaking and shelf kinds.
+pub struct Biquad {
+    pub kind: BiquadKind,
+    pub cutoff: f32,
+    pub q: f32,
+    pub gain: f32,
+
+    // normalized so a0 = 1
+    b: [f32; 3],
+    a: [f32; 2],
+    // transposed direct form II state
+    z: [f32; 2],
 }
 
-/// Splits an incoming stream into N pieces and then joins them back after
-/// running the components over the input samples provided.
-pub struct SplitJoin(Vec<Box<dyn Filter>>);
+impl Biquad {
+    pub fn new(kind: BiquadKind, cutoff: f32, q: f32, gain: f32) -> Self {
+        let mut this = Self {
+            kind,
+            cutoff,
+            q,
+            gain,
+            b: [1., 0., 0.],
+            a: [0., 0.],
+            z: [0., 0.],
+        };
+        this.update();
+        this
+    }
 
-impl Filter for SplitJoin {
-    fn process(&mut self, samples: &mut [f32]) {
-        let mut copies = Vec::new();
-        copies.resize_with(self.0.len(), || samples.to_vec());
+    pub fn low_pass(cutoff: f32, q: f32) -> Self {
+        Self::new(BiquadKind::LowPass, cutoff, q, 0.)
+    }
 
-        for (comp, inputs) in self.0.iter_mut().zip(copies.iter_mut()) {
-            comp.process(inputs);
-        }
+    pub fn high_pass(cutoff: f32, q: f32) -> Self {
+        Self::new(BiquadKind::HighPass, cutoff, q, 0.)
+    }
 
-        for (idx, s) in samples.iter_mut().enumerate() {
-            *s = copies.iter().map(|o| o[idx]).sum();
-        }
+    pub fn band_pass(cutoff: f32, q: f32) -> Self {
+        Self::new(BiquadKind::BandPass, cutoff, q, 0.)
     }
-}
 
-pub struct SquareWave {
-    pub phase_inc: f32,
-    pub phase: f32,
-    pub volume: f32,
+    pub fn notch(cutoff: f32, q: f32) -> Self {
+        Self::new(BiquadKind::Notch, cutoff, q, 0.)
+    }
+
+    pub fn peaking(cutoff: f32, q: f32, gain: f32) -> Self {
+        Self::new(BiquadKind::Peaking, cutoff, q, gain)
+    }
+
+    pub fn low_shelf(cutoff: f32, q: f32, gain: f32) -> Self {
+        Self::new(BiquadKind::LowShelf, cutoff, q, gain)
+    }
+
+    pub fn high_shelf(cutoff: f32, q: f32, gain: f32) -> Self {
+        Self::new(BiquadKind::HighShelf, cutoff, q, gain)
+    }
+
+    /// Recomputes the coefficients after changing any of the public fields.
+    pub fn update(&mut self) {
+        let nyquist = SAMPLING_FREQ as f32 / 2.;
+        let w0 = TAU * self.cutoff.clamp(1., nyquist * 0.99) / SAMPLING_FREQ as f32;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2. * self.q.max(0.01));
+        let a = 10f32.powf(self.gain / 40.);
+        let shelf = 2. * a.sqrt() * alpha;
+
+        let (b, a) = match self.kind {
+            BiquadKind::LowPass => (
+                [(1. - cos) / 2., 1. - cos, (1. - cos) / 2.],
+                [1. + alpha, -2. * cos, 1. - alpha],
+            ),
+            BiquadKind::HighPass => (
+                [(1. + cos) / 2., -(1. + cos), (1. + cos) / 2.],
+                [1. + alpha, -2. * cos, 1. - alpha],
+            ),
+            BiquadKind::BandPass => ([alpha, 0., -alpha], [1. + alpha, -2. * cos, 1. - alpha]),
+            BiquadKind::Notch => ([1., -2. * cos, 1.], [1. + alpha, -2. * cos, 1. - alpha]),
+            BiquadKind::Peaking => (
+                [1. + alpha * a, -2. * cos, 1. - alpha * a],
+                [1. + alpha / a, -2. * cos, 1. - alpha / a],
+            ),
+            BiquadKind::LowShelf => (
+                [
+                    a * ((a + 1.) - (a - 1.) * cos + shelf),
+                    2. * a * ((a - 1.) - (a + 1.) * cos),
+                    a * ((a + 1.) - (a - 1.) * cos - shelf),
+                ],
+                [
+                    (a + 1.) + (a - 1.) * cos + shelf,
+                    -2. * ((a - 1.) + (a + 1.) * cos),
+                    (a + 1.) + (a - 1.) * cos - shelf,
+                ],
+            ),
+            BiquadKind::HighShelf => (
+                [
+                    a * ((a + 1.) + (a - 1.) * cos + shelf),
+                    -2. * a * ((a - 1.) + (a + 1.) * cos),
+                    a * ((a + 1.) + (a - 1.) * cos - shelf),
+                ],
+                [
+                    (a + 1.) - (a - 1.) * cos + shelf,
+                    2. * ((a - 1.) - (a + 1.) * cos),
+                    (a + 1.) - (a - 1.) * cos - shelf,
+                ],
+            ),
+        };
+
+        self.b = [b[0] / a[0], b[1] / a[0], b[2] / a[0]];
+        self.a = [a[1] / a[0], a[2] / a[0]];
+    }
+
+    /// Forgets the signal so far, as if it had only ever had silence.
+    pub fn clear(&mut self) {
+        self.z = [0., 0.];
+    }
 }
 
-impl Filter for SquareWave {
+impl Filter for Biquad {
     fn process(&mut self, samples: &mut [f32]) {
+        let [b0, b1, b2] = self.b;
+        let [a1, a2] = self.a;
         for s in samples.iter_mut() {
-            *s = if self.phase > 0.5 {
-                self.volume
-            } else {
-                -self.volume
-            };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
+            let x = *s;
+            let y = b0 * x + self.z[0];
+            self.z[0] = b1 * x - a1 * y + self.z[1];
+            self.z[1] = b2 * x - a2 * y;
+            *s = y;
         }
     }
 }
 
-pub struct Rng {
-    pub v: u32,
+impl Params for Biquad {
+    fn params(&self) -> Vec<ParamInfo> {
+        vec![
+            ParamInfo::new("cutoff", 20., 20000.),
+            ParamInfo::new("q", 0.1, 20.).random_range(0.3, 8.),
+            ParamInfo::new("gain", -24., 24.).random_range(-12., 12.),
+        ]
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        Some(match name {
+            "cutoff" => self.cutoff,
+            "q" => self.q,
+            "gain" => self.gain,
+            _ => return None,
+        })
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name {
+            "cutoff" => self.cutoff = value,
+            "q" => self.q = value,
+            "gain" => self.gain = value,
+            _ => return false,
+        }
+        self.update();
+        true
+    }
 }
 
-impl Rng {
-    fn next(&mut self) -> f32 {
-        self.v ^= self.v << 13;
-        self.v ^= self.v >> 17;
-        self.v ^= self.v << 5;
+/// Four cascaded one-pole low-passes with negative feedback around them, after
+/// the Moog transistor ladder. Each stage saturates, as does the input, so
+/// `drive` pushes it into distortion, and `resonance` (0..=1) self-oscillates
+/// near the top of its range.
+pub struct Ladder {
+    pub cutoff: f32,
+    pub resonance: f32,
+    pub drive: f32,
+    /// Turn the output up or down so that whichever is louder, the passband
+    /// or the resonant peak, stays at the level it was with no resonance.
+    pub compensate: bool,
 
-        // convert to full scale
-        self.v as i32 as f32 / i32::MAX as f32
+    g: Smoothed,
+    k: Smoothed,
+    gain: Smoothed,
+    stages: [f32; 4],
+}
+
+impl Ladder {
+    pub fn new(cutoff: f32, resonance: f32) -> Self {
+        let mut this = Self {
+            cutoff,
+            resonance,
+            drive: 1.,
+            compensate: false,
+            g: Smoothed::new(0.),
+            k: Smoothed::new(4. * resonance.clamp(0., 1.)),
+            gain: Smoothed::new(1.),
+            stages: [0.; 4],
+        };
+        this.update();
+        this.g.reset(this.g.target());
+        this
+    }
+
+    /// Recomputes the filter after changing any of the public fields.
+    pub fn update(&mut self) {
+        let nyquist = SAMPLING_FREQ as f32 / 2.;
+        let cutoff = self.cutoff.clamp(1., nyquist * 0.9);
+        self.g
+            .set(1. - (-TAU * cutoff / SAMPLING_FREQ as f32).exp());
     }
 }
 
-impl Default for Rng {
+impl Default for Ladder {
     fn default() -> Self {
-        Rng { v: 0xdeadbeef }
+        Self::new(2000., 0.)
     }
 }
 
-pub struct Scale(f32);
+/// Output gain making up for feedback `k` in a four pole ladder. The
+/// passband drops to 1/(1 + k) and the peak at cutoff rises to 4/(4 - k)
+/// times where it started, so this is whichever brings the louder one back.
+/// Self-oscillation would need no gain at all, so it stops a bit short.
+fn ladder_compensation(k: f32) -> f32 {
+    (1. + k).min(4. - k).max(0.25)
+}
 
-impl Filter for Scale {
+impl Filter for Ladder {
     fn process(&mut self, samples: &mut [f32]) {
+        self.k.set(4. * self.resonance.clamp(0., 1.));
+        self.gain.set(self.drive);
         for s in samples.iter_mut() {
-            *s *= self.0;
+            let (g, k) = (self.g.next_value(), self.k.next_value());
+            let mut x = (self.gain.next_value() * *s - k * self.stages[3]).tanh();
+            for stage in self.stages.iter_mut() {
+                *stage += g * (x.tanh() - stage.tanh());
+                x = *stage;
+            }
+            *s = if self.compensate {
+                x * ladder_compensation(k)
+            } else {
+                x
+            };
         }
     }
 }
 
-pub struct StringSynth {
-    pub delay: DelayLine,
-    pub lpf: LowPass,
-    pub snoop: Snoop,
+impl Params for Ladder {
+    fn params(&self) -> Vec<ParamInfo> {
+        vec![
+            ParamInfo::new("cutoff", 20., 20000.),
+            ParamInfo::new("resonance", 0., 1.).random_range(0., 0.85),
+            ParamInfo::new("drive", 0.1, 10.),
+            ParamInfo::new("compensate", 0., 1.),
+        ]
+    }
 
-    pub last: f32,
+    fn get_param(&self, name: &str) -> Option<f32> {
+        Some(match name {
+            "cutoff" => self.cutoff,
+            "resonance" => self.resonance,
+            "drive" => self.drive,
+            "compensate" => self.compensate as u8 as f32,
+            _ => return None,
+        })
+    }
 
-    /// number of samples of noise burst remaining
-    pub trigger_count: u32,
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name {
+            "cutoff" => self.cutoff = value,
+            "resonance" => self.resonance = value,
+            "drive" => self.drive = value,
+            "compensate" => self.compensate = value >= 0.5,
+            _ => return false,
+        }
+        self.update();
+        true
+    }
+}
 
-    pub rng: Rng,
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SvfMode {
+    LowPass,
+    BandPass,
+    HighPass,
 }
 
-impl StringSynth {
-    pub fn tune(&mut self, freq: f32) {
-        // FIXME: not perfect; will be slightly out of tune until we implement
-        // fractional delays
-        self.delay
-            .set_len((SAMPLING_FREQ as f32 / freq).round() as usize);
+/// All three outputs of a [`Svf`] for one sample.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SvfOutputs {
+    pub low: f32,
+    pub band: f32,
+    pub high: f32,
+}
+
+/// Zero-delay-feedback state variable filter (Andrew Simper's trapezoidal
+/// version), which stays stable and in tune right up to nyquist. `process`
+/// outputs whichever of the taps `mode` selects; [`Svf::tick`] gives all of
+/// them.
+///
+/// With `key_tracking` at 1 the cutoff follows the note set with
+/// [`Svf::set_note`] exactly, relative to A440; at 0 it ignores it.
+///
+/// With `compensate` set, `process` scales its output so the resonant peak
+/// comes out at unity however high `q` goes.
+pub struct Svf {
+    pub mode: SvfMode,
+    pub cutoff: f32,
+    pub q: f32,
+    pub key_tracking: f32,
+    pub compensate: bool,
+
+    note_freq: f32,
+    g: Smoothed,
+    k: Smoothed,
+    a: [f32; 3],
+    ic: [f32; 2],
+}
+
+impl Svf {
+    pub fn new(mode: SvfMode, cutoff: f32, q: f32) -> Self {
+        let mut this = Self {
+            mode,
+            cutoff,
+            q,
+            key_tracking: 0.,
+            compensate: false,
+            note_freq: 440.,
+            g: Smoothed::new(0.),
+            k: Smoothed::new(0.),
+            a: [0.; 3],
+            ic: [0.; 2],
+        };
+        this.update();
+        this.g.reset(this.g.target());
+        this.k.reset(this.k.target());
+        this.coefficients();
+        this
     }
 
-    pub fn new(depth: usize) -> StringSynth {
-        StringSynth {
-            delay: DelayLine::new(depth),
-            lpf: LowPass::default(),
-            rng: Rng::default(),
-            snoop: Snoop::new("string.wav".to_string()),
-            last: 0.,
-            trigger_count: 0,
+    pub fn set_note(&mut self, freq: f32) {
+        self.note_freq = freq;
+        self.update();
+    }
+
+    /// Recomputes the filter after changing any of the public fields.
+    pub fn update(&mut self) {
+        let nyquist = SAMPLING_FREQ as f32 / 2.;
+        let cutoff = self.cutoff * (self.note_freq / 440.).powf(self.key_tracking);
+        let cutoff = cutoff.clamp(1., nyquist * 0.99);
+        self.g
+            .set((std::f32::consts::PI * cutoff / SAMPLING_FREQ as f32).tan());
+        self.k.set(1. / self.q.max(0.01));
+    }
+
+    fn coefficients(&mut self) {
+        let (g, k) = (self.g.next_value(), self.k.next_value());
+        let a1 = 1. / (1. + g * (g + k));
+        let a2 = g * a1;
+        self.a = [a1, a2, g * a2];
+    }
+
+    pub fn tick(&mut self, x: f32) -> SvfOutputs {
+        if self.g.is_ramping() || self.k.is_ramping() {
+            self.coefficients();
+        }
+        let [a1, a2, a3] = self.a;
+        let [ic1, ic2] = self.ic;
+        let v3 = x - ic2;
+        let v1 = a1 * ic1 + a2 * v3;
+        let v2 = ic2 + a2 * ic1 + a3 * v3;
+        self.ic = [2. * v1 - ic1, 2. * v2 - ic2];
+        SvfOutputs {
+            low: v2,
+            band: v1,
+            high: x - self.k.value() * v1 - v2,
         }
     }
 }
 
-impl Filter for StringSynth {
+impl Svf {
+    /// Gain that brings the peak of the selected output down to unity for
+    /// damping `k` (1/q).
+    fn compensation(&self, k: f32) -> f32 {
+        match self.mode {
+            // the band output peaks at q
+            SvfMode::BandPass => k,
+            // no peak at all below q = 1/sqrt(2)
+            _ if k >= std::f32::consts::SQRT_2 => 1.,
+            _ => k * (1. - k * k / 4.).sqrt(),
+        }
+    }
+}
+
+impl Filter for Svf {
     fn process(&mut self, samples: &mut [f32]) {
         for s in samples.iter_mut() {
-            let loop_in = if self.trigger_count > 0 {
-                self.trigger_count -= 1;
-                self.rng.next() + self.last
+            let out = self.tick(*s);
+            let gain = if self.compensate {
+                self.compensation(self.k.value())
             } else {
-                self.last
+                1.
             };
+            *s = gain
+                * match self.mode {
+                    SvfMode::LowPass => out.low,
+                    SvfMode::BandPass => out.band,
+                    SvfMode::HighPass => out.high,
+                };
+        }
+    }
+}
 
-            let mut samp = [loop_in];
-            self.delay.process(&mut samp);
-            self.lpf.process(&mut samp);
-            self.snoop.process(&mut samp);
-            self.last = samp[0];
-            *s = self.last;
+impl Params for Svf {
+    fn params(&self) -> Vec<ParamInfo> {
+        vec![
+            ParamInfo::new("cutoff", 20., 20000.),
+            ParamInfo::new("q", 0.1, 20.).random_range(0.3, 8.),
+            ParamInfo::new("key_tracking", 0., 1.),
+            ParamInfo::new("compensate", 0., 1.),
+        ]
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        Some(match name {
+            "cutoff" => self.cutoff,
+            "q" => self.q,
+            "key_tracking" => self.key_tracking,
+            "compensate" => self.compensate as u8 as f32,
+            _ => return None,
+        })
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name {
+            "cutoff" => self.cutoff = value,
+            "q" => self.q = value,
+            "key_tracking" => self.key_tracking = value,
+            "compensate" => self.compensate = value >= 0.5,
+            _ => return false,
+        }
+        self.update();
+        true
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AdsrStage {
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// Linear attack/decay/sustain/release envelope. Times are in seconds and
+/// sustain is a level in 0..=1. Multiplies whatever passes through it by the
+/// current envelope level.
+pub struct Adsr {
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+
+    stage: AdsrStage,
+    level: f32,
+    release_step: f32,
+}
+
+impl Adsr {
+    pub fn new(attack: f32, decay: f32, sustain: f32, release: f32) -> Self {
+        Self {
+            attack,
+            decay,
+            sustain: sustain.clamp(0., 1.),
+            release,
+            stage: AdsrStage::Idle,
+            level: 0.,
+            release_step: 0.,
+        }
+    }
+
+    pub fn note_on(&mut self) {
+        self.stage = AdsrStage::Attack;
+    }
+
+    pub fn note_off(&mut self) {
+        self.choke(self.release);
+    }
+
+    /// Releases over `secs` instead of the configured release time.
+    pub fn choke(&mut self, secs: f32) {
+        if self.stage != AdsrStage::Idle {
+            self.stage = AdsrStage::Release;
+            self.release_step = step_for(self.level, secs);
+        }
+    }
+
+    pub fn stage(&self) -> AdsrStage {
+        self.stage
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.stage == AdsrStage::Idle
+    }
+
+    pub fn next_level(&mut self) -> f32 {
+        match self.stage {
+            AdsrStage::Idle => {}
+            AdsrStage::Attack => {
+                self.level += step_for(1., self.attack);
+                if self.level >= 1. {
+                    self.level = 1.;
+                    self.stage = AdsrStage::Decay;
+                }
+            }
+            AdsrStage::Decay => {
+                self.level -= step_for(1. - self.sustain, self.decay);
+                if self.level <= self.sustain {
+                    self.level = self.sustain;
+                    self.stage = AdsrStage::Sustain;
+                }
+            }
+            AdsrStage::Sustain => {
+                self.level = self.sustain;
+            }
+            AdsrStage::Release => {
+                self.level -= self.release_step;
+                if self.level <= 0. {
+                    self.level = 0.;
+                    self.stage = AdsrStage::Idle;
+                }
+            }
         }
+        self.level
+    }
+}
+
+/// Per-sample increment to cover `distance` in `secs` seconds. Zero-length
+/// segments complete immediately.
+fn step_for(distance: f32, secs: f32) -> f32 {
+    if secs <= 0. {
+        f32::INFINITY
+    } else {
+        distance / (secs * SAMPLING_FREQ as f32)
+    }
+}
+
+impl Params for Adsr {
+    fn params(&self) -> Vec<ParamInfo> {
+        vec![
+            ParamInfo::new("attack", 0., 2.),
+            ParamInfo::new("decay", 0., 2.),
+            ParamInfo::new("sustain", 0., 1.),
+            ParamInfo::new("release", 0., 5.),
+        ]
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        Some(match name {
+            "attack" => self.attack,
+            "decay" => self.decay,
+            "sustain" => self.sustain,
+            "release" => self.release,
+            _ => return None,
+        })
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name {
+            "attack" => self.attack = value,
+            "decay" => self.decay = value,
+            "sustain" => self.sustain = value.clamp(0., 1.),
+            "release" => self.release = value,
+            _ => return false,
+        }
+        true
+    }
+}
+
+impl Default for Adsr {
+    fn default() -> Self {
+        // plucks ring out on their own, so just avoid clicks on either end
+        Self::new(0.002, 0., 1., 0.3)
+    }
+}
+
+impl Filter for Adsr {
+    fn process(&mut self, samples: &mut [f32]) {
+        for s in samples.iter_mut() {
+            *s *= self.next_level();
+        }
+    }
+}
+
+// y(n) = c0 x(n) + c1 x(n - 1) + ... + c(M-1) x(n - M + 1)
+//
+// frequency sampling design: sample the desired magnitude response at
+// omega_k = 2 pi k / M for k in 0..=(M-1)/2, then take the inverse DFT. with an
+// odd M and a real, even response this comes out as a cosine series that is
+// symmetric about the middle tap, i.e. linear phase with a delay of (M-1)/2.
+
+/// Linear phase FIR filter designed by frequency sampling.
+pub struct FIR {
+    coeffs: Vec<f32>,
+    /// the last `coeffs.len()` inputs, stored twice over so that they can
+    /// always be read as one contiguous slice
+    history: Vec<f32>,
+    pos: usize,
+    latency: usize,
+}
+
+impl FIR {
+    /// Designs a `2 * taps + 1` long filter. `freq_resp_curve` maps a
+    /// frequency in Hz, from 0 up to nyquist, to the desired gain there.
+    pub fn new(taps: usize, freq_resp_curve: impl Fn(f32) -> f32) -> Self {
+        debug_assert!(taps > 0);
+
+        // since freq response is symmetrical around the origin
+        let m = taps * 2 + 1;
+
+        let samples: Vec<(f32, f32)> = (1..=taps)
+            .map(|k| {
+                let omega_k = TAU * k as f32 / m as f32;
+                let hz = omega_k / TAU * SAMPLING_FREQ as f32;
+                (omega_k, freq_resp_curve(hz))
+            })
+            .collect();
+        let freq0 = freq_resp_curve(0.);
+
+        let coeffs = (0..m)
+            .map(|n| {
+                let t = n as f32 - taps as f32;
+                let sum: f32 = samples
+                    .iter()
+                    .map(|&(omega_k, resp)| resp * (omega_k * t).cos())
+                    .sum();
+                (freq0 + 2. * sum) / m as f32
+            })
+            .collect();
+        Self::from_coeffs(coeffs)
+    }
+
+    /// Uses `coeffs` as the impulse response directly. The latency reported
+    /// assumes it is linear phase.
+    pub fn from_coeffs(coeffs: Vec<f32>) -> Self {
+        assert!(!coeffs.is_empty(), "FIR needs at least one tap");
+        let n = coeffs.len();
+        Self {
+            history: vec![0.; n * 2],
+            pos: 0,
+            latency: (n - 1) / 2,
+            coeffs,
+        }
+    }
+
+    pub fn coeffs(&self) -> &[f32] {
+        &self.coeffs
+    }
+
+    /// Tapers the ends of the impulse response, which trades a wider
+    /// transition band for less ripple and more stopband attenuation.
+    pub fn with_window(mut self, window: Window) -> Self {
+        window.apply(&mut self.coeffs);
+        self
+    }
+
+    /// Designs a filter matching a measured magnitude response.
+    pub fn from_response(taps: usize, response: &Response) -> Self {
+        Self::new(taps, |hz| response.gain(hz))
+    }
+
+    /// Uses an impulse response recorded in a WAV file as the filter. Since
+    /// it needn't be linear phase, its peak is taken as the latency.
+    pub fn from_wav(path: &Path) -> io::Result<Self> {
+        let (rate, ir) = read_wav(path)?;
+        let ir = resample(&ir, rate);
+        if ir.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is empty", path.display()),
+            ));
+        }
+        let peak = (0..ir.len())
+            .max_by(|&a, &b| ir[a].abs().total_cmp(&ir[b].abs()))
+            .unwrap();
+        let mut fir = Self::from_coeffs(ir);
+        fir.latency = peak;
+        Ok(fir)
+    }
+
+    /// Loads a filter designed elsewhere: an impulse response if `path` is a
+    /// WAV file, otherwise a magnitude response as read by
+    /// [`Response::parse`], designed into a filter with `taps` and `window`.
+    pub fn load(path: &Path, taps: usize, window: Window) -> Result<Self, String> {
+        let is_wav = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("wav"));
+        let err = |e| format!("{}: {e}", path.display());
+        if is_wav {
+            Self::from_wav(path).map_err(|e| err(e.to_string()))
+        } else {
+            let contents = std::fs::read_to_string(path).map_err(|e| err(e.to_string()))?;
+            let response = Response::parse(&contents).map_err(err)?;
+            Ok(Self::from_response(taps, &response).with_window(window))
+        }
+    }
+}
+
+/// A measured magnitude response, as (frequency in Hz, gain in dB) points in
+/// increasing order of frequency.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response(pub Vec<(f32, f32)>);
+
+impl Response {
+    /// Reads two comma, semicolon or whitespace separated columns of
+    /// frequency and dB, like most measurement software exports. Blank lines,
+    /// `#` comments and a header line are skipped, as are any further
+    /// columns.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut points = Vec::new();
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut cols = line
+                .split(|c: char| c == ',' || c == ';' || c.is_whitespace())
+                .filter(|c| !c.is_empty())
+                .map(str::parse::<f32>);
+            match (cols.next(), cols.next()) {
+                (Some(Ok(hz)), Some(Ok(db))) => points.push((hz, db)),
+                // a header
+                _ if points.is_empty() && lineno == 0 => {}
+                _ => return Err(format!("line {}: expected <hz>, <db>", lineno + 1)),
+            }
+        }
+        if points.is_empty() {
+            return Err("no points in response".to_string());
+        }
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Ok(Response(points))
+    }
+
+    /// Linear gain at `hz`, interpolating in dB over log frequency and
+    /// holding the ends.
+    pub fn gain(&self, hz: f32) -> f32 {
+        let points = &self.0;
+        let i = points.partition_point(|&(f, _)| f < hz);
+        let db = if i == 0 {
+            points[0].1
+        } else if i == points.len() {
+            points[i - 1].1
+        } else {
+            let ((f0, d0), (f1, d1)) = (points[i - 1], points[i]);
+            let t = if f0 > 0. {
+                (hz / f0).ln() / (f1 / f0).ln()
+            } else {
+                (hz - f0) / (f1 - f0)
+            };
+            d0 + (d1 - d0) * t
+        };
+        10f32.powf(db / 20.)
+    }
+}
+
+impl Filter for FIR {
+    fn process(&mut self, samples: &mut [f32]) {
+        let n = self.coeffs.len();
+        for sample in samples.iter_mut() {
+            self.history[self.pos] = *sample;
+            self.history[self.pos + n] = *sample;
+            // oldest first, ending with the sample just written
+            let window = &self.history[self.pos + 1..=self.pos + n];
+            *sample = self
+                .coeffs
+                .iter()
+                .rev()
+                .zip(window)
+                .map(|(c, x)| c * x)
+                .sum();
+            self.pos = (self.pos + 1) % n;
+        }
+    }
+
+    fn latency(&self) -> usize {
+        self.latency
+    }
+}
+
+impl Params for FIR {
+    fn params(&self) -> Vec<ParamInfo> {
+        Vec::new()
+    }
+
+    fn get_param(&self, _name: &str) -> Option<f32> {
+        None
+    }
+
+    fn set_param(&mut self, _name: &str, _value: f32) -> bool {
+        false
+    }
+}
+
+#[derive(Default)]
+pub struct Pipe {
+    pub components: Vec<Box<dyn Filter>>,
+}
+
+impl Pipe {
+    pub fn new(components: Vec<Box<dyn Filter>>) -> Pipe {
+        Self { components }
+    }
+
+    pub fn add<T>(&mut self, comp: T)
+    where
+        T: Filter,
+    {
+        self.components.push(Box::new(comp));
+    }
+}
+
+impl Filter for Pipe {
+    fn process(&mut self, samples: &mut [f32]) {
+        for comp in self.components.iter_mut() {
+            comp.process(samples);
+        }
+    }
+
+    fn latency(&self) -> usize {
+        self.components.iter().map(|c| c.latency()).sum()
+    }
+
+    fn describe(&self) -> Node {
+        chain_node(self.components.iter().map(|c| &**c))
+    }
+}
+
+/// A filter with parameters, which is what goes in a [`Rack`].
+pub trait Effect: Filter + Params {}
+
+impl<T: Filter + Params> Effect for T {}
+
+/// How long a [`Rack`] takes to fade between its effects and the dry
+/// signal: 50ms.
+pub const DRY_FADE: u32 = SAMPLING_FREQ as u32 / 20;
+
+/// A chain put together at run time, for when the order comes from a patch
+/// rather than the code, unlike [`SynthBuilder`]'s.
+///
+/// Its `dry` parameter fades over to what went in, delayed to line up, for
+/// hearing what the effects are adding. It's for listening rather than
+/// part of the sound, so it stays out of snapshots.
+pub struct Rack {
+    pub effects: Vec<Box<dyn Effect>>,
+    /// 0 for the effects, 1 for only the dry signal
+    pub dry: f32,
+    dry_mix: Smoothed,
+    dry_line: DelayLine,
+    dry_buf: Vec<f32>,
+}
+
+impl Default for Rack {
+    fn default() -> Self {
+        Self {
+            effects: Vec::new(),
+            dry: 0.,
+            dry_mix: Smoothed::new(0.),
+            dry_line: DelayLine::new(1),
+            dry_buf: Vec::new(),
+        }
+    }
+}
+
+impl Rack {
+    pub fn add(&mut self, effect: impl Effect) {
+        self.effects.push(Box::new(effect));
+    }
+}
+
+impl Filter for Rack {
+    fn process(&mut self, samples: &mut [f32]) {
+        let mut dry = std::mem::take(&mut self.dry_buf);
+        dry.clear();
+        dry.extend_from_slice(samples);
+        let latency = self.latency();
+        if self.dry_line.len() != latency + 1 {
+            self.dry_line.set_len(latency + 1);
+        }
+        self.dry_line.process(&mut dry);
+
+        for effect in &mut self.effects {
+            effect.process(samples);
+        }
+
+        self.dry_mix.set_over(self.dry, DRY_FADE);
+        if self.dry_mix.value() > 0. || self.dry_mix.is_ramping() {
+            for (s, d) in samples.iter_mut().zip(&dry) {
+                let mix = self.dry_mix.next_value();
+                *s += (d - *s) * mix;
+            }
+        }
+        self.dry_buf = dry;
+    }
+
+    fn latency(&self) -> usize {
+        self.effects.iter().map(|e| e.latency()).sum()
+    }
+
+    fn describe(&self) -> Node {
+        chain_of(self.effects.iter().map(|e| e.describe()))
+    }
+}
+
+impl Params for Rack {
+    fn params(&self) -> Vec<ParamInfo> {
+        let mut out = vec![ParamInfo::new("dry", 0., 1.).not_random()];
+        out.extend(self.effects.iter().flat_map(|e| e.params()));
+        out
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        self.effects.iter().find_map(|e| e.get_param(name))
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        if name == "dry" {
+            self.dry = value.clamp(0., 1.);
+            return true;
+        }
+        // every effect with this parameter gets it, like in a Chain
+        let mut found = false;
+        for effect in &mut self.effects {
+            found |= effect.set_param(name, value);
+        }
+        found
+    }
+}
+
+/// Reads a WAV file as f32 samples, keeping only the first channel.
+pub fn read_wav_mono(path: &Path) -> io::Result<Vec<f32>> {
+    Ok(read_wav(path)?.1)
+}
+
+/// Converts `samples` recorded at `rate` to our sampling rate, with linear
+/// interpolation.
+pub fn resample(samples: &[f32], rate: u32) -> Vec<f32> {
+    if rate as usize == SAMPLING_FREQ || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let step = rate as f32 / SAMPLING_FREQ as f32;
+    let len = ((samples.len() - 1) as f32 / step) as usize + 1;
+    (0..len)
+        .map(|i| {
+            let pos = i as f32 * step;
+            let idx = pos as usize;
+            let frac = pos - idx as f32;
+            let next = samples.get(idx + 1).copied().unwrap_or(0.);
+            samples[idx] + (next - samples[idx]) * frac
+        })
+        .collect()
+}
+
+/// Reads the first channel of a WAV file, along with its sample rate.
+pub fn read_wav(path: &Path) -> io::Result<(u32, Vec<f32>)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let (header, data) = wav::read(&mut reader)?;
+    let samples: Vec<f32> = match data {
+        BitDepth::Eight(v) => v.iter().map(|&s| (s as f32 - 128.) / 128.).collect(),
+        BitDepth::Sixteen(v) => v.iter().map(|&s| s as f32 / i16::MAX as f32).collect(),
+        BitDepth::TwentyFour(v) => v.iter().map(|&s| s as f32 / 8388607.).collect(),
+        BitDepth::ThirtyTwoFloat(v) => v,
+        BitDepth::Empty => Vec::new(),
+    };
+    let samples = samples
+        .into_iter()
+        .step_by(header.channel_count.max(1) as usize)
+        .collect();
+    Ok((header.sampling_rate, samples))
+}
+
+/// A block of audio passed from one part of the graph to another: what's
+/// routed into it earlier in the block (some of the voices, say) comes out
+/// somewhere that isn't downstream of them, like a sidechain's key. Both
+/// ends are on the audio thread, so its lock is never waited on.
+#[derive(Clone, Default)]
+pub struct Bus(Arc<Mutex<Vec<f32>>>);
+
+impl Bus {
+    /// Starts a block of `len` samples, silent until something's added.
+    pub fn start(&self, len: usize) {
+        if let Ok(mut buf) = self.0.try_lock() {
+            buf.clear();
+            buf.resize(len, 0.);
+        }
+    }
+
+    /// Mixes `samples` into the block.
+    pub fn add(&self, samples: &[f32]) {
+        if let Ok(mut buf) = self.0.try_lock() {
+            for (b, s) in buf.iter_mut().zip(samples) {
+                *b += s;
+            }
+        }
+    }
+
+    /// Starts a block with `len` samples from `next`.
+    pub fn fill(&self, len: usize, next: impl FnMut() -> f32) {
+        if let Ok(mut buf) = self.0.try_lock() {
+            buf.clear();
+            buf.extend(std::iter::repeat_with(next).take(len));
+        }
+    }
+
+    /// Copies the block into `samples`, and empties it so a block nothing
+    /// was routed into comes out silent.
+    pub fn take(&self, samples: &mut [f32]) {
+        samples.fill(0.);
+        if let Ok(mut buf) = self.0.try_lock() {
+            for (s, b) in samples.iter_mut().zip(buf.iter_mut()) {
+                *s = std::mem::take(b);
+            }
+        }
+    }
+}
+
+/// Replaces what comes in with the block routed in.
+impl Filter for Bus {
+    fn process(&mut self, samples: &mut [f32]) {
+        self.take(samples);
+    }
+}
+
+impl Params for Bus {
+    fn params(&self) -> Vec<ParamInfo> {
+        Vec::new()
+    }
+
+    fn get_param(&self, _name: &str) -> Option<f32> {
+        None
+    }
+
+    fn set_param(&mut self, _name: &str, _value: f32) -> bool {
+        false
+    }
+}
+
+pub struct NoopFilter;
+
+impl Filter for NoopFilter {
+    fn process(&mut self, _samples: &mut [f32]) {}
+}
+
+impl Params for NoopFilter {
+    fn params(&self) -> Vec<ParamInfo> {
+        Vec::new()
+    }
+
+    fn get_param(&self, _name: &str) -> Option<f32> {
+        None
+    }
+
+    fn set_param(&mut self, _name: &str, _value: f32) -> bool {
+        false
+    }
+}
+
+/// A filter that can be left out of the chain, passing audio through
+/// untouched.
+impl<F: Filter> Filter for Option<F> {
+    fn process(&mut self, samples: &mut [f32]) {
+        if let Some(f) = self {
+            f.process(samples);
+        }
+    }
+
+    fn latency(&self) -> usize {
+        self.as_ref().map_or(0, F::latency)
+    }
+
+    fn describe(&self) -> Node {
+        self.as_ref()
+            .map_or_else(|| NoopFilter.describe(), F::describe)
+    }
+}
+
+impl<F: Params> Params for Option<F> {
+    fn params(&self) -> Vec<ParamInfo> {
+        self.as_ref().map_or_else(Vec::new, F::params)
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        self.as_ref()?.get_param(name)
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        self.as_mut().is_some_and(|f| f.set_param(name, value))
+    }
+}
+
+/// Puts a filter's parameters under `name.` so several of the same kind can
+/// sit in one chain.
+pub struct Named<F> {
+    pub name: Cow<'static, str>,
+    pub inner: F,
+}
+
+impl<F> Named<F> {
+    pub fn new(name: impl Into<Cow<'static, str>>, inner: F) -> Self {
+        Self {
+            name: name.into(),
+            inner,
+        }
+    }
+}
+
+impl<F: Filter> Filter for Named<F> {
+    fn process(&mut self, samples: &mut [f32]) {
+        self.inner.process(samples);
+    }
+
+    fn latency(&self) -> usize {
+        self.inner.latency()
+    }
+
+    fn describe(&self) -> Node {
+        self.inner.describe()
+    }
+}
+
+impl<F: Params> Params for Named<F> {
+    fn params(&self) -> Vec<ParamInfo> {
+        nested(&self.name, &self.inner)
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        let name = name.strip_prefix(&*self.name)?.strip_prefix('.')?;
+        self.inner.get_param(name)
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        let Some(name) = name
+            .strip_prefix(&*self.name)
+            .and_then(|n| n.strip_prefix('.'))
+        else {
+            return false;
+        };
+        self.inner.set_param(name, value)
+    }
+}
+
+/// Room for the side signal to wait for the effects, more than any of
+/// them is late by.
+const SIDE_LAG_LEN: usize = 4096;
+
+/// Voices and the mono effects after them. Played wide, the effects get
+/// the middle of what the voices make, and the difference between its
+/// sides goes round them, as late as they are, to be put back after.
+pub struct Synth<S: 'static + Filter + Send, F: Filter = NoopFilter> {
+    pub synth: S,
+    pub filter: F,
+    pub volume: f32,
+    gain: Smoothed,
+    /// the side signal on its way round the effects
+    side: VecDeque<f32>,
+}
+
+impl<S: 'static + Filter + Send, F: Filter> Filter for Synth<S, F> {
+    fn process(&mut self, samples: &mut [f32]) {
+        // so a source that has been bypassed comes out silent
+        samples.fill(0.);
+        self.synth.process(samples);
+        self.filter.process(samples);
+        self.gain.set(self.volume);
+        for s in samples.iter_mut() {
+            *s *= self.gain.next_value();
+        }
+    }
+
+    fn process_wide(&mut self, left: &mut [f32], right: &mut [f32]) {
+        left.fill(0.);
+        right.fill(0.);
+        self.synth.process_wide(left, right);
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            (*l, *r) = ((*l + *r) / 2., (*l - *r) / 2.);
+        }
+        self.filter.process(left);
+        let lag = self.filter.latency().min(SIDE_LAG_LEN - 1);
+        for r in right.iter_mut() {
+            self.side.push_back(*r);
+            *r = if self.side.len() > lag {
+                self.side.pop_front().unwrap_or(0.)
+            } else {
+                0.
+            };
+        }
+        // the effects got quicker
+        while self.side.len() > lag {
+            self.side.pop_front();
+        }
+        self.gain.set(self.volume);
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            let gain = self.gain.next_value();
+            (*l, *r) = ((*l + *r) * gain, (*l - *r) * gain);
+        }
+    }
+
+    fn latency(&self) -> usize {
+        self.synth.latency() + self.filter.latency()
+    }
+
+    fn describe(&self) -> Node {
+        Node::with_children(
+            "Synth",
+            vec![
+                self.synth.describe(),
+                chain_node([&self.filter as &dyn Filter]),
+            ],
+        )
+    }
+}
+
+impl<S: 'static + Filter + Send + Params, F: Filter + Params> Params for Synth<S, F> {
+    fn params(&self) -> Vec<ParamInfo> {
+        let mut out = vec![ParamInfo::new("volume", 0., 2.).not_random()];
+        out.extend(self.synth.params());
+        out.extend(self.filter.params());
+        out
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name {
+            "volume" => Some(self.volume),
+            _ => self
+                .synth
+                .get_param(name)
+                .or_else(|| self.filter.get_param(name)),
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name {
+            "volume" => self.volume = value,
+            _ => return self.synth.set_param(name, value) || self.filter.set_param(name, value),
+        }
+        true
+    }
+}
+
+pub struct Chain<H: Filter, T: Filter>(pub H, pub T);
+
+impl<H: Filter, T: Filter> Filter for Chain<H, T> {
+    fn process(&mut self, samples: &mut [f32]) {
+        self.1.process(samples);
+        self.0.process(samples);
+    }
+
+    fn latency(&self) -> usize {
+        self.0.latency() + self.1.latency()
+    }
+
+    fn describe(&self) -> Node {
+        chain_node([&self.1 as &dyn Filter, &self.0])
+    }
+}
+
+impl<H: Filter + Params, T: Filter + Params> Params for Chain<H, T> {
+    fn params(&self) -> Vec<ParamInfo> {
+        let mut out = self.1.params();
+        out.extend(self.0.params());
+        out
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        self.1.get_param(name).or_else(|| self.0.get_param(name))
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        // every filter with this parameter gets it
+        let found = self.1.set_param(name, value);
+        self.0.set_param(name, value) || found
+    }
+}
+
+/// Splits an incoming stream into N pieces and then joins them back after
+/// running the components over the input samples provided. Branches with less
+/// latency than the slowest one are delayed to match so they stay in phase.
+pub struct SplitJoin {
+    branches: Vec<Box<dyn Filter>>,
+    /// compensation delay for each branch and the latency it was sized for
+    compensation: Vec<(usize, Option<DelayLine>)>,
+}
+
+impl SplitJoin {
+    pub fn new(branches: Vec<Box<dyn Filter>>) -> Self {
+        let mut this = Self {
+            branches,
+            compensation: Vec::new(),
+        };
+        this.compensate();
+        this
+    }
+
+    /// Resizes the compensation delays if any branch's latency has changed.
+    fn compensate(&mut self) {
+        let latencies: Vec<usize> = self.branches.iter().map(|b| b.latency()).collect();
+        if latencies.len() == self.compensation.len()
+            && latencies
+                .iter()
+                .zip(&self.compensation)
+                .all(|(&l, &(c, _))| l == c)
+        {
+            return;
+        }
+
+        let max = latencies.iter().copied().max().unwrap_or(0);
+        self.compensation = latencies
+            .into_iter()
+            .map(|l| {
+                // a DelayLine of length n delays by n - 1
+                let delay = (l < max).then(|| DelayLine::new(max - l + 1));
+                (l, delay)
+            })
+            .collect();
+    }
+}
+
+impl Filter for SplitJoin {
+    fn process(&mut self, samples: &mut [f32]) {
+        self.compensate();
+
+        let mut copies = Vec::new();
+        copies.resize_with(self.branches.len(), || samples.to_vec());
+
+        for ((comp, (_, delay)), inputs) in self
+            .branches
+            .iter_mut()
+            .zip(self.compensation.iter_mut())
+            .zip(copies.iter_mut())
+        {
+            comp.process(inputs);
+            if let Some(delay) = delay {
+                delay.process(inputs);
+            }
+        }
+
+        for (idx, s) in samples.iter_mut().enumerate() {
+            *s = copies.iter().map(|o| o[idx]).sum();
+        }
+    }
+
+    fn latency(&self) -> usize {
+        self.branches.iter().map(|b| b.latency()).max().unwrap_or(0)
+    }
+
+    fn describe(&self) -> Node {
+        Node::with_children(
+            "SplitJoin",
+            self.branches.iter().map(|b| b.describe()).collect(),
+        )
+    }
+}
+
+#[derive(Clone)]
+pub struct SquareWave {
+    pub phase_inc: f32,
+    pub phase: f32,
+    pub volume: f32,
+}
+
+impl Filter for SquareWave {
+    fn process(&mut self, samples: &mut [f32]) {
+        for s in samples.iter_mut() {
+            *s = if self.phase > 0.5 {
+                self.volume
+            } else {
+                -self.volume
+            };
+            self.phase = (self.phase + self.phase_inc) % 1.0;
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Rng {
+    pub v: u32,
+}
+
+impl Rng {
+    pub fn next_f32(&mut self) -> f32 {
+        self.v ^= self.v << 13;
+        self.v ^= self.v >> 17;
+        self.v ^= self.v << 5;
+
+        // convert to full scale
+        self.v as i32 as f32 / i32::MAX as f32
+    }
+}
+
+impl Rng {
+    /// An independent stream. Zero would get stuck, so it is nudged.
+    pub fn with_seed(seed: u32) -> Self {
+        Rng { v: seed.max(1) }
+    }
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Rng::with_seed(0xdeadbeef)
+    }
+}
+
+/// The seed for voice `index` of a synth seeded with `seed`. Streams from
+/// seeds next to each other start out alike, so they're spread out first.
+pub fn voice_seed(seed: u32, index: usize) -> u32 {
+    seed.wrapping_add(index as u32).wrapping_mul(0x9e3779b9)
+}
+
+pub struct Scale(f32);
+
+impl Filter for Scale {
+    fn process(&mut self, samples: &mut [f32]) {
+        for s in samples.iter_mut() {
+            *s *= self.0;
+        }
+    }
+}
+
+/// A source that can be used to excite a physical model. `restart` is called
+/// on every note-on, right before the burst is pulled out of it.
+pub trait Exciter: Filter {
+    fn restart(&mut self) {}
+
+    /// Makes an independent copy for another voice.
+    fn boxed_clone(&self) -> Box<dyn Exciter>;
+
+    /// Natural length of the burst in samples, if the exciter has one.
+    fn burst_len(&self) -> Option<usize> {
+        None
+    }
+
+    /// Switches to a different random stream, for exciters that have one, so
+    /// that copies don't all make the same burst.
+    fn reseed(&mut self, _seed: u32) {}
+}
+
+#[derive(Clone)]
+pub struct Noise {
+    pub rng: Rng,
+    pub volume: f32,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Noise {
+            rng: Rng::default(),
+            volume: 1.,
+        }
+    }
+}
+
+impl Filter for Noise {
+    fn process(&mut self, samples: &mut [f32]) {
+        for s in samples.iter_mut() {
+            *s = self.rng.next_f32() * self.volume;
+        }
+    }
+}
+
+impl Exciter for Noise {
+    fn boxed_clone(&self) -> Box<dyn Exciter> {
+        Box::new(self.clone())
+    }
+
+    fn reseed(&mut self, seed: u32) {
+        self.rng = Rng::with_seed(seed);
+    }
+}
+
+impl Exciter for SquareWave {
+    fn restart(&mut self) {
+        self.phase = 0.;
+    }
+
+    fn boxed_clone(&self) -> Box<dyn Exciter> {
+        Box::new(self.clone())
+    }
+}
+
+/// Plays back a fixed buffer once per restart, then silence.
+#[derive(Clone)]
+pub struct SamplePlayer {
+    pub samples: Vec<f32>,
+    pub pos: usize,
+}
+
+impl SamplePlayer {
+    pub fn new(samples: Vec<f32>) -> Self {
+        // start off finished so nothing plays until the first restart
+        let pos = samples.len();
+        Self { samples, pos }
+    }
+}
+
+impl Filter for SamplePlayer {
+    fn process(&mut self, samples: &mut [f32]) {
+        for s in samples.iter_mut() {
+            *s = self.samples.get(self.pos).copied().unwrap_or(0.);
+            self.pos = (self.pos + 1).min(self.samples.len());
+        }
+    }
+}
+
+impl Exciter for SamplePlayer {
+    fn restart(&mut self) {
+        self.pos = 0;
+    }
+
+    fn burst_len(&self) -> Option<usize> {
+        Some(self.samples.len())
+    }
+
+    fn boxed_clone(&self) -> Box<dyn Exciter> {
+        Box::new(self.clone())
+    }
+}
+
+/// Exciter selection as written on the command line: `noise`, `square`,
+/// `sample:<path>[@<start>..<end>]` with the slice bounds in samples, or
+/// `input` for what's coming in from the capture device.
+#[derive(Clone, Debug)]
+pub enum ExciterKind {
+    Noise,
+    Square,
+    Sample(PathBuf, Option<Range<usize>>),
+    Input,
+}
+
+impl std::str::FromStr for ExciterKind {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        Ok(match value {
+            "noise" => ExciterKind::Noise,
+            "square" => ExciterKind::Square,
+            "input" => ExciterKind::Input,
+            _ => {
+                let Some(spec) = value.strip_prefix("sample:") else {
+                    return Err(format!("unknown exciter {value:?}"));
+                };
+                match spec.rsplit_once('@') {
+                    Some((path, range)) => {
+                        let (start, end) = range
+                            .split_once("..")
+                            .ok_or_else(|| format!("bad sample slice {range:?}"))?;
+                        let parse = |v: &str| {
+                            v.parse::<usize>()
+                                .map_err(|e| format!("bad sample slice {range:?}: {e}"))
+                        };
+                        ExciterKind::Sample(path.into(), Some(parse(start)?..parse(end)?))
+                    }
+                    None => ExciterKind::Sample(spec.into(), None),
+                }
+            }
+        })
+    }
+}
+
+impl ExciterKind {
+    pub fn build(&self) -> io::Result<Box<dyn Exciter>> {
+        Ok(match self {
+            ExciterKind::Noise => Box::<Noise>::default(),
+            ExciterKind::Square => Box::new(SquareWave {
+                phase_inc: 440. / SAMPLING_FREQ as f32,
+                phase: 0.,
+                volume: 1.,
+            }),
+            ExciterKind::Sample(path, range) => {
+                let mut samples = read_wav_mono(path)?;
+                if let Some(range) = range {
+                    let end = range.end.min(samples.len());
+                    samples.truncate(end);
+                    samples.drain(..range.start.min(end));
+                }
+                Box::new(SamplePlayer::new(samples))
+            }
+            ExciterKind::Input => Box::<LiveInput>::default(),
+        })
+    }
+}
+
+/// Ways of playing the string.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Articulation {
+    Open,
+    /// Damped by the side of the hand, so it dies out quickly.
+    PalmMute,
+    /// Choked shortly after the pluck even if the note is held.
+    Staccato,
+    /// Natural harmonic from lightly touching the string at 1/n of its
+    /// length, which kills every mode that doesn't have a node there.
+    Harmonic(u8),
+}
+
+impl Articulation {
+    pub const ALL: [Articulation; 5] = [
+        Articulation::Open,
+        Articulation::PalmMute,
+        Articulation::Staccato,
+        Articulation::Harmonic(2),
+        Articulation::Harmonic(3),
+    ];
+
+    pub fn index(self) -> usize {
+        Self::ALL.iter().position(|&a| a == self).unwrap_or(0)
+    }
+
+    /// Multiplier on the fundamental. Only the modes that are multiples of
+    /// the touched node survive, which is equivalent to dividing the loop.
+    fn pitch_ratio(self) -> f32 {
+        match self {
+            Articulation::Harmonic(n) => n.max(1) as f32,
+            _ => 1.,
+        }
+    }
+
+    /// Extra gain applied to the signal each time around the loop.
+    fn loop_gain(self) -> f32 {
+        match self {
+            Articulation::PalmMute => 0.93,
+            _ => 1.,
+        }
+    }
+}
+
+/// How note velocity maps onto how hard the string is plucked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VelocityCurve {
+    Linear,
+    /// Velocity in decibels, over [`VELOCITY_RANGE_DB`].
+    Exponential,
+    /// Every note as hard as it goes.
+    Fixed,
+}
+
+/// How much quieter the softest note is than the hardest with
+/// [`VelocityCurve::Exponential`].
+const VELOCITY_RANGE_DB: f32 = 40.;
+
+impl VelocityCurve {
+    pub const ALL: [VelocityCurve; 3] = [
+        VelocityCurve::Linear,
+        VelocityCurve::Exponential,
+        VelocityCurve::Fixed,
+    ];
+
+    pub fn index(self) -> usize {
+        Self::ALL.iter().position(|&c| c == self).unwrap_or(0)
+    }
+
+    /// Strength in 0..=1 for a velocity in 0..=1.
+    pub fn shape(self, velocity: f32) -> f32 {
+        let velocity = velocity.clamp(0., 1.);
+        match self {
+            VelocityCurve::Linear => velocity,
+            VelocityCurve::Exponential => 10f32.powf(VELOCITY_RANGE_DB * (velocity - 1.) / 20.),
+            VelocityCurve::Fixed => 1.,
+        }
+    }
+}
+
+/// How long a staccato note rings before it gets choked, in seconds.
+const STACCATO_LEN: f32 = 0.12;
+
+/// Noise fed into a string bowed as hard as it goes, each sample.
+const BOW_GAIN: f32 = 0.05;
+
+/// The right hand loop of a [`StringSynth`] played wide, with noise of its
+/// own and tuned a little apart from the left, so the two sides ring alike
+/// without being the same.
+struct SideLoop {
+    delay: DelayLine,
+    /// state of its own, with the settings copied from the left's
+    damping: Damping,
+    exciter: Box<dyn Exciter>,
+    last: f32,
+}
+
+/// Seeds the right hand loop's noise apart from the left's.
+const SIDE_SEED: u32 = 0x5bd1e995;
+
+pub struct StringSynth {
+    pub delay: DelayLine,
+    pub damping: Damping,
+    pub env: Adsr,
+    pub tremolo: Lfo,
+    /// 0 disables the tremolo, 1 swings all the way down to silence
+    pub tremolo_depth: f32,
+    /// How far, in cents, the loop length wanders either side of the note.
+    /// Each voice wanders on its own, so unison notes beat against each
+    /// other the way a piano's strings do.
+    pub drift_depth: f32,
+    /// How fast it wanders, in Hz. Each voice is a bit off this.
+    pub drift_rate: f32,
+    /// 0..1
+    drift_phase: f32,
+    drift_skew: f32,
+    /// current detune in cents
+    drift_cents: f32,
+    pub snoop: Snoop,
+
+    pub last: f32,
+
+    /// frequency of the current note before pitch bend
+    pub note_freq: f32,
+    /// current pitch bend in semitones
+    pub bend: f32,
+
+    pub articulation: Articulation,
+    /// samples left until a staccato note is choked
+    staccato_remaining: u32,
+
+    /// How long to excite the string for on each pluck, in milliseconds.
+    /// Exciters playing a recording use its length instead.
+    pub excitation_ms: f32,
+    /// number of samples of noise burst remaining
+    pub trigger_count: u32,
+    /// How velocity maps onto the strength of the pluck, which the burst
+    /// is scaled by.
+    pub velocity_curve: VelocityCurve,
+    /// How much a soft pluck shortens the burst too, in 0..=1. Exciters
+    /// playing a recording always play all of it.
+    pub velocity_length: f32,
+    /// How much a soft pluck turns the whole note down too, in 0..=1.
+    pub velocity_gain: f32,
+    /// the current note's strength, from its velocity
+    strength: f32,
+    /// How hard the string is bowed while the note is held, in 0..=1,
+    /// which keeps exciting it for as long as it's above zero.
+    pub bow: f32,
+    bow_level: Smoothed,
+    bow_rng: Rng,
+    held: bool,
+    /// per-note pressure, which bows the string as hard if it's more than
+    /// `bow`
+    pressure: f32,
+    /// How much like a drum it is, in 0..=1: the chance of each sample
+    /// going round the loop with its sign flipped, up to a half, where the
+    /// pitch is lost in a snare-like rattle.
+    pub drum: f32,
+    drum_rng: Rng,
+
+    /// Set with [`StringSynth::set_exciter`], which reaches both loops.
+    exciter: Box<dyn Exciter>,
+    /// How far apart, in cents, the loops for each side are tuned, either
+    /// side of the note, when played wide. At 0 both sides play the one
+    /// loop.
+    pub spread: f32,
+    side: SideLoop,
+}
+
+impl StringSynth {
+    pub fn tune(&mut self, freq: f32) {
+        // the loop also goes through the damping filter and the one sample of
+        // feedback via `last`, so take those out of the delay line's share
+        let delay = |freq: f32| SAMPLING_FREQ as f32 / freq - 1. - self.damping.phase_delay(freq);
+        let apart = 2f32.powf(self.spread / 2400.);
+        let (left, right) = (delay(freq * apart), delay(freq / apart));
+        self.delay.set_delay(left.max(1.));
+        self.side.delay.set_delay(right.max(1.));
+    }
+
+    /// Plucks both loops with `exciter`, each with noise of its own from
+    /// `seed`.
+    pub fn set_exciter(&mut self, exciter: &dyn Exciter, seed: u32) {
+        self.exciter = exciter.boxed_clone();
+        self.exciter.reseed(seed);
+        self.side.exciter = exciter.boxed_clone();
+        self.side.exciter.reseed(seed ^ SIDE_SEED);
+    }
+
+    pub fn set_bend(&mut self, semitones: f32) {
+        self.bend = semitones;
+        let semitones = semitones + self.drift_cents / 100.;
+        self.tune(self.note_freq * self.articulation.pitch_ratio() * 2f32.powf(semitones / 12.));
+    }
+
+    /// Starts this voice's drift somewhere of its own, so it doesn't move
+    /// in step with the others.
+    pub fn scatter_drift(&mut self, seed: u32) {
+        let mut rng = Rng::with_seed(seed.wrapping_mul(0x9e3779b9));
+        self.drift_phase = 0.5 + 0.5 * rng.next_f32();
+        self.drift_skew = 1. + 0.25 * rng.next_f32();
+        self.bow_rng = Rng::with_seed(seed.wrapping_add(1));
+        self.drum_rng = Rng::with_seed(seed.wrapping_add(2));
+    }
+
+    /// Moves the drift on by `samples` and retunes to match.
+    fn drift(&mut self, samples: usize) {
+        if self.drift_depth == 0. && self.drift_cents == 0. {
+            return;
+        }
+        let inc = self.drift_rate * self.drift_skew * samples as f32 / SAMPLING_FREQ as f32;
+        self.drift_phase = (self.drift_phase + inc).rem_euclid(1.);
+        self.drift_cents = self.drift_depth * (self.drift_phase * TAU).sin();
+        self.set_bend(self.bend);
+    }
+
+    pub fn set_articulation(&mut self, articulation: Articulation) {
+        self.articulation = articulation;
+        self.set_bend(self.bend);
+    }
+
+    /// Plucks the string. `velocity` is in 0..=1.
+    pub fn note_on(&mut self, freq: f32, velocity: f32) {
+        self.note_freq = freq;
+        self.damping.set_note(freq, velocity);
+        self.set_bend(self.bend);
+        self.exciter.restart();
+        self.side.exciter.restart();
+        self.strength = self.velocity_curve.shape(velocity);
+        let soft = 1. - self.strength;
+        self.trigger_count = match self.exciter.burst_len() {
+            Some(len) => len as u32,
+            None => {
+                let ms = self.excitation_ms * (1. - self.velocity_length.clamp(0., 1.) * soft);
+                (ms * SAMPLING_FREQ as f32 / 1000.).round() as u32
+            }
+        };
+        self.env.note_on();
+        self.tremolo.note_on();
+        self.held = true;
+        self.staccato_remaining = match self.articulation {
+            Articulation::Staccato => (STACCATO_LEN * SAMPLING_FREQ as f32) as u32,
+            _ => 0,
+        };
+    }
+
+    pub fn note_off(&mut self) {
+        self.env.note_off();
+        self.held = false;
+    }
+
+    pub fn new(depth: usize) -> StringSynth {
+        StringSynth {
+            delay: DelayLine::new(depth),
+            damping: Damping::default(),
+            env: Adsr::default(),
+            tremolo: Lfo::new(5.),
+            tremolo_depth: 0.,
+            drift_depth: 0.,
+            drift_rate: 0.3,
+            drift_phase: 0.,
+            drift_skew: 1.,
+            drift_cents: 0.,
+            exciter: Box::<Noise>::default(),
+            snoop: Snoop::new("string"),
+            last: 0.,
+            note_freq: 440.,
+            bend: 0.,
+            articulation: Articulation::Open,
+            staccato_remaining: 0,
+            // 50 samples at 44.1kHz, which it used to be fixed at
+            excitation_ms: 1.134,
+            trigger_count: 0,
+            velocity_curve: VelocityCurve::Linear,
+            velocity_length: 0.,
+            velocity_gain: 0.,
+            strength: 1.,
+            bow: 0.,
+            bow_level: Smoothed::new(0.),
+            bow_rng: Rng::default(),
+            held: false,
+            pressure: 0.,
+            drum: 0.,
+            drum_rng: Rng::with_seed(0x1234567),
+            spread: 0.,
+            side: SideLoop {
+                delay: DelayLine::new(depth),
+                damping: Damping::default(),
+                exciter: Box::new(Noise {
+                    rng: Rng::with_seed(SIDE_SEED),
+                    ..Noise::default()
+                }),
+                last: 0.,
+            },
+        }
+    }
+}
+
+impl Voice for StringSynth {
+    fn note_on(&mut self, freq: f32, velocity: f32) {
+        StringSynth::note_on(self, freq, velocity);
+    }
+
+    fn note_off(&mut self) {
+        StringSynth::note_off(self);
+    }
+
+    fn set_bend(&mut self, semitones: f32) {
+        StringSynth::set_bend(self, semitones);
+    }
+
+    fn is_active(&self) -> bool {
+        !self.env.is_idle()
+    }
+
+    fn set_expression(&mut self, pressure: f32, timbre: f32) {
+        self.pressure = pressure;
+        self.damping.set_timbre(timbre - 0.5);
+        // the damping's phase delay is part of the tuning
+        self.set_bend(self.bend);
+    }
+}
+
+impl Params for StringSynth {
+    fn params(&self) -> Vec<ParamInfo> {
+        let mut out = vec![
+            ParamInfo::new("articulation", 0., (Articulation::ALL.len() - 1) as f32).not_random(),
+            ParamInfo::new("tremolo.depth", 0., 1.),
+            ParamInfo::new("drift.depth", 0., 10.),
+            ParamInfo::new("drift.rate", 0.01, 2.),
+            ParamInfo::new("excitation", 0.1, 50.),
+            ParamInfo::new("velocity.curve", 0., (VelocityCurve::ALL.len() - 1) as f32)
+                .not_random(),
+            ParamInfo::new("velocity.length", 0., 1.),
+            ParamInfo::new("velocity.gain", 0., 1.),
+            ParamInfo::new("bow", 0., 1.),
+            ParamInfo::new("drum", 0., 1.),
+            ParamInfo::new("spread", 0., 20.),
+        ];
+        out.extend(nested("damping", &self.damping));
+        out.extend(nested("env", &self.env));
+        out.extend(nested("tremolo", &self.tremolo));
+        out
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name {
+            "articulation" => return Some(self.articulation.index() as f32),
+            "excitation" => return Some(self.excitation_ms),
+            "bow" => return Some(self.bow),
+            "drum" => return Some(self.drum),
+            "spread" => return Some(self.spread),
+            _ => {}
+        }
+        match name.split_once('.')? {
+            ("tremolo", "depth") => Some(self.tremolo_depth),
+            ("velocity", "curve") => Some(self.velocity_curve.index() as f32),
+            ("velocity", "length") => Some(self.velocity_length),
+            ("velocity", "gain") => Some(self.velocity_gain),
+            ("drift", "depth") => Some(self.drift_depth),
+            ("drift", "rate") => Some(self.drift_rate),
+            ("damping", rest) => self.damping.get_param(rest),
+            ("env", rest) => self.env.get_param(rest),
+            ("tremolo", rest) => self.tremolo.get_param(rest),
+            _ => None,
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        if name == "articulation" {
+            let idx = (value.round().max(0.) as usize).min(Articulation::ALL.len() - 1);
+            self.set_articulation(Articulation::ALL[idx]);
+            return true;
+        }
+        if name == "excitation" {
+            self.excitation_ms = value.max(0.);
+            return true;
+        }
+        if name == "bow" {
+            self.bow = value.clamp(0., 1.);
+            return true;
+        }
+        if name == "spread" {
+            self.spread = value.max(0.);
+            self.set_bend(self.bend);
+            return true;
+        }
+        if name == "drum" {
+            self.drum = value.clamp(0., 1.);
+            return true;
+        }
+        match name.split_once('.') {
+            Some(("tremolo", "depth")) => {
+                self.tremolo_depth = value;
+                true
+            }
+            Some(("velocity", "curve")) => {
+                let idx = (value.round().max(0.) as usize).min(VelocityCurve::ALL.len() - 1);
+                self.velocity_curve = VelocityCurve::ALL[idx];
+                true
+            }
+            Some(("velocity", "length")) => {
+                self.velocity_length = value.clamp(0., 1.);
+                true
+            }
+            Some(("velocity", "gain")) => {
+                self.velocity_gain = value.clamp(0., 1.);
+                true
+            }
+            Some(("drift", "depth")) => {
+                self.drift_depth = value.max(0.);
+                true
+            }
+            Some(("drift", "rate")) => {
+                self.drift_rate = value;
+                true
+            }
+            Some(("damping", rest)) => self.damping.set_param(rest, value),
+            Some(("env", rest)) => self.env.set_param(rest, value),
+            Some(("tremolo", rest)) => self.tremolo.set_param(rest, value),
+            _ => false,
+        }
+    }
+}
+
+impl StringSynth {
+    /// Plays the left loop into `left`, and the right into `right` if it's
+    /// there.
+    fn play(&mut self, left: &mut [f32], mut right: Option<&mut [f32]>) {
+        // a few cents at well under a hertz hardly moves within a block
+        self.drift(left.len());
+        self.bow_level.set(if self.held {
+            self.bow.max(self.pressure) * BOW_GAIN
+        } else {
+            0.
+        });
+        if right.is_some() {
+            let state = (self.side.damping.last_in, self.side.damping.last_out);
+            self.side.damping = self.damping.clone();
+            (self.side.damping.last_in, self.side.damping.last_out) = state;
+        }
+        let gain = 1. - self.velocity_gain * (1. - self.strength);
+        for i in 0..left.len() {
+            if self.staccato_remaining > 0 {
+                self.staccato_remaining -= 1;
+                if self.staccato_remaining == 0 {
+                    self.env.choke(0.02);
+                }
+            }
+
+            let plucked = self.trigger_count > 0;
+            if plucked {
+                self.trigger_count -= 1;
+            }
+            let bow = self.bow_level.next_value();
+            let flip = self.drum > 0. && 0.5 + 0.5 * self.drum_rng.next_f32() < 0.5 * self.drum;
+            let trem = 1. - self.tremolo_depth * (0.5 + 0.5 * self.tremolo.next_value());
+            let out = self.env.next_level() * trem * gain;
+
+            let mut loop_in = self.last;
+            if plucked {
+                let mut burst = [0.];
+                self.exciter.process(&mut burst);
+                loop_in += burst[0] * self.strength;
+            }
+            if bow > 0. {
+                loop_in += bow * self.bow_rng.next_f32();
+            }
+            let mut samp = [loop_in];
+            self.delay.process(&mut samp);
+            self.damping.process(&mut samp);
+            if flip {
+                samp[0] = -samp[0];
+            }
+            samp[0] *= self.articulation.loop_gain();
+            self.snoop.process(&mut samp);
+            self.last = samp[0];
+            left[i] = samp[0] * out;
+
+            let Some(right) = right.as_deref_mut() else {
+                continue;
+            };
+            let side = &mut self.side;
+            let mut loop_in = side.last;
+            if plucked {
+                let mut burst = [0.];
+                side.exciter.process(&mut burst);
+                loop_in += burst[0] * self.strength;
+            }
+            if bow > 0. {
+                loop_in += bow * self.bow_rng.next_f32();
+            }
+            let mut samp = [loop_in];
+            side.delay.process(&mut samp);
+            side.damping.process(&mut samp);
+            if flip {
+                samp[0] = -samp[0];
+            }
+            samp[0] *= self.articulation.loop_gain();
+            side.last = samp[0];
+            right[i] = samp[0] * out;
+        }
+    }
+}
+
+impl Filter for StringSynth {
+    fn process(&mut self, samples: &mut [f32]) {
+        self.play(samples, None);
+    }
+
+    /// With a spread, a loop for each side.
+    fn process_wide(&mut self, left: &mut [f32], right: &mut [f32]) {
+        if self.spread == 0. {
+            self.play(left, None);
+            right.copy_from_slice(left);
+        } else {
+            self.play(left, Some(right));
+        }
+    }
+
+    fn describe(&self) -> Node {
+        let parts: [&dyn Filter; 4] = [&*self.exciter, &self.delay, &self.damping, &self.env];
+        Node::with_children(
+            "StringSynth",
+            parts.into_iter().map(|p| p.describe()).collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Estimates the fundamental by finding the autocorrelation peak, with
+    /// parabolic interpolation for sub-sample accuracy.
+    fn measure_freq(samples: &[f32], min_lag: usize, max_lag: usize) -> f32 {
+        let corr = |lag: usize| -> f32 {
+            samples
+                .iter()
+                .zip(&samples[lag..])
+                .map(|(a, b)| a * b)
+                .sum()
+        };
+        let best = (min_lag..max_lag)
+            .max_by(|&a, &b| corr(a).total_cmp(&corr(b)))
+            .unwrap();
+        let (l, c, r) = (corr(best - 1), corr(best), corr(best + 1));
+        let offset = 0.5 * (l - r) / (l - 2. * c + r);
+        SAMPLING_FREQ as f32 / (best as f32 + offset)
+    }
+
+    /// Pretends to look ahead by delaying its input.
+    struct Lookahead(DelayLine);
+
+    impl Filter for Lookahead {
+        fn process(&mut self, samples: &mut [f32]) {
+            self.0.process(samples);
+        }
+
+        fn latency(&self) -> usize {
+            self.0.len() - 1
+        }
+    }
+
+    #[test]
+    fn test_split_join_latency() {
+        let mut sj = SplitJoin::new(vec![
+            Box::new(NoopFilter),
+            Box::new(Lookahead(DelayLine::new(4))),
+        ]);
+        assert_eq!(sj.latency(), 3);
+
+        let mut samples = [0.; 8];
+        samples[0] = 1.;
+        sj.process(&mut samples);
+        assert_eq!(samples, [0., 0., 0., 2., 0., 0., 0., 0.]);
+    }
+
+    fn sine(freq: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| (TAU * freq * i as f32 / SAMPLING_FREQ as f32).sin())
+            .collect()
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0f32, |m, s| m.max(s.abs()))
+    }
+
+    #[test]
+    fn test_fir_lowpass() {
+        let mut fir = FIR::new(100, |hz| if hz <= 1000. { 1. } else { 0. });
+        assert_eq!(fir.latency(), 100);
+
+        let mut low = sine(200., 4000);
+        fir.process(&mut low);
+        assert!((peak(&low[1000..]) - 1.).abs() < 0.1);
+
+        let mut fir = FIR::new(100, |hz| if hz <= 1000. { 1. } else { 0. });
+        let mut high = sine(5000., 4000);
+        fir.process(&mut high);
+        assert!(peak(&high[1000..]) < 0.1);
+
+        // silence in, silence out once the history has flushed
+        let mut silence = [0.; 256];
+        fir.process(&mut silence);
+        silence.fill(0.);
+        fir.process(&mut silence);
+        assert_eq!(peak(&silence), 0.);
+    }
+
+    #[test]
+    fn test_fir_window() {
+        let design = || FIR::new(30, |hz| if hz <= 2000. { 1. } else { 0. });
+        let stopband = |mut fir: FIR| {
+            let mut s = sine(8000., 4000);
+            fir.process(&mut s);
+            peak(&s[1000..])
+        };
+        let rect = stopband(design());
+        let blackman = stopband(design().with_window(Window::Blackman));
+        assert!(blackman < rect / 10., "{blackman} vs {rect}");
+    }
+
+    #[test]
+    fn test_fir_response() {
+        let response =
+            Response::parse("Freq(Hz), SPL(dB)\n# comment\n100, 0\n1000;-20\n\n10000 -40 0.5\n")
+                .unwrap();
+        assert_eq!(response.0, [(100., 0.), (1000., -20.), (10000., -40.)]);
+        assert!((response.gain(10.) - 1.).abs() < 1e-6);
+        assert!((response.gain(1000.) - 0.1).abs() < 1e-6);
+        // halfway between in log frequency is halfway in dB
+        assert!((response.gain(3162.3) - 10f32.powf(-1.5)).abs() < 1e-4);
+        assert!(Response::parse("100, 0\nloud, 3\n").is_err());
+
+        let mut fir = FIR::from_response(100, &response);
+        let mut s = sine(3162.3, 4000);
+        fir.process(&mut s);
+        let db = 20. * peak(&s[1000..]).log10();
+        // frequency sampling leaks a little from the louder low end
+        assert!((db + 30.).abs() < 2.5, "{db}dB");
+    }
+
+    #[test]
+    fn test_biquad() {
+        let gain = |mut filter: Biquad, freq: f32| {
+            let mut s = sine(freq, 8000);
+            filter.process(&mut s);
+            20. * peak(&s[4000..]).log10()
+        };
+        const Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+        assert!(gain(Biquad::low_pass(1000., Q), 100.).abs() < 0.5);
+        assert!((gain(Biquad::low_pass(1000., Q), 1000.) + 3.).abs() < 0.5);
+        assert!(gain(Biquad::low_pass(1000., Q), 10000.) < -35.);
+        assert!(gain(Biquad::high_pass(1000., Q), 100.) < -35.);
+        assert!(gain(Biquad::notch(1000., 2.), 1000.) < -30.);
+        assert!((gain(Biquad::peaking(1000., 1., 6.), 1000.) - 6.).abs() < 0.5);
+        assert!((gain(Biquad::low_shelf(500., Q, -6.), 50.) + 6.).abs() < 0.5);
+        assert!((gain(Biquad::high_shelf(500., Q, 6.), 10000.) - 6.).abs() < 0.5);
+    }
+
+    #[test]
+    fn test_dc_blocker() {
+        let mut s: Vec<f32> = sine(440., 8000).iter().map(|s| s * 0.5 + 0.5).collect();
+        DcBlocker::default().process(&mut s);
+        let tail = &s[4000..];
+        let mean = tail.iter().sum::<f32>() / tail.len() as f32;
+        assert!(mean.abs() < 0.01, "{mean}");
+        assert!(peak(tail) > 0.49);
+    }
+
+    #[test]
+    fn test_ladder() {
+        let gain = |mut filter: Ladder, freq: f32| {
+            let mut s: Vec<f32> = sine(freq, 8000).iter().map(|s| s * 0.1).collect();
+            filter.process(&mut s);
+            20. * (peak(&s[4000..]) / 0.1).log10()
+        };
+
+        assert!(gain(Ladder::new(1000., 0.), 100.).abs() < 1.);
+        // 24dB/octave
+        assert!(gain(Ladder::new(1000., 0.), 8000.) < -60.);
+        assert!(gain(Ladder::new(1000., 0.9), 1000.) > gain(Ladder::new(1000., 0.), 1000.) + 10.);
+        let compensated = |resonance| {
+            let mut ladder = Ladder::new(1000., resonance);
+            ladder.compensate = true;
+            ladder
+        };
+        // some resonance gets the bass back, a lot keeps the peak in check
+        assert!(gain(compensated(0.25), 100.).abs() < 1.);
+        assert!(gain(compensated(0.9), 1000.) < 1.);
+
+        let mut named = Named::new("ladder", Ladder::default());
+        assert!(named.set_param("ladder.resonance", 0.5));
+        assert!(!named.set_param("resonance", 0.5));
+        assert_eq!(named.inner.resonance, 0.5);
+    }
+
+    #[test]
+    fn test_svf() {
+        let gain = |mut filter: Svf, freq: f32| {
+            let mut s = sine(freq, 8000);
+            filter.process(&mut s);
+            20. * peak(&s[4000..]).log10()
+        };
+        const Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+        assert!(gain(Svf::new(SvfMode::LowPass, 1000., Q), 100.).abs() < 0.5);
+        assert!((gain(Svf::new(SvfMode::LowPass, 1000., Q), 1000.) + 3.).abs() < 0.5);
+        assert!(gain(Svf::new(SvfMode::LowPass, 1000., Q), 10000.) < -35.);
+        assert!(gain(Svf::new(SvfMode::HighPass, 1000., Q), 100.) < -35.);
+        assert!((gain(Svf::new(SvfMode::BandPass, 1000., 1.), 1000.)).abs() < 0.5);
+        for mode in [SvfMode::LowPass, SvfMode::BandPass, SvfMode::HighPass] {
+            let mut svf = Svf::new(mode, 1000., 10.);
+            assert!(gain(Svf::new(mode, 1000., 10.), 1000.) > 15.);
+            svf.compensate = true;
+            assert!(gain(svf, 1000.).abs() < 0.5);
+        }
+
+        // an octave up the keyboard moves the cutoff up an octave
+        let mut tracked = Svf::new(SvfMode::LowPass, 1000., Q);
+        tracked.key_tracking = 1.;
+        tracked.set_note(880.);
+        assert!((gain(tracked, 2000.) + 3.).abs() < 0.5);
+    }
+
+    #[test]
+    fn test_string_tuning() {
+        for freq in [220., 659.25, 1000., 1318.5] {
+            let mut string = StringSynth::new(100);
+            string.note_on(freq, 1.);
+            let mut out = vec![0.; SAMPLING_FREQ / 4];
+            string.process(&mut out);
+
+            let period = SAMPLING_FREQ as f32 / freq;
+            let got = measure_freq(
+                &out[2000..],
+                (period * 0.75) as usize,
+                (period * 1.25) as usize,
+            );
+            let cents = 1200. * (got / freq).log2();
+            assert!(cents.abs() < 3., "{freq}Hz came out as {got}Hz");
+        }
+    }
+
+    #[test]
+    fn test_string_drift() {
+        let voices = |seeds: [u32; 2]| {
+            seeds.map(|seed| {
+                let mut string = StringSynth::new(100);
+                string.scatter_drift(seed);
+                assert!(string.set_param("drift.depth", 4.));
+                string.note_on(440., 1.);
+                string
+            })
+        };
+        // two voices on the same note pull apart, but only by a few cents
+        let [mut a, mut b] = voices([0, 1]);
+        let mut apart = 0f32;
+        for _ in 0..SAMPLING_FREQ / 64 {
+            a.process(&mut [0.; 64]);
+            b.process(&mut [0.; 64]);
+            assert!(a.drift_cents.abs() <= 4.);
+            apart = apart.max((a.drift_cents - b.drift_cents).abs());
+        }
+        assert!(apart > 1., "{apart}");
+
+        // and the same seed moves in step
+        let [mut a, mut b] = voices([3, 3]);
+        a.process(&mut [0.; 4096]);
+        b.process(&mut [0.; 4096]);
+        assert_eq!(a.drift_cents, b.drift_cents);
+    }
+
+    #[test]
+    fn test_excitation_length() {
+        let mut string = StringSynth::new(100);
+        string.note_on(440., 1.);
+        assert_eq!(string.trigger_count, 50);
+
+        assert!(string.set_param("excitation", 10.));
+        string.note_on(440., 1.);
+        assert_eq!(string.trigger_count as usize, SAMPLING_FREQ / 100);
+    }
+
+    #[test]
+    fn test_string_velocity() {
+        assert_eq!(VelocityCurve::Linear.shape(0.5), 0.5);
+        assert!((VelocityCurve::Exponential.shape(0.5) - 0.1).abs() < 1e-6);
+        assert_eq!(VelocityCurve::Fixed.shape(0.), 1.);
+
+        let peak = |set: &[(&str, f32)], velocity| {
+            let mut string = StringSynth::new(100);
+            for &(name, value) in set {
+                assert!(string.set_param(name, value), "{name}");
+            }
+            string.note_on(440., velocity);
+            let count = string.trigger_count;
+            let mut out = vec![0.; 4096];
+            string.process(&mut out);
+            (out.iter().fold(0f32, |m, s| m.max(s.abs())), count)
+        };
+        let (loud, full) = peak(&[], 1.);
+        let (soft, count) = peak(&[], 0.5);
+        assert_eq!(count, full);
+        assert!((soft / loud - 0.5).abs() < 0.01, "{soft} {loud}");
+        let (fixed, _) = peak(&[("velocity.curve", 2.)], 0.5);
+        assert_eq!(fixed, loud);
+
+        // shorter, and turned down on the way out as well
+        let (_, count) = peak(&[("velocity.length", 1.)], 0.5);
+        assert_eq!(count, full / 2);
+        let (quieter, _) = peak(&[("velocity.gain", 1.)], 0.5);
+        assert!((quieter / loud - 0.25).abs() < 0.01, "{quieter} {loud}");
+    }
+
+    #[test]
+    fn test_string_bow() {
+        let rms = |samples: &[f32]| {
+            (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+        };
+        let play = |bow: f32| {
+            let mut string = StringSynth::new(500);
+            assert!(string.set_param("bow", bow));
+            string.note_on(220., 1.);
+            let mut out = vec![0.; 4 * SAMPLING_FREQ];
+            string.process(&mut out);
+            let held = rms(&out[3 * SAMPLING_FREQ..]);
+            string.note_off();
+            string.process(&mut out);
+            (held, string.bow_level.value())
+        };
+        let (plucked, _) = play(0.);
+        let (bowed, after) = play(1.);
+        let (gently, _) = play(0.25);
+        assert!(bowed > 4. * plucked, "{bowed} {plucked}");
+        assert!(gently < bowed && gently > plucked);
+        // letting go of the key stops the bow
+        assert_eq!(after, 0.);
+    }
+
+    #[test]
+    fn test_string_drum() {
+        // how much each period looks like the last, which noise doesn't
+        let pitched = |drum: f32| {
+            let mut string = StringSynth::new(500);
+            assert!(string.set_param("drum", drum));
+            string.note_on(220., 1.);
+            let mut out = vec![0.; SAMPLING_FREQ / 2];
+            string.process(&mut out);
+            let window = &out[SAMPLING_FREQ / 10..];
+            let lag = (SAMPLING_FREQ as f32 / 220.).round() as usize;
+            let corr: f32 = window.iter().zip(&window[lag..]).map(|(a, b)| a * b).sum();
+            corr / window.iter().map(|s| s * s).sum::<f32>()
+        };
+        let string = pitched(0.);
+        let drum = pitched(1.);
+        assert!(string > 0.9, "{string}");
+        assert!(drum.abs() < 0.3, "{drum}");
+    }
+
+    #[test]
+    fn test_string_spread() {
+        let wide = |spread: f32| {
+            let mut string = StringSynth::new(500);
+            string.set_exciter(&Noise::default(), 7);
+            assert!(string.set_param("spread", spread));
+            string.note_on(220., 1.);
+            let mut out = [vec![0.; SAMPLING_FREQ / 4], vec![0.; SAMPLING_FREQ / 4]];
+            let [left, right] = &mut out;
+            string.process_wide(left, right);
+            out
+        };
+        let [left, right] = wide(0.);
+        assert_eq!(left, right);
+
+        // each side rings at the note, a few cents either side of it, with
+        // noise of its own
+        let [left, right] = wide(6.);
+        let period = SAMPLING_FREQ as f32 / 220.;
+        let lags = ((period * 0.75) as usize, (period * 1.25) as usize);
+        let cents =
+            |side: &[f32]| 1200. * (measure_freq(&side[2000..], lags.0, lags.1) / 220.).log2();
+        assert!((cents(&left) - 3.).abs() < 2., "{}", cents(&left));
+        assert!((cents(&right) + 3.).abs() < 2., "{}", cents(&right));
+        // the first time round the loop is the noise going in
+        let first = |side: &[f32]| side[200..400].to_vec();
+        let (left, right) = (first(&left), first(&right));
+        let dot = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+        let corr = dot(&left, &right) / (dot(&left, &left) * dot(&right, &right)).sqrt();
+        assert!(corr.abs() < 0.5, "{corr}");
+    }
+
+    /// Something louder on the left than the right.
+    struct Leaning;
+
+    impl Filter for Leaning {
+        fn process(&mut self, samples: &mut [f32]) {
+            samples.fill(0.5);
+        }
+
+        fn process_wide(&mut self, left: &mut [f32], right: &mut [f32]) {
+            left.fill(1.);
+            right.fill(0.);
+        }
+    }
+
+    #[test]
+    fn test_synth_wide() {
+        // the effects get the middle and the side goes round them, as late
+        let mut synth = SynthBuilder::new(Leaning)
+            .chain(Lookahead(DelayLine::new(3)))
+            .build();
+        let (mut left, mut right) = ([0.; 4], [0.; 4]);
+        synth.process_wide(&mut left, &mut right);
+        assert_eq!(left, [0., 0., 1., 1.]);
+        assert_eq!(right, [0., 0., 0., 0.]);
+        synth.process_wide(&mut left, &mut right);
+        assert_eq!(left, [1.; 4]);
+        assert_eq!(right, [0.; 4]);
+    }
+
+    #[test]
+    fn test_rack_dry() {
+        let mut rack = Rack::default();
+        rack.add(Ladder::new(200., 0.));
+        let sine: Vec<f32> = (0..SAMPLING_FREQ / 10)
+            .map(|i| (TAU * 5000. * i as f32 / SAMPLING_FREQ as f32).sin())
+            .collect();
+        let peak = |s: &[f32]| s.iter().fold(0f32, |m, s| m.max(s.abs()));
+
+        let mut wet = sine.clone();
+        rack.process(&mut wet);
+        assert!(peak(&wet[wet.len() / 2..]) < 0.1);
+
+        assert!(rack.set_param("dry", 1.));
+        // it isn't part of the sound, so it isn't saved
+        assert_eq!(rack.get_param("dry"), None);
+        let mut dry = sine.clone();
+        rack.process(&mut dry);
+        let faded = DRY_FADE as usize;
+        assert!(peak(&dry[..faded / 4]) < 0.5);
+        assert_eq!(dry[faded..], sine[faded..]);
     }
 }
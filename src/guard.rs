@@ -0,0 +1,298 @@
+//! Keeps one misbehaving filter from taking down the whole audio callback,
+//! whether by panicking or by taking too long.
+
+use std::any::Any;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::filters::Filter;
+use crate::params::{ParamInfo, Params};
+use crate::snapshot::Node;
+use crate::stereo::StereoFilter;
+
+/// Something went wrong with a node of the graph while processing audio.
+#[derive(Clone, Debug)]
+pub struct EngineError {
+    pub node: String,
+    pub message: String,
+    /// Whether the node is skipped from now on.
+    pub bypassed: bool,
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.bypassed {
+            write!(f, "{} failed and was bypassed: {}", self.node, self.message)
+        } else {
+            write!(f, "{}: {}", self.node, self.message)
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
+
+/// Where engine failures get sent, usually the UI thread.
+pub type Reporter = Arc<dyn Fn(EngineError) + Send + Sync>;
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Runs `f` over `samples`, and if it panics, silences them and returns the
+/// panic message.
+pub fn catch(samples: &mut [f32], f: impl FnOnce(&mut [f32])) -> Result<(), String> {
+    panic::catch_unwind(AssertUnwindSafe(|| f(samples))).map_err(|payload| {
+        samples.fill(0.);
+        panic_message(&*payload)
+    })
+}
+
+/// [`catch`] for a pair of channels.
+pub fn catch_stereo(
+    left: &mut [f32],
+    right: &mut [f32],
+    f: impl FnOnce(&mut [f32], &mut [f32]),
+) -> Result<(), String> {
+    panic::catch_unwind(AssertUnwindSafe(|| f(left, right))).map_err(|payload| {
+        left.fill(0.);
+        right.fill(0.);
+        panic_message(&*payload)
+    })
+}
+
+/// How long a guarded node has been taking, shared with the
+/// [`Watchdog`](crate::watchdog::Watchdog).
+#[derive(Debug, Default)]
+pub struct Load {
+    /// smoothed over the last few blocks
+    nanos: AtomicU64,
+    /// set to have the node skipped from the next block on
+    shed: AtomicBool,
+}
+
+impl Load {
+    /// Nanoseconds a block, lately.
+    pub fn nanos(&self) -> u64 {
+        self.nanos.load(Ordering::Relaxed)
+    }
+
+    fn measure(&self, nanos: u64) {
+        let old = self.nanos();
+        self.nanos.store((old * 7 + nanos) / 8, Ordering::Relaxed);
+    }
+
+    pub fn is_shed(&self) -> bool {
+        self.shed.load(Ordering::Relaxed)
+    }
+
+    pub fn shed(&self) {
+        self.shed.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Wraps a filter so that if it ever panics, that block comes out silent and
+/// from then on the filter is skipped, passing its input straight through.
+/// It also times the filter, and can be told to skip it for taking too long.
+pub struct Guarded<F> {
+    inner: F,
+    bypassed: bool,
+    report: Reporter,
+    load: Arc<Load>,
+}
+
+impl<F> Guarded<F> {
+    pub fn new(inner: F, report: Reporter) -> Self {
+        Self {
+            inner,
+            bypassed: false,
+            report,
+            load: Arc::default(),
+        }
+    }
+
+    pub fn is_bypassed(&self) -> bool {
+        self.bypassed || self.load.is_shed()
+    }
+
+    pub fn load(&self) -> Arc<Load> {
+        self.load.clone()
+    }
+
+    fn report(&mut self, node: String, message: String) {
+        self.bypassed = true;
+        (self.report)(EngineError {
+            node,
+            message,
+            bypassed: true,
+        });
+    }
+}
+
+/// Makes [`Guarded`] nodes that all report to the same place, keeping track
+/// of them for the watchdog.
+pub struct Guards {
+    pub report: Reporter,
+    /// Each node's name and load.
+    pub loads: Vec<(String, Arc<Load>)>,
+}
+
+impl Guards {
+    pub fn new(report: Reporter) -> Self {
+        Self {
+            report,
+            loads: Vec::new(),
+        }
+    }
+
+    pub fn guard<F>(&mut self, name: &str, inner: F) -> Guarded<F> {
+        let guarded = Guarded::new(inner, self.report.clone());
+        self.loads.push((name.to_string(), guarded.load()));
+        guarded
+    }
+}
+
+impl<F> Deref for Guarded<F> {
+    type Target = F;
+    fn deref(&self) -> &F {
+        &self.inner
+    }
+}
+
+impl<F> DerefMut for Guarded<F> {
+    fn deref_mut(&mut self) -> &mut F {
+        &mut self.inner
+    }
+}
+
+impl<F: Filter> Filter for Guarded<F> {
+    fn process(&mut self, samples: &mut [f32]) {
+        if self.is_bypassed() {
+            return;
+        }
+        let start = Instant::now();
+        if let Err(message) = catch(samples, |s| self.inner.process(s)) {
+            self.report(self.inner.describe().name, message);
+        }
+        self.load.measure(start.elapsed().as_nanos() as u64);
+    }
+
+    fn latency(&self) -> usize {
+        if self.is_bypassed() {
+            0
+        } else {
+            self.inner.latency()
+        }
+    }
+
+    fn describe(&self) -> Node {
+        let mut node = self.inner.describe();
+        if self.is_bypassed() {
+            node.name += " (bypassed)";
+        }
+        node
+    }
+
+    fn process_wide(&mut self, left: &mut [f32], right: &mut [f32]) {
+        if self.is_bypassed() {
+            return;
+        }
+        let start = Instant::now();
+        if let Err(message) = catch_stereo(left, right, |l, r| self.inner.process_wide(l, r)) {
+            self.report(self.inner.describe().name, message);
+        }
+        self.load.measure(start.elapsed().as_nanos() as u64);
+    }
+}
+
+impl<F: StereoFilter> StereoFilter for Guarded<F> {
+    fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) {
+        if self.is_bypassed() {
+            return;
+        }
+        let start = Instant::now();
+        if let Err(message) = catch_stereo(left, right, |l, r| self.inner.process_stereo(l, r)) {
+            self.report(self.inner.describe().name, message);
+        }
+        self.load.measure(start.elapsed().as_nanos() as u64);
+    }
+
+    fn latency(&self) -> usize {
+        if self.is_bypassed() {
+            0
+        } else {
+            self.inner.latency()
+        }
+    }
+
+    fn describe(&self) -> Node {
+        let mut node = self.inner.describe();
+        if self.is_bypassed() {
+            node.name += " (bypassed)";
+        }
+        node
+    }
+}
+
+impl<F: Params> Params for Guarded<F> {
+    fn params(&self) -> Vec<ParamInfo> {
+        self.inner.params()
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        self.inner.get_param(name)
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        self.inner.set_param(name, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    struct Explodes;
+
+    impl Filter for Explodes {
+        fn process(&mut self, _samples: &mut [f32]) {
+            panic!("kaboom");
+        }
+    }
+
+    #[test]
+    fn test_guarded_bypass() {
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let report: Reporter = {
+            let errors = errors.clone();
+            Arc::new(move |e| errors.lock().unwrap().push(e))
+        };
+        let mut guarded = Guarded::new(Explodes, report);
+
+        let mut samples = [1.; 4];
+        guarded.process(&mut samples);
+        assert_eq!(samples, [0.; 4]);
+        assert!(guarded.is_bypassed());
+
+        samples.fill(1.);
+        guarded.process(&mut samples);
+        assert_eq!(samples, [1.; 4]);
+
+        let errors = errors.lock().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].node, "Explodes");
+        assert_eq!(errors[0].message, "kaboom");
+        assert_eq!(guarded.describe().name, "Explodes (bypassed)");
+    }
+}
@@ -0,0 +1,239 @@
+//! Playing more than the keys pressed: each note can be pulled onto a
+//! scale, and turned into a chord, before it gets to the voices. With a
+//! scale as well, the chords come out in it, so a major triad on the
+//! second degree of a major key plays minor.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Semitones above each note to play along with it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chord(pub Vec<i32>);
+
+/// A name like "maj", "min7" or "sus4", or semitones like "0,4,7".
+impl FromStr for Chord {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        let intervals: &[i32] = match value {
+            "maj" => &[0, 4, 7],
+            "min" => &[0, 3, 7],
+            "dim" => &[0, 3, 6],
+            "aug" => &[0, 4, 8],
+            "sus2" => &[0, 2, 7],
+            "sus4" => &[0, 5, 7],
+            "7" => &[0, 4, 7, 10],
+            "maj7" => &[0, 4, 7, 11],
+            "min7" => &[0, 3, 7, 10],
+            "5" => &[0, 7],
+            "octave" => &[0, 12],
+            _ => {
+                return value
+                    .split(',')
+                    .map(|n| n.trim().parse::<i32>().ok().filter(|n| n.abs() <= 48))
+                    .collect::<Option<Vec<_>>>()
+                    .filter(|intervals| !intervals.is_empty())
+                    .map(Chord)
+                    .ok_or_else(|| format!("unknown chord {value:?}"))
+            }
+        };
+        Ok(Chord(intervals.to_vec()))
+    }
+}
+
+const NAMES: [&str; 7] = ["C", "D", "E", "F", "G", "A", "B"];
+const NATURALS: [u8; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+/// The pitch classes in a key, counting from C.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Scale {
+    /// sorted, each in 0..12
+    classes: Vec<u8>,
+}
+
+impl Scale {
+    /// The nearest note to `note` that's in the scale, going down when two
+    /// are as near.
+    pub fn quantize(&self, note: i32) -> i32 {
+        (0..12)
+            .flat_map(|d| [note - d, note + d])
+            .find(|n| self.classes.contains(&(n.rem_euclid(12) as u8)))
+            .unwrap_or(note)
+    }
+}
+
+/// `<key>:<scale>`, like "C:major" or "F#:minor_pentatonic".
+impl FromStr for Scale {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        let err = || format!("expected <key>:<scale> like \"C:major\", got {value:?}");
+        let (key, name) = value.split_once(':').ok_or_else(err)?;
+        let mut chars = key.trim().chars();
+        let letter = chars
+            .next()
+            .ok_or_else(err)?
+            .to_ascii_uppercase()
+            .to_string();
+        let mut root = NAMES
+            .iter()
+            .position(|&n| n == letter)
+            .map(|i| NATURALS[i] as i32)
+            .ok_or_else(|| format!("unknown key {key:?}"))?;
+        for accidental in chars {
+            root += match accidental {
+                '#' => 1,
+                'b' => -1,
+                _ => return Err(format!("unknown key {key:?}")),
+            };
+        }
+        let steps: &[u8] = match name.trim() {
+            "major" | "ionian" => &[0, 2, 4, 5, 7, 9, 11],
+            "minor" | "aeolian" => &[0, 2, 3, 5, 7, 8, 10],
+            "dorian" => &[0, 2, 3, 5, 7, 9, 10],
+            "phrygian" => &[0, 1, 3, 5, 7, 8, 10],
+            "lydian" => &[0, 2, 4, 6, 7, 9, 11],
+            "mixolydian" => &[0, 2, 4, 5, 7, 9, 10],
+            "locrian" => &[0, 1, 3, 5, 6, 8, 10],
+            "harmonic_minor" => &[0, 2, 3, 5, 7, 8, 11],
+            "pentatonic" => &[0, 2, 4, 7, 9],
+            "minor_pentatonic" => &[0, 3, 5, 7, 10],
+            "blues" => &[0, 3, 5, 6, 7, 10],
+            other => return Err(format!("unknown scale {other:?}")),
+        };
+        let mut classes: Vec<u8> = steps
+            .iter()
+            .map(|&step| (root + step as i32).rem_euclid(12) as u8)
+            .collect();
+        classes.sort();
+        Ok(Scale { classes })
+    }
+}
+
+#[derive(Default)]
+pub struct Harmonizer {
+    pub chord: Option<Chord>,
+    pub scale: Option<Scale>,
+    /// what each key held down is playing, to let go of when it comes up
+    held: HashMap<u8, Vec<u8>>,
+    /// how many keys held down are playing each note, since two chords can
+    /// share one and it should only stop when neither plays it
+    sounding: HashMap<u8, usize>,
+}
+
+impl Harmonizer {
+    pub fn new(chord: Option<Chord>, scale: Option<Scale>) -> Self {
+        Self {
+            chord,
+            scale,
+            held: HashMap::new(),
+            sounding: HashMap::new(),
+        }
+    }
+
+    /// Whether it does anything at all.
+    pub fn is_active(&self) -> bool {
+        self.chord.is_some() || self.scale.is_some()
+    }
+
+    /// The notes to play for `note`, lowest first, leaving out any that
+    /// land off the end of the keyboard.
+    pub fn notes(&self, note: i32) -> Vec<i32> {
+        let quantize = |n| self.scale.as_ref().map_or(n, |scale| scale.quantize(n));
+        let root = quantize(note);
+        let mut notes: Vec<i32> = match &self.chord {
+            Some(chord) => chord.0.iter().map(|i| quantize(root + i)).collect(),
+            None => vec![root],
+        };
+        notes.sort();
+        notes.dedup();
+        notes.retain(|n| (0..=127).contains(n));
+        notes
+    }
+
+    /// The notes to start for a key going down.
+    pub fn note_on(&mut self, note: u8) -> Vec<u8> {
+        let notes: Vec<u8> = self
+            .notes(note as i32)
+            .into_iter()
+            .map(|n| n as u8)
+            .collect();
+        // the same key again without coming up first lets go of what it was
+        // playing
+        for old in self.held.insert(note, notes.clone()).unwrap_or_default() {
+            self.let_go(old);
+        }
+        for &n in &notes {
+            *self.sounding.entry(n).or_default() += 1;
+        }
+        notes
+    }
+
+    /// The notes to stop for a key coming up, leaving out any that another
+    /// key held down is still playing.
+    pub fn note_off(&mut self, note: u8) -> Vec<u8> {
+        match self.held.remove(&note) {
+            Some(notes) => notes.into_iter().filter(|&n| self.let_go(n)).collect(),
+            None if self.sounding.contains_key(&note) => Vec::new(),
+            None => vec![note],
+        }
+    }
+
+    /// Counts a key playing `note` coming up, returning whether nothing
+    /// plays it any more.
+    fn let_go(&mut self, note: u8) -> bool {
+        let Some(count) = self.sounding.get_mut(&note) else {
+            return true;
+        };
+        *count -= 1;
+        if *count == 0 {
+            self.sounding.remove(&note);
+            return true;
+        }
+        false
+    }
+
+    /// The notes a key that's down is playing.
+    pub fn held(&self, note: u8) -> Vec<u8> {
+        self.held.get(&note).cloned().unwrap_or_else(|| vec![note])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_harmonizer() {
+        assert_eq!("min".parse(), Ok(Chord(vec![0, 3, 7])));
+        assert_eq!("0, -12,7".parse(), Ok(Chord(vec![0, -12, 7])));
+        assert!("0,x".parse::<Chord>().is_err());
+        assert!("Cmajor".parse::<Scale>().is_err());
+        assert!("H:major".parse::<Scale>().is_err());
+
+        let scale: Scale = "C:major".parse().unwrap();
+        assert_eq!(scale.quantize(61), 60);
+        assert_eq!(scale.quantize(66), 65);
+        assert_eq!(scale.quantize(-1), -1);
+        let f_sharp: Scale = "F#:pentatonic".parse().unwrap();
+        assert_eq!(f_sharp.classes, [1, 3, 6, 8, 10]);
+
+        let mut harmonizer = Harmonizer::new(Some("maj".parse().unwrap()), Some(scale));
+        // D minor on the second degree, and the C# pulled down to C first
+        assert_eq!(harmonizer.notes(62), [62, 65, 69]);
+        assert_eq!(harmonizer.note_on(61), [60, 64, 67]);
+        assert_eq!(harmonizer.held(61), [60, 64, 67]);
+        harmonizer.scale = None;
+        assert_eq!(harmonizer.note_off(61), [60, 64, 67]);
+        assert_eq!(harmonizer.note_off(61), [61]);
+        assert_eq!(harmonizer.notes(125), [125]);
+
+        // C and E major both play the E, which stops once neither does
+        let mut harmonizer = Harmonizer::new(Some("maj".parse().unwrap()), None);
+        assert_eq!(harmonizer.note_on(60), [60, 64, 67]);
+        assert_eq!(harmonizer.note_on(64), [64, 68, 71]);
+        assert_eq!(harmonizer.note_off(60), [60, 67]);
+        assert_eq!(harmonizer.note_off(64), [64, 68, 71]);
+        assert_eq!(harmonizer.note_on(67), [67, 71, 74]);
+        assert_eq!(harmonizer.note_off(71), []);
+        assert_eq!(harmonizer.note_off(67), [67, 71, 74]);
+    }
+}
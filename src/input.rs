@@ -0,0 +1,149 @@
+//! Sound coming in from a capture device, for plucking strings with. The
+//! capture callback writes it into one ring for the whole program, and the
+//! voices read the latest of it from there without locking, so a patch can
+//! pick it as the exciter whenever it likes.
+
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::AudioSubsystem;
+
+use crate::filters::{Exciter, Filter, SAMPLING_FREQ};
+
+/// How much of the input the ring keeps, in samples. A power of two.
+const RING_LEN: usize = 1 << 15;
+/// How far behind the input a pluck starts playing it, so it doesn't get
+/// ahead of what's been captured when the callbacks don't line up.
+const LAG: usize = 1024;
+
+/// The latest input, as f32 bits so it can be shared without a lock.
+pub struct Ring {
+    samples: Box<[AtomicU32]>,
+    /// count of samples ever written
+    written: AtomicUsize,
+}
+
+impl Ring {
+    fn new() -> Self {
+        Self {
+            samples: (0..RING_LEN).map(|_| AtomicU32::new(0)).collect(),
+            written: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn write(&self, samples: &[f32]) {
+        let written = self.written.load(Ordering::Relaxed);
+        for (i, s) in samples.iter().enumerate() {
+            let idx = written.wrapping_add(i) & (RING_LEN - 1);
+            self.samples[idx].store(s.to_bits(), Ordering::Relaxed);
+        }
+        self.written
+            .store(written.wrapping_add(samples.len()), Ordering::Release);
+    }
+
+    pub fn written(&self) -> usize {
+        self.written.load(Ordering::Acquire)
+    }
+
+    /// The sample written `at`, or nothing if it hasn't been yet or has
+    /// been written over since.
+    pub fn read(&self, at: usize) -> Option<f32> {
+        let written = self.written();
+        let behind = written.wrapping_sub(at);
+        // leaving a block's worth of room for the writer coming round
+        if behind == 0 || behind > RING_LEN - LAG {
+            return None;
+        }
+        Some(f32::from_bits(
+            self.samples[at & (RING_LEN - 1)].load(Ordering::Relaxed),
+        ))
+    }
+}
+
+/// The one ring the capture device writes into.
+pub fn ring() -> &'static Ring {
+    static RING: OnceLock<Ring> = OnceLock::new();
+    RING.get_or_init(Ring::new)
+}
+
+pub struct Capture;
+
+impl AudioCallback for Capture {
+    type Channel = f32;
+
+    fn callback(&mut self, samples: &mut [f32]) {
+        ring().write(samples);
+    }
+}
+
+/// Starts capturing from the device `name` picks by the start of its name,
+/// or the default one, in mono. It keeps going until the device is dropped.
+pub fn open(audio: &AudioSubsystem, name: Option<&str>) -> Result<AudioDevice<Capture>, String> {
+    let device = match name {
+        Some(name) => {
+            let count = audio.num_audio_capture_devices().unwrap_or(0);
+            let found = (0..count)
+                .filter_map(|idx| audio.audio_capture_device_name(idx).ok())
+                .find(|device| device.starts_with(name));
+            Some(found.ok_or_else(|| format!("no input device {name:?}"))?)
+        }
+        None => None,
+    };
+    let spec = AudioSpecDesired {
+        freq: Some(SAMPLING_FREQ as i32),
+        channels: Some(1),
+        samples: Some(256),
+    };
+    let capture = audio.open_capture(device.as_deref(), &spec, |_| Capture)?;
+    capture.resume();
+    Ok(capture)
+}
+
+/// Plucks with whatever's coming in, a little behind it, starting over on
+/// each note. Silent with nothing capturing.
+#[derive(Clone, Default)]
+pub struct LiveInput {
+    at: usize,
+}
+
+impl Filter for LiveInput {
+    fn process(&mut self, samples: &mut [f32]) {
+        let ring = ring();
+        for s in samples.iter_mut() {
+            *s = ring.read(self.at).unwrap_or(0.);
+            self.at = self.at.wrapping_add(1);
+        }
+    }
+}
+
+impl Exciter for LiveInput {
+    fn restart(&mut self) {
+        self.at = ring().written().wrapping_sub(LAG);
+    }
+
+    fn boxed_clone(&self) -> Box<dyn Exciter> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring() {
+        let ring = Ring::new();
+        assert_eq!(ring.read(0), None);
+        let input: Vec<f32> = (0..2 * LAG).map(|i| i as f32).collect();
+        ring.write(&input);
+        assert_eq!(ring.read(5), Some(5.));
+        // not written yet
+        assert_eq!(ring.read(2 * LAG), None);
+        // written over
+        for _ in 0..RING_LEN / LAG {
+            ring.write(&input[..LAG]);
+        }
+        assert_eq!(ring.read(5), None);
+    }
+}
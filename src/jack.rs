@@ -0,0 +1,276 @@
+//! Output through a JACK server, as ports other programs can be patched
+//! into, instead of whatever SDL picks. Only built with the `jack` feature,
+//! since it links against libjack.
+
+use std::ffi::{c_char, c_int, c_ulong, c_void, CStr};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::audio_thread::AudioEvent;
+use crate::clock::{EngineClock, Stamp};
+use crate::filters::SAMPLING_FREQ;
+use crate::midi::{parse_midi, MidiEvent};
+use crate::queue;
+use crate::spsc::{self, Consumer, Producer};
+
+#[repr(C)]
+struct RawClient {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct RawPort {
+    _private: [u8; 0],
+}
+
+type Nframes = u32;
+
+#[repr(C)]
+struct RawMidiEvent {
+    /// frames into the current cycle
+    time: Nframes,
+    size: usize,
+    buffer: *mut u8,
+}
+
+const PORT_IS_INPUT: c_ulong = 0x1;
+const PORT_IS_OUTPUT: c_ulong = 0x2;
+const NO_START_SERVER: c_int = 0x1;
+const AUDIO_TYPE: &CStr = c"32 bit float mono audio";
+const MIDI_TYPE: &CStr = c"8 bit raw midi";
+
+/// How much MIDI can wait for the forwarding thread, which is far more than
+/// comes in over a few milliseconds.
+const MIDI_QUEUE_LEN: usize = 1024;
+/// How often the forwarding thread passes MIDI on to the control loop.
+const FORWARD_EVERY: Duration = Duration::from_millis(1);
+
+#[link(name = "jack")]
+extern "C" {
+    fn jack_client_open(
+        name: *const c_char,
+        options: c_int,
+        status: *mut c_int,
+        ...
+    ) -> *mut RawClient;
+    fn jack_client_close(client: *mut RawClient) -> c_int;
+    fn jack_get_sample_rate(client: *mut RawClient) -> Nframes;
+    fn jack_port_register(
+        client: *mut RawClient,
+        name: *const c_char,
+        kind: *const c_char,
+        flags: c_ulong,
+        buffer_size: c_ulong,
+    ) -> *mut RawPort;
+    fn jack_set_process_callback(
+        client: *mut RawClient,
+        callback: extern "C" fn(Nframes, *mut c_void) -> c_int,
+        arg: *mut c_void,
+    ) -> c_int;
+    fn jack_activate(client: *mut RawClient) -> c_int;
+    fn jack_deactivate(client: *mut RawClient) -> c_int;
+    fn jack_port_get_buffer(port: *mut RawPort, frames: Nframes) -> *mut c_void;
+    fn jack_midi_get_event_count(buffer: *mut c_void) -> u32;
+    fn jack_midi_event_get(event: *mut RawMidiEvent, buffer: *mut c_void, index: u32) -> c_int;
+}
+
+type Render = Box<dyn FnMut(&mut [f32], &mut [f32]) + Send>;
+
+/// What the process callback needs, which JACK hands back to it.
+struct State {
+    render: Render,
+    left: *mut RawPort,
+    right: *mut RawPort,
+    midi_in: *mut RawPort,
+    /// MIDI for the forwarding thread, since the control loop's queue has a
+    /// lock that the process callback mustn't wait on
+    midi: Option<Producer<MidiEvent>>,
+    /// MIDI lost to the forwarding thread falling behind
+    lost: Arc<AtomicU64>,
+    clock: EngineClock,
+}
+
+impl State {
+    /// Passes on this cycle's MIDI, timed to play the same distance into
+    /// the next block as it is into this one, like events from other MIDI
+    /// inputs.
+    fn forward_midi(&mut self, frames: Nframes, now: Stamp) {
+        let Some(send) = &mut self.midi else {
+            return;
+        };
+        // SAFETY: the port is ours and registered, and JACK owns the buffer
+        // for the rest of this cycle
+        let buffer = unsafe { jack_port_get_buffer(self.midi_in, frames) };
+        for idx in 0..unsafe { jack_midi_get_event_count(buffer) } {
+            let mut event = RawMidiEvent {
+                time: 0,
+                size: 0,
+                buffer: ptr::null_mut(),
+            };
+            if unsafe { jack_midi_event_get(&mut event, buffer, idx) } != 0 || event.size == 0 {
+                continue;
+            }
+            // SAFETY: JACK says there are `size` bytes there, and
+            // parse_midi won't read past them
+            let data = unsafe { std::slice::from_raw_parts(event.buffer, event.size) };
+            if let Some(parsed) = parse_midi(Stamp(now.0 + event.time as u64), data) {
+                if send.push(parsed).is_err() {
+                    self.lost.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+extern "C" fn process(frames: Nframes, arg: *mut c_void) -> c_int {
+    // SAFETY: `arg` is the State that `open` leaked, and only this callback
+    // touches it until the client is closed
+    let state = unsafe { &mut *(arg as *mut State) };
+    let now = state.clock.now();
+    state.forward_midi(frames, now);
+    // SAFETY: output port buffers hold `frames` floats for this cycle
+    let (left, right) = unsafe {
+        (
+            std::slice::from_raw_parts_mut(
+                jack_port_get_buffer(state.left, frames) as *mut f32,
+                frames as usize,
+            ),
+            std::slice::from_raw_parts_mut(
+                jack_port_get_buffer(state.right, frames) as *mut f32,
+                frames as usize,
+            ),
+        )
+    };
+    // unwinding out of here would abort, and render already catches
+    // anything the engine throws
+    (state.render)(left, right);
+    0
+}
+
+/// Hands MIDI from the process callback on to the control loop until
+/// `running` goes false or the control loop does.
+fn forward(
+    mut midi: Consumer<MidiEvent>,
+    send: queue::Sender<AudioEvent>,
+    lost: Arc<AtomicU64>,
+    running: Arc<AtomicBool>,
+) {
+    while running.load(Ordering::Relaxed) {
+        while let Some(event) = midi.pop() {
+            if send.send(AudioEvent::Midi(event)).is_err() {
+                return;
+            }
+        }
+        let lost = lost.swap(0, Ordering::Relaxed);
+        if lost > 0 {
+            println!("jack: {lost} MIDI events lost waiting to be passed on");
+        }
+        thread::sleep(FORWARD_EVERY);
+    }
+}
+
+/// A running JACK client. Dropping it disconnects from the server.
+pub struct Client {
+    client: *mut RawClient,
+    state: *mut State,
+    forwarder: Option<(Arc<AtomicBool>, JoinHandle<()>)>,
+}
+
+// SAFETY: the handles are only used to shut down, which JACK allows from
+// any thread
+unsafe impl Send for Client {}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // SAFETY: deactivating waits for the callback to finish for good,
+        // so the state can go once it has
+        unsafe {
+            jack_deactivate(self.client);
+            jack_client_close(self.client);
+            drop(Box::from_raw(self.state));
+        }
+        if let Some((running, forwarder)) = self.forwarder.take() {
+            running.store(false, Ordering::Relaxed);
+            let _ = forwarder.join();
+        }
+    }
+}
+
+/// Connects to the JACK server as "synthtoy", with `render` filling the
+/// "out_l" and "out_r" ports every cycle. With `midi`, anything arriving on
+/// a "midi_in" port gets sent there, stamped on `clock`, by a thread of its
+/// own.
+pub fn open(
+    render: impl FnMut(&mut [f32], &mut [f32]) + Send + 'static,
+    midi: Option<queue::Sender<AudioEvent>>,
+    clock: EngineClock,
+) -> Result<Client, String> {
+    let mut status = 0;
+    // SAFETY: plain FFI calls with valid strings; every failure is checked
+    unsafe {
+        let client = jack_client_open(c"synthtoy".as_ptr(), NO_START_SERVER, &mut status);
+        if client.is_null() {
+            return Err(format!(
+                "couldn't connect to the server (status {status:#x})"
+            ));
+        }
+        let fail = |message: String| {
+            jack_client_close(client);
+            Err(message)
+        };
+
+        let rate = jack_get_sample_rate(client);
+        if rate as usize != SAMPLING_FREQ {
+            return fail(format!(
+                "the server runs at {rate}Hz, but synthtoy only runs at {SAMPLING_FREQ}Hz"
+            ));
+        }
+        let register = |name: &CStr, kind: &CStr, flags| {
+            jack_port_register(client, name.as_ptr(), kind.as_ptr(), flags, 0)
+        };
+        let left = register(c"out_l", AUDIO_TYPE, PORT_IS_OUTPUT);
+        let right = register(c"out_r", AUDIO_TYPE, PORT_IS_OUTPUT);
+        let midi_in = match midi {
+            Some(_) => register(c"midi_in", MIDI_TYPE, PORT_IS_INPUT),
+            None => ptr::null_mut(),
+        };
+        if left.is_null() || right.is_null() || (midi.is_some() && midi_in.is_null()) {
+            return fail("couldn't register ports".to_string());
+        }
+
+        let lost = Arc::new(AtomicU64::new(0));
+        let (producer, forwarder) = match midi {
+            Some(send) => {
+                let (producer, consumer) = spsc::channel(MIDI_QUEUE_LEN);
+                let running = Arc::new(AtomicBool::new(true));
+                let (lost, still) = (lost.clone(), running.clone());
+                let forwarder = thread::spawn(move || forward(consumer, send, lost, still));
+                (Some(producer), Some((running, forwarder)))
+            }
+            None => (None, None),
+        };
+        let state = Box::into_raw(Box::new(State {
+            render: Box::new(render),
+            left,
+            right,
+            midi_in,
+            midi: producer,
+            lost,
+            clock,
+        }));
+        let client = Client {
+            client,
+            state,
+            forwarder,
+        };
+        if jack_set_process_callback(client.client, process, state as *mut c_void) != 0
+            || jack_activate(client.client) != 0
+        {
+            return Err("couldn't start processing".to_string());
+        }
+        Ok(client)
+    }
+}
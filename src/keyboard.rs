@@ -0,0 +1,128 @@
+//! Playing from the computer keyboard, which can't sense how hard keys are
+//! hit, so velocity has to come from somewhere else.
+
+use std::time::Duration;
+
+use sdl2::keyboard::Mod;
+
+use crate::clock::Stamp;
+
+/// Holds shorter than this play at full velocity.
+const TAP: Duration = Duration::from_millis(30);
+/// Holds longer than this play at the lowest velocity.
+const LONG_HOLD: Duration = Duration::from_secs(1);
+const LOWEST: f32 = 20.;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VelocityMode {
+    /// Every note gets this MIDI velocity.
+    Fixed(u8),
+    /// Each note is as loud as the previous key was short: quick taps play
+    /// loud, long holds play soft.
+    Held,
+}
+
+/// A MIDI velocity (1..=127), or `held`.
+impl std::str::FromStr for VelocityMode {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        if value == "held" {
+            return Ok(VelocityMode::Held);
+        }
+        value
+            .parse::<u8>()
+            .ok()
+            .filter(|v| (1..=127).contains(v))
+            .map(VelocityMode::Fixed)
+            .ok_or_else(|| format!("expected a velocity 1..=127 or \"held\", got {value:?}"))
+    }
+}
+
+pub struct KeyVelocity {
+    mode: VelocityMode,
+    pressed: Option<Stamp>,
+    next: f32,
+}
+
+impl KeyVelocity {
+    pub fn new(mode: VelocityMode) -> Self {
+        Self {
+            mode,
+            pressed: None,
+            next: 127.,
+        }
+    }
+
+    /// A key went down; returns the velocity (0..=1) to play it at.
+    pub fn key_down(&mut self, now: Stamp) -> f32 {
+        self.pressed = Some(now);
+        match self.mode {
+            VelocityMode::Fixed(v) => v as f32 / 127.,
+            VelocityMode::Held => self.next / 127.,
+        }
+    }
+
+    pub fn key_up(&mut self, now: Stamp) {
+        let Some(pressed) = self.pressed.take() else {
+            return;
+        };
+        let held = now.saturating_duration_since(pressed).clamp(TAP, LONG_HOLD);
+        // interpolate over log time, since it's taps vs holds that matter
+        let t = (held.as_secs_f32() / TAP.as_secs_f32()).ln()
+            / (LONG_HOLD.as_secs_f32() / TAP.as_secs_f32()).ln();
+        self.next = 127. + (LOWEST - 127.) * t;
+    }
+}
+
+/// Changes a note for the modifier keys held when it's played: Shift puts
+/// it an octave up, Ctrl an octave down, and Alt plays it half as loud.
+pub fn modify(keymod: Mod, freq: f32, velocity: f32) -> (f32, f32) {
+    let mut freq = freq;
+    let mut velocity = velocity;
+    if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+        freq *= 2.;
+    }
+    if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) {
+        freq /= 2.;
+    }
+    if keymod.intersects(Mod::LALTMOD | Mod::RALTMOD) {
+        velocity = (velocity / 2.).max(1. / 127.);
+    }
+    (freq, velocity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_velocity() {
+        assert_eq!("held".parse(), Ok(VelocityMode::Held));
+        assert_eq!("64".parse(), Ok(VelocityMode::Fixed(64)));
+        assert!("0".parse::<VelocityMode>().is_err());
+        assert!("200".parse::<VelocityMode>().is_err());
+
+        let t0 = Stamp::default();
+        let ms = |n| t0 + Duration::from_millis(n);
+        let mut fixed = KeyVelocity::new(VelocityMode::Fixed(64));
+        assert_eq!(fixed.key_down(t0), 64. / 127.);
+        fixed.key_up(ms(2000));
+        assert_eq!(fixed.key_down(ms(2001)), 64. / 127.);
+
+        let mut held = KeyVelocity::new(VelocityMode::Held);
+        assert_eq!(held.key_down(t0), 1.);
+        held.key_up(ms(5000));
+        assert_eq!(held.key_down(ms(5001)), LOWEST / 127.);
+        held.key_up(ms(5011));
+        assert_eq!(held.key_down(ms(5100)), 1.);
+        held.key_up(ms(5100 + 173));
+        let mid = held.key_down(ms(6000));
+        assert!(mid > 0.4 && mid < 0.7, "{mid}");
+
+        assert_eq!(modify(Mod::NOMOD, 440., 1.), (440., 1.));
+        assert_eq!(modify(Mod::RSHIFTMOD, 440., 1.), (880., 1.));
+        assert_eq!(modify(Mod::LCTRLMOD | Mod::LALTMOD, 440., 1.), (220., 0.5));
+        // both octave keys cancel out
+        assert_eq!(modify(Mod::LSHIFTMOD | Mod::RCTRLMOD, 440., 1.), (440., 1.));
+    }
+}
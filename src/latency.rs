@@ -0,0 +1,255 @@
+//! Measuring how long sound takes to go out of the audio interface and come
+//! back in, for playing through the computer live: clicks go out of the
+//! output and are listened for on an input wired to it.
+
+use std::time::{Duration, Instant};
+
+use sdl2::audio::{AudioCallback, AudioSpec, AudioSpecDesired};
+use sdl2::AudioSubsystem;
+
+use crate::filters::SAMPLING_FREQ;
+use crate::outputs::OutputMap;
+
+/// Frames between clicks, long enough for the last one to have died away.
+const CLICK_PERIOD: usize = SAMPLING_FREQ / 2;
+/// Frames of quiet before the first click, to hear the noise floor in.
+const SETTLE: usize = SAMPLING_FREQ / 2;
+const CLICK: f32 = 0.8;
+/// How many times louder than the noise floor a click has to come back to
+/// count, and how loud it has to be if there's no noise at all.
+const MIN_SNR: f32 = 10.;
+const MIN_LEVEL: f32 = 0.01;
+
+fn frames(n: usize) -> Duration {
+    Duration::from_secs_f64(n as f64 / SAMPLING_FREQ as f64)
+}
+
+/// Sends a click every [`CLICK_PERIOD`], noting when each went out.
+struct Clicks {
+    outputs: OutputMap,
+    channels: usize,
+    position: usize,
+    clicks: usize,
+    sent: Vec<Instant>,
+}
+
+impl AudioCallback for Clicks {
+    type Channel = f32;
+    fn callback(&mut self, out: &mut [f32]) {
+        let now = Instant::now();
+        out.fill(0.);
+        for (i, frame) in out.chunks_exact_mut(self.channels).enumerate() {
+            let n = self.position + i;
+            if n >= SETTLE
+                && (n - SETTLE).is_multiple_of(CLICK_PERIOD)
+                && self.sent.len() < self.clicks
+            {
+                frame[self.outputs.left] = CLICK;
+                frame[self.outputs.right] = CLICK;
+                self.sent.push(now + frames(i));
+            }
+        }
+        self.position += out.len() / self.channels;
+    }
+}
+
+/// Keeps everything that comes in, and when each block of it did.
+struct Listener {
+    samples: Vec<f32>,
+    /// the index of the first sample of each block, and when it came in
+    blocks: Vec<(usize, Instant)>,
+}
+
+impl AudioCallback for Listener {
+    type Channel = f32;
+    fn callback(&mut self, input: &mut [f32]) {
+        // it was all allocated up front, and anything past that is late
+        if self.samples.len() + input.len() > self.samples.capacity() {
+            return;
+        }
+        self.blocks.push((self.samples.len(), Instant::now()));
+        self.samples.extend_from_slice(input);
+    }
+}
+
+/// When the sample at `index` was read, as if each block were read out at
+/// the rate it plays.
+fn time_of(blocks: &[(usize, Instant)], index: usize) -> Option<Instant> {
+    let &(start, at) = blocks.iter().rev().find(|(start, _)| *start <= index)?;
+    Some(at + frames(index - start))
+}
+
+/// The sample being read at `time`.
+fn index_at(blocks: &[(usize, Instant)], time: Instant) -> usize {
+    match blocks.iter().rev().find(|(_, at)| *at <= time) {
+        Some(&(start, at)) => {
+            start + ((time - at).as_secs_f64() * SAMPLING_FREQ as f64).round() as usize
+        }
+        None => 0,
+    }
+}
+
+/// Where a click starts in `window`, as the first sample at least half as
+/// loud as the loudest, if it's loud enough over `noise` to be one.
+fn onset(window: &[f32], noise: f32) -> Option<usize> {
+    let peak = window.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+    if peak < (MIN_SNR * noise).max(MIN_LEVEL) {
+        return None;
+    }
+    window.iter().position(|s| s.abs() >= peak / 2.)
+}
+
+/// How long each of the clicks `sent` took to come back in `samples`,
+/// leaving out any that didn't.
+fn round_trips(sent: &[Instant], blocks: &[(usize, Instant)], samples: &[f32]) -> Vec<Duration> {
+    let Some(&first) = sent.first() else {
+        return Vec::new();
+    };
+    let quiet = &samples[..index_at(blocks, first).min(samples.len())];
+    let noise = if quiet.is_empty() {
+        0.
+    } else {
+        (quiet.iter().map(|s| s * s).sum::<f32>() / quiet.len() as f32).sqrt()
+    };
+    sent.iter()
+        .filter_map(|&at| {
+            let start = index_at(blocks, at).min(samples.len());
+            let end = (start + CLICK_PERIOD).min(samples.len());
+            let heard = start + onset(&samples[start..end], noise)?;
+            time_of(blocks, heard)?.checked_duration_since(at)
+        })
+        .collect()
+}
+
+fn millis(d: Duration) -> String {
+    format!("{:.1}ms", d.as_secs_f64() * 1000.)
+}
+
+fn buffer(spec: &AudioSpec) -> Duration {
+    Duration::from_secs_f64(spec.samples as f64 / spec.freq as f64)
+}
+
+/// Finds the capture device `name` picks.
+fn input_device(audio: &AudioSubsystem, name: Option<&str>) -> Result<Option<String>, String> {
+    let Some(name) = name else { return Ok(None) };
+    (0..audio.num_audio_capture_devices().unwrap_or(0))
+        .filter_map(|i| audio.audio_capture_device_name(i).ok())
+        .find(|device| device.starts_with(name))
+        .map(Some)
+        .ok_or_else(|| format!("no input device {name:?}"))
+}
+
+/// Sends `clicks` clicks out of `output` and listens for them on `input`,
+/// then says how long they took to get round and how much of that is the
+/// buffers on each side.
+pub fn measure(
+    audio: &AudioSubsystem,
+    output: Option<&str>,
+    outputs: OutputMap,
+    input: Option<&str>,
+    clicks: usize,
+) -> Result<(), String> {
+    let clicks = clicks.max(1);
+    let input = input_device(audio, input)?;
+    let length = SETTLE + clicks * CLICK_PERIOD + SAMPLING_FREQ;
+    let desired = |channels| AudioSpecDesired {
+        freq: Some(SAMPLING_FREQ as i32),
+        channels: Some(channels),
+        samples: Some(256),
+    };
+
+    let listener = audio.open_capture(input.as_deref(), &desired(1), |spec| Listener {
+        samples: Vec::with_capacity(length * spec.channels as usize),
+        blocks: Vec::with_capacity(length / spec.samples.max(1) as usize + 16),
+    })?;
+    let player =
+        audio.open_playback(output, &desired(outputs.channels() as u8), |spec| Clicks {
+            outputs,
+            channels: spec.channels as usize,
+            position: 0,
+            clicks,
+            sent: Vec::with_capacity(clicks),
+        })?;
+    let (out_spec, in_spec) = (*player.spec(), *listener.spec());
+    if out_spec.freq != SAMPLING_FREQ as i32 || in_spec.freq != SAMPLING_FREQ as i32 {
+        return Err(format!(
+            "the devices run at {}Hz out and {}Hz in, not {SAMPLING_FREQ}Hz",
+            out_spec.freq, in_spec.freq
+        ));
+    }
+
+    println!("latency: sending {clicks} clicks, wire an output to the input");
+    listener.resume();
+    player.resume();
+    std::thread::sleep(frames(length));
+    let sent = player.close_and_get_callback().sent;
+    let Listener { samples, blocks } = listener.close_and_get_callback();
+
+    let mut trips = round_trips(&sent, &blocks, &samples);
+    if trips.is_empty() {
+        return Err(
+            "heard none of the clicks: is an output wired to the input, and up loud enough?"
+                .to_string(),
+        );
+    }
+    trips.sort();
+    let median = trips[trips.len() / 2];
+    let (out_buffer, in_buffer) = (buffer(&out_spec), buffer(&in_spec));
+    println!(
+        "round trip: {} ({} samples), heard {} of {clicks} clicks, {} to {}",
+        millis(median),
+        (median.as_secs_f64() * SAMPLING_FREQ as f64).round(),
+        trips.len(),
+        millis(trips[0]),
+        millis(trips[trips.len() - 1]),
+    );
+    println!(
+        "output buffer: {} ({} samples)",
+        millis(out_buffer),
+        out_spec.samples
+    );
+    println!(
+        "input buffer: {} ({} samples)",
+        millis(in_buffer),
+        in_spec.samples
+    );
+    println!(
+        "the converters, drivers and cable: {}",
+        millis(median.saturating_sub(out_buffer + in_buffer))
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trips() {
+        let start = Instant::now();
+        // blocks of 256 coming in on time, starting a little before the
+        // clicks go out
+        let samples_len = SETTLE + 3 * CLICK_PERIOD;
+        let blocks: Vec<_> = (0..samples_len / 256)
+            .map(|b| (b * 256, start + frames(b * 256)))
+            .collect();
+        let mut samples = vec![0.001; samples_len];
+        let sent: Vec<_> = (0..3)
+            .map(|i| start + frames(SETTLE + i * CLICK_PERIOD))
+            .collect();
+        // the second click never comes back
+        for i in [0, 2] {
+            let at = SETTLE + i * CLICK_PERIOD + 441;
+            samples[at] = 0.3;
+            samples[at + 1] = 0.6;
+            samples[at + 2] = -0.2;
+        }
+        let trips = round_trips(&sent, &blocks, &samples);
+        assert_eq!(trips.len(), 2);
+        for trip in trips {
+            assert!((trip.as_secs_f64() - 0.010).abs() < 0.0001, "{trip:?}");
+        }
+
+        assert_eq!(onset(&[0.001; 100], 0.001), None);
+    }
+}
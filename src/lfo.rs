@@ -0,0 +1,215 @@
+use crate::filters::{Rng, SAMPLING_FREQ};
+use crate::params::{ParamInfo, Params};
+use crate::wavetable::{SineWave, SquareWave, TriangleWave, WavetableSource};
+
+/// The wave an LFO follows.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LfoShape {
+    #[default]
+    Sine,
+    Triangle,
+    Square,
+    /// A new random level every cycle, held until the next.
+    SampleHold,
+}
+
+impl LfoShape {
+    pub const ALL: [LfoShape; 4] = [
+        LfoShape::Sine,
+        LfoShape::Triangle,
+        LfoShape::Square,
+        LfoShape::SampleHold,
+    ];
+
+    pub fn index(self) -> usize {
+        Self::ALL.iter().position(|&s| s == self).unwrap_or(0)
+    }
+}
+
+/// `sine`, `triangle`, `square` or `sh` for sample and hold.
+impl std::str::FromStr for LfoShape {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        Ok(match value {
+            "sine" => LfoShape::Sine,
+            "triangle" => LfoShape::Triangle,
+            "square" => LfoShape::Square,
+            "sh" => LfoShape::SampleHold,
+            _ => return Err(format!("unknown lfo shape {value:?}")),
+        })
+    }
+}
+
+impl std::fmt::Display for LfoShape {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LfoShape::Sine => "sine",
+            LfoShape::Triangle => "triangle",
+            LfoShape::Square => "square",
+            LfoShape::SampleHold => "sh",
+        })
+    }
+}
+
+/// How an LFO's phase relates to notes and tempo.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LfoSync {
+    /// Runs continuously at `rate` and ignores notes.
+    Free,
+    /// Restarts at `phase` (0..1) on every note-on.
+    Retrigger { phase: f32 },
+    /// Takes `beats` beats per cycle at the current tempo. The phase is
+    /// derived from the song position plus `phase`, so every voice synced
+    /// this way lines up.
+    Tempo { beats: f32, phase: f32 },
+}
+
+pub struct Lfo {
+    /// Rate in Hz when not tempo-synced.
+    pub rate: f32,
+    pub sync: LfoSync,
+    pub shape: LfoShape,
+    bpm: f32,
+    /// 0..1
+    phase: f32,
+    /// the sample and hold's level for this cycle
+    held: f32,
+    rng: Rng,
+}
+
+impl Lfo {
+    pub fn new(rate: f32) -> Self {
+        Self {
+            rate,
+            sync: LfoSync::Free,
+            shape: LfoShape::Sine,
+            bpm: 120.,
+            phase: 0.,
+            held: 0.,
+            rng: Rng::default(),
+        }
+    }
+
+    pub fn with_shape(mut self, shape: LfoShape) -> Self {
+        self.shape = shape;
+        self
+    }
+
+    pub fn with_sync(mut self, sync: LfoSync) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    pub fn set_tempo(&mut self, bpm: f32) {
+        self.bpm = bpm;
+    }
+
+    pub fn note_on(&mut self) {
+        if let LfoSync::Retrigger { phase } = self.sync {
+            self.phase = phase.rem_euclid(1.);
+        }
+    }
+
+    /// Snaps a tempo-synced LFO to the absolute song position `beat`.
+    pub fn sync_to_beat(&mut self, beat: f64) {
+        if let LfoSync::Tempo { beats, phase } = self.sync {
+            self.phase = ((beat / beats as f64) as f32 + phase).rem_euclid(1.);
+        }
+    }
+
+    fn phase_inc(&self) -> f32 {
+        let hz = match self.sync {
+            LfoSync::Tempo { beats, .. } => self.bpm / 60. / beats,
+            _ => self.rate,
+        };
+        hz / SAMPLING_FREQ as f32
+    }
+
+    /// Returns the current value in -1..=1 and advances by one sample.
+    pub fn next_value(&mut self) -> f32 {
+        self.next_block(1)
+    }
+
+    /// Returns the current value and advances by `len` samples, for
+    /// modulation that only gets updated every so often.
+    pub fn next_block(&mut self, len: usize) -> f32 {
+        let v = match self.shape {
+            LfoShape::Sine => SineWave.at_phase(self.phase),
+            LfoShape::Triangle => TriangleWave.at_phase(self.phase),
+            LfoShape::Square => SquareWave.at_phase(self.phase),
+            LfoShape::SampleHold => self.held,
+        };
+        let phase = self.phase + self.phase_inc() * len as f32;
+        if phase >= 1. {
+            self.held = self.rng.next_f32();
+        }
+        self.phase = phase.rem_euclid(1.);
+        v
+    }
+}
+
+impl Params for Lfo {
+    fn params(&self) -> Vec<ParamInfo> {
+        vec![
+            ParamInfo::new("rate", 0.01, 20.),
+            ParamInfo::new("bpm", 20., 300.).not_random(),
+            ParamInfo::new("shape", 0., (LfoShape::ALL.len() - 1) as f32).not_random(),
+        ]
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name {
+            "rate" => Some(self.rate),
+            "bpm" => Some(self.bpm),
+            "shape" => Some(self.shape.index() as f32),
+            _ => None,
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name {
+            "rate" => self.rate = value,
+            "bpm" => self.set_tempo(value),
+            "shape" => {
+                let index = (value.round().max(0.) as usize).min(LfoShape::ALL.len() - 1);
+                self.shape = LfoShape::ALL[index];
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lfo_shapes() {
+        // a cycle a second, a sample at a time
+        let cycle = |shape| {
+            let mut lfo = Lfo::new(1.).with_shape(shape);
+            (0..SAMPLING_FREQ)
+                .map(|_| lfo.next_value())
+                .collect::<Vec<_>>()
+        };
+        let quarter = SAMPLING_FREQ / 4;
+        for shape in [LfoShape::Sine, LfoShape::Triangle, LfoShape::Square] {
+            let wave = cycle(shape);
+            assert!(
+                wave[quarter] > 0.99 && wave[3 * quarter] < -0.99,
+                "{shape:?}"
+            );
+        }
+        assert!(cycle(LfoShape::Triangle)[quarter / 2] < cycle(LfoShape::Sine)[quarter / 2]);
+
+        // held for a whole cycle, then somewhere else
+        let mut lfo = Lfo::new(10.).with_shape(LfoShape::SampleHold);
+        let held: Vec<f32> = (0..SAMPLING_FREQ * 3 / 10 + 100)
+            .map(|_| lfo.next_value())
+            .collect();
+        let changes = held.windows(2).filter(|w| w[0] != w[1]).count();
+        assert_eq!(changes, 3);
+        assert!(held.iter().all(|v| (-1. ..=1.).contains(v)));
+    }
+}
@@ -0,0 +1,459 @@
+//! Patches written as a chain, for live coding:
+//!
+//! ```text
+//! string(8, exciter=square) |> lpf(2000, resonance=0.6) |> reverb(0.3)
+//! ```
+//!
+//! The source comes first, then the effects in the order they're to run.
+//! A bare argument is the node's own value, the same as on the command
+//! line, and `name=value` sets one of its parameters. A chain compiles to a
+//! [`Patch`], so it gets built, checked and reloaded like any other.
+
+use std::fmt;
+
+use crate::audio_thread::Stage;
+use crate::patch::{Diagnostic, Entry, Patch, Value};
+
+/// Where an effect's bare argument goes.
+#[derive(Clone, Copy)]
+enum Arg {
+    /// the node's own value, like a cutoff or a delay time
+    Node,
+    /// a parameter, with the node just switched on
+    Param(&'static str),
+}
+
+struct Effect {
+    name: &'static str,
+    node: &'static str,
+    arg: Arg,
+    /// named arguments that set nodes of their own, as (argument, node)
+    nodes: &'static [(&'static str, &'static str)],
+    example: &'static str,
+}
+
+const EFFECTS: [Effect; 8] = [
+    Effect {
+        name: "distortion",
+        node: "distortion",
+        arg: Arg::Node,
+        nodes: &[("oversample", "oversample")],
+        example: "distortion(tanh)",
+    },
+    Effect {
+        name: "ladder",
+        node: "ladder",
+        arg: Arg::Node,
+        nodes: &[],
+        example: "ladder(2000)",
+    },
+    Effect {
+        name: "lpf",
+        node: "ladder",
+        arg: Arg::Node,
+        nodes: &[],
+        example: "lpf(2000)",
+    },
+    Effect {
+        name: "fir",
+        node: "fir",
+        arg: Arg::Node,
+        nodes: &[("taps", "fir_taps"), ("window", "fir_window")],
+        example: "fir(\"response.wav\")",
+    },
+    Effect {
+        name: "delay",
+        node: "delay",
+        arg: Arg::Node,
+        nodes: &[("bpm", "bpm")],
+        example: "delay(1/8)",
+    },
+    Effect {
+        name: "reverb",
+        node: "reverb",
+        arg: Arg::Param("mix"),
+        nodes: &[],
+        example: "reverb(0.3)",
+    },
+    Effect {
+        name: "ir",
+        node: "ir",
+        arg: Arg::Node,
+        nodes: &[],
+        example: "ir(\"hall.wav\")",
+    },
+    Effect {
+        name: "ping_pong",
+        node: "ping_pong",
+        arg: Arg::Node,
+        nodes: &[("bpm", "bpm")],
+        example: "ping_pong(1/8)",
+    },
+];
+
+/// Sources whose bare argument is how many voices to play them on.
+const SOURCES: [&str; 5] = ["string", "eks", "fm", "noise", "wavetable"];
+
+/// Named arguments to a source that are nodes rather than parameters.
+const SOURCE_NODES: [&str; 3] = ["voices", "exciter", "seed"];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Open,
+    Close,
+    Comma,
+    Equals,
+    Pipe,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) => f.write_str(word),
+            Token::Quoted(s) => write!(f, "{s:?}"),
+            Token::Open => f.write_str("("),
+            Token::Close => f.write_str(")"),
+            Token::Comma => f.write_str(","),
+            Token::Equals => f.write_str("="),
+            Token::Pipe => f.write_str("|>"),
+        }
+    }
+}
+
+/// Splits a chain into tokens, each with its line. `#` comments out the
+/// rest of a line.
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>, Diagnostic> {
+    let mut tokens = Vec::new();
+    for (idx, raw) in text.lines().enumerate() {
+        let line = idx + 1;
+        let mut chars = raw.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            let token = match c {
+                '#' => break,
+                c if c.is_whitespace() => continue,
+                '(' => Token::Open,
+                ')' => Token::Close,
+                ',' => Token::Comma,
+                '=' => Token::Equals,
+                '|' if chars.next_if(|&(_, c)| c == '>').is_some() => Token::Pipe,
+                '"' => {
+                    let mut out = String::new();
+                    loop {
+                        match chars.next() {
+                            Some((_, '"')) => break,
+                            Some((_, '\\')) => match chars.next() {
+                                Some((_, c)) => out.push(c),
+                                None => break,
+                            },
+                            Some((_, c)) => out.push(c),
+                            None => return Err(Diagnostic::new(line, None, "unterminated string")),
+                        }
+                    }
+                    Token::Quoted(out)
+                }
+                _ => {
+                    let mut end = start + c.len_utf8();
+                    while let Some(&(i, c)) = chars.peek() {
+                        if c.is_whitespace() || "()=,\"#|".contains(c) {
+                            break;
+                        }
+                        end = i + c.len_utf8();
+                        chars.next();
+                    }
+                    let word = &raw[start..end];
+                    if word == "|" {
+                        return Err(Diagnostic::new(line, None, "expected |> between stages"));
+                    }
+                    Token::Word(word.to_string())
+                }
+            };
+            tokens.push((line, token));
+        }
+    }
+    Ok(tokens)
+}
+
+/// A bare word is a number or a boolean if it reads as one, and otherwise
+/// a string, so `delay(1/8)` and `distortion(tanh)` need no quotes.
+fn value(token: Token) -> Option<Value> {
+    Some(match token {
+        Token::Quoted(s) => Value::String(s),
+        Token::Word(word) => match word.as_str() {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ => match word.replace('_', "").parse() {
+                Ok(n) => Value::Number(n),
+                Err(_) => Value::String(word),
+            },
+        },
+        _ => return None,
+    })
+}
+
+/// `name(<value>, <name>=<value>, ...)`, the parentheses optional when
+/// there's nothing in them.
+struct Call {
+    line: usize,
+    name: String,
+    bare: Option<Value>,
+    named: Vec<(String, Value)>,
+}
+
+impl Call {
+    fn error(&self, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(self.line, Some(&self.name), message)
+    }
+}
+
+fn parse_calls(tokens: Vec<(usize, Token)>) -> Result<Vec<Call>, Diagnostic> {
+    let Some(&(last_line, _)) = tokens.last() else {
+        return Err(Diagnostic::new(
+            1,
+            None,
+            "expected a source, like string(8)",
+        ));
+    };
+    let mut tokens = tokens.into_iter().peekable();
+    let mut calls = Vec::new();
+    loop {
+        let mut call = match tokens.next() {
+            Some((line, Token::Word(name))) => Call {
+                line,
+                name,
+                bare: None,
+                named: Vec::new(),
+            },
+            Some((line, other)) => {
+                return Err(Diagnostic::new(
+                    line,
+                    None,
+                    format!("expected a source or effect, got {other}"),
+                ))
+            }
+            None => {
+                return Err(Diagnostic::new(
+                    last_line,
+                    None,
+                    "expected a source or effect after |>",
+                ))
+            }
+        };
+        if tokens.next_if(|(_, t)| *t == Token::Open).is_some() {
+            loop {
+                let Some((_, token)) = tokens.next() else {
+                    return Err(call.error("expected )"));
+                };
+                if token == Token::Close && call.bare.is_none() && call.named.is_empty() {
+                    break;
+                }
+                match token {
+                    Token::Word(name) if tokens.next_if(|(_, t)| *t == Token::Equals).is_some() => {
+                        let value = tokens.next().and_then(|(_, t)| value(t));
+                        let value = value
+                            .ok_or_else(|| call.error(format!("expected a value for {name}")))?;
+                        call.named.push((name, value));
+                    }
+                    token => {
+                        let value = value(token.clone())
+                            .ok_or_else(|| call.error(format!("unexpected {token}")))?;
+                        if call.bare.is_some() || !call.named.is_empty() {
+                            return Err(call.error("only the first argument can go without a name"));
+                        }
+                        call.bare = Some(value);
+                    }
+                }
+                match tokens.next() {
+                    Some((_, Token::Comma)) => {}
+                    Some((_, Token::Close)) => break,
+                    _ => return Err(call.error("expected , or ) after an argument")),
+                }
+            }
+        }
+        calls.push(call);
+        match tokens.next() {
+            Some((_, Token::Pipe)) => continue,
+            Some((line, other)) => {
+                return Err(Diagnostic::new(
+                    line,
+                    None,
+                    format!("expected |> or the end, got {other}"),
+                ))
+            }
+            None => return Ok(calls),
+        }
+    }
+}
+
+/// Compiles a chain to the patch it stands for.
+pub fn parse(text: &str) -> Result<Patch, Vec<Diagnostic>> {
+    let calls = tokenize(text).and_then(parse_calls).map_err(|e| vec![e])?;
+    let mut patch = Patch::default();
+    let mut errors = Vec::new();
+    let mut chain = Vec::new();
+    let mut stereo: Option<&str> = None;
+    let entry = |line, key: &str, value| Entry {
+        line,
+        key: key.to_string(),
+        value,
+    };
+
+    let (source, effects) = calls.split_first().unwrap();
+    let line = source.line;
+    if SOURCES.contains(&source.name.as_str()) {
+        patch
+            .nodes
+            .push(entry(line, "synth", Value::String(source.name.clone())));
+        if let Some(voices) = &source.bare {
+            patch.nodes.push(entry(line, "voices", voices.clone()));
+        }
+    } else if source.name == "sampler" {
+        match &source.bare {
+            Some(Value::String(path)) => patch.nodes.push(entry(
+                line,
+                "synth",
+                Value::String(format!("sampler:{path}")),
+            )),
+            _ => errors.push(source.error("expected a file, like sampler(\"piano.wav\")")),
+        }
+    } else {
+        errors.push(source.error(format!(
+            "a chain starts with a source: {} or sampler",
+            SOURCES.join(", ")
+        )));
+    }
+    for (name, value) in &source.named {
+        if SOURCE_NODES.contains(&name.as_str()) {
+            patch.nodes.push(entry(line, name, value.clone()));
+        } else {
+            patch.params.push(entry(line, name, value.clone()));
+        }
+    }
+
+    for call in effects {
+        let Some(effect) = EFFECTS.iter().find(|e| e.name == call.name) else {
+            let message = if SOURCES.contains(&call.name.as_str()) || call.name == "sampler" {
+                "there's only one source, at the start".to_string()
+            } else {
+                let names: Vec<_> = EFFECTS.iter().map(|e| e.name).collect();
+                format!("unknown effect, expected one of {}", names.join(", "))
+            };
+            errors.push(call.error(message));
+            continue;
+        };
+        let line = call.line;
+        if patch.nodes.iter().any(|e| e.key == effect.node) {
+            errors.push(call.error(format!("{} is in there twice", effect.node)));
+            continue;
+        }
+        let is_rack = Stage::ALL.iter().any(|s| s.name() == effect.node);
+        match (is_rack, stereo) {
+            (true, Some(before)) => errors.push(call.error(format!(
+                "{before} is stereo, so it has to go after every other effect"
+            ))),
+            (true, None) => chain.push(effect.node),
+            (false, _) => stereo = Some(effect.name),
+        }
+        match (effect.arg, &call.bare) {
+            (Arg::Node, Some(value)) => patch.nodes.push(entry(line, effect.node, value.clone())),
+            (Arg::Node, None) => {
+                errors.push(call.error(format!("needs a value, like {}", effect.example)));
+            }
+            (Arg::Param(param), bare) => {
+                patch
+                    .nodes
+                    .push(entry(line, effect.node, Value::Bool(true)));
+                if let Some(value) = bare {
+                    let key = format!("{}.{param}", effect.node);
+                    patch.params.push(entry(line, &key, value.clone()));
+                }
+            }
+        }
+        for (name, value) in &call.named {
+            match effect.nodes.iter().find(|(arg, _)| arg == name) {
+                Some((_, node)) => patch.nodes.push(entry(line, node, value.clone())),
+                None => {
+                    let key = format!("{}.{name}", effect.node);
+                    patch.params.push(entry(line, &key, value.clone()));
+                }
+            }
+        }
+    }
+    if !chain.is_empty() {
+        let chain = Value::String(chain.join(", "));
+        patch.nodes.push(entry(line, "chain", chain));
+    }
+
+    if errors.is_empty() {
+        Ok(patch)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_live_chain() {
+        let patch = parse(
+            "string(4, exciter=square, tremolo.depth=0.2)\n\
+             |> lpf(2_000, resonance=0.5) # darker\n\
+             |> delay(1/8, feedback=0.3) |> reverb(0.3)\n",
+        )
+        .unwrap();
+        let nodes: Vec<_> = patch.nodes.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            nodes,
+            [
+                "synth = \"string\"",
+                "voices = 4",
+                "exciter = \"square\"",
+                "ladder = 2000",
+                "delay = \"1/8\"",
+                "reverb = true",
+                "chain = \"ladder, delay, reverb\"",
+            ]
+        );
+        assert_eq!(
+            patch.param_values(),
+            [
+                ("tremolo.depth".to_string(), 0.2),
+                ("ladder.resonance".to_string(), 0.5),
+                ("delay.feedback".to_string(), 0.3),
+                ("reverb.mix".to_string(), 0.3),
+            ]
+        );
+        assert_eq!(patch.nodes[4].line, 3);
+        assert_eq!(patch.validate(), []);
+
+        // the same sound in another order is another patch
+        let reordered = parse("string(4) |> reverb |> ladder(2000)").unwrap();
+        assert_eq!(
+            reordered.nodes.last().unwrap().value,
+            Value::String("reverb, ladder".to_string())
+        );
+
+        let fields = |text| {
+            parse(text)
+                .unwrap_err()
+                .into_iter()
+                .map(|e| e.field.unwrap_or_default())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            fields("lpf(2000) |> phaser |> ladder |> string"),
+            ["lpf", "phaser", "ladder", "string"]
+        );
+        assert_eq!(
+            fields("fm |> ping_pong(1/4) |> reverb |> reverb"),
+            ["reverb", "reverb"]
+        );
+        assert_eq!(fields("string |> ladder(1000, 2)"), ["ladder"]);
+        assert!(parse("string |>").is_err());
+        assert!(parse("string | ladder(1000)").is_err());
+        assert!(parse("sampler(\"unclosed)").is_err());
+    }
+}
@@ -1,38 +1,656 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub mod audio_thread;
+pub mod calibrate;
+pub mod clock;
+pub mod console;
+pub mod delay;
+pub mod distortion;
+pub mod dynamics;
+pub mod eks;
+pub mod event_log;
+pub mod expression;
 pub mod filters;
+pub mod guard;
+pub mod harmonizer;
+pub mod input;
+#[cfg(feature = "jack")]
+pub mod jack;
+pub mod keyboard;
+pub mod latency;
+pub mod lfo;
+pub mod live;
+pub mod metronome;
 pub mod midi;
+pub mod modulation;
+pub mod mpe;
 pub mod note;
+pub mod osc;
+pub mod outputs;
+pub mod params;
+pub mod patch;
+pub mod pressure;
+pub mod queue;
+pub mod random;
+pub mod recording;
+pub mod reload;
+pub mod render;
+pub mod reverb;
+pub mod sequencer;
+pub mod session;
+pub mod siggen;
+pub mod signal;
+pub mod smf;
+pub mod snapshot;
+pub mod snoop;
+pub mod sources;
+pub mod spsc;
+pub mod stereo;
+pub mod strum;
+pub mod tone;
+pub mod tuning;
+pub mod voice;
+pub mod watchdog;
 pub mod wavetable;
+pub mod window;
 
-use audio_thread::{AudioEvent, AudioSubsystemCrimesWrapper};
-use midi::{initialize_midi, MidiDevice, MidiEvent};
+use audio_thread::{AudioConfig, AudioEvent, AudioSubsystemCrimesWrapper, Backend, Order};
+use clock::{Clock, DriverClock};
+use console::Console;
+use delay::DelayTime;
+use distortion::{Curve, Waveshaper};
+use dynamics::Key;
+use event_log::{EventLog, Field};
+use expression::{Calibration, ExpressionConfig, Response};
+use filters::{ExciterKind, FIR};
+use guard::{EngineError, Reporter};
+use harmonizer::{Chord, Scale};
+use keyboard::{KeyVelocity, VelocityMode};
+use midi::{initialize_midi, CcMap, CcMapping, CcTarget, MidiDevice, MidiEvent};
+use modulation::{LfoConfig, ModConfig};
+use patch::Patch;
+use pressure::PressureConfig;
+use queue::Overflow;
+use reload::Watcher;
+use reverb::ConvolutionReverb;
+use sequencer::{Control, Pattern, Song};
+use session::Session;
+use siggen::{Siggen, Signal};
+use snapshot::Snapshots;
+use sources::SynthKind;
+use strum::{StrumConfig, StrumDirection};
+use tuning::Tuning;
+use voice::KeyPressure;
+use wavetable::PositionEnvelope;
+use window::Window;
 
-use clap::{builder::ValueParser, Parser};
+use clap::{
+    builder::ValueParser, parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches,
+    Subcommand,
+};
 use note::key_to_freq;
+use outputs::OutputMap;
 use sdl2::{
     event::{Event, EventType},
-    keyboard::Keycode,
+    keyboard::{Keycode, Mod},
 };
 
 type Error = Box<dyn std::error::Error + 'static>;
 
+/// The keys that pick the sequencer's patterns, 1 to 9.
+const PATTERN_KEYS: [Keycode; 9] = [
+    Keycode::Num1,
+    Keycode::Num2,
+    Keycode::Num3,
+    Keycode::Num4,
+    Keycode::Num5,
+    Keycode::Num6,
+    Keycode::Num7,
+    Keycode::Num8,
+    Keycode::Num9,
+];
+
 #[derive(Clone, Debug, clap::Parser)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// MIDI device to get input from. Can be "virtual" to create a virtual
     /// midi input device that can be sent to from other software.
     #[clap(long, value_parser = ValueParser::new(MidiDevice::from_str))]
     midi_device: Option<MidiDevice>,
 
+    /// Plays a MIDI file through the synth, in time, alongside anything
+    /// played live.
+    #[clap(long)]
+    play: Option<PathBuf>,
+
+    /// Records everything played, live or from --play, to a MIDI file,
+    /// which is saved on the way out and whenever R is pressed.
+    #[clap(long)]
+    record: Option<PathBuf>,
+
+    /// Records the output to a WAV file from the start, until S is pressed,
+    /// and again over it if S is pressed again. Without it, S records to a
+    /// new file in the current directory each time.
+    #[clap(long)]
+    record_wav: Option<PathBuf>,
+
+    /// A pattern for the step sequencer, which O saves back to. G starts
+    /// and stops it, and I starts and stops playing notes into it a step
+    /// at a time, with A for a rest. The file has a step per line, a pitch
+    /// and a velocity like "C3 110", or "-" to rest. Given more than once,
+    /// the files are patterns 1, 2 and so on, which the number keys pick
+    /// between.
+    #[clap(long)]
+    sequence: Vec<PathBuf>,
+
+    /// Chains the sequencer's patterns into a song, as
+    /// "<pattern>[x<repeats>],..." like "1x4, 2x2, 1". H switches between
+    /// playing it and the pattern picked, J chains the pattern picked onto
+    /// its end, and shift J clears it.
+    #[clap(long, value_parser = ValueParser::new(Song::from_str))]
+    song: Option<Song>,
+
+    /// Writes down every note, parameter change, patch change and thing
+    /// going wrong in the engine to this file, as a JSON object a line with
+    /// "t" in seconds from the start.
+    #[clap(long)]
+    log: Option<PathBuf>,
+
+    /// How many notes, controller moves and keys can wait for the audio
+    /// thread before the queue is full.
+    #[clap(long, default_value_t = 1024)]
+    queue: usize,
+
+    /// What a full queue does: "drop-oldest" loses the oldest event, taking
+    /// clock ticks and controller moves first and never a note off,
+    /// "coalesce-cc" merges a controller's (or a key's aftertouch) moves
+    /// when it can and drops like drop-oldest when it can't, and "block"
+    /// makes the keyboard, console and OSC wait while MIDI still drops.
+    /// Pedals and other switches are never merged. Anything lost gets
+    /// printed, and written to --log.
+    #[clap(long, default_value = "coalesce-cc", value_parser = ValueParser::new(Overflow::from_str))]
+    overflow: Overflow,
+
     /// Lists midi devices then exits.
     #[clap(long)]
     midi_list: bool,
+
+    /// Audio device to play through, by the start of its name. Without one
+    /// it's the system default.
+    #[clap(long)]
+    output_device: Option<String>,
+
+    /// Audio device to capture from for the "input" exciter, by the start
+    /// of its name. It's opened when the exciter starts out as "input", on
+    /// the default device without this, or whenever this is given, so a
+    /// patch can switch to it while playing.
+    #[clap(long)]
+    input_device: Option<String>,
+
+    /// Which of the device's outputs the mix goes to, as "<left>/<right>"
+    /// counting from 1, like "3/4", or one output for a mono mix.
+    #[clap(long, default_value = "1/2", value_parser = ValueParser::new(OutputMap::from_str))]
+    outputs: OutputMap,
+
+    /// Sends the metronome to this output of the device on its own,
+    /// counting from 1, rather than into the mix, for a click only the
+    /// player hears that stays out of recordings. Not with --jack.
+    #[clap(long, value_parser = ValueParser::new(outputs::output))]
+    click_output: Option<usize>,
+
+    /// When the audio can't keep up, bypasses whichever effect is taking
+    /// longest, instead of only saying which it is.
+    #[clap(long)]
+    shed: bool,
+
+    /// Milliseconds the output takes to fade in when starting and out when
+    /// quitting, so the speakers don't pop.
+    #[clap(long, default_value_t = 10., value_parser = ValueParser::new(millis))]
+    fade: f32,
+
+    /// Lists audio output devices and how many channels each has, then
+    /// exits.
+    #[clap(long)]
+    list_outputs: bool,
+
+    /// Pitch bend range in semitones, in each direction.
+    #[clap(long, default_value_t = 2.)]
+    bend_range: f32,
+
+    /// What plays the notes: "string", "eks" (the string with Jaffe and
+    /// Smith's extensions), "wavetable:sine", "wavetable:triangle",
+    /// "wavetable:square", "fm", "noise", or "sampler:<file.wav>" to
+    /// repitch a recording of middle C. A wavetable given several waves,
+    /// like "wavetable:sine,square", morphs between them by its "position"
+    /// parameter.
+    #[clap(long, default_value = "string", value_parser = ValueParser::new(SynthKind::from_str))]
+    synth: SynthKind,
+
+    /// Moves a wavetable through its waves over each note instead, as
+    /// "<secs>:<position>,..." breakpoints, e.g. "0:0,0.5:1,2:0.25". Ending
+    /// it with ",loop" starts it over after the last, crossfading back
+    /// round.
+    #[clap(long, value_parser = ValueParser::new(PositionEnvelope::from_str))]
+    wave_position: Option<PositionEnvelope>,
+
+    /// What to pluck the string with: "noise", "square",
+    /// "sample:<file.wav>[@<start>..<end>]" to use a slice of a WAV file
+    /// (bounds in samples), or "input" for what's coming in from the input
+    /// device.
+    #[clap(long, default_value = "noise", value_parser = ValueParser::new(ExciterKind::from_str))]
+    exciter: ExciterKind,
+
+    /// Where each voice's noise comes from. The same seed plays a patch the
+    /// same way every time, and another one gives it different plucks.
+    #[clap(long, default_value_t = sources::DEFAULT_SEED)]
+    seed: u32,
+
+    /// Adds a test signal to the voices, ahead of the effects:
+    /// "sweep:<from>-<to>:<secs>s" for a sine sweep between two frequencies
+    /// in Hz, "white" or "pink" noise, "impulse:<hz>" for a click train, or
+    /// "dc:<level>". Its level is the "siggen.level" parameter.
+    #[clap(long, value_parser = ValueParser::new(Signal::from_str))]
+    siggen: Option<Signal>,
+
+    /// Adds a compressor after the effects that listens to something else:
+    /// "beat" ducks everything on every beat, so it pumps in time, and
+    /// "highpass:<hz>" or "lowpass:<hz>" have it react to only the top or
+    /// the bottom of the sound, and "below:<note>" (say "below:C2") ducks
+    /// everything under the voices playing that note or lower, such as a
+    /// kick or a bass line. Its parameters are the compressor's, under
+    /// "sidechain.", and the key's under "sidechain.key.".
+    #[clap(long, value_parser = ValueParser::new(Key::from_str))]
+    sidechain: Option<Key>,
+
+    /// Maps a MIDI CC onto a synth parameter, as
+    /// "<cc>=<param>[:<min>..<max>]", e.g. "74=damping.brightness". May be
+    /// given multiple times; overrides the General MIDI defaults and
+    /// --cc-map. The "latch" parameter holds notes after their keys come up,
+    /// like the L key, and "sustain" (on CC64 to start with) holds them only
+    /// until it goes back down. The mod wheel (CC1) starts out on
+    /// "vibrato.depth", with its speed in "vibrato.rate".
+    #[clap(long, value_parser = ValueParser::new(CcMapping::from_str))]
+    cc: Vec<CcMapping>,
+
+    /// File with one CC mapping per line, in the same format as --cc.
+    #[clap(long)]
+    cc_map: Option<PathBuf>,
+
+    /// Turns the MIDI notes starting at this one into articulation key
+    /// switches: open, palm mute, staccato, 2nd harmonic, 3rd harmonic.
+    #[clap(long)]
+    key_switches: Option<u8>,
+
+    /// Plays MIDI channels 2 to 16 as an MPE lower zone, each note with
+    /// its own bend, pressure and timbre (CC74). Controllers that announce
+    /// their zones don't need it.
+    #[clap(long)]
+    mpe: bool,
+
+    /// Plays in the tuning in this Scala scale (.scl) file, with middle C
+    /// as its first degree unless --keymap says otherwise.
+    #[clap(long)]
+    tuning: Option<PathBuf>,
+
+    /// Maps keys to the degrees of the --tuning scale as this Scala keyboard
+    /// mapping (.kbm) file says.
+    #[clap(long, requires = "tuning")]
+    keymap: Option<PathBuf>,
+
+    /// Frequency of the A above middle C, which every note moves with,
+    /// from the computer keyboard and MIDI alike.
+    #[clap(long, default_value_t = note::A4)]
+    a4: f32,
+
+    /// Semitones to move every note by, which can be fractions of one,
+    /// like "-0.5" for a quarter tone down.
+    #[clap(long, default_value_t = 0., allow_negative_numbers = true)]
+    transpose: f32,
+
+    /// Velocity of computer keyboard notes: a fixed MIDI velocity, or "held"
+    /// to play each note as loud as the previous key was short. Holding Alt
+    /// plays them half as loud, and Shift or Ctrl an octave up or down.
+    #[clap(long, default_value = "127", value_parser = ValueParser::new(VelocityMode::from_str))]
+    key_velocity: VelocityMode,
+
+    /// Number of voices of polyphony. Each can be placed in the stereo
+    /// field with its own "voice1.pan" parameter and so on, -1 to 1.
+    #[clap(long, default_value_t = 8)]
+    voices: usize,
+
+    /// Plays only this voice, counting from 1, for hearing how notes get
+    /// shared out. The rest still play, unheard. It's also the "solo"
+    /// parameter, where 0 is all of them.
+    #[clap(long)]
+    solo: Option<usize>,
+
+    /// Gives each voice a snoop tap of its own, "voice1" and so on, which
+    /// the W key captures along with the others.
+    #[clap(long)]
+    tap_voices: bool,
+
+    /// Plays a chord for every note: a name ("maj", "min", "dim", "aug",
+    /// "sus2", "sus4", "7", "maj7", "min7", "5" or "octave") or semitones
+    /// above the note like "0,4,7".
+    #[clap(long, value_parser = ValueParser::new(Chord::from_str))]
+    chord: Option<Chord>,
+
+    /// Pulls every note onto a scale, given as "<key>:<scale>" like
+    /// "D:dorian". The scales are major, minor, the other modes by name,
+    /// harmonic_minor, pentatonic, minor_pentatonic and blues. With --chord,
+    /// the chords keep to the scale too.
+    #[clap(long, value_parser = ValueParser::new(Scale::from_str))]
+    scale: Option<Scale>,
+
+    /// Strums chords, spreading their notes over this many milliseconds.
+    #[clap(long, value_parser = ValueParser::new(millis))]
+    strum: Option<f32>,
+
+    /// Strum direction: "up", "down", or "alternate".
+    #[clap(long, default_value = "up", value_parser = ValueParser::new(StrumDirection::from_str))]
+    strum_direction: StrumDirection,
+
+    /// Random strum timing variation, as a fraction of the gap between
+    /// notes.
+    #[clap(long, default_value_t = 0.3)]
+    strum_humanize: f32,
+
+    /// Overdrives the voices with this curve: "tanh", "hard" (clipping),
+    /// "fold" (foldback) or "asym" (asymmetric). Its parameters are
+    /// "distortion.curve", "distortion.drive" and "distortion.level".
+    #[clap(long, value_parser = ValueParser::new(Curve::from_str))]
+    distortion: Option<Curve>,
+
+    /// How many times the sample rate --distortion runs at, to cut down on
+    /// aliasing. 1 turns oversampling off.
+    #[clap(long, default_value_t = 4)]
+    oversample: usize,
+
+    /// Runs everything through a resonant ladder low-pass starting at this
+    /// cutoff in Hz. Its parameters are "ladder.cutoff", "ladder.resonance"
+    /// and "ladder.drive".
+    #[clap(long)]
+    ladder: Option<f32>,
+
+    /// Sends channel aftertouch to a synth parameter, as
+    /// "<param>[:<min>..<max>]" like --cc. With strings, "bow" keeps them
+    /// sounding for as long as a key is pressed, louder the harder.
+    #[clap(long, value_parser = ValueParser::new(CcTarget::from_str))]
+    pressure: Option<CcTarget>,
+
+    /// Time constant in milliseconds of the smoothing applied to aftertouch
+    /// as it rises.
+    #[clap(long, default_value_t = 30., value_parser = ValueParser::new(millis))]
+    pressure_smoothing: f32,
+
+    /// The same as it falls. Without it, it's --pressure-smoothing.
+    #[clap(long, value_parser = ValueParser::new(millis))]
+    pressure_release: Option<f32>,
+
+    /// What polyphonic aftertouch does to the note under the key: "pressure"
+    /// adds to its pressure, bowing strings and brightening FM, "timbre"
+    /// brightens it, and "off" ignores it. It's also the "key_pressure"
+    /// parameter. The hardest pressed key is a --mod source as well.
+    #[clap(long, default_value = "pressure", value_parser = ValueParser::new(KeyPressure::from_str))]
+    key_pressure: KeyPressure,
+
+    /// Sends the expression pedal (CC11) to a synth parameter, as
+    /// "<param>[:<min>..<max>]" like --cc. With or without it, the pedal is
+    /// the "expression" --mod source. Press E to learn the pedal's range
+    /// from a sweep.
+    #[clap(long, value_parser = ValueParser::new(CcTarget::from_str))]
+    expression: Option<CcTarget>,
+
+    /// Response of the expression pedal: "linear", "exp", "log" or "s".
+    #[clap(long, default_value = "linear", value_parser = ValueParser::new(Response::from_str))]
+    expression_curve: Response,
+
+    /// Raw CC values the expression pedal sends at either end of its
+    /// travel, as "<low>..<high>", for a pedal that has been learned before.
+    #[clap(long, value_parser = ValueParser::new(Calibration::from_str))]
+    expression_range: Option<Calibration>,
+
+    /// Filters everything with a FIR loaded from a file: either a WAV file
+    /// holding its impulse response, or a measured magnitude response as
+    /// lines of "<hz>, <db>".
+    #[clap(long)]
+    fir: Option<PathBuf>,
+
+    /// Half the length of FIRs designed from a magnitude response.
+    #[clap(long, default_value_t = 100)]
+    fir_taps: usize,
+
+    /// Window applied to FIRs designed from a magnitude response: "rect",
+    /// "hann", "hamming", "blackman" or "kaiser[:<beta>]".
+    #[clap(long, default_value = "hann", value_parser = ValueParser::new(Window::from_str))]
+    fir_window: Window,
+
+    /// Adds an echo this far apart: milliseconds ("350ms"), or a note length
+    /// at --bpm like "1/8", "1/8." (dotted) or "1/8t" (triplet). Its
+    /// parameters are "delay.time", "delay.beats", "delay.bpm",
+    /// "delay.feedback" and "delay.mix".
+    #[clap(long, value_parser = ValueParser::new(DelayTime::from_str))]
+    delay: Option<DelayTime>,
+
+    /// Adds echoes that go back and forth between left and right, this far
+    /// apart, in the same format as --delay. Its parameters are
+    /// "ping_pong.time", "ping_pong.beats", "ping_pong.bpm",
+    /// "ping_pong.feedback_left" (how much of a left echo comes back on the
+    /// right), "ping_pong.feedback_right" and "ping_pong.mix".
+    #[clap(long, value_parser = ValueParser::new(DelayTime::from_str))]
+    ping_pong: Option<DelayTime>,
+
+    /// Tempo in beats per minute, for tempo synced effects.
+    #[clap(long, default_value_t = 120.)]
+    bpm: f32,
+
+    /// Adds an LFO, as <shape>:<rate>:<param>=<depth>,... The shape is
+    /// "sine", "triangle", "square" or "sh" (sample and hold), and the rate
+    /// is in Hz or a note length at --bpm like "1/4". Each depth, in -1..1,
+    /// is how much of the parameter's range it swings either way, like
+    /// "sine:0.5:ladder.cutoff=0.1,pan.position=0.5". Can be given more than
+    /// once, and the LFOs' parameters are "lfo1.rate", "lfo1.shape",
+    /// "lfo1.depth.<param>" and so on.
+    #[clap(long, value_parser = ValueParser::new(LfoConfig::from_str))]
+    lfo: Vec<LfoConfig>,
+
+    /// Routes a modulation source onto parameters, as
+    /// <source>:<param>=<depth>,... The sources are "envelope" (one for
+    /// every note, with parameters "envelope.attack" and so on),
+    /// "velocity", "aftertouch", "key_pressure" (the hardest pressed key's
+    /// polyphonic aftertouch), "wheel" (CC1), "expression" (CC11, through
+    /// --expression-curve and --expression-range), "random" (new with each
+    /// note) and the LFOs as "lfo1" and so on. Can be given more than once, and
+    /// each routing's depth is the "<source>.depth.<param>" parameter.
+    #[clap(long = "mod", value_parser = ValueParser::new(ModConfig::from_str))]
+    mods: Vec<ModConfig>,
+
+    /// Adds reverb at the end of the chain. Its parameters are
+    /// "reverb.room_size", "reverb.damping" and "reverb.mix".
+    #[clap(long)]
+    reverb: bool,
+
+    /// Adds convolution reverb with the impulse response in this WAV file.
+    /// Its wet/dry mix is the "ir.mix" parameter.
+    #[clap(long)]
+    ir: Option<PathBuf>,
+
+    /// Order of the effects after the voices, as their names separated by
+    /// commas, like "ladder, distortion". Any left out follow in the usual
+    /// order: distortion, ladder, fir, delay, reverb, ir. More go in as
+    /// "+distortion:<curve>", "+ladder:<cutoff>", "+delay:<time>",
+    /// "+reverb", "+tone" or "+compressor", as many as wanted, and the
+    /// second ladder's parameters start with "ladder2." and so on.
+    #[clap(long, value_parser = ValueParser::new(Order::from_str))]
+    chain: Option<Order>,
+
+    /// Makes the compressor at the very end of the chain a brickwall limiter
+    /// instead. Its parameters are "compressor.threshold", "compressor.ratio",
+    /// "compressor.attack", "compressor.release", "compressor.makeup" and
+    /// "compressor.limit".
+    #[clap(long)]
+    limiter: bool,
+
+    /// Takes the sources, effects and starting parameters from a patch file
+    /// instead of the command line, and picks up changes to it while
+    /// playing. A file ending in .chain is a chain to live code, like
+    /// `string(8) |> lpf(2000) |> reverb(0.3)`. Pressing K saves what's
+    /// playing as a new patch, with or without this.
+    #[clap(long)]
+    patch: Option<PathBuf>,
+
+    /// Picks up where the last run left off, which is saved every few
+    /// seconds and on quitting: the sources and effects with their
+    /// parameters, the LFOs and modulation routing, and the sequencer's
+    /// patterns and song. They take the place of the ones from --patch or
+    /// the command line.
+    #[clap(long)]
+    restore_last_session: bool,
+
+    /// Plays through a JACK server instead of the default output device,
+    /// on ports "synthtoy:out_l" and "synthtoy:out_r". Needs building with
+    /// the "jack" feature.
+    #[clap(long)]
+    jack: bool,
+
+    /// With --jack, also takes MIDI from a "synthtoy:midi_in" port.
+    #[clap(long)]
+    jack_midi: bool,
+
+    /// Runs without a window, taking notes only over MIDI, until Ctrl-C.
+    #[clap(long)]
+    headless: bool,
+
+    /// Takes commands on stdin while playing, like `set ladder.cutoff 800`
+    /// or `chain add reverb`; `help` lists them.
+    #[clap(long)]
+    console: bool,
+
+    /// Listens for OSC messages on this UDP port, like
+    /// `/synthtoy/note_on 60 0.8` or `/synthtoy/param/ladder.cutoff 800`.
+    #[clap(long)]
+    osc_port: Option<u16>,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+enum Command {
+    /// Works with patch files.
+    Patch {
+        #[clap(subcommand)]
+        action: PatchCommand,
+    },
+    /// Renders a MIDI file to a WAV file as fast as it can, without
+    /// playing it.
+    Render {
+        midi: PathBuf,
+        wav: PathBuf,
+        /// Patch to render with. Without one it's the default strings.
+        #[clap(long)]
+        patch: Option<PathBuf>,
+        /// Seconds to keep rendering after the last event.
+        #[clap(long, default_value_t = render::DEFAULT_TAIL)]
+        tail: f32,
+        /// Also renders each voice soloed, to "<wav>-voice1.wav" and so on.
+        #[clap(long)]
+        per_voice: bool,
+    },
+    /// Renders MIDI files to WAV files without playing them, several at a
+    /// time.
+    RenderBatch {
+        /// A directory of MIDI files, or a manifest listing one
+        /// "<file.mid> [<file.wav>]" per line. Quote paths with spaces.
+        input: PathBuf,
+        /// Patch to render with. Without one it's the default strings.
+        #[clap(long)]
+        patch: Option<PathBuf>,
+        /// Directory the WAV files go in.
+        #[clap(long, default_value = ".")]
+        out: PathBuf,
+        /// Number of files to render at once. Defaults to one per CPU.
+        #[clap(long)]
+        jobs: Option<usize>,
+        /// Seconds to keep rendering after the last event.
+        #[clap(long, default_value_t = render::DEFAULT_TAIL)]
+        tail: f32,
+    },
+    /// Plays pink noise at a known level to set how loud this system
+    /// plays, which is kept for every run after.
+    Calibrate,
+    /// Sends clicks out of the output device and listens for them on an
+    /// input wired to it, to find the round trip latency for playing
+    /// through live.
+    MeasureLatency {
+        /// Audio device to listen on, by the start of its name. Without one
+        /// it's the system default.
+        #[clap(long)]
+        input_device: Option<String>,
+        /// How many clicks to send.
+        #[clap(long, default_value_t = 8)]
+        clicks: usize,
+    },
+}
+
+#[derive(Clone, Debug, Subcommand)]
+enum PatchCommand {
+    /// Checks a patch file for unknown nodes and parameters, values out of
+    /// range and files that can't be loaded, without playing anything.
+    Validate { file: PathBuf },
 }
+
 fn main() -> Result<(), Error> {
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches)?;
+
+    match &args.command {
+        Some(Command::Patch {
+            action: PatchCommand::Validate { file },
+        }) => return validate_patch(file),
+        Some(Command::Render {
+            midi,
+            wav,
+            patch,
+            tail,
+            per_voice,
+        }) => return render(midi, wav, patch.as_deref(), *tail, *per_voice),
+        Some(Command::RenderBatch {
+            input,
+            patch,
+            out,
+            jobs,
+            tail,
+        }) => return render_batch(input, patch.as_deref(), out, *jobs, *tail),
+        Some(Command::Calibrate) => {
+            let audio = sdl2::init()?.audio()?;
+            let output = output_device(&audio, args.output_device.as_deref(), args.outputs)?;
+            return Ok(calibrate::run(&audio, output.as_deref(), args.outputs)?);
+        }
+        Some(Command::MeasureLatency {
+            input_device,
+            clicks,
+        }) => {
+            let audio = sdl2::init()?.audio()?;
+            let output = output_device(&audio, args.output_device.as_deref(), args.outputs)?;
+            return Ok(latency::measure(
+                &audio,
+                output.as_deref(),
+                args.outputs,
+                input_device.as_deref(),
+                *clicks,
+            )?);
+        }
+        None => {}
+    }
 
+    if args.list_outputs {
+        return list_outputs();
+    }
     if args.midi_list {
         let input = midir::MidiInput::new("synthtoy")?;
         for port in input.ports() {
@@ -41,61 +659,553 @@ fn main() -> Result<(), Error> {
         }
         return Ok(());
     }
-    run(args)
+    let nodes = given_nodes(&matches);
+    run(args, nodes)
+}
+
+/// A length of time in milliseconds, which can't be negative or too long
+/// for a [`Duration`].
+fn millis(value: &str) -> Result<f32, String> {
+    value
+        .trim()
+        .parse::<f32>()
+        .ok()
+        .filter(|ms| Duration::try_from_secs_f32(ms / 1000.).is_ok())
+        .ok_or_else(|| format!("{value:?} isn't a number of milliseconds"))
+}
+
+/// The sources and effects given on the command line, as patch entries.
+fn given_nodes(matches: &ArgMatches) -> Vec<patch::Entry> {
+    patch::NODES
+        .into_iter()
+        .filter(|&key| matches.value_source(key) == Some(ValueSource::CommandLine))
+        .filter_map(|key| {
+            let raw = matches.get_raw(key)?.next()?.to_str()?;
+            patch::node_entry(key, raw)
+        })
+        .collect()
+}
+
+/// Loads a patch, printing everything wrong with it if it isn't valid.
+fn load_patch(path: &Path) -> Result<Patch, Error> {
+    let text = std::fs::read_to_string(path)?;
+    let (patch, errors) = match Patch::read(path, &text) {
+        Ok(patch) => {
+            let errors = patch.validate();
+            (Some(patch), errors)
+        }
+        Err(errors) => (None, errors),
+    };
+    for error in &errors {
+        println!("{}: {error}", path.display());
+    }
+    if let Some(patch) = patch.as_ref().filter(|p| p.written_for < patch::VERSION) {
+        println!(
+            "{}: written for version {}, read as version {}",
+            path.display(),
+            patch.written_for,
+            patch::VERSION
+        );
+    }
+    match patch {
+        Some(patch) if errors.is_empty() => Ok(patch),
+        _ => Err(format!("{} problem(s) in {}", errors.len(), path.display()).into()),
+    }
+}
+
+fn validate_patch(path: &Path) -> Result<(), Error> {
+    load_patch(path)?;
+    println!("{}: ok", path.display());
+    Ok(())
+}
+
+fn render(
+    midi: &Path,
+    wav: &Path,
+    patch: Option<&Path>,
+    tail: f32,
+    per_voice: bool,
+) -> Result<(), Error> {
+    let patch = patch.map(load_patch).transpose()?.unwrap_or_default();
+    let job = render::Job {
+        midi: midi.to_path_buf(),
+        wav: wav.to_path_buf(),
+    };
+    render::run_job(&job, &patch, tail)?;
+    if per_voice {
+        for path in render::run_voices(&job, &patch, tail)? {
+            println!("{}", path.display());
+        }
+    }
+    Ok(())
+}
+
+fn render_batch(
+    input: &Path,
+    patch: Option<&Path>,
+    out: &Path,
+    jobs: Option<usize>,
+    tail: f32,
+) -> Result<(), Error> {
+    let patch = patch.map(load_patch).transpose()?.unwrap_or_default();
+    let work = render::find_jobs(input, out)?;
+    let threads =
+        jobs.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let failed = render::render_batch(&work, &patch, threads, tail)
+        .iter()
+        .filter(|r| r.is_err())
+        .count();
+    if failed > 0 {
+        return Err(format!("{failed} of {} files failed to render", work.len()).into());
+    }
+    Ok(())
+}
+
+/// Saves `nodes` with `params` as a new patch in the current directory.
+fn save_patch(nodes: Vec<patch::Entry>, params: &[(String, f32)]) {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |t| t.as_secs());
+    let path = PathBuf::from(format!("synthtoy-{secs}.toml"));
+    let patch = Patch::from_state(nodes, params);
+    match std::fs::write(&path, patch.to_string()) {
+        Ok(()) => println!("patch: saved to {}", path.display()),
+        Err(e) => println!("patch: {}: {e}", path.display()),
+    }
+}
+
+/// Finds the device `name` picks, and checks it has the outputs asked for,
+/// so SDL doesn't quietly mix them down to what it does have.
+fn output_device(
+    audio: &sdl2::AudioSubsystem,
+    name: Option<&str>,
+    map: OutputMap,
+) -> Result<Option<String>, Error> {
+    let devices = outputs::probe(audio);
+    let device = match name {
+        Some(name) => Some(
+            devices
+                .iter()
+                .find(|d| d.name.starts_with(name))
+                .ok_or_else(|| format!("no output device {name:?}, see --list-outputs"))?,
+        ),
+        None => None,
+    };
+    let needed = map.needed();
+    if let Some(device) = device {
+        if let Some(channels) = device.channels.filter(|&c| c < needed) {
+            return Err(
+                format!("{} only has {channels} outputs, not {needed}", device.name).into(),
+            );
+        }
+    }
+    Ok(device.map(|d| d.name.clone()))
+}
+
+fn list_outputs() -> Result<(), Error> {
+    let audio = sdl2::init()?.audio()?;
+    for device in outputs::probe(&audio) {
+        match device.channels {
+            Some(channels) => println!("{}: {channels} channels", device.name),
+            None => println!("{}: unknown channels", device.name),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "jack")]
+fn jack_backend(midi: Option<queue::Sender<AudioEvent>>) -> Result<Backend, Error> {
+    Ok(Backend::Jack { midi })
 }
 
-fn run(args: Args) -> Result<(), Error> {
+#[cfg(not(feature = "jack"))]
+fn jack_backend(_midi: Option<queue::Sender<AudioEvent>>) -> Result<Backend, Error> {
+    Err("this build has no JACK support, rebuild with --features jack".into())
+}
+
+/// `nodes` are the sources and effects given on the command line, which
+/// the patch replaces if there is one.
+fn run(args: Args, mut nodes: Vec<patch::Entry>) -> Result<(), Error> {
+    let session_path = session::last_session_path();
+    let patch = args.patch.as_deref().map(load_patch).transpose()?;
+    let session = if args.restore_last_session {
+        let path = session_path
+            .as_deref()
+            .ok_or("nowhere to find the last session without $HOME")?;
+        Some(Session::load(path)?)
+    } else {
+        None
+    };
+    // what the last session had become goes in place of the patch
+    let restored = session.as_ref().map(Session::patch);
+    let starting = restored.as_ref().or(patch.as_ref());
+    let restore = starting.map_or(Vec::new(), Patch::param_values);
+
     let ctx = sdl2::init().unwrap();
     let audio = ctx.audio().unwrap();
-    let video = ctx.video().unwrap();
-    let event = ctx.event()?;
-    event.register_custom_event::<MidiEvent>()?;
-    let mut pump = ctx.event_pump().unwrap();
-    pump.enable_event(EventType::KeyDown);
+    let plucks_input = starting
+        .map_or(&nodes, |p| &p.nodes)
+        .iter()
+        .any(|e| e.key == "exciter" && e.value == patch::Value::String("input".to_string()));
+    // keeps capturing until we return
+    let _capture = if plucks_input || args.input_device.is_some() {
+        Some(input::open(&audio, args.input_device.as_deref())?)
+    } else {
+        None
+    };
+    // the window is there to take keys, which a headless run goes without
+    let ui = if args.headless {
+        signal::install();
+        None
+    } else {
+        let video = ctx.video().unwrap();
+        let event = ctx.event()?;
+        event.register_custom_event::<MidiEvent>()?;
+        event.register_custom_event::<EngineError>()?;
+        let mut pump = ctx.event_pump().unwrap();
+        pump.enable_event(EventType::KeyDown);
+        pump.enable_event(EventType::KeyUp);
+
+        let win = video.window("synthtoy", 200, 200);
+        let mut win = win.build().unwrap();
+        win.show();
+        Some((event, pump, win))
+    };
+    let song = args.play.as_deref().map(smf::load).transpose()?;
+    if args.headless
+        && args.midi_device.is_none()
+        && !args.jack_midi
+        && song.is_none()
+        && args.siggen.is_none()
+        && args.osc_port.is_none()
+    {
+        println!(
+            "nothing to play notes with: --headless wants --midi-device, --jack-midi, --osc-port or --play"
+        );
+    }
+
+    // MIDI can't wait for room in the queue, but the UI can
+    let (send_midi, recv_audio) = queue::bounded(args.queue.max(1), args.overflow);
+    let send_audio = send_midi.blocking();
 
-    let (send_audio, recv_audio) = mpsc::channel();
+    let mut cc_map = CcMap::general_midi();
+    if let Some(path) = &args.cc_map {
+        cc_map.load(path)?;
+    }
+    for mapping in args.cc {
+        cc_map.insert(mapping);
+    }
+
+    // the sources and effects, from the patch or the command line
+    let nodes_config = match starting {
+        Some(patch) => {
+            nodes = patch.nodes.clone();
+            // it has been validated already
+            patch
+                .audio_config()
+                .map_err(|errors| errors[0].to_string())?
+        }
+        None => AudioConfig {
+            voices: match args.wave_position {
+                Some(env) => args.synth.with_position(env)?,
+                None => args.synth,
+            }
+            .build_voices(args.voices.max(1), &*args.exciter.build()?, args.seed)?,
+            distortion: args
+                .distortion
+                .map(|curve| Waveshaper::new(curve, args.oversample)),
+            ladder: args.ladder,
+            fir: args
+                .fir
+                .map(|path| FIR::load(&path, args.fir_taps.max(1), args.fir_window))
+                .transpose()?,
+            delay: args.delay,
+            ping_pong: args.ping_pong,
+            bpm: args.bpm,
+            reverb: args.reverb,
+            convolution: args
+                .ir
+                .map(|path| ConvolutionReverb::load(&path, 0.3))
+                .transpose()?,
+            siggen: args.siggen.map(Siggen::new),
+            sidechain: args.sidechain,
+            order: args.chain.unwrap_or_default(),
+            limiter: args.limiter,
+            tuning: {
+                let mut tuning = args
+                    .tuning
+                    .map(|path| Tuning::load(&path, args.keymap.as_deref()))
+                    .transpose()?
+                    .unwrap_or_default();
+                tuning.set_reference(args.a4, args.transpose)?;
+                tuning
+            },
+            nodes: nodes.clone(),
+            ..AudioConfig::default()
+        },
+    };
+    let clock = Clock::new(Instant::now(), nodes_config.bpm);
+    // set by the audio thread, once a recording has really started
+    let recording = Arc::new(AtomicBool::new(false));
+    let audio_config = AudioConfig {
+        bend_range: args.bend_range,
+        cc_map,
+        key_switch_base: args.key_switches,
+        mpe: if args.mpe { 15 } else { 0 },
+        chord: args.chord,
+        scale: args.scale,
+        strum: args.strum.map(|ms| StrumConfig {
+            time: Duration::from_secs_f32(ms / 1000.),
+            direction: args.strum_direction,
+            humanize: args.strum_humanize,
+            window: Duration::from_millis(30),
+        }),
+        pressure: args.pressure.map(|target| PressureConfig {
+            target,
+            smoothing: Duration::from_secs_f32(args.pressure_smoothing / 1000.),
+            release: Duration::from_secs_f32(
+                args.pressure_release.unwrap_or(args.pressure_smoothing) / 1000.,
+            ),
+        }),
+        key_pressure: args.key_pressure,
+        solo: args.solo.and_then(|voice| voice.checked_sub(1)),
+        tap_voices: args.tap_voices,
+        lfos: session.as_ref().map_or(args.lfo, |s| s.lfos.clone()),
+        mods: session.as_ref().map_or(args.mods, |s| s.mods.clone()),
+        expression: ExpressionConfig {
+            target: args.expression,
+            response: args.expression_curve,
+            calibration: args.expression_range.unwrap_or_default(),
+        },
+        restore,
+        record: args.record,
+        record_wav: args.record_wav.clone(),
+        recording: recording.clone(),
+        shed: args.shed,
+        fade: Duration::from_secs_f32(args.fade / 1000.),
+        output_gain: calibrate::load_gain(),
+        patterns: match &session {
+            Some(session) => session.patterns.clone(),
+            // ones that aren't there yet get saved there
+            None => args
+                .sequence
+                .iter()
+                .map(|path| {
+                    if path.exists() {
+                        Pattern::load(path)
+                    } else {
+                        Ok(Pattern::default())
+                    }
+                })
+                .collect::<Result<_, _>>()?,
+        },
+        sequences: args.sequence,
+        song: session
+            .as_ref()
+            .map_or(args.song.unwrap_or_default(), |s| s.song.clone()),
+        session: session_path,
+        log: match &args.log {
+            Some(path) => {
+                let log = EventLog::create(path, clock.engine())
+                    .map_err(|e| format!("can't log to {}: {e}", path.display()))?;
+                let patch = args.patch.as_deref().map(Path::to_string_lossy);
+                log.log(
+                    clock.engine().now(),
+                    "start",
+                    &[("patch", patch.as_deref().map_or(Field::Null, Field::Text))],
+                );
+                Some(log)
+            }
+            None => None,
+        },
+        clock: clock.clone(),
+        watch: args
+            .patch
+            .clone()
+            .zip(patch)
+            .map(|(path, patch)| Watcher::new(path, patch)),
+        ..nodes_config
+    };
+
+    let snapshots = Arc::new(Snapshots::default());
 
-    let win = video.window("synthtoy", 200, 200);
-    let mut win = win.build().unwrap();
-    win.show();
+    let backend = if args.jack {
+        jack_backend(args.jack_midi.then(|| send_midi.clone()))?
+    } else {
+        let outputs = OutputMap {
+            click: args.click_output,
+            ..args.outputs
+        };
+        Backend::Sdl {
+            device: output_device(&audio, args.output_device.as_deref(), outputs)?,
+            audio: AudioSubsystemCrimesWrapper(audio),
+            outputs,
+        }
+    };
 
-    let _audio_thread = {
-        let crime = AudioSubsystemCrimesWrapper(audio);
+    let engine_thread = {
+        let snapshots = snapshots.clone();
+        let report: Reporter = match &ui {
+            Some((event, ..)) => {
+                let sender = event.event_sender();
+                Arc::new(move |e| {
+                    // nothing better to do if the UI has gone away
+                    let _ = sender.push_custom_event(e);
+                })
+            }
+            None => Arc::new(|e| println!("audio: {e}")),
+        };
         std::thread::spawn(move || {
-            audio_thread::audio_thread(crime, recv_audio);
-        });
+            audio_thread::audio_thread(backend, audio_config, snapshots, report, recv_audio);
+        })
+    };
+
+    if args.record_wav.is_some() {
+        send_audio.send(AudioEvent::ToggleWavRecording)?;
+    }
+
+    if args.console {
+        console::spawn(send_audio.clone(), clock.engine());
+    }
+    if let Some(port) = args.osc_port {
+        osc::spawn(port, send_audio.clone(), clock.engine())
+            .map_err(|e| format!("can't listen for OSC on port {port}: {e}"))?;
+    }
+
+    if let Some(song) = song {
+        let send_midi = send_midi.clone();
+        let engine = clock.engine();
+        std::thread::spawn(move || smf::play(&song, &send_midi, engine));
+    }
+
+    let engine = clock.engine();
+    let _midi = args
+        .midi_device
+        .map(move |d| initialize_midi(d, send_midi, engine))
+        .transpose()?;
+
+    let quit = || -> Result<(), Error> {
+        send_audio.send(AudioEvent::Terminate)?;
+        // it has a recording and the session to save on the way out
+        engine_thread
+            .join()
+            .map_err(|_| "the audio thread panicked")?;
+        Ok(())
     };
 
-    let _midi = args.midi_device.map({
-        let send_audio = send_audio.clone();
-        move |d| initialize_midi(d, send_audio)
-    });
+    let Some((_event, mut pump, mut win)) = ui else {
+        signal::wait();
+        return quit();
+    };
+
+    let mut key_velocity = KeyVelocity::new(args.key_velocity);
+    // the note each key is playing, which the modifiers held when it went
+    // down decided
+    let mut held_keys: HashMap<Keycode, f32> = HashMap::new();
+    // SDL stamps key presses in milliseconds since it started
+    let mut keys = DriverClock::new(clock.engine());
+    let mut title = String::new();
 
     loop {
-        let ev = pump.wait_event();
+        // the clock in the title moves on even with nothing happening
+        let now = format!(
+            "synthtoy {}{}",
+            clock.now(),
+            if recording.load(Ordering::Relaxed) {
+                " (recording)"
+            } else {
+                ""
+            }
+        );
+        if now != title {
+            win.set_title(&now)?;
+            title = now;
+        }
+        let Some(ev) = pump.wait_event_timeout(100) else {
+            continue;
+        };
         match &ev {
             Event::Quit { .. } => {
+                quit()?;
                 break;
             }
+            ev if ev.is_user_event() => {
+                if let Some(err) = ev.as_user_event_type::<EngineError>() {
+                    println!("audio: {err}");
+                }
+            }
+            Event::KeyUp {
+                timestamp,
+                keycode: Some(keycode),
+                ..
+            } => {
+                if let Some(freq) = held_keys.remove(keycode) {
+                    let now = keys.stamp(*timestamp as u64 * 1000, Instant::now());
+                    key_velocity.key_up(now);
+                    send_audio.send(AudioEvent::KeyUp(freq, now))?;
+                }
+            }
             Event::KeyDown {
+                timestamp,
                 keycode: Some(keycode),
+                keymod,
+                repeat,
                 ..
             } => match keycode {
-                Keycode::O => {}
-                Keycode::I => {}
+                Keycode::O => send_audio.send(AudioEvent::Sequencer(Control::Save))?,
+                Keycode::I => send_audio.send(AudioEvent::Sequencer(Control::StepEntry))?,
                 Keycode::Q => {
-                    send_audio.send(AudioEvent::Terminate).unwrap();
+                    quit()?;
                     break;
                 }
-                Keycode::G => {}
-                Keycode::S => {
-                    // let lock = dev.lock();
-                    // lock.0.synth.snoop.save().unwrap();
-                    // lock.0.snoop.save().unwrap();
+                Keycode::G => send_audio.send(AudioEvent::Sequencer(Control::PlayStop))?,
+                Keycode::A => send_audio.send(AudioEvent::Sequencer(Control::Rest))?,
+                Keycode::H => send_audio.send(AudioEvent::Sequencer(Control::SongMode))?,
+                Keycode::J if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) => {
+                    send_audio.send(AudioEvent::Sequencer(Control::ClearSong))?
+                }
+                Keycode::J => send_audio.send(AudioEvent::Sequencer(Control::Chain))?,
+                k if PATTERN_KEYS.contains(k) => {
+                    let idx = PATTERN_KEYS.iter().position(|p| p == k).unwrap_or(0);
+                    send_audio.send(AudioEvent::Sequencer(Control::Select(idx)))?
                 }
+                Keycode::L => send_audio.send(AudioEvent::ToggleLatch)?,
+                Keycode::E => send_audio.send(AudioEvent::LearnExpression)?,
+                Keycode::R => send_audio.send(AudioEvent::SaveRecording)?,
+                Keycode::W => send_audio.send(AudioEvent::ToggleSnoops)?,
+                Keycode::D => send_audio.send(AudioEvent::ToggleDry)?,
+                Keycode::T => send_audio.send(AudioEvent::ToggleClick)?,
+                Keycode::Y => send_audio.send(AudioEvent::Console(Console::Randomize(None)))?,
+                Keycode::U => send_audio.send(AudioEvent::Console(Console::Mutate(
+                    None,
+                    random::DEFAULT_MUTATION,
+                )))?,
+                Keycode::Backspace => send_audio.send(AudioEvent::Console(Console::Undo))?,
+                Keycode::Space => send_audio.send(AudioEvent::ReleaseAll)?,
+                Keycode::P => match snapshots.take(Duration::from_millis(500)) {
+                    Some(snapshot) => print!("{snapshot}"),
+                    None => println!("timed out waiting for a snapshot"),
+                },
+                Keycode::K => match snapshots.take(Duration::from_millis(500)) {
+                    Some(snapshot) => {
+                        // the patch may have been edited since it was loaded
+                        let edited = args.patch.as_deref().and_then(|path| {
+                            let text = std::fs::read_to_string(path).ok()?;
+                            Patch::read(path, &text).ok()
+                        });
+                        let nodes = edited.map_or_else(|| nodes.clone(), |p| p.nodes);
+                        save_patch(nodes, &snapshot.params);
+                    }
+                    None => println!("timed out waiting for a snapshot"),
+                },
+                Keycode::S => send_audio.send(AudioEvent::ToggleWavRecording)?,
                 &k => {
-                    if let Some(n) = key_to_freq(k) {
-                        send_audio.send(AudioEvent::PlayNote(n))?;
+                    if let (Some(n), false) = (key_to_freq(k), repeat) {
+                        let now = keys.stamp(*timestamp as u64 * 1000, Instant::now());
+                        let velocity = key_velocity.key_down(now);
+                        let (n, velocity) = keyboard::modify(*keymod, n, velocity);
+                        held_keys.insert(k, n);
+                        send_audio.send(AudioEvent::KeyDown(n, velocity, now))?;
                     }
                 }
             },
@@ -0,0 +1,236 @@
+//! A click to play along to, mixed in after the effects so they leave it
+//! alone, or sent to an output of its own, and loudest on the first beat of
+//! the bar. It clicks when it's told a beat has come, by [`Beats`] on the
+//! control side, so it stays in time with the session clock or MIDI clock
+//! rather than keeping its own.
+
+use std::f32::consts::TAU;
+
+use crate::clock::{Clock, Stamp, BEATS_PER_BAR};
+use crate::filters::{Bus, SAMPLING_FREQ};
+use crate::params::{ParamInfo, Params};
+use crate::stereo::StereoFilter;
+
+const DOWNBEAT_HZ: f32 = 1500.;
+const BEAT_HZ: f32 = 1000.;
+/// How loud the other beats are next to the first.
+const BEAT_LEVEL: f32 = 0.5;
+/// How long a click takes to die down by 60dB.
+const DECAY_SECS: f32 = 0.03;
+
+/// Clicks on each beat set as its "click" parameter, counted from a bar
+/// line, while it's on.
+pub struct Metronome {
+    pub on: bool,
+    /// beats to the bar
+    pub meter: u32,
+    pub level: f32,
+    /// where the click goes instead of the mix, if it has an output of its
+    /// own
+    pub out: Option<Bus>,
+
+    /// which beat of the bar it's on
+    beat: u32,
+    /// how far into the click, while one is sounding
+    click: Option<u32>,
+}
+
+impl Default for Metronome {
+    fn default() -> Self {
+        Self {
+            on: false,
+            meter: BEATS_PER_BAR as u32,
+            level: 0.3,
+            out: None,
+            beat: 0,
+            click: None,
+        }
+    }
+}
+
+impl Metronome {
+    /// Clicks for the beat `beats` after a bar line, from the next sample.
+    pub fn click(&mut self, beats: u64) {
+        if self.on {
+            self.beat = (beats % self.meter.max(1) as u64) as u32;
+            self.click = Some(0);
+        }
+    }
+
+    fn next(&mut self) -> f32 {
+        let Some(n) = self.click else { return 0. };
+        let t = n as f32 / SAMPLING_FREQ as f32;
+        if t > DECAY_SECS {
+            self.click = None;
+            return 0.;
+        }
+        self.click = Some(n + 1);
+        let (hz, level) = if self.beat == 0 {
+            (DOWNBEAT_HZ, 1.)
+        } else {
+            (BEAT_HZ, BEAT_LEVEL)
+        };
+        // -60dB by the end
+        let envelope = (-6.9 * t / DECAY_SECS).exp();
+        (TAU * hz * t).sin() * envelope * level * self.level
+    }
+}
+
+impl StereoFilter for Metronome {
+    fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) {
+        if !self.on {
+            return;
+        }
+        if let Some(out) = self.out.take() {
+            out.fill(left.len(), || self.next());
+            self.out = Some(out);
+            return;
+        }
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            let s = self.next();
+            *l += s;
+            *r += s;
+        }
+    }
+}
+
+impl Params for Metronome {
+    fn params(&self) -> Vec<ParamInfo> {
+        vec![
+            ParamInfo::new("on", 0., 1.).not_random(),
+            ParamInfo::new("meter", 1., 16.).not_random(),
+            ParamInfo::new("level", 0., 1.).not_random(),
+            // set rather than turned, at the moment each beat comes
+            ParamInfo::new("click", 0., f32::MAX).not_random(),
+        ]
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        Some(match name {
+            "on" => self.on as u8 as f32,
+            "meter" => self.meter as f32,
+            "level" => self.level,
+            "click" => self.beat as f32,
+            _ => return None,
+        })
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name {
+            "on" => self.on = value >= 0.5,
+            "click" => self.click(value.max(0.) as u64),
+            "meter" => self.meter = value.round().clamp(1., 16.) as u32,
+            "level" => self.level = value.clamp(0., 1.),
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Tells the [`Metronome`] when each beat of the session clock comes, for
+/// while nothing else is keeping time. MIDI clock, while it's playing, says
+/// when its beats come itself.
+#[derive(Debug, Default)]
+pub struct Beats {
+    /// when the next one is, while counting
+    next: Option<Stamp>,
+}
+
+impl Beats {
+    /// Counts from the next beat on `clock` after `now`.
+    pub fn start(&mut self, clock: &Clock, now: Stamp) {
+        self.next = Some(clock.next(now, 1));
+    }
+
+    pub fn stop(&mut self) {
+        self.next = None;
+    }
+
+    /// When [`Beats::poll`] next has something to do.
+    pub fn next_deadline(&self) -> Option<Stamp> {
+        self.next
+    }
+
+    /// The beat that has come by `now`, if one has: when it was, and how
+    /// many beats after the bar line the clock's meter started at.
+    pub fn poll(&mut self, clock: &Clock, now: Stamp) -> Option<(Stamp, u64)> {
+        let at = self.next.filter(|at| *at <= now)?;
+        // from halfway to the next, so the one just gone isn't found again
+        self.next = Some(clock.next(at + clock.beat() / 2, 1));
+        Some((at, clock.since_downbeat(at)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_metronome() {
+        let mut metronome = Metronome::default();
+        let len = SAMPLING_FREQ / 2;
+        let (mut left, mut right) = (vec![0.; len * 4], vec![0.; len * 4]);
+        metronome.set_param("click", 0.);
+        metronome.process_stereo(&mut left, &mut right);
+        assert!(left.iter().all(|s| *s == 0.));
+
+        metronome.set_param("meter", 3.);
+        metronome.set_param("on", 1.);
+        // a beat every half second, from the seventh
+        for n in 0..4 {
+            let block = n * len..(n + 1) * len;
+            metronome.set_param("click", (n + 6) as f32);
+            metronome.process_stereo(&mut left[block.clone()], &mut right[block]);
+        }
+        assert_eq!(left, right);
+        let peak = |n: usize| {
+            left[n * len..(n + 1) * len]
+                .iter()
+                .fold(0f32, |peak, s| peak.max(s.abs()))
+        };
+        // the bar starts again on the fourth beat
+        let (downbeat, beat) = (peak(0), peak(1));
+        assert!(downbeat > 0.2 && beat > 0.1, "{downbeat} {beat}");
+        assert!((peak(2) - beat).abs() < 0.01);
+        assert!((peak(3) - downbeat).abs() < 0.01);
+        assert!(downbeat > beat * 1.5);
+
+        // on its own output, leaving the mix alone
+        let bus = Bus::default();
+        metronome.out = Some(bus.clone());
+        let (mut left, mut right) = (vec![0.; len], vec![0.; len]);
+        metronome.set_param("click", 0.);
+        metronome.process_stereo(&mut left, &mut right);
+        assert!(left.iter().chain(&right).all(|s| *s == 0.));
+        let mut click = vec![0.; len];
+        bus.take(&mut click);
+        assert!(click.iter().fold(0f32, |peak, s| peak.max(s.abs())) > 0.2);
+    }
+
+    #[test]
+    fn test_beats() {
+        let start = Stamp::default();
+        let ms = |n| start + Duration::from_millis(n);
+        let clock = Clock::new(Instant::now(), 120.);
+        let mut beats = Beats::default();
+        beats.start(&clock, ms(100));
+        assert_eq!(beats.next_deadline(), Some(ms(500)));
+        assert_eq!(beats.poll(&clock, ms(400)), None);
+        assert_eq!(beats.poll(&clock, ms(510)), Some((ms(500), 1)));
+        assert_eq!(beats.next_deadline(), Some(ms(1000)));
+        // at the new tempo from where it changed
+        clock.set_bpm(ms(1000), 60.);
+        assert_eq!(beats.poll(&clock, ms(1000)), Some((ms(1000), 2)));
+        assert_eq!(beats.next_deadline(), Some(ms(2000)));
+        // a song in 3/4 starting halfway through the beat
+        clock.set_meter(ms(2500), 3);
+        beats.start(&clock, ms(2500));
+        assert_eq!(beats.poll(&clock, ms(2500)), Some((ms(2500), 0)));
+        assert_eq!(beats.poll(&clock, ms(3500)), Some((ms(3500), 1)));
+        assert_eq!(beats.poll(&clock, ms(4500)), Some((ms(4500), 2)));
+        assert_eq!(beats.poll(&clock, ms(5500)), Some((ms(5500), 3)));
+        beats.stop();
+        assert_eq!(beats.next_deadline(), None);
+    }
+}
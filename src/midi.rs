@@ -1,10 +1,16 @@
-use std::sync::mpsc;
+use std::{collections::HashMap, path::Path, time::Instant};
 
 use midir::MidiInputConnection;
 
-use crate::{audio_thread::AudioEvent, Error};
+use crate::{
+    audio_thread::AudioEvent,
+    clock::{DriverClock, EngineClock, Stamp},
+    params::Params,
+    queue,
+    tuning::Retuning,
+    Error,
+};
 
-#[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub enum MidiDevice {
     Named(String),
@@ -15,34 +21,107 @@ impl std::str::FromStr for MidiDevice {
     type Err = String;
     fn from_str(value: &str) -> Result<Self, String> {
         Ok(match value {
-            "virtual" => todo!("virtual midi devices not yet supported"), //MidiDevice::Virtual,
+            "virtual" => MidiDevice::Virtual,
             _ => MidiDevice::Named(value.to_string()),
         })
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct MidiEvent {
-    pub timestamp: u64,
+    /// When it happened on the engine clock, or into the song for ones
+    /// from a MIDI file.
+    pub at: Stamp,
     pub channel: u8,
     pub inner: MidiEventInner,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum MidiEventInner {
-    Down { velocity: u8, note: u8 },
-    Up { velocity: u8, note: u8 },
-    KeyPressure { key: u8, pressure: u8 },
+    Down {
+        velocity: u8,
+        note: u8,
+    },
+    Up {
+        velocity: u8,
+        note: u8,
+    },
+    KeyPressure {
+        key: u8,
+        pressure: u8,
+    },
+    /// Aftertouch for the whole channel.
+    ChannelPressure(u8),
+    ControlChange {
+        controller: u8,
+        value: u8,
+    },
+    /// 14 bit bend amount, centered on [`PITCH_BEND_CENTER`]
     PitchBend(u16),
+    /// A MIDI Tuning Standard SysEx message, which isn't on any channel.
+    Tuning(Retuning),
+    /// A MIDI clock tick, [`crate::clock::TICKS_PER_BEAT`] to the beat.
+    Clock,
+    /// The song starting over from the top.
+    Start,
+    /// The song carrying on from where it stopped.
+    Continue,
+    Stop,
+    /// Song Position Pointer: where the song is, in sixteenth notes from
+    /// the top, for carrying on from there.
+    SongPosition(u16),
+}
+
+pub const PITCH_BEND_CENTER: u16 = 0x2000;
+
+impl MidiEvent {
+    /// The raw message, as [`parse_midi`] would read it back.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let status = |cmd: u8| cmd << 4 | self.channel & 0xf;
+        match self.inner {
+            MidiEventInner::Up { velocity, note } => vec![status(0x8), note, velocity],
+            MidiEventInner::Down { velocity, note } => vec![status(0x9), note, velocity],
+            MidiEventInner::KeyPressure { key, pressure } => vec![status(0xa), key, pressure],
+            MidiEventInner::ControlChange { controller, value } => {
+                vec![status(0xb), controller, value]
+            }
+            MidiEventInner::ChannelPressure(value) => vec![status(0xd), value],
+            MidiEventInner::PitchBend(bend) => {
+                vec![status(0xe), (bend & 0x7f) as u8, (bend >> 7) as u8]
+            }
+            MidiEventInner::Tuning(ref retuning) => retuning.to_sysex(),
+            MidiEventInner::Clock => vec![0xf8],
+            MidiEventInner::Start => vec![0xfa],
+            MidiEventInner::SongPosition(at) => vec![0xf2, (at & 0x7f) as u8, (at >> 7) as u8],
+            MidiEventInner::Continue => vec![0xfb],
+            MidiEventInner::Stop => vec![0xfc],
+        }
+    }
+}
+
+/// Converts a raw pitch bend value into semitones given the bend range.
+pub fn pitch_bend_semitones(bend: u16, range: f32) -> f32 {
+    (bend as f32 - PITCH_BEND_CENTER as f32) / PITCH_BEND_CENTER as f32 * range
 }
 
-pub fn parse_midi(timestamp: u64, midi: &[u8]) -> Option<MidiEvent> {
-    let byte0 = midi[0];
+/// Reads a raw message, or nothing if it's one that isn't used here or
+/// it's too short for what it says it is.
+pub fn parse_midi(at: Stamp, midi: &[u8]) -> Option<MidiEvent> {
+    let &byte0 = midi.first()?;
     let cmd = (byte0 & 0xf0) >> 4;
-    let channel = byte0 & 0xf;
+    // system messages aren't on a channel at all
+    let channel = if cmd == 0xf { 0 } else { byte0 & 0xf };
+    let len = match (cmd, byte0) {
+        (0x8 | 0x9 | 0xa | 0xb | 0xe, _) | (_, 0xf2) => 3,
+        (0xd, _) => 2,
+        _ => 1,
+    };
+    if midi.len() < len {
+        return None;
+    }
 
     Some(MidiEvent {
-        timestamp,
+        at,
         channel,
         inner: match cmd {
             0x8 => MidiEventInner::Up {
@@ -57,12 +136,19 @@ pub fn parse_midi(timestamp: u64, midi: &[u8]) -> Option<MidiEvent> {
                 key: midi[1],
                 pressure: midi[2],
             },
-            0xe => MidiEventInner::PitchBend(u16::from_le_bytes([midi[1], midi[2]])),
+            0xb => MidiEventInner::ControlChange {
+                controller: midi[1],
+                value: midi[2],
+            },
+            0xd => MidiEventInner::ChannelPressure(midi[1]),
+            0xe => MidiEventInner::PitchBend((midi[2] as u16) << 7 | midi[1] as u16),
             0xf => match byte0 {
-                0xf8 => {
-                    // timing tick
-                    return None;
-                }
+                0xf0 => MidiEventInner::Tuning(Retuning::from_sysex(midi)?),
+                0xf2 => MidiEventInner::SongPosition((midi[2] as u16) << 7 | midi[1] as u16),
+                0xf8 => MidiEventInner::Clock,
+                0xfa => MidiEventInner::Start,
+                0xfb => MidiEventInner::Continue,
+                0xfc => MidiEventInner::Stop,
                 n => {
                     println!("unk sys command {n}");
                     return None;
@@ -76,39 +162,252 @@ pub fn parse_midi(timestamp: u64, midi: &[u8]) -> Option<MidiEvent> {
     })
 }
 
+/// Where a CC gets routed to. `range` overrides the parameter's own range.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CcTarget {
+    pub param: String,
+    pub range: Option<(f32, f32)>,
+}
+
+impl CcTarget {
+    /// Sets the parameter from a controller position in 0..=1.
+    pub fn apply(&self, value: f32, target: &mut impl Params) -> bool {
+        let Some(mut info) = target.param_info(&self.param) else {
+            println!("controller mapped to unknown parameter {}", self.param);
+            return false;
+        };
+        if let Some((min, max)) = self.range {
+            info.min = min;
+            info.max = max;
+        }
+        target.set_param(&self.param, info.denormalize(value))
+    }
+}
+
+/// `<param>[:<min>..<max>]`
+impl std::str::FromStr for CcTarget {
+    type Err = String;
+    fn from_str(target: &str) -> Result<Self, String> {
+        let (param, range) = match target.split_once(':') {
+            Some((param, range)) => {
+                let parse = |v: &str| {
+                    v.trim()
+                        .parse::<f32>()
+                        .map_err(|e| format!("bad range {range:?}: {e}"))
+                };
+                let (min, max) = range
+                    .split_once("..")
+                    .ok_or_else(|| format!("expected <min>..<max>, got {range:?}"))?;
+                (param, Some((parse(min)?, parse(max)?)))
+            }
+            None => (target, None),
+        };
+
+        Ok(CcTarget {
+            param: param.trim().to_string(),
+            range,
+        })
+    }
+}
+
+/// A single `<cc>=<param>[:<min>..<max>]` mapping, as accepted by `--cc` and
+/// in CC map files.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CcMapping(pub u8, pub CcTarget);
+
+impl std::str::FromStr for CcMapping {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        let (cc, target) = value
+            .split_once('=')
+            .ok_or_else(|| format!("expected <cc>=<param>, got {value:?}"))?;
+        let cc = cc
+            .trim()
+            .parse::<u8>()
+            .ok()
+            .filter(|&cc| cc < 128)
+            .ok_or_else(|| format!("bad controller number {cc:?}"))?;
+
+        Ok(CcMapping(cc, target.parse()?))
+    }
+}
+
+/// Routes MIDI CC numbers onto named synth parameters.
+#[derive(Clone, Debug, Default)]
+pub struct CcMap(pub HashMap<u8, CcTarget>);
+
+impl CcMap {
+    /// The General MIDI 2 sound controllers, mod wheel and sustain pedal,
+    /// mapped onto whatever is closest.
+    pub fn general_midi() -> Self {
+        let mut map = CcMap::default();
+        for (cc, param) in [
+            (1, "vibrato.depth"),
+            (7, "volume"),
+            (10, "pan.position"),
+            (64, "sustain"),
+            (71, "ladder.resonance"),
+            (72, "env.release"),
+            (73, "env.attack"),
+            (74, "damping.brightness"),
+            (91, "reverb.mix"),
+        ] {
+            map.insert(CcMapping(
+                cc,
+                CcTarget {
+                    param: param.to_string(),
+                    range: None,
+                },
+            ));
+        }
+        map
+    }
+
+    pub fn insert(&mut self, CcMapping(cc, target): CcMapping) {
+        self.0.insert(cc, target);
+    }
+
+    /// Loads mappings from a file with one `<cc>=<param>[:<min>..<max>]` per
+    /// line. Blank lines and lines starting with `#` are ignored.
+    pub fn load(&mut self, path: &Path) -> Result<(), Error> {
+        let contents = std::fs::read_to_string(path)?;
+        for (lineno, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mapping = line
+                .parse::<CcMapping>()
+                .map_err(|e| format!("{}:{}: {e}", path.display(), lineno + 1))?;
+            self.insert(mapping);
+        }
+        Ok(())
+    }
+
+    /// Applies a CC value to its mapped parameter, if there is one.
+    pub fn apply(&self, controller: u8, value: u8, target: &mut impl Params) -> bool {
+        let Some(mapped) = self.0.get(&controller) else {
+            return false;
+        };
+        mapped.apply(value as f32 / 127., target)
+    }
+}
+
+/// Opens `dev`, sending what comes in on it to the audio thread, stamped
+/// on `clock` with the spacing the driver timestamped it with.
 pub fn initialize_midi(
     dev: MidiDevice,
-    send_midi: mpsc::Sender<AudioEvent>,
+    send_midi: queue::Sender<AudioEvent>,
+    clock: EngineClock,
 ) -> Result<Option<MidiInputConnection<()>>, Error> {
-    if let MidiDevice::Named(n) = dev {
-        let mut the_port = None;
-        let input = midir::MidiInput::new("synthtoy")?;
-        for port in input.ports() {
-            let name = input.port_name(&port)?;
-            if name.starts_with(&n) {
-                the_port = Some(port);
+    let input = midir::MidiInput::new("synthtoy")?;
+    let mut driver = DriverClock::new(clock);
+    let callback = move |ts, data: &[u8], _: &mut ()| {
+        let at = driver.stamp(ts, Instant::now());
+        if let Some(ev) = parse_midi(at, data) {
+            // far too many to print
+            if ev.inner != MidiEventInner::Clock {
+                println!("{:?}", &ev);
             }
+            send_midi.send(AudioEvent::Midi(ev)).unwrap();
         }
+    };
 
-        if let Some(p) = the_port {
-            Ok(Some(input.connect(
-                &p,
-                "synthtoy-in",
-                {
-                    let send_midi = send_midi;
-                    move |ts, data, _| {
-                        if let Some(ev) = parse_midi(ts, data) {
-                            println!("{:?}", &ev);
-                            send_midi.send(AudioEvent::Midi(ev)).unwrap();
-                        }
-                    }
-                },
-                (),
-            )?))
-        } else {
-            Ok(None)
+    match dev {
+        MidiDevice::Named(n) => {
+            let mut the_port = None;
+            for port in input.ports() {
+                let name = input.port_name(&port)?;
+                if name.starts_with(&n) {
+                    the_port = Some(port);
+                }
+            }
+
+            if let Some(p) = the_port {
+                Ok(Some(input.connect(&p, "synthtoy-in", callback, ())?))
+            } else {
+                Ok(None)
+            }
+        }
+        #[cfg(unix)]
+        MidiDevice::Virtual => {
+            use midir::os::unix::VirtualInput;
+            Ok(Some(input.create_virtual("synthtoy", callback, ())?))
+        }
+        #[cfg(not(unix))]
+        MidiDevice::Virtual => {
+            Err("virtual midi devices are not supported on this platform".into())
         }
-    } else {
-        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pitch_bend() {
+        let bend = |lsb, msb| match parse_midi(Stamp::default(), &[0xe0, lsb, msb])
+            .unwrap()
+            .inner
+        {
+            MidiEventInner::PitchBend(v) => v,
+            other => panic!("not a bend: {other:?}"),
+        };
+        assert_eq!(bend(0x00, 0x40), PITCH_BEND_CENTER);
+        assert_eq!(bend(0x7f, 0x7f), 0x3fff);
+        assert_eq!(bend(0x00, 0x00), 0);
+
+        assert_eq!(pitch_bend_semitones(PITCH_BEND_CENTER, 2.), 0.);
+        assert_eq!(pitch_bend_semitones(0, 2.), -2.);
+    }
+
+    #[test]
+    fn test_song_position() {
+        let event = parse_midi(Stamp::default(), &[0xf2, 0x01, 0x02]).unwrap();
+        assert_eq!(event.inner, MidiEventInner::SongPosition(0x101));
+        assert_eq!(event.to_bytes(), [0xf2, 0x01, 0x02]);
+        // cut short, it isn't anything
+        assert!(parse_midi(Stamp::default(), &[0xf2, 0x01]).is_none());
+        assert!(parse_midi(Stamp::default(), &[0x90, 60]).is_none());
+        assert!(parse_midi(Stamp::default(), &[]).is_none());
+    }
+
+    #[test]
+    fn test_cc_mapping() {
+        assert!(matches!(
+            parse_midi(Stamp::default(), &[0xb3, 74, 100]).unwrap(),
+            MidiEvent {
+                channel: 3,
+                inner: MidiEventInner::ControlChange {
+                    controller: 74,
+                    value: 100
+                },
+                ..
+            }
+        ));
+
+        assert_eq!(
+            "74=damping.brightness".parse(),
+            Ok(CcMapping(
+                74,
+                CcTarget {
+                    param: "damping.brightness".to_string(),
+                    range: None
+                }
+            ))
+        );
+        assert_eq!(
+            "1 = volume:0.5..1".parse(),
+            Ok(CcMapping(
+                1,
+                CcTarget {
+                    param: "volume".to_string(),
+                    range: Some((0.5, 1.))
+                }
+            ))
+        );
+        assert!("128=volume".parse::<CcMapping>().is_err());
+        assert!("7=volume:1".parse::<CcMapping>().is_err());
     }
 }
@@ -0,0 +1,653 @@
+//! The modulation matrix: LFOs, an envelope, velocity, aftertouch, the mod
+//! wheel, the expression pedal and a random value per note, each routed onto any of the engine's
+//! parameters with a depth of its own. Each routing swings its parameter
+//! away from where it's been set by as much of the parameter's range as its
+//! depth says, and the ones on the same parameter add up. They move every
+//! [`CONTROL_PERIOD`] samples.
+
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+use crate::delay::DelayTime;
+use crate::filters::{Adsr, AdsrStage, Rng};
+use crate::lfo::{Lfo, LfoShape, LfoSync};
+use crate::params::{nested, ParamInfo, Params};
+use crate::snapshot::Node;
+use crate::stereo::StereoFilter;
+
+/// How often the routed parameters get set, in samples.
+pub const CONTROL_PERIOD: usize = 32;
+
+/// The mod wheel's controller number.
+pub const MOD_WHEEL_CC: u8 = 1;
+
+/// Something to modulate with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// An LFO from --lfo, counting from 0. In -1..=1.
+    Lfo(usize),
+    /// Starts with each note, and lets go once no notes are held, by their
+    /// keys or a pedal, or once it gets to its sustain for notes that don't
+    /// come up. In 0..=1.
+    Envelope,
+    /// The latest note's, in 0..=1.
+    Velocity,
+    /// Channel aftertouch, in 0..=1.
+    Aftertouch,
+    /// Polyphonic aftertouch, from the hardest pressed key down, in 0..=1.
+    KeyPressure,
+    /// The mod wheel, in 0..=1.
+    Wheel,
+    /// The expression pedal, calibrated and shaped, in 0..=1.
+    Expression,
+    /// Somewhere new in -1..=1 with each note.
+    Random,
+}
+
+impl std::str::FromStr for Source {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        Ok(match value {
+            "envelope" => Source::Envelope,
+            "velocity" => Source::Velocity,
+            "aftertouch" => Source::Aftertouch,
+            "key_pressure" => Source::KeyPressure,
+            "wheel" => Source::Wheel,
+            "expression" => Source::Expression,
+            "random" => Source::Random,
+            _ => {
+                return value
+                    .strip_prefix("lfo")
+                    .and_then(|n| n.parse::<usize>().ok()?.checked_sub(1))
+                    .map(Source::Lfo)
+                    .ok_or_else(|| format!("unknown modulation source {value:?}"))
+            }
+        })
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Lfo(idx) => write!(f, "lfo{}", idx + 1),
+            Source::Envelope => f.write_str("envelope"),
+            Source::Velocity => f.write_str("velocity"),
+            Source::Aftertouch => f.write_str("aftertouch"),
+            Source::KeyPressure => f.write_str("key_pressure"),
+            Source::Wheel => f.write_str("wheel"),
+            Source::Expression => f.write_str("expression"),
+            Source::Random => f.write_str("random"),
+        }
+    }
+}
+
+/// `<param>=<depth>,...`, with each depth clamped to -1..=1.
+fn parse_routes(routes: &str) -> Result<Vec<(String, f32)>, String> {
+    routes
+        .split(',')
+        .map(|route| {
+            let (param, depth) = route
+                .split_once('=')
+                .ok_or_else(|| format!("expected <param>=<depth>, got {route:?}"))?;
+            let depth = depth
+                .trim()
+                .parse::<f32>()
+                .map_err(|_| format!("bad depth {depth:?}"))?;
+            Ok((param.trim().to_string(), depth.clamp(-1., 1.)))
+        })
+        .collect()
+}
+
+fn fmt_routes(f: &mut fmt::Formatter<'_>, routes: &[(String, f32)]) -> fmt::Result {
+    for (i, (param, depth)) in routes.iter().enumerate() {
+        let comma = if i == 0 { "" } else { "," };
+        write!(f, "{comma}{param}={depth}")?;
+    }
+    Ok(())
+}
+
+/// How fast an LFO goes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rate {
+    Hz(f32),
+    /// beats to a cycle, at the tempo
+    Beats(f32),
+}
+
+/// An LFO and where it goes, as written on the command line:
+/// `<shape>:<rate>:<param>=<depth>,...`, like
+/// `sine:0.5:ladder.cutoff=0.2,pan.position=-0.5`. The rate is in Hz, or a
+/// note length like "1/4" for a cycle that long at the tempo.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LfoConfig {
+    pub shape: LfoShape,
+    pub rate: Rate,
+    /// parameters and their depths, as a share of their range in -1..=1
+    pub routes: Vec<(String, f32)>,
+}
+
+impl std::str::FromStr for LfoConfig {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        let mut parts = value.splitn(3, ':');
+        let (Some(shape), Some(rate), Some(routes)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(format!(
+                "expected <shape>:<rate>:<param>=<depth>,..., got {value:?}"
+            ));
+        };
+        let rate = if rate.contains('/') {
+            match rate.parse()? {
+                DelayTime::Beats(beats) => Rate::Beats(beats),
+                DelayTime::Ms(_) => unreachable!("note lengths are in beats"),
+            }
+        } else {
+            let hz = rate.strip_suffix("Hz").unwrap_or(rate).trim();
+            Rate::Hz(
+                hz.parse()
+                    .ok()
+                    .filter(|hz: &f32| *hz > 0.)
+                    .ok_or_else(|| format!("bad lfo rate {rate:?}"))?,
+            )
+        };
+        Ok(LfoConfig {
+            shape: shape.parse()?,
+            rate,
+            routes: parse_routes(routes)?,
+        })
+    }
+}
+
+/// Writes it the way it's read.
+impl fmt::Display for LfoConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.rate {
+            Rate::Hz(hz) => write!(f, "{}:{hz}:", self.shape)?,
+            // in quarter notes, which are a beat each
+            Rate::Beats(beats) => write!(f, "{}:{beats}/4:", self.shape)?,
+        }
+        fmt_routes(f, &self.routes)
+    }
+}
+
+/// Any other source and where it goes, as written on the command line:
+/// `<source>:<param>=<depth>,...`, like `velocity:ladder.cutoff=0.3`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModConfig {
+    pub source: Source,
+    pub routes: Vec<(String, f32)>,
+}
+
+impl std::str::FromStr for ModConfig {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        let (source, routes) = value
+            .split_once(':')
+            .ok_or_else(|| format!("expected <source>:<param>=<depth>,..., got {value:?}"))?;
+        Ok(ModConfig {
+            source: source.trim().parse()?,
+            routes: parse_routes(routes)?,
+        })
+    }
+}
+
+/// Writes it the way it's read.
+impl fmt::Display for ModConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.source)?;
+        fmt_routes(f, &self.routes)
+    }
+}
+
+/// A parameter with something modulating it.
+struct Target {
+    name: String,
+    min: f32,
+    max: f32,
+    /// where it's been set, which the modulation swings it away from
+    base: f32,
+    /// what's modulating it, and how much
+    routes: Vec<(Source, f32)>,
+}
+
+/// An engine with a modulation matrix on its parameters. Setting one that's
+/// modulated moves where it swings from, and reading it back gives that
+/// rather than wherever it's been swung to, so patches don't save a random
+/// moment of it.
+///
+/// Its own parameters are "lfo1.rate", "lfo1.shape" and so on for each LFO
+/// in turn, "envelope.attack" and the rest of the envelope's, and
+/// "<source>.depth.<param>" for each routing, like
+/// "velocity.depth.ladder.cutoff".
+pub struct Modulated<E> {
+    pub engine: E,
+    lfos: Vec<Lfo>,
+    /// each LFO's output for this control period
+    values: Vec<f32>,
+    envelope: Adsr,
+    /// its level for this control period
+    level: f32,
+    /// whether the voices are holding any notes, for the envelope to let
+    /// go when they aren't
+    held: bool,
+    velocity: f32,
+    aftertouch: f32,
+    key_pressure: f32,
+    wheel: f32,
+    expression: f32,
+    random: f32,
+    rng: Rng,
+    targets: Vec<Target>,
+    /// samples left of this control period
+    countdown: usize,
+}
+
+impl<E: StereoFilter + Params> Modulated<E> {
+    /// Routes `lfos` and `mods` onto `engine`'s parameters, leaving out any
+    /// that it doesn't have. Tempo synced LFOs start out at `bpm`.
+    pub fn new(engine: E, lfos: &[LfoConfig], mods: &[ModConfig], bpm: f32) -> Self {
+        let known = engine.params();
+        let mut targets: Vec<Target> = Vec::new();
+        let routes = lfos
+            .iter()
+            .enumerate()
+            .map(|(idx, config)| (Source::Lfo(idx), &config.routes))
+            .chain(mods.iter().map(|config| (config.source, &config.routes)));
+        for (source, routes) in routes {
+            if matches!(source, Source::Lfo(idx) if idx >= lfos.len()) {
+                println!("mod: no {source}, there being {} lfos", lfos.len());
+                continue;
+            }
+            for (name, depth) in routes {
+                if let Some(target) = targets.iter_mut().find(|t| t.name == *name) {
+                    target.routes.push((source, *depth));
+                    continue;
+                }
+                let Some(info) = known.iter().find(|p| p.name == *name) else {
+                    println!("{source}: no parameter {name} to modulate");
+                    continue;
+                };
+                targets.push(Target {
+                    name: name.clone(),
+                    min: info.min,
+                    max: info.max,
+                    base: engine.get_param(name).unwrap_or(info.min),
+                    routes: vec![(source, *depth)],
+                });
+            }
+        }
+        let lfos: Vec<Lfo> = lfos
+            .iter()
+            .map(|config| {
+                let (rate, sync) = match config.rate {
+                    Rate::Hz(hz) => (hz, LfoSync::Free),
+                    Rate::Beats(beats) => (1., LfoSync::Tempo { beats, phase: 0. }),
+                };
+                let mut lfo = Lfo::new(rate).with_sync(sync).with_shape(config.shape);
+                lfo.set_tempo(bpm);
+                lfo
+            })
+            .collect();
+        Self {
+            engine,
+            values: vec![0.; lfos.len()],
+            lfos,
+            envelope: Adsr::new(0.01, 0.3, 0.6, 0.5),
+            level: 0.,
+            held: false,
+            velocity: 0.,
+            aftertouch: 0.,
+            key_pressure: 0.,
+            wheel: 0.,
+            expression: 0.,
+            random: 0.,
+            rng: Rng::default(),
+            targets,
+            countdown: 0,
+        }
+    }
+
+    /// A note starting, with `velocity` in 0..=1. The envelope stays up
+    /// for as long as [`Modulated::set_held`] says notes are held, or lets
+    /// go on its own if it never does.
+    pub fn note_on(&mut self, velocity: f32) {
+        self.velocity = velocity;
+        self.random = self.rng.next_f32();
+        self.envelope.note_on();
+    }
+
+    /// Whether the voices are holding any notes, by their keys, the latch
+    /// or the sustain pedal. The envelope lets go once they aren't.
+    pub fn set_held(&mut self, held: bool) {
+        if self.held && !held {
+            self.envelope.note_off();
+        }
+        self.held = held;
+    }
+
+    pub fn release_all(&mut self) {
+        self.held = false;
+        self.envelope.note_off();
+    }
+
+    /// Lines the tempo synced LFOs up with the song being `beat` beats in,
+    /// as when MIDI clock starts it or moves it somewhere.
+    pub fn sync_to_beat(&mut self, beat: f64) {
+        for lfo in &mut self.lfos {
+            lfo.sync_to_beat(beat);
+        }
+    }
+
+    /// Sets a source that comes from outside, which are the two kinds of
+    /// aftertouch, the mod wheel and the expression pedal, to a value in
+    /// 0..=1.
+    pub fn set_source(&mut self, source: Source, value: f32) {
+        let value = value.clamp(0., 1.);
+        match source {
+            Source::Aftertouch => self.aftertouch = value,
+            Source::KeyPressure => self.key_pressure = value,
+            Source::Wheel => self.wheel = value,
+            Source::Expression => self.expression = value,
+            _ => {}
+        }
+    }
+
+    fn value(&self, source: Source) -> f32 {
+        match source {
+            Source::Lfo(idx) => self.values[idx],
+            Source::Envelope => self.level,
+            Source::Velocity => self.velocity,
+            Source::Aftertouch => self.aftertouch,
+            Source::KeyPressure => self.key_pressure,
+            Source::Wheel => self.wheel,
+            Source::Expression => self.expression,
+            Source::Random => self.random,
+        }
+    }
+
+    /// Moves the sources on a control period and sets what they're routed
+    /// to.
+    fn update(&mut self) {
+        for (value, lfo) in self.values.iter_mut().zip(self.lfos.iter_mut()) {
+            *value = lfo.next_block(CONTROL_PERIOD);
+        }
+        for _ in 0..CONTROL_PERIOD {
+            self.level = self.envelope.next_level();
+        }
+        if !self.held && self.envelope.stage() == AdsrStage::Sustain {
+            self.envelope.note_off();
+        }
+        for target in &self.targets {
+            let swing: f32 = target
+                .routes
+                .iter()
+                .map(|&(source, depth)| depth * self.value(source))
+                .sum();
+            let value = target.base + swing * (target.max - target.min);
+            self.engine
+                .set_param(&target.name, value.clamp(target.min, target.max));
+        }
+    }
+
+    /// The LFO a parameter name like "lfo2.rate" is about, and the rest of
+    /// the name.
+    fn lfo_param<'a>(&self, name: &'a str) -> Option<(usize, &'a str)> {
+        let (number, rest) = name.strip_prefix("lfo")?.split_once('.')?;
+        let idx = number.parse::<usize>().ok()?.checked_sub(1)?;
+        (idx < self.lfos.len()).then_some((idx, rest))
+    }
+
+    /// The routing a parameter name like "wheel.depth.pan.position" is
+    /// about.
+    fn route(&mut self, name: &str) -> Option<&mut f32> {
+        let (source, param) = name.split_once(".depth.")?;
+        let source: Source = source.parse().ok()?;
+        self.targets
+            .iter_mut()
+            .find(|t| t.name == param)?
+            .routes
+            .iter_mut()
+            .find(|(s, _)| *s == source)
+            .map(|(_, depth)| depth)
+    }
+}
+
+impl<E: StereoFilter + Params> StereoFilter for Modulated<E> {
+    fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) {
+        if self.targets.is_empty() {
+            self.engine.process_stereo(left, right);
+            return;
+        }
+        let mut done = 0;
+        while done < left.len() {
+            if self.countdown == 0 {
+                self.update();
+                self.countdown = CONTROL_PERIOD;
+            }
+            let end = (done + self.countdown).min(left.len());
+            self.engine
+                .process_stereo(&mut left[done..end], &mut right[done..end]);
+            self.countdown -= end - done;
+            done = end;
+        }
+    }
+
+    fn latency(&self) -> usize {
+        self.engine.latency()
+    }
+
+    fn describe(&self) -> Node {
+        self.engine.describe()
+    }
+}
+
+impl<E: StereoFilter + Params> Params for Modulated<E> {
+    fn params(&self) -> Vec<ParamInfo> {
+        let mut out = self.engine.params();
+        for (idx, lfo) in self.lfos.iter().enumerate() {
+            out.extend(nested(&Source::Lfo(idx).to_string(), lfo));
+        }
+        out.extend(nested("envelope", &self.envelope));
+        for target in &self.targets {
+            for (source, _) in &target.routes {
+                let name = format!("{source}.depth.{}", target.name);
+                out.push(ParamInfo::new(&name, -1., 1.));
+            }
+        }
+        out
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        if let Some(target) = self.targets.iter().find(|t| t.name == name) {
+            return Some(target.base);
+        }
+        if let Some((source, param)) = name.split_once(".depth.") {
+            let source: Source = source.parse().ok()?;
+            return self
+                .targets
+                .iter()
+                .find(|t| t.name == param)?
+                .routes
+                .iter()
+                .find(|(s, _)| *s == source)
+                .map(|&(_, depth)| depth);
+        }
+        if let Some((idx, rest)) = self.lfo_param(name) {
+            return self.lfos[idx].get_param(rest);
+        }
+        if let Some(rest) = name.strip_prefix("envelope.") {
+            return self.envelope.get_param(rest);
+        }
+        self.engine.get_param(name)
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        if let Some(target) = self.targets.iter_mut().find(|t| t.name == name) {
+            // the next update takes it from here
+            target.base = value.clamp(target.min, target.max);
+            return self.engine.set_param(name, value);
+        }
+        if name.contains(".depth.") {
+            return match self.route(name) {
+                Some(depth) => {
+                    *depth = value.clamp(-1., 1.);
+                    true
+                }
+                None => false,
+            };
+        }
+        if let Some((idx, rest)) = self.lfo_param(name) {
+            return self.lfos[idx].set_param(rest, value);
+        }
+        if let Some(rest) = name.strip_prefix("envelope.") {
+            return self.envelope.set_param(rest, value);
+        }
+        self.engine.set_param(name, value)
+    }
+}
+
+impl<E> Deref for Modulated<E> {
+    type Target = E;
+    fn deref(&self) -> &E {
+        &self.engine
+    }
+}
+
+impl<E> DerefMut for Modulated<E> {
+    fn deref_mut(&mut self) -> &mut E {
+        &mut self.engine
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes down where its one parameter is set at the start of each
+    /// block.
+    #[derive(Default)]
+    struct Probe {
+        level: f32,
+        seen: Vec<f32>,
+    }
+
+    impl StereoFilter for Probe {
+        fn process_stereo(&mut self, _left: &mut [f32], _right: &mut [f32]) {
+            self.seen.push(self.level);
+        }
+    }
+
+    impl Params for Probe {
+        fn params(&self) -> Vec<ParamInfo> {
+            vec![ParamInfo::new("level", 0., 2.)]
+        }
+
+        fn get_param(&self, name: &str) -> Option<f32> {
+            (name == "level").then_some(self.level)
+        }
+
+        fn set_param(&mut self, name: &str, value: f32) -> bool {
+            self.level = value;
+            name == "level"
+        }
+    }
+
+    fn probe(level: f32) -> Probe {
+        Probe {
+            level,
+            ..Probe::default()
+        }
+    }
+
+    /// Runs `blocks` control periods, returning where the parameter was
+    /// for each.
+    fn run(engine: &mut Modulated<Probe>, blocks: usize) -> Vec<f32> {
+        engine.seen.clear();
+        let (mut left, mut right) = (vec![0.; CONTROL_PERIOD * blocks], vec![0.; 0]);
+        right.resize(left.len(), 0.);
+        engine.process_stereo(&mut left, &mut right);
+        engine.seen.clone()
+    }
+
+    #[test]
+    fn test_modulated() {
+        let config: LfoConfig = "square:1/4:level=0.25,missing=1".parse().unwrap();
+        assert_eq!(config.rate, Rate::Beats(1.));
+        assert!("sine:0:level=1".parse::<LfoConfig>().is_err());
+        assert!("sine:2Hz".parse::<LfoConfig>().is_err());
+
+        // a beat a second
+        let mut engine = Modulated::new(probe(1.), &[config], &[], 60.);
+        // half a range up, in the first half of the square's cycle
+        assert_eq!(run(&mut engine, 4), [1.5; 4]);
+        assert_eq!(engine.get_param("level"), Some(1.));
+        // and down in the second half, three quarters of a beat in
+        engine.sync_to_beat(8.75);
+        assert_eq!(run(&mut engine, 1), [0.5]);
+        engine.sync_to_beat(0.);
+
+        engine.set_param("level", 0.2);
+        engine.set_param("lfo1.depth.level", -1.);
+        assert_eq!(run(&mut engine, 4), [0.; 4]);
+        assert_eq!(engine.get_param("lfo1.depth.level"), Some(-1.));
+        assert!(!engine.set_param("lfo1.depth.missing", 1.));
+        assert!(engine.set_param("lfo1.rate", 3.));
+        assert!(engine.params().iter().any(|p| p.name == "lfo1.shape"));
+    }
+
+    #[test]
+    fn test_matrix() {
+        assert_eq!("lfo2".parse(), Ok(Source::Lfo(1)));
+        assert_eq!("key_pressure".parse(), Ok(Source::KeyPressure));
+        assert_eq!("expression".parse(), Ok(Source::Expression));
+        assert!("lfo0".parse::<Source>().is_err());
+        assert!("velocity".parse::<ModConfig>().is_err());
+        let mods: Vec<ModConfig> = ["velocity:level=0.5", "wheel:level=-0.25", "lfo1:level=1"]
+            .iter()
+            .map(|m| m.parse().unwrap())
+            .collect();
+        // with no LFOs, the last one has nothing to go on
+        let mut engine = Modulated::new(probe(0.5), &[], &mods, 120.);
+        assert_eq!(engine.get_param("velocity.depth.level"), Some(0.5));
+        assert_eq!(engine.get_param("lfo1.depth.level"), None);
+
+        engine.note_on(0.5);
+        assert_eq!(run(&mut engine, 1), [1.]);
+        engine.set_source(Source::Wheel, 1.);
+        assert_eq!(run(&mut engine, 1), [0.5]);
+        assert!(engine.set_param("wheel.depth.level", 0.));
+        assert_eq!(run(&mut engine, 1), [1.]);
+
+        // polyphonic aftertouch and the expression pedal get routed like
+        // the rest
+        let mods = [
+            "key_pressure:level=0.5".parse().unwrap(),
+            "expression:level=0.25".parse().unwrap(),
+        ];
+        let mut engine = Modulated::new(probe(0.), &[], &mods, 120.);
+        engine.set_source(Source::KeyPressure, 0.5);
+        assert_eq!(run(&mut engine, 1), [0.5]);
+        engine.set_source(Source::Expression, 1.);
+        assert_eq!(run(&mut engine, 1), [1.]);
+
+        // the envelope rises with a note, and falls once it's let go
+        let mods = ["envelope:level=0.5".parse().unwrap()];
+        let mut engine = Modulated::new(probe(0.), &[], &mods, 120.);
+        engine.set_param("envelope.attack", 0.);
+        engine.set_param("envelope.decay", 0.);
+        engine.note_on(1.);
+        engine.set_held(true);
+        let held = run(&mut engine, 4);
+        assert!(held.iter().all(|&l| l == held[3] && l > 0.), "{held:?}");
+        // however many notes have gone, only once none are held
+        engine.set_held(true);
+        assert_eq!(run(&mut engine, 1), [held[3]]);
+        engine.set_held(false);
+        let released = run(&mut engine, 2000);
+        assert!(released[0] < held[3] && released[1999] == 0.);
+
+        // and a note with no key up lets go on its own
+        engine.note_on(1.);
+        assert_eq!(*run(&mut engine, 2000).last().unwrap(), 0.);
+    }
+}
@@ -0,0 +1,196 @@
+//! MIDI Polyphonic Expression, from controllers that play each note on a
+//! channel of its own so it can be bent, pressed and brightened on its own.
+//!
+//! A zone is a master channel, whose messages apply to every note as usual,
+//! and the member channels next to it that the notes come in on: channel 1
+//! and the ones above it for the lower zone, channel 16 and the ones below
+//! it for the upper one. Controllers set them up with the MPE Configuration
+//! Message, RPN 6 on the master channel, and `--mpe` sets up a lower zone
+//! for those that don't.
+
+use crate::midi::{self, MidiEvent, MidiEventInner};
+use crate::voice::PerNote;
+
+/// The CC member channels send timbre on.
+pub const TIMBRE_CC: u8 = 74;
+
+/// How far member channels bend each way until told otherwise, as the
+/// spec has it.
+const MEMBER_BEND_RANGE: f32 = 48.;
+
+const RPN_MSB: u8 = 101;
+const RPN_LSB: u8 = 100;
+const DATA_ENTRY: u8 = 6;
+const RPN_BEND_RANGE: (u8, u8) = (0, 0);
+const RPN_MPE_CONFIG: (u8, u8) = (0, 6);
+
+/// Where a MIDI event goes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Route {
+    /// Not for a member channel, so it's played as usual.
+    Normal,
+    /// It set up a zone or a bend range, which is all it does.
+    Configured,
+    NoteOn {
+        note: u8,
+        velocity: u8,
+    },
+    NoteOff(u8),
+    PerNote(PerNote),
+}
+
+pub struct Zones {
+    /// member channels in each zone, 0 for no zone
+    lower: u8,
+    upper: u8,
+    bend_range: [f32; 16],
+    /// the registered parameter each channel has picked, as (MSB, LSB)
+    rpn: [(u8, u8); 16],
+}
+
+impl Zones {
+    /// Starts with a lower zone of `members` channels, or none.
+    pub fn new(members: u8) -> Self {
+        let mut zones = Self {
+            lower: 0,
+            upper: 0,
+            bend_range: [MEMBER_BEND_RANGE; 16],
+            rpn: [(127, 127); 16],
+        };
+        zones.configure(0, members);
+        zones
+    }
+
+    pub fn is_member(&self, channel: u8) -> bool {
+        (1..=self.lower).contains(&channel) || (15 - self.upper..15).contains(&channel)
+    }
+
+    /// Sets up the zone mastered by `master`, 0 or 15, shrinking the other
+    /// if they overlap.
+    fn configure(&mut self, master: u8, members: u8) {
+        let members = members.min(15);
+        if master == 0 {
+            self.lower = members;
+            self.upper = self.upper.min(14u8.saturating_sub(members));
+        } else {
+            self.upper = members;
+            self.lower = self.lower.min(14u8.saturating_sub(members));
+        }
+        self.bend_range = [MEMBER_BEND_RANGE; 16];
+    }
+
+    pub fn route(&mut self, event: &MidiEvent) -> Route {
+        let channel = event.channel & 15;
+        let member = self.is_member(channel);
+        let rpn = &mut self.rpn[channel as usize];
+        match event.inner {
+            // the parameter numbers themselves are spoken for on any channel
+            MidiEventInner::ControlChange {
+                controller: RPN_MSB,
+                value,
+            } => {
+                rpn.0 = value;
+                Route::Configured
+            }
+            MidiEventInner::ControlChange {
+                controller: RPN_LSB,
+                value,
+            } => {
+                rpn.1 = value;
+                Route::Configured
+            }
+            MidiEventInner::ControlChange {
+                controller: DATA_ENTRY,
+                value,
+            } => match *rpn {
+                RPN_MPE_CONFIG if channel == 0 || channel == 15 => {
+                    self.configure(channel, value);
+                    let zone = if channel == 0 { "lower" } else { "upper" };
+                    println!("mpe: {zone} zone with {value} member channels");
+                    Route::Configured
+                }
+                RPN_BEND_RANGE if member => {
+                    self.bend_range[channel as usize] = value as f32;
+                    Route::Configured
+                }
+                _ => Route::Normal,
+            },
+            _ if !member => Route::Normal,
+            MidiEventInner::ControlChange {
+                controller: TIMBRE_CC,
+                value,
+            } => Route::PerNote(PerNote::Timbre(value as f32 / 127.)),
+            MidiEventInner::Down { velocity: 0, note } | MidiEventInner::Up { note, .. } => {
+                Route::NoteOff(note)
+            }
+            MidiEventInner::Down { note, velocity } => Route::NoteOn { note, velocity },
+            MidiEventInner::PitchBend(bend) => {
+                let range = self.bend_range[channel as usize];
+                Route::PerNote(PerNote::Bend(midi::pitch_bend_semitones(bend, range)))
+            }
+            MidiEventInner::ChannelPressure(value) => {
+                Route::PerNote(PerNote::Pressure(value as f32 / 127.))
+            }
+            _ => Route::Normal,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::Stamp;
+
+    fn on(channel: u8, inner: MidiEventInner) -> MidiEvent {
+        MidiEvent {
+            at: Stamp::default(),
+            channel,
+            inner,
+        }
+    }
+
+    fn cc(channel: u8, controller: u8, value: u8) -> MidiEvent {
+        on(channel, MidiEventInner::ControlChange { controller, value })
+    }
+
+    #[test]
+    fn test_mpe_zones() {
+        let mut zones = Zones::new(0);
+        let down = MidiEventInner::Down {
+            note: 60,
+            velocity: 100,
+        };
+        assert_eq!(zones.route(&on(1, down.clone())), Route::Normal);
+
+        // an MPE Configuration Message for a lower zone of 7 members
+        for event in [cc(0, 101, 0), cc(0, 100, 6), cc(0, 6, 7)] {
+            assert_eq!(zones.route(&event), Route::Configured);
+        }
+        assert!(zones.is_member(1) && zones.is_member(7) && !zones.is_member(8));
+        assert_eq!(
+            zones.route(&on(1, down.clone())),
+            Route::NoteOn {
+                note: 60,
+                velocity: 100
+            }
+        );
+        // the master channel plays as usual
+        assert_eq!(zones.route(&on(0, down)), Route::Normal);
+        assert_eq!(
+            zones.route(&on(3, MidiEventInner::PitchBend(0x3fff))),
+            Route::PerNote(PerNote::Bend(midi::pitch_bend_semitones(0x3fff, 48.)))
+        );
+        assert_eq!(
+            zones.route(&cc(3, TIMBRE_CC, 127)),
+            Route::PerNote(PerNote::Timbre(1.))
+        );
+        assert_eq!(zones.route(&cc(3, 7, 127)), Route::Normal);
+
+        // an upper zone takes what it needs from the lower one
+        for event in [cc(15, 101, 0), cc(15, 100, 6), cc(15, 6, 10)] {
+            zones.route(&event);
+        }
+        assert_eq!((zones.lower, zones.upper), (4, 10));
+        assert!(zones.is_member(5) && zones.is_member(14) && !zones.is_member(15));
+    }
+}
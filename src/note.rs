@@ -1,5 +1,12 @@
+use std::fmt;
+use std::str::FromStr;
+
 use sdl2::keyboard::Keycode;
 
+/// The A above middle C, which everything here is tuned from. `--a4` moves
+/// it while playing, in [`crate::tuning::Tuning`].
+pub const A4: f32 = 440.;
+
 #[derive(Clone, Copy, Debug)]
 #[repr(u32)]
 #[allow(unused)]
@@ -48,7 +55,7 @@ impl Note {
     }
 
     pub fn freq(self, octave: u32) -> f32 {
-        440f32 * 2f32.powf(octave as f32 - 4.) * self.ratio()
+        A4 * 2f32.powf(octave as f32 - 4.) * self.ratio()
     }
 }
 
@@ -91,6 +98,107 @@ pub fn midi_note_to_freq(note: u8) -> f32 {
     }
 }
 
+const NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// A pitch as a (possibly fractional) MIDI note number, so 60 is middle C
+/// (C4) and 69 is A4 = 440Hz.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub struct Pitch(pub f32);
+
+impl Pitch {
+    pub fn from_freq(freq: f32) -> Self {
+        Pitch(69. + 12. * (freq / A4).log2())
+    }
+
+    pub fn freq(self) -> f32 {
+        A4 * 2f32.powf((self.0 - 69.) / 12.)
+    }
+
+    /// Nearest MIDI note, and how far off it this pitch is in cents.
+    pub fn nearest(self) -> (i32, f32) {
+        let note = self.0.round();
+        (note as i32, (self.0 - note) * 100.)
+    }
+}
+
+/// Parses any of:
+///
+/// - a note name with octave: "C4", "C#4", "Bb-1"
+/// - a note name with its frequency: "A440", "A432"; the frequency wins
+/// - a MIDI note number: "62"
+/// - a frequency: "440Hz"
+///
+/// each optionally followed by a detune in cents, like "62+30c" or "E2-5c".
+impl FromStr for Pitch {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        let err = || format!("invalid pitch {value:?}");
+        let s = value.trim();
+
+        let (base, cents) = match s.strip_suffix(['c', 'C']) {
+            Some(rest) => {
+                let split = rest.rfind(['+', '-']).filter(|&i| i > 0).ok_or_else(err)?;
+                let cents: f32 = rest[split..].parse().map_err(|_| err())?;
+                (&rest[..split], cents)
+            }
+            None => (s, 0.),
+        };
+
+        let mut chars = base.chars();
+        let pitch = match chars.next().ok_or_else(err)?.to_ascii_uppercase() {
+            letter @ 'A'..='G' => {
+                let mut note = NAMES.iter().position(|&n| n == letter.to_string()).unwrap() as i32;
+                let mut rest = chars.as_str();
+                loop {
+                    if let Some(r) = rest.strip_prefix('#') {
+                        note += 1;
+                        rest = r;
+                    } else if let Some(r) = rest.strip_prefix('b') {
+                        note -= 1;
+                        rest = r;
+                    } else {
+                        break;
+                    }
+                }
+                let number: f32 = rest.parse().map_err(|_| err())?;
+                if rest.contains('.') || number >= 10. {
+                    Pitch::from_freq(number)
+                } else if number.fract() == 0. {
+                    Pitch((note + 12 * (number as i32 + 1)) as f32)
+                } else {
+                    return Err(err());
+                }
+            }
+            _ => match base.strip_suffix("Hz").or_else(|| base.strip_suffix("hz")) {
+                Some(freq) => Pitch::from_freq(freq.trim().parse().map_err(|_| err())?),
+                None => Pitch(base.parse().map_err(|_| err())?),
+            },
+        };
+        if !pitch.0.is_finite() {
+            return Err(err());
+        }
+        Ok(Pitch(pitch.0 + cents / 100.))
+    }
+}
+
+/// Formats as the nearest note name, with the detune in cents if there is
+/// any, e.g. "C#4" or "D4+30c".
+impl fmt::Display for Pitch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (note, cents) = self.nearest();
+        let name = NAMES[note.rem_euclid(12) as usize];
+        let octave = note.div_euclid(12) - 1;
+        write!(f, "{name}{octave}")?;
+        let cents = cents.round() as i32;
+        if cents != 0 {
+            write!(f, "{cents:+}c")?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -107,4 +215,27 @@ mod tests {
         check(22, 29.14);
         check(69, 440.);
     }
+
+    #[test]
+    fn test_pitch_parse() {
+        let parse = |s: &str| s.parse::<Pitch>().unwrap().0;
+        assert_eq!(parse("C4"), 60.);
+        assert_eq!(parse("C#4"), 61.);
+        assert_eq!(parse("Db4"), 61.);
+        assert_eq!(parse("c-1"), 0.);
+        assert_eq!(parse("B-1-50c"), 10.5);
+        assert_eq!(parse("62"), 62.);
+        assert!((parse("62+30c") - 62.3).abs() < 1e-4);
+        assert!((parse("A440") - 69.).abs() < 1e-4);
+        assert!((parse("261.63Hz") - 60.).abs() < 1e-3);
+        for bad in ["", "H4", "C", "C4+30", "+30c", "C4x", "0Hz"] {
+            assert!(bad.parse::<Pitch>().is_err(), "{bad:?} parsed");
+        }
+
+        assert_eq!(Pitch(61.).to_string(), "C#4");
+        assert_eq!(Pitch(62.3).to_string(), "D4+30c");
+        assert_eq!(Pitch(-0.2).to_string(), "C-1-20c");
+        assert_eq!(Pitch::from_freq(440.).to_string(), "A4");
+        assert!((Pitch(57.).freq() - 220.).abs() < 1e-3);
+    }
 }
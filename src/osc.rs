@@ -0,0 +1,293 @@
+//! Taking notes and parameter changes as Open Sound Control messages over
+//! UDP, so TouchOSC, SuperCollider, Max and the like can play the synth.
+//!
+//! - `/synthtoy/note_on <note> [velocity]`: a MIDI note number, and a
+//!   velocity in 0..=1 if it's a float or 0..=127 if it's an int
+//! - `/synthtoy/note_off <note>`
+//! - `/synthtoy/param/<name> <value>`: sets a parameter, like
+//!   `/synthtoy/param/ladder.cutoff 800`
+//! - `/synthtoy/release_all`
+//! - `/synthtoy/randomize [group]`, `/synthtoy/mutate [group] [amount]` and
+//!   `/synthtoy/undo`, as on the console
+//!
+//! Bundles are taken apart and played straight away, whatever their time
+//! tags say.
+
+use std::io;
+use std::net::UdpSocket;
+
+use crate::audio_thread::AudioEvent;
+use crate::clock::{EngineClock, Stamp};
+use crate::console::Console;
+use crate::midi::{MidiEvent, MidiEventInner};
+use crate::queue::Sender;
+use crate::random::DEFAULT_MUTATION;
+
+const PREFIX: &str = "/synthtoy/";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Arg {
+    Int(i32),
+    Float(f32),
+    Str(String),
+}
+
+impl Arg {
+    fn number(&self) -> Option<f32> {
+        match *self {
+            Arg::Int(i) => Some(i as f32),
+            Arg::Float(f) => Some(f),
+            Arg::Str(_) => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub address: String,
+    pub args: Vec<Arg>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let out = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| format!("truncated at byte {}", self.pos))?;
+        self.pos += len;
+        Ok(out)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    /// A string, nul terminated and padded out to four bytes.
+    fn string(&mut self) -> Result<String, String> {
+        let rest = &self.data[self.pos.min(self.data.len())..];
+        let len = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(|| format!("unterminated string at byte {}", self.pos))?;
+        let text = String::from_utf8_lossy(&rest[..len]).into_owned();
+        self.bytes((len + 4) & !3)?;
+        Ok(text)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+}
+
+/// The messages in a packet, which is either one message or a bundle of
+/// them.
+pub fn decode(packet: &[u8]) -> Result<Vec<Message>, String> {
+    let mut out = Vec::new();
+    decode_into(packet, &mut out)?;
+    Ok(out)
+}
+
+fn decode_into(packet: &[u8], out: &mut Vec<Message>) -> Result<(), String> {
+    let mut r = Reader {
+        data: packet,
+        pos: 0,
+    };
+    let address = r.string()?;
+    if address == "#bundle" {
+        // the time tag
+        r.bytes(8)?;
+        while !r.is_empty() {
+            let len = r.u32()? as usize;
+            decode_into(r.bytes(len)?, out)?;
+        }
+        return Ok(());
+    }
+    if !address.starts_with('/') {
+        return Err(format!("{address:?} isn't an address"));
+    }
+    // very old senders leave the type tags out, and so the arguments too
+    let tags = if r.is_empty() {
+        String::new()
+    } else {
+        r.string()?
+    };
+    let mut args = Vec::new();
+    for tag in tags.chars().skip_while(|&c| c == ',') {
+        args.push(match tag {
+            'i' => Arg::Int(r.u32()? as i32),
+            'f' => Arg::Float(f32::from_bits(r.u32()?)),
+            'd' => {
+                let bytes = r.bytes(8)?.try_into().unwrap();
+                Arg::Float(f64::from_be_bytes(bytes) as f32)
+            }
+            's' | 'S' => Arg::Str(r.string()?),
+            'T' => Arg::Int(1),
+            'F' => Arg::Int(0),
+            _ => return Err(format!("{address}: can't read arguments of type {tag:?}")),
+        });
+    }
+    out.push(Message { address, args });
+    Ok(())
+}
+
+impl Message {
+    /// What the message asks for.
+    pub fn event(&self, at: Stamp) -> Result<AudioEvent, String> {
+        let address = &self.address;
+        let command = address
+            .strip_prefix(PREFIX)
+            .ok_or_else(|| format!("{address}: not under {PREFIX}"))?;
+        let number = |i: usize| {
+            self.args
+                .get(i)
+                .and_then(Arg::number)
+                .filter(|n| n.is_finite())
+                .ok_or_else(|| format!("{address}: expected a number for argument {}", i + 1))
+        };
+        let midi = |inner| {
+            AudioEvent::Midi(MidiEvent {
+                at,
+                channel: 0,
+                inner,
+            })
+        };
+        let note = || number(0).map(|n| n.round().clamp(0., 127.) as u8);
+        Ok(match command {
+            "note_on" => {
+                let velocity = match self.args.get(1) {
+                    None => 100,
+                    Some(Arg::Float(v)) => (v.clamp(0., 1.) * 127.).round() as u8,
+                    Some(_) => number(1)?.round().clamp(0., 127.) as u8,
+                };
+                midi(MidiEventInner::Down {
+                    note: note()?,
+                    velocity,
+                })
+            }
+            "note_off" => midi(MidiEventInner::Up {
+                note: note()?,
+                velocity: 0,
+            }),
+            "release_all" => AudioEvent::ReleaseAll,
+            "randomize" | "mutate" | "undo" => {
+                let group = self.args.iter().find_map(|arg| match arg {
+                    Arg::Str(group) => Some(group.clone()),
+                    _ => None,
+                });
+                let amount = self.args.iter().find_map(Arg::number);
+                AudioEvent::Console(match command {
+                    "randomize" => Console::Randomize(group),
+                    "mutate" => {
+                        Console::Mutate(group, amount.unwrap_or(DEFAULT_MUTATION).clamp(0., 1.))
+                    }
+                    _ => Console::Undo,
+                })
+            }
+            _ => match command.strip_prefix("param/") {
+                Some(name) => AudioEvent::Console(Console::Set(name.to_string(), number(0)?)),
+                None => return Err(format!("{address}: unknown address")),
+            },
+        })
+    }
+}
+
+/// Listens on `port` on every interface, sending what comes in to the
+/// audio thread and printing anything that doesn't make sense. Messages are
+/// stamped on `clock` as they arrive.
+pub fn spawn(port: u16, send: Sender<AudioEvent>, clock: EngineClock) -> io::Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", port))?;
+    std::thread::spawn(move || {
+        let mut buf = vec![0; 65536];
+        loop {
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e) => {
+                    println!("osc: {e}");
+                    continue;
+                }
+            };
+            let now = clock.now();
+            let messages = decode(&buf[..len]).unwrap_or_else(|e| {
+                println!("osc: {e}");
+                Vec::new()
+            });
+            for message in messages {
+                match message.event(now) {
+                    Ok(event) => {
+                        if send.send(event).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => println!("osc: {e}"),
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn padded(s: &str) -> Vec<u8> {
+        let mut out = s.as_bytes().to_vec();
+        out.resize((s.len() + 4) & !3, 0);
+        out
+    }
+
+    #[test]
+    fn test_osc() {
+        let mut note_on = padded("/synthtoy/note_on");
+        note_on.extend(padded(",if"));
+        note_on.extend(60i32.to_be_bytes());
+        note_on.extend(0.5f32.to_be_bytes());
+        let mut param = padded("/synthtoy/param/ladder.cutoff");
+        param.extend(padded(",f"));
+        param.extend(800f32.to_be_bytes());
+
+        let mut bundle = padded("#bundle");
+        bundle.extend([0, 0, 0, 0, 0, 0, 0, 1]);
+        for message in [&note_on, &param] {
+            bundle.extend((message.len() as u32).to_be_bytes());
+            bundle.extend(message);
+        }
+        let messages = decode(&bundle).unwrap();
+        assert_eq!(
+            messages[0],
+            Message {
+                address: "/synthtoy/note_on".to_string(),
+                args: vec![Arg::Int(60), Arg::Float(0.5)],
+            }
+        );
+
+        let now = Stamp(441);
+        assert!(matches!(
+            messages[0].event(now),
+            Ok(AudioEvent::Midi(MidiEvent {
+                at: Stamp(441),
+                inner: MidiEventInner::Down {
+                    note: 60,
+                    velocity: 64
+                },
+                ..
+            }))
+        ));
+        assert!(matches!(
+            messages[1].event(now),
+            Ok(AudioEvent::Console(Console::Set(name, 800.))) if name == "ladder.cutoff"
+        ));
+
+        assert!(decode(&note_on[..note_on.len() - 2]).is_err());
+        let unknown = Message {
+            address: "/synthtoy/explode".to_string(),
+            args: Vec::new(),
+        };
+        assert!(unknown.event(now).is_err());
+    }
+}
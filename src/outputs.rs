@@ -0,0 +1,178 @@
+//! Which outputs of an audio interface the mix goes to, for interfaces with
+//! more than one stereo pair, and the click if it goes somewhere of its
+//! own, and finding out what they have.
+
+use std::ffi::c_int;
+use std::mem::MaybeUninit;
+
+use sdl2::sys::SDL_AudioSpec;
+use sdl2::AudioSubsystem;
+
+/// SDL can't open devices with more channels than this.
+const MAX_CHANNELS: usize = 8;
+
+extern "C" {
+    // SDL 2.0.16, which is newer than the bindings. Linking against it
+    // makes that the oldest SDL the program starts with at all, rather
+    // than only when listing devices, since the dynamic linker resolves
+    // every symbol at load.
+    fn SDL_GetAudioDeviceSpec(index: c_int, iscapture: c_int, spec: *mut SDL_AudioSpec) -> c_int;
+}
+
+/// The output channels the left and right of the mix go to, and the click
+/// if it's kept out of the mix, counting from zero.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputMap {
+    pub left: usize,
+    pub right: usize,
+    pub click: Option<usize>,
+}
+
+impl Default for OutputMap {
+    fn default() -> Self {
+        Self {
+            left: 0,
+            right: 1,
+            click: None,
+        }
+    }
+}
+
+impl OutputMap {
+    /// How many outputs the device needs to have.
+    pub fn needed(self) -> usize {
+        self.left.max(self.right).max(self.click.unwrap_or(0)) + 1
+    }
+
+    /// How many channels to open the device with. SDL only takes some
+    /// counts, so this can be more than are used.
+    pub fn channels(self) -> usize {
+        let needed = self.needed();
+        [2, 4, 6, 8].into_iter().find(|&n| n >= needed).unwrap()
+    }
+
+    /// Interleaves a block into frames of `channels` channels, with silence
+    /// on the ones the mix doesn't go to. Both sides on one output are
+    /// mixed down at half each, so it's no louder than either. `click` goes
+    /// on its own output, if it has one.
+    pub fn interleave(
+        self,
+        out: &mut [f32],
+        channels: usize,
+        left: &[f32],
+        right: &[f32],
+        click: &[f32],
+    ) {
+        let mono = self.left == self.right;
+        for (i, frame) in out.chunks_exact_mut(channels).enumerate() {
+            let (Some(l), Some(r)) = (left.get(i), right.get(i)) else {
+                break;
+            };
+            frame.fill(0.);
+            if mono {
+                frame[self.left] = (l + r) * 0.5;
+            } else {
+                frame[self.left] = *l;
+                frame[self.right] = *r;
+            }
+            if let (Some(out), Some(c)) = (self.click, click.get(i)) {
+                frame[out] += c;
+            }
+        }
+    }
+}
+
+/// One output, counting from 1 the way interfaces label them, as an index
+/// from zero.
+pub fn output(value: &str) -> Result<usize, String> {
+    value
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .filter(|n| (1..=MAX_CHANNELS).contains(n))
+        .map(|n| n - 1)
+        .ok_or_else(|| format!("bad output {value:?}, expected 1 to {MAX_CHANNELS}"))
+}
+
+/// `<left>/<right>`, counting from 1 the way interfaces label them, or a
+/// single channel to get both mixed down.
+impl std::str::FromStr for OutputMap {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        let (left, right) = match value.split_once('/') {
+            Some((left, right)) => (output(left)?, output(right)?),
+            None => (output(value)?, output(value)?),
+        };
+        Ok(Self {
+            left,
+            right,
+            click: None,
+        })
+    }
+}
+
+/// A playback device, as SDL reports it.
+#[derive(Clone, Debug)]
+pub struct Device {
+    pub name: String,
+    /// Not every driver says.
+    pub channels: Option<usize>,
+}
+
+/// Lists the playback devices.
+pub fn probe(audio: &AudioSubsystem) -> Vec<Device> {
+    (0..audio.num_audio_playback_devices().unwrap_or(0))
+        .filter_map(|idx| {
+            let name = audio.audio_playback_device_name(idx).ok()?;
+            let mut spec = MaybeUninit::<SDL_AudioSpec>::zeroed();
+            // SAFETY: the index is in range and SDL fills in the spec on
+            // success
+            let channels = unsafe {
+                (SDL_GetAudioDeviceSpec(idx as c_int, 0, spec.as_mut_ptr()) == 0)
+                    .then(|| spec.assume_init().channels as usize)
+            };
+            Some(Device { name, channels })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_map() {
+        let pair = |left, right| OutputMap {
+            left,
+            right,
+            click: None,
+        };
+        assert_eq!("3/4".parse(), Ok(pair(2, 3)));
+        assert_eq!("5".parse(), Ok(pair(4, 4)));
+        assert!("0/1".parse::<OutputMap>().is_err());
+        assert!("9/10".parse::<OutputMap>().is_err());
+
+        assert_eq!(OutputMap::default().channels(), 2);
+        let map = pair(2, 3);
+        assert_eq!(map.channels(), 4);
+        assert_eq!(pair(4, 4).channels(), 6);
+
+        let mut out = [9.; 8];
+        map.interleave(&mut out, 4, &[1., 2.], &[3., 4.], &[]);
+        assert_eq!(out, [0., 0., 1., 3., 0., 0., 2., 4.]);
+        let mut out = [9.; 6];
+        pair(4, 4).interleave(&mut out, 6, &[1.], &[2.], &[]);
+        assert_eq!(out, [0., 0., 0., 0., 1.5, 0.]);
+
+        // the click on the third output, for headphones say
+        let map = OutputMap {
+            click: Some(2),
+            ..OutputMap::default()
+        };
+        assert_eq!(map.needed(), 3);
+        assert_eq!(map.channels(), 4);
+        let mut out = [9.; 4];
+        map.interleave(&mut out, 4, &[1.], &[2.], &[0.5]);
+        assert_eq!(out, [1., 2., 0.5, 0.]);
+    }
+}
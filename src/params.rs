@@ -0,0 +1,189 @@
+//! Named, runtime adjustable parameters, so that things like MIDI CCs can poke
+//! at the synth without knowing its concrete type.
+
+use std::ops::{Add, Mul, Sub};
+
+use crate::filters::SAMPLING_FREQ;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParamInfo {
+    pub name: String,
+    pub min: f32,
+    pub max: f32,
+    /// The part of the range that's safe to land on at random, or `None`
+    /// for switches and levels, which aren't for randomizing.
+    pub random: Option<(f32, f32)>,
+}
+
+impl ParamInfo {
+    pub fn new(name: &str, min: f32, max: f32) -> Self {
+        Self {
+            name: name.to_string(),
+            min,
+            max,
+            random: Some((min, max)),
+        }
+    }
+
+    /// Keeps randomizing to `min..=max`, where the ends of the whole range
+    /// are too much to land on by chance.
+    pub fn random_range(self, min: f32, max: f32) -> Self {
+        Self {
+            random: Some((min, max)),
+            ..self
+        }
+    }
+
+    /// Leaves it out of randomizing altogether.
+    pub fn not_random(self) -> Self {
+        Self {
+            random: None,
+            ..self
+        }
+    }
+
+    /// Maps 0..=1 onto the parameter's range.
+    pub fn denormalize(&self, v: f32) -> f32 {
+        self.min + (self.max - self.min) * v
+    }
+}
+
+pub trait Params {
+    fn params(&self) -> Vec<ParamInfo>;
+    fn get_param(&self, name: &str) -> Option<f32>;
+    /// Returns false if there is no parameter called `name`.
+    fn set_param(&mut self, name: &str, value: f32) -> bool;
+
+    fn param_info(&self, name: &str) -> Option<ParamInfo> {
+        self.params().into_iter().find(|p| p.name == name)
+    }
+}
+
+impl<P: Params + ?Sized> Params for Box<P> {
+    fn params(&self) -> Vec<ParamInfo> {
+        (**self).params()
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        (**self).get_param(name)
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        (**self).set_param(name, value)
+    }
+}
+
+/// Lists the parameters of `inner` as `prefix.name`.
+pub fn nested(prefix: &str, inner: &impl Params) -> Vec<ParamInfo> {
+    inner
+        .params()
+        .into_iter()
+        .map(|p| ParamInfo {
+            name: format!("{prefix}.{}", p.name),
+            ..p
+        })
+        .collect()
+}
+
+/// How long a [`Smoothed`] value takes to reach a new setting: 10ms.
+pub const RAMP_SAMPLES: u32 = SAMPLING_FREQ as u32 / 100;
+
+/// A continuously variable setting that ramps linearly to new values over
+/// [`RAMP_SAMPLES`] instead of jumping, so tweaking it live doesn't click or
+/// zipper. Filters usually keep the setting itself in a public field and
+/// chase it with one of these.
+#[derive(Clone, Copy, Debug)]
+pub struct Smoothed<T = f32> {
+    current: T,
+    target: T,
+    step: T,
+    remaining: u32,
+}
+
+impl<T> Smoothed<T>
+where
+    T: Copy + PartialEq + Add<Output = T> + Sub<Output = T> + Mul<f32, Output = T>,
+{
+    pub fn new(value: T) -> Self {
+        Self {
+            current: value,
+            target: value,
+            step: value * 0.,
+            remaining: 0,
+        }
+    }
+
+    /// Starts ramping towards `target`, unless it's already heading there.
+    pub fn set(&mut self, target: T) {
+        self.set_over(target, RAMP_SAMPLES);
+    }
+
+    /// [`Smoothed::set`], taking `samples` to get there instead.
+    pub fn set_over(&mut self, target: T, samples: u32) {
+        if target == self.target {
+            return;
+        }
+        let samples = samples.max(1);
+        self.target = target;
+        self.step = (target - self.current) * (1. / samples as f32);
+        self.remaining = samples;
+    }
+
+    /// Jumps straight to `value`.
+    pub fn reset(&mut self, value: T) {
+        *self = Self::new(value);
+    }
+
+    pub fn is_ramping(&self) -> bool {
+        self.remaining > 0
+    }
+
+    pub fn target(&self) -> T {
+        self.target
+    }
+
+    /// The value most recently handed out.
+    pub fn value(&self) -> T {
+        self.current
+    }
+
+    /// The value for the next sample.
+    pub fn next_value(&mut self) -> T {
+        match self.remaining {
+            0 => {}
+            1 => {
+                self.current = self.target;
+                self.remaining = 0;
+            }
+            _ => {
+                self.current = self.current + self.step;
+                self.remaining -= 1;
+            }
+        }
+        self.current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_smoothed() {
+        let mut gain = Smoothed::new(1.);
+        assert_eq!(gain.next_value(), 1.);
+        gain.set(0.);
+        let ramp: Vec<f32> = (0..RAMP_SAMPLES).map(|_| gain.next_value()).collect();
+        assert!(ramp.windows(2).all(|w| w[1] < w[0]));
+        assert!(ramp[0] > 0.99);
+        assert_eq!(ramp[RAMP_SAMPLES as usize - 1], 0.);
+        assert!(!gain.is_ramping());
+
+        // setting the same target again doesn't restart the ramp
+        gain.set(1.);
+        gain.next_value();
+        gain.set(1.);
+        let rest = (1..RAMP_SAMPLES).map(|_| gain.next_value()).last();
+        assert_eq!(rest, Some(1.));
+    }
+}
@@ -0,0 +1,852 @@
+//! Patch files: which sources and effects to use, and what to set their
+//! parameters to, in TOML.
+//!
+//! ```toml
+//! version = 1
+//! synth = "string"
+//! ladder = 2000
+//! reverb = true
+//! chain = "ladder, distortion"
+//!
+//! [params]
+//! ladder.resonance = 0.6
+//! "reverb.mix" = 0.2
+//! ```
+//!
+//! The keys at the top are the command line options of the same names, and
+//! `[params]` holds parameters as `<name> = <number>`, with the name quoted
+//! or not. `chain` puts the effects in some other order than the usual,
+//! and can add more of them, like `chain = "ladder, +ladder:400"`.
+//! `version` says which version of all that the patch was written for, so
+//! that patches keep loading after something gets renamed.
+//!
+//! A patch can also be written as a chain, which [`live`] compiles down to
+//! the same thing.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use serde::de::{self, Deserialize, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use toml::Spanned;
+
+use crate::audio_thread::{self, AudioConfig};
+use crate::distortion::{Curve, Waveshaper};
+use crate::filters::{ExciterKind, FIR};
+use crate::guard::{Guards, Reporter};
+use crate::live;
+use crate::note;
+use crate::params::Params;
+use crate::reverb::ConvolutionReverb;
+use crate::siggen::Siggen;
+use crate::sources::{SynthKind, DEFAULT_SEED};
+use crate::tuning::Tuning;
+use crate::window::Window;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Number(f64),
+    Bool(bool),
+}
+
+impl From<&Value> for toml::Value {
+    fn from(value: &Value) -> Self {
+        match *value {
+            Value::String(ref s) => toml::Value::String(s.clone()),
+            // whole numbers as integers, the way they'd be typed
+            Value::Number(n) if n.fract() == 0. && n.abs() < (1u64 << 53) as f64 => {
+                toml::Value::Integer(n as i64)
+            }
+            Value::Number(n) => toml::Value::Float(n),
+            Value::Bool(b) => toml::Value::Boolean(b),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        toml::Value::from(self).fmt(f)
+    }
+}
+
+/// One `key = value` line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// 1-based, or 0 for entries that didn't come from a file
+    pub line: usize,
+    pub key: String,
+    pub value: Value,
+}
+
+impl Entry {
+    pub fn new(key: impl Into<String>, value: Value) -> Self {
+        Self {
+            line: 0,
+            key: key.into(),
+            value,
+        }
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let table = toml::Table::from_iter([(self.key.clone(), (&self.value).into())]);
+        let line = toml::to_string(&table).map_err(|_| fmt::Error)?;
+        f.write_str(line.trim_end())
+    }
+}
+
+/// Something wrong with a patch, and where.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub line: usize,
+    /// The key it's about, if it got as far as having one.
+    pub field: Option<String>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(line: usize, field: Option<&str>, message: impl Into<String>) -> Self {
+        Self {
+            line,
+            field: field.map(str::to_string),
+            message: message.into(),
+        }
+    }
+
+    fn at(entry: &Entry, message: impl Into<String>) -> Self {
+        Self::new(entry.line, Some(&entry.key), message)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.field {
+            Some(field) => write!(f, "line {}: {field}: {}", self.line, self.message),
+            None => write!(f, "line {}: {}", self.line, self.message),
+        }
+    }
+}
+
+/// The version patches are written for now. Patches with no `version` are
+/// from before there was one, which makes them version 1.
+pub const VERSION: u32 = 1;
+
+/// Something that changed from one version to the next.
+#[derive(Clone, Copy, Debug)]
+pub enum Change {
+    /// A top level key was renamed.
+    RenameNode(&'static str, &'static str),
+    RenameParam(&'static str, &'static str),
+    /// A parameter's range or units changed, and old values go through the
+    /// function to mean the same thing.
+    ConvertParam(&'static str, fn(f64) -> f64),
+}
+
+/// `MIGRATIONS[0]` is what changed going from version 1 to 2, and so on.
+/// Bumping [`VERSION`] means adding an entry here.
+const MIGRATIONS: &[&[Change]] = &[];
+
+const _: () = assert!(MIGRATIONS.len() as u32 + 1 == VERSION);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Patch {
+    /// The version the file said it was for. Everything else has been
+    /// brought up to [`VERSION`] already.
+    pub written_for: u32,
+    /// The top level keys, picking sources and effects.
+    pub nodes: Vec<Entry>,
+    pub params: Vec<Entry>,
+}
+
+impl Default for Patch {
+    fn default() -> Self {
+        Self {
+            written_for: VERSION,
+            nodes: Vec::new(),
+            params: Vec::new(),
+        }
+    }
+}
+
+/// The top level keys that can be given on the command line too.
+pub const NODES: [&str; 24] = [
+    "synth",
+    "wave_position",
+    "exciter",
+    "seed",
+    "voices",
+    "distortion",
+    "oversample",
+    "ladder",
+    "fir",
+    "fir_taps",
+    "fir_window",
+    "delay",
+    "ping_pong",
+    "bpm",
+    "reverb",
+    "ir",
+    "limiter",
+    "chain",
+    "siggen",
+    "sidechain",
+    "tuning",
+    "keymap",
+    "a4",
+    "transpose",
+];
+
+/// The entry for the node `key`, from its value as given on the command
+/// line.
+pub fn node_entry(key: &str, raw: &str) -> Option<Entry> {
+    let value = match key {
+        "voices" | "seed" | "oversample" | "ladder" | "fir_taps" | "bpm" | "a4" | "transpose" => {
+            Value::Number(raw.parse().ok()?)
+        }
+        "reverb" | "limiter" => Value::Bool(raw.parse().ok()?),
+        _ if NODES.contains(&key) => Value::String(raw.to_string()),
+        _ => return None,
+    };
+    Some(Entry::new(key, value))
+}
+
+/// Writes the patch out the way it's read, at the current version.
+impl fmt::Display for Patch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let table = |entries: &[Entry]| -> toml::Table {
+            entries
+                .iter()
+                .map(|e| (e.key.clone(), (&e.value).into()))
+                .collect()
+        };
+        let mut top = toml::Table::new();
+        top.insert("version".to_string(), toml::Value::Integer(VERSION.into()));
+        top.extend(table(&self.nodes));
+        top.insert(
+            "params".to_string(),
+            toml::Value::Table(table(&self.params)),
+        );
+        f.write_str(&toml::to_string(&top).map_err(|_| fmt::Error)?)
+    }
+}
+
+/// What a key in a patch holds, keeping where each key was for
+/// [`Diagnostic`]s. Sections and dotted keys are tables.
+enum Item {
+    Value(Value),
+    Table(Vec<(Spanned<String>, Item)>),
+    /// Something there's no use for in a patch, like an array, and what.
+    Other(&'static str),
+}
+
+impl<'de> Deserialize<'de> for Item {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ItemVisitor;
+
+        impl<'de> Visitor<'de> for ItemVisitor {
+            type Value = Item;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a string, number, boolean or table")
+            }
+
+            fn visit_bool<E: de::Error>(self, b: bool) -> Result<Item, E> {
+                Ok(Item::Value(Value::Bool(b)))
+            }
+
+            fn visit_i64<E: de::Error>(self, n: i64) -> Result<Item, E> {
+                Ok(Item::Value(Value::Number(n as f64)))
+            }
+
+            fn visit_u64<E: de::Error>(self, n: u64) -> Result<Item, E> {
+                Ok(Item::Value(Value::Number(n as f64)))
+            }
+
+            fn visit_f64<E: de::Error>(self, n: f64) -> Result<Item, E> {
+                Ok(Item::Value(Value::Number(n)))
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<Item, E> {
+                Ok(Item::Value(Value::String(s.to_string())))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Item, A::Error> {
+                while seq.next_element::<IgnoredAny>()?.is_some() {}
+                Ok(Item::Other("an array"))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Item, A::Error> {
+                let mut entries = Vec::new();
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(Item::Table(entries))
+            }
+        }
+
+        deserializer.deserialize_any(ItemVisitor)
+    }
+}
+
+/// The 1-based line `at` bytes into `text` is on.
+fn line_at(text: &str, at: usize) -> usize {
+    text[..at.min(text.len())].matches('\n').count() + 1
+}
+
+/// Adds the values in `table` to `out`, with the keys of tables within
+/// joined on with dots, so `ladder.resonance` means the same quoted or not.
+fn flatten(
+    text: &str,
+    prefix: &str,
+    table: Vec<(Spanned<String>, Item)>,
+    out: &mut Vec<Entry>,
+    errors: &mut Vec<Diagnostic>,
+) {
+    for (key, item) in table {
+        let line = line_at(text, key.span().start);
+        let key = format!("{prefix}{}", key.into_inner());
+        match item {
+            Item::Value(value) => out.push(Entry { line, key, value }),
+            Item::Table(inner) => flatten(text, &format!("{key}."), inner, out, errors),
+            Item::Other(what) => errors.push(Diagnostic::new(
+                line,
+                Some(&key),
+                format!("expected a number, got {what}"),
+            )),
+        }
+    }
+}
+
+impl Patch {
+    /// A patch that sets up `nodes` and sets `params` to what they are now.
+    pub fn from_state(nodes: Vec<Entry>, params: &[(String, f32)]) -> Self {
+        let params = params
+            .iter()
+            // the shortest text that reads back as the same f32, rather
+            // than the f64 it widens to
+            .map(|(name, value)| {
+                Entry::new(name, Value::Number(value.to_string().parse().unwrap()))
+            })
+            .collect();
+        Self {
+            written_for: VERSION,
+            nodes,
+            params,
+        }
+    }
+
+    /// Reads a patch file, which is a chain (see [`live`]) if its name ends
+    /// in `.chain`.
+    pub fn read(path: &Path, text: &str) -> Result<Self, Vec<Diagnostic>> {
+        if path.extension().is_some_and(|ext| ext == "chain") {
+            live::parse(text)
+        } else {
+            Patch::parse(text)
+        }
+    }
+
+    /// Reads a patch, reporting every entry that doesn't make sense rather
+    /// than just the first. A file that isn't TOML at all only gets the
+    /// first thing wrong with it, though.
+    pub fn parse(text: &str) -> Result<Self, Vec<Diagnostic>> {
+        let top = match toml::from_str(text) {
+            Ok(Item::Table(top)) => top,
+            Ok(_) => unreachable!("a TOML document is a table"),
+            Err(e) => {
+                let line = e.span().map_or(0, |span| line_at(text, span.start));
+                return Err(vec![Diagnostic::new(line, None, e.message())]);
+            }
+        };
+        let mut patch = Patch::default();
+        let mut errors = Vec::new();
+        let mut version = None;
+
+        for (key, item) in top {
+            let line = line_at(text, key.span().start);
+            let key = key.into_inner();
+            let value = match (key.as_str(), item) {
+                ("params", Item::Table(params)) => {
+                    flatten(text, "", params, &mut patch.params, &mut errors);
+                    continue;
+                }
+                (_, Item::Table(_)) => {
+                    errors.push(Diagnostic::new(
+                        line,
+                        None,
+                        format!("unknown section [{key}], the only one is [params]"),
+                    ));
+                    continue;
+                }
+                (_, Item::Other(what)) => {
+                    errors.push(Diagnostic::new(
+                        line,
+                        Some(&key),
+                        format!("expected a string, number or boolean, got {what}"),
+                    ));
+                    continue;
+                }
+                (_, Item::Value(value)) => value,
+            };
+            let entry = Entry { line, key, value };
+            if entry.key == "version" {
+                match count(&entry) {
+                    Ok(v) if v as u32 <= VERSION => version = Some(v as u32),
+                    Ok(v) => errors.push(Diagnostic::at(
+                        &entry,
+                        format!("version {v} patches need a newer synthtoy, this one reads up to {VERSION}"),
+                    )),
+                    Err(e) => errors.push(Diagnostic::at(&entry, e)),
+                }
+            } else {
+                patch.nodes.push(entry);
+            }
+        }
+        patch.written_for = version.unwrap_or(1);
+
+        if errors.is_empty() {
+            patch.migrate(MIGRATIONS);
+            Ok(patch)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Applies the changes since the version the patch was written for, with
+    /// `migrations` laid out like [`MIGRATIONS`].
+    fn migrate(&mut self, migrations: &[&[Change]]) {
+        for changes in &migrations[self.written_for as usize - 1..] {
+            for change in *changes {
+                let (entries, from) = match *change {
+                    Change::RenameNode(from, _) => (&mut self.nodes, from),
+                    Change::RenameParam(from, _) | Change::ConvertParam(from, _) => {
+                        (&mut self.params, from)
+                    }
+                };
+                let Some(entry) = entries.iter_mut().find(|e| e.key == from) else {
+                    continue;
+                };
+                match *change {
+                    Change::RenameNode(_, to) | Change::RenameParam(_, to) => {
+                        entry.key = to.to_string()
+                    }
+                    Change::ConvertParam(_, convert) => {
+                        if let Value::Number(n) = &mut entry.value {
+                            *n = convert(*n);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sets up the sources and effects, loading any files they need. Voices
+    /// default to strings, and everything else to being left out.
+    pub fn audio_config(&self) -> Result<AudioConfig, Vec<Diagnostic>> {
+        let mut errors = Vec::new();
+        let mut config = AudioConfig {
+            nodes: self.nodes.clone(),
+            ..AudioConfig::default()
+        };
+        let mut synth = SynthKind::String;
+        let mut wave_position = None;
+        let mut exciter = ExciterKind::Noise;
+        let mut voices = 8;
+        let mut seed = DEFAULT_SEED;
+        let mut distortion = None;
+        let mut oversample = 4;
+        let mut fir: Option<(&Entry, PathBuf)> = None;
+        let mut fir_taps = 100;
+        let mut fir_window = Window::Hann;
+        let mut ir: Option<(&Entry, PathBuf)> = None;
+        let mut tuning: Option<(&Entry, PathBuf)> = None;
+        let mut keymap: Option<(&Entry, PathBuf)> = None;
+        let mut a4 = note::A4;
+        let mut transpose = 0.;
+
+        for entry in &self.nodes {
+            let result = (|| -> Result<(), String> {
+                match entry.key.as_str() {
+                    "synth" => synth = parsed(entry)?,
+                    "wave_position" => wave_position = Some(parsed(entry)?),
+                    "exciter" => exciter = parsed(entry)?,
+                    "voices" => voices = count(entry)?,
+                    "seed" => seed = whole(entry)?,
+                    "distortion" => distortion = Some(parsed::<Curve>(entry)?),
+                    "oversample" => oversample = count(entry)?,
+                    "ladder" => config.ladder = Some(number(entry)?),
+                    "fir" => fir = Some((entry, string(entry)?.into())),
+                    "fir_taps" => fir_taps = count(entry)?,
+                    "fir_window" => fir_window = parsed(entry)?,
+                    "delay" => config.delay = Some(parsed(entry)?),
+                    "ping_pong" => config.ping_pong = Some(parsed(entry)?),
+                    "bpm" => config.bpm = number(entry)?,
+                    "reverb" => config.reverb = boolean(entry)?,
+                    "ir" => ir = Some((entry, string(entry)?.into())),
+                    "limiter" => config.limiter = boolean(entry)?,
+                    "chain" => config.order = parsed(entry)?,
+                    "siggen" => config.siggen = Some(Siggen::new(parsed(entry)?)),
+                    "sidechain" => config.sidechain = Some(parsed(entry)?),
+                    "tuning" => tuning = Some((entry, string(entry)?.into())),
+                    "keymap" => keymap = Some((entry, string(entry)?.into())),
+                    "a4" => {
+                        a4 = Some(number(entry)?)
+                            .filter(|&hz| hz > 0.)
+                            .ok_or("expected a frequency above 0Hz")?
+                    }
+                    "transpose" => transpose = number(entry)?,
+                    _ => return Err("unknown node".to_string()),
+                }
+                Ok(())
+            })();
+            if let Err(message) = result {
+                errors.push(Diagnostic::at(entry, message));
+            }
+        }
+
+        // the files only get looked at once everything is known, so the
+        // errors point at the line naming the file
+        let line_of = |key: &str| self.nodes.iter().find(|e| e.key == key);
+        let file_error = |key: &str, message: String| match line_of(key) {
+            Some(entry) => Diagnostic::at(entry, message),
+            None => Diagnostic::new(0, Some(key), message),
+        };
+        if let Some(env) = wave_position {
+            match synth.clone().with_position(env) {
+                Ok(moving) => synth = moving,
+                Err(e) => errors.push(file_error("wave_position", e)),
+            }
+        }
+        match exciter.build() {
+            Ok(exciter) => match synth.build_voices(voices, &*exciter, seed) {
+                Ok(voices) => config.voices = voices,
+                Err(e) => errors.push(file_error("synth", e.to_string())),
+            },
+            Err(e) => errors.push(file_error("exciter", e.to_string())),
+        }
+        config.distortion = distortion.map(|curve| Waveshaper::new(curve, oversample));
+        if let Some((entry, path)) = fir {
+            match FIR::load(&path, fir_taps, fir_window) {
+                Ok(fir) => config.fir = Some(fir),
+                Err(e) => errors.push(Diagnostic::at(entry, e)),
+            }
+        }
+        if let Some((entry, path)) = ir {
+            match ConvolutionReverb::load(&path, 0.3) {
+                Ok(ir) => config.convolution = Some(ir),
+                Err(e) => errors.push(Diagnostic::at(entry, format!("{}: {e}", path.display()))),
+            }
+        }
+        match (tuning, keymap) {
+            (Some((entry, path)), keymap) => {
+                match Tuning::load(&path, keymap.as_ref().map(|(_, path)| path.as_path())) {
+                    Ok(tuning) => config.tuning = tuning,
+                    Err(e) => errors.push(Diagnostic::at(entry, e)),
+                }
+            }
+            (None, Some((entry, _))) => errors.push(Diagnostic::at(
+                entry,
+                "a keymap needs a tuning to map".to_string(),
+            )),
+            (None, None) => {}
+        }
+        if let Err(e) = config.tuning.set_reference(a4, transpose) {
+            errors.push(file_error("transpose", e));
+        }
+
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// The values in `[params]`, leaving out any that aren't numbers.
+    pub fn param_values(&self) -> Vec<(String, f32)> {
+        self.params
+            .iter()
+            .filter_map(|entry| Some((entry.key.clone(), number(entry).ok()?)))
+            .collect()
+    }
+
+    /// Checks that everything in the patch exists and is in range, by
+    /// building it.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut config = match self.audio_config() {
+            Ok(config) => config,
+            // the parameters depend on what there is to set
+            Err(errors) => return errors,
+        };
+        let report: Reporter = Arc::new(|_| {});
+        let engine = audio_thread::build_engine(&mut config, &mut Guards::new(report));
+        let known = engine.params();
+
+        let mut errors = Vec::new();
+        for entry in &self.params {
+            let value = match number(entry) {
+                Ok(value) => value,
+                Err(e) => {
+                    errors.push(Diagnostic::at(entry, e));
+                    continue;
+                }
+            };
+            match known.iter().find(|p| p.name == entry.key) {
+                Some(info) if !(info.min..=info.max).contains(&value) => {
+                    errors.push(Diagnostic::at(
+                        entry,
+                        format!("{value} is outside {}..{}", info.min, info.max),
+                    ));
+                }
+                Some(_) => {}
+                None => {
+                    let mut message = "unknown parameter".to_string();
+                    if let Some(close) = closest(&entry.key, known.iter().map(|p| &*p.name)) {
+                        message += &format!(", did you mean {close}?");
+                    }
+                    errors.push(Diagnostic::at(entry, message));
+                }
+            }
+        }
+        errors
+    }
+}
+
+fn string(entry: &Entry) -> Result<&str, String> {
+    match &entry.value {
+        Value::String(s) => Ok(s),
+        other => Err(format!("expected a string, got {other}")),
+    }
+}
+
+fn number(entry: &Entry) -> Result<f32, String> {
+    match entry.value {
+        Value::Number(n) => Ok(n as f32),
+        ref other => Err(format!("expected a number, got {other}")),
+    }
+}
+
+fn count(entry: &Entry) -> Result<usize, String> {
+    match entry.value {
+        Value::Number(n) if n >= 1. && n.fract() == 0. => Ok(n as usize),
+        ref other => Err(format!("expected a whole number above 0, got {other}")),
+    }
+}
+
+fn whole(entry: &Entry) -> Result<u32, String> {
+    match entry.value {
+        Value::Number(n) if (0. ..=u32::MAX as f64).contains(&n) && n.fract() == 0. => Ok(n as u32),
+        ref other => Err(format!("expected a whole number, got {other}")),
+    }
+}
+
+fn boolean(entry: &Entry) -> Result<bool, String> {
+    match entry.value {
+        Value::Bool(b) => Ok(b),
+        ref other => Err(format!("expected true or false, got {other}")),
+    }
+}
+
+/// A string value read the same way as on the command line.
+fn parsed<T: FromStr<Err = String>>(entry: &Entry) -> Result<T, String> {
+    string(entry)?.parse()
+}
+
+/// The name from `names` nearest to `name` by edit distance, if any are
+/// near enough to be a plausible typo.
+fn closest<'a>(name: &str, names: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let distance = |a: &str, b: &str| {
+        let b: Vec<char> = b.chars().collect();
+        let mut prev: Vec<usize> = (0..=b.len()).collect();
+        for (i, ca) in a.chars().enumerate() {
+            let mut row = vec![i + 1];
+            for (j, cb) in b.iter().enumerate() {
+                let cost = if ca == *cb { 0 } else { 1 };
+                row.push((prev[j] + cost).min(prev[j + 1] + 1).min(row[j] + 1));
+            }
+            prev = row;
+        }
+        prev[b.len()]
+    };
+    names
+        .map(|candidate| (distance(name, candidate), candidate))
+        .filter(|&(d, _)| d <= 3)
+        .min_by_key(|&(d, _)| d)
+        .map(|(_, candidate)| candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patch_validate() {
+        let patch = Patch::parse(
+            "# a comment\n\
+             synth = \"string\"\n\
+             ladder = 2_000 # Hz\n\
+             reverb = true\n\
+             \n\
+             [params]\n\
+             ladder.resonance = 0.5\n\
+             \"reverb.mix\" = 0.25\n",
+        )
+        .unwrap();
+        assert_eq!(patch.nodes.len(), 3);
+        assert_eq!(patch.params[1].value, Value::Number(0.25));
+        assert_eq!(patch.validate(), []);
+        // plucking with the input needs nothing capturing to build
+        let patch = Patch::parse("exciter = \"input\"\n").unwrap();
+        assert_eq!(patch.validate(), []);
+
+        // past the first, a file that isn't TOML has nothing to go on
+        let errors = Patch::parse("ladder = 1\nladder 2000\nreverb = yes\n").unwrap_err();
+        let lines: Vec<usize> = errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, [2]);
+        assert_eq!(
+            Patch::parse("ladder = 1\nladder = 2\n").unwrap_err()[0].line,
+            2
+        );
+        let errors = Patch::parse("reverb = [true]\n[effects]\nladder = 1\n").unwrap_err();
+        let lines: Vec<usize> = errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, [1, 2]);
+
+        let errors = Patch::parse("ladder = \"loud\"\nir = \"/nonexistent.wav\"\nflanger = true\n")
+            .unwrap()
+            .validate();
+        let fields: Vec<_> = errors.iter().map(|e| e.field.as_deref().unwrap()).collect();
+        assert_eq!(fields, ["ladder", "flanger", "ir"]);
+
+        let errors =
+            Patch::parse("ladder = 2000\n[params]\nladder.cutof = 100\nladder.resonance = 3\n")
+                .unwrap()
+                .validate();
+        assert_eq!(
+            errors.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            [
+                "line 3: ladder.cutof: unknown parameter, did you mean ladder.cutoff?",
+                "line 4: ladder.resonance: 3 is outside 0..1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_patch_migrate() {
+        let patch = Patch::parse("synth = \"fm\"\n").unwrap();
+        assert_eq!(patch.written_for, 1);
+        let patch = Patch::parse(&format!("version = {VERSION}\n")).unwrap();
+        assert_eq!(patch.written_for, VERSION);
+        let errors = Patch::parse(&format!("version = {}\n", VERSION + 1)).unwrap_err();
+        assert_eq!(errors[0].field.as_deref(), Some("version"));
+
+        // as if there had been a version 2 and 3
+        let migrations: &[&[Change]] = &[
+            &[
+                Change::RenameNode("echo", "delay"),
+                Change::ConvertParam("delay.mix", |percent| percent / 100.),
+            ],
+            &[Change::RenameParam("delay.mix", "delay.wet")],
+        ];
+        let mut patch =
+            Patch::parse("echo = \"1/4\"\n[params]\ndelay.mix = 25\ndelay.feedback = 0.5\n")
+                .unwrap();
+        patch.migrate(migrations);
+        assert_eq!(patch.nodes[0].key, "delay");
+        assert_eq!(
+            patch.param_values(),
+            [
+                ("delay.wet".to_string(), 0.25),
+                ("delay.feedback".to_string(), 0.5)
+            ]
+        );
+
+        // already up to date with version 2
+        let mut patch = Patch::parse("[params]\ndelay.mix = 25\n").unwrap();
+        patch.written_for = 2;
+        patch.migrate(migrations);
+        assert_eq!(patch.param_values(), [("delay.wet".to_string(), 25.)]);
+    }
+
+    #[test]
+    fn test_patch_save() {
+        let nodes = vec![
+            Entry::new("ladder", Value::Number(2000.)),
+            Entry::new("reverb", Value::Bool(true)),
+            Entry::new("chain", Value::String("reverb, ladder".to_string())),
+        ];
+        let params = [
+            ("ladder.resonance".to_string(), 0.3),
+            ("reverb.mix".to_string(), 0.25),
+        ];
+        let text = Patch::from_state(nodes, &params).to_string();
+        let patch = Patch::parse(&text).unwrap();
+        assert_eq!(patch.validate(), []);
+        assert_eq!(patch.param_values(), params);
+
+        // the reverb goes first
+        let mut config = patch.audio_config().unwrap();
+        let report: Reporter = Arc::new(|_| {});
+        let engine = audio_thread::build_engine(&mut config, &mut Guards::new(report));
+        let names: Vec<_> = engine.params().into_iter().map(|p| p.name).collect();
+        let position = |prefix| names.iter().position(|n| n.starts_with(prefix)).unwrap();
+        assert!(position("reverb.") < position("ladder."));
+
+        assert!(Patch::parse("chain = \"ladder, ladder\"\n")
+            .unwrap()
+            .validate()[0]
+            .message
+            .contains("twice"));
+    }
+
+    #[test]
+    fn test_patch_extras() {
+        let patch = Patch::parse(
+            "ladder = 2000\n\
+             chain = \"+ladder:400, ladder, +distortion:fold, +ladder:8000, +tone\"\n\
+             \n\
+             [params]\n\
+             ladder2.resonance = 0.5\n",
+        )
+        .unwrap();
+        assert_eq!(patch.validate(), []);
+        let mut config = patch.audio_config().unwrap();
+        let report: Reporter = Arc::new(|_| {});
+        let engine = audio_thread::build_engine(&mut config, &mut Guards::new(report));
+        let names: Vec<_> = engine.params().into_iter().map(|p| p.name).collect();
+        let position = |name| names.iter().position(|n| n == name).unwrap();
+        // numbered after the one of each there always is
+        assert!(position("ladder2.cutoff") < position("ladder.cutoff"));
+        assert!(position("ladder.cutoff") < position("distortion2.drive"));
+        assert!(position("distortion2.drive") < position("ladder3.cutoff"));
+        assert_eq!(engine.get_param("ladder2.cutoff"), Some(400.));
+        assert_eq!(engine.get_param("tone2.bass"), Some(0.));
+
+        for chain in ["+phaser", "+ladder", "+reverb:big"] {
+            let patch = Patch::parse(&format!("chain = \"{chain}\"\n")).unwrap();
+            assert_eq!(patch.validate().len(), 1, "{chain}");
+        }
+    }
+
+    #[test]
+    fn test_patch_sidechain() {
+        let patch =
+            Patch::parse("sidechain = \"beat\"\n\n[params]\nsidechain.ratio = 4\n").unwrap();
+        assert_eq!(patch.validate(), []);
+        let mut config = patch.audio_config().unwrap();
+        let report: Reporter = Arc::new(|_| {});
+        let engine = audio_thread::build_engine(&mut config, &mut Guards::new(report));
+        // ducking hard to start with, until the patch's parameters go on
+        assert_eq!(engine.get_param("sidechain.ratio"), Some(8.));
+        // the tempo reaches the beat it ducks on
+        assert!(engine
+            .params()
+            .iter()
+            .any(|p| p.name == "sidechain.key.bpm"));
+
+        assert!(!Patch::parse("sidechain = \"kick\"\n")
+            .unwrap()
+            .validate()
+            .is_empty());
+    }
+}
@@ -0,0 +1,126 @@
+//! Channel aftertouch routed onto a synth parameter. Most keyboards only send
+//! a coarse, steppy pressure signal, so it is slewed towards each new value
+//! at control rate rather than applied as it comes in.
+
+use std::time::Duration;
+
+use crate::clock::Stamp;
+use crate::midi::CcTarget;
+
+/// How often the smoothed value is pushed to the synth while it's moving.
+pub const CONTROL_PERIOD: Duration = Duration::from_millis(5);
+
+#[derive(Clone, Debug)]
+pub struct PressureConfig {
+    pub target: CcTarget,
+    /// Time constant of the smoothing while pressure rises; zero applies it
+    /// immediately.
+    pub smoothing: Duration,
+    /// The same while it falls, so a swell can come in quickly and die away
+    /// slowly, or the other way around.
+    pub release: Duration,
+}
+
+pub struct Pressure {
+    pub config: PressureConfig,
+    goal: f32,
+    current: f32,
+    /// last time `current` was advanced, if it hasn't caught up with `goal`
+    moving_since: Option<Stamp>,
+}
+
+impl Pressure {
+    pub fn new(config: PressureConfig) -> Self {
+        Self {
+            config,
+            goal: 0.,
+            current: 0.,
+            moving_since: None,
+        }
+    }
+
+    /// Takes a new raw pressure value (0..=127).
+    pub fn set(&mut self, now: Stamp, pressure: u8) {
+        self.goal = pressure as f32 / 127.;
+        self.moving_since.get_or_insert(now);
+    }
+
+    /// When [`Pressure::update`] next has something to do.
+    pub fn next_deadline(&self) -> Option<Stamp> {
+        self.moving_since.map(|t| t + CONTROL_PERIOD)
+    }
+
+    /// Advances the smoothing to `now`, returning the position (0..=1) to set
+    /// the target parameter to if it has moved.
+    pub fn update(&mut self, now: Stamp) -> Option<f32> {
+        let since = self.moving_since?;
+        if now < since + CONTROL_PERIOD {
+            return None;
+        }
+        let tau = if self.goal > self.current {
+            self.config.smoothing
+        } else {
+            self.config.release
+        }
+        .as_secs_f32();
+        let dt = (now - since).as_secs_f32();
+        let coeff = if tau > 0. { 1. - (-dt / tau).exp() } else { 1. };
+        self.current += (self.goal - self.current) * coeff;
+
+        // a tenth of a MIDI step is close enough
+        if (self.goal - self.current).abs() < 0.1 / 127. {
+            self.current = self.goal;
+            self.moving_since = None;
+        } else {
+            self.moving_since = Some(now);
+        }
+        Some(self.current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pressure_smoothing() {
+        let mut pressure = Pressure::new(PressureConfig {
+            target: "volume".parse().unwrap(),
+            smoothing: Duration::from_millis(20),
+            release: Duration::from_millis(100),
+        });
+        let t0 = Stamp::default();
+        assert_eq!(pressure.next_deadline(), None);
+
+        pressure.set(t0, 127);
+        assert_eq!(pressure.update(t0), None);
+        assert_eq!(pressure.next_deadline(), Some(t0 + CONTROL_PERIOD));
+
+        // one time constant in, it should be about 63% of the way there
+        let mut now = t0;
+        let mut value = 0.;
+        for _ in 0..4 {
+            now += CONTROL_PERIOD;
+            value = pressure.update(now).unwrap();
+        }
+        assert!((value - 0.632).abs() < 0.01, "{value}");
+
+        while pressure.next_deadline().is_some() {
+            now += CONTROL_PERIOD;
+            value = pressure.update(now).unwrap();
+        }
+        assert_eq!(value, 1.);
+        assert!(now - t0 < Duration::from_millis(200));
+
+        // letting go takes the release time instead
+        pressure.set(now, 0);
+        let start = now;
+        for _ in 0..20 {
+            now += CONTROL_PERIOD;
+            value = pressure.update(now).unwrap();
+        }
+        assert!((value - 0.368).abs() < 0.01, "{value}");
+        // to the nearest sample, each time
+        assert!(((now - start).as_secs_f32() - 0.1).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,376 @@
+//! A bounded queue for everything headed to the audio thread, from MIDI
+//! inputs and the UI alike, so a stalled audio thread can't make it grow
+//! without end. What happens when it's full is up to its [`Overflow`].
+//!
+//! It works like [`std::sync::mpsc`], errors and all, apart from
+//! [`Sender::blocking`] for senders that can afford to wait.
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{RecvError, RecvTimeoutError, SendError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// What a full queue does with one more.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// Makes room by losing the oldest, taking what's
+    /// [`Coalesce::expendable`] before anything else and never anything
+    /// [`Coalesce::essential`].
+    DropOldest,
+    /// Replaces a waiting move of the same controller, if there is one,
+    /// and otherwise loses the oldest like [`Overflow::DropOldest`].
+    #[default]
+    CoalesceCc,
+    /// Waits for room in blocking senders, which is the UI's, and loses
+    /// the oldest like [`Overflow::DropOldest`] for the rest, which can't
+    /// wait.
+    Block,
+}
+
+impl std::str::FromStr for Overflow {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        Ok(match value {
+            "drop-oldest" => Overflow::DropOldest,
+            "coalesce-cc" => Overflow::CoalesceCc,
+            "block" => Overflow::Block,
+            _ => return Err(format!("unknown overflow policy {value:?}")),
+        })
+    }
+}
+
+/// Things that make an older one not worth passing on, and that matter
+/// more or less than the rest when something has to go.
+pub trait Coalesce {
+    fn replaces(&self, older: &Self) -> bool;
+
+    /// Goes first when there's no room, since another will soon be along.
+    fn expendable(&self) -> bool {
+        false
+    }
+
+    /// Never goes, even past the queue's capacity, since losing it would
+    /// leave something stuck.
+    fn essential(&self) -> bool {
+        false
+    }
+}
+
+/// What's been lost to overflow.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Overflowed {
+    pub dropped: u64,
+    pub coalesced: u64,
+}
+
+impl Overflowed {
+    pub fn is_empty(&self) -> bool {
+        *self == Overflowed::default()
+    }
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    senders: usize,
+    /// the receiver has gone
+    closed: bool,
+    overflowed: Overflowed,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    capacity: usize,
+    overflow: Overflow,
+    /// signalled when something goes in, or the last sender goes
+    filled: Condvar,
+    /// signalled when something comes out, or the receiver goes
+    emptied: Condvar,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+    blocking: bool,
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Makes a queue that holds up to `capacity`.
+pub fn bounded<T: Coalesce>(capacity: usize, overflow: Overflow) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            items: VecDeque::with_capacity(capacity),
+            senders: 1,
+            closed: false,
+            overflowed: Overflowed::default(),
+        }),
+        capacity: capacity.max(1),
+        overflow,
+        filled: Condvar::new(),
+        emptied: Condvar::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+            blocking: false,
+        },
+        Receiver { shared },
+    )
+}
+
+impl<T: Coalesce> Sender<T> {
+    /// Another sender, which waits for room when the queue is full and
+    /// blocks, instead of losing anything.
+    pub fn blocking(&self) -> Self {
+        let mut sender = self.clone();
+        sender.blocking = true;
+        sender
+    }
+
+    /// Fails only once the receiver has gone.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let shared = &*self.shared;
+        let mut state = shared.lock();
+        loop {
+            if state.closed {
+                return Err(SendError(value));
+            }
+            if state.items.len() < shared.capacity {
+                break;
+            }
+            if shared.overflow == Overflow::Block && self.blocking {
+                state = shared
+                    .emptied
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner);
+                continue;
+            }
+            if shared.overflow == Overflow::CoalesceCc {
+                if let Some(older) = state.items.iter().rposition(|o| value.replaces(o)) {
+                    // at the back, so it keeps its place in time
+                    state.items.remove(older);
+                    state.overflowed.coalesced += 1;
+                    break;
+                }
+            }
+            let victim = state.items.iter().position(T::expendable);
+            match victim.or_else(|| state.items.iter().position(|o| !o.essential())) {
+                Some(victim) => {
+                    state.items.remove(victim);
+                }
+                // everything waiting has to stay, so this goes past capacity
+                None if value.essential() => break,
+                None => {
+                    state.overflowed.dropped += 1;
+                    return Ok(());
+                }
+            }
+            state.overflowed.dropped += 1;
+            break;
+        }
+        state.items.push_back(value);
+        drop(state);
+        shared.filled.notify_one();
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+            blocking: self.blocking,
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            self.shared.filled.notify_all();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Waits for the next one, failing once every sender has gone and
+    /// there's nothing left.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let shared = &*self.shared;
+        let mut state = shared.lock();
+        loop {
+            if let Some(value) = state.items.pop_front() {
+                drop(state);
+                shared.emptied.notify_one();
+                return Ok(value);
+            }
+            if state.senders == 0 {
+                return Err(RecvError);
+            }
+            state = shared
+                .filled
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let shared = &*self.shared;
+        let mut state = shared.lock();
+        loop {
+            if let Some(value) = state.items.pop_front() {
+                drop(state);
+                shared.emptied.notify_one();
+                return Ok(value);
+            }
+            if state.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            state = shared
+                .filled
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    /// Everything waiting, without waiting for more.
+    pub fn try_iter(&self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(|| {
+            let value = self.shared.lock().items.pop_front();
+            self.shared.emptied.notify_one();
+            value
+        })
+    }
+
+    /// What's been lost since it was last asked.
+    pub fn take_overflowed(&self) -> Overflowed {
+        std::mem::take(&mut self.shared.lock().overflowed)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.emptied.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum Event {
+        Note(u8),
+        Off(u8),
+        /// A controller number and value.
+        Cc(u8, u8),
+    }
+    use Event::*;
+
+    impl Coalesce for Event {
+        fn replaces(&self, older: &Self) -> bool {
+            matches!((self, older), (Cc(new, _), Cc(old, _)) if new == old)
+        }
+
+        fn expendable(&self) -> bool {
+            matches!(self, Cc(..))
+        }
+
+        fn essential(&self) -> bool {
+            matches!(self, Off(_))
+        }
+    }
+
+    #[test]
+    fn test_queue() {
+        let (send, recv) = bounded(3, Overflow::DropOldest);
+        for note in 0..4 {
+            send.send(Note(note)).unwrap();
+        }
+        assert_eq!(
+            recv.try_iter().collect::<Vec<_>>(),
+            [Note(1), Note(2), Note(3)]
+        );
+        assert_eq!(
+            recv.take_overflowed(),
+            Overflowed {
+                dropped: 1,
+                coalesced: 0
+            }
+        );
+        assert!(recv.take_overflowed().is_empty());
+        assert_eq!(
+            recv.recv_timeout(Duration::from_millis(1)),
+            Err(RecvTimeoutError::Timeout)
+        );
+
+        // controllers go first, and note offs never do
+        for event in [Off(1), Cc(1, 0), Note(2), Note(3), Off(4), Off(5), Off(6)] {
+            send.send(event).unwrap();
+        }
+        send.send(Note(7)).unwrap();
+        assert_eq!(
+            recv.try_iter().collect::<Vec<_>>(),
+            [Off(1), Off(4), Off(5), Off(6)]
+        );
+        assert_eq!(recv.take_overflowed().dropped, 4);
+
+        // the newest move of a controller goes to the back in place of the
+        // older one
+        let (send, recv) = bounded(3, Overflow::CoalesceCc);
+        for event in [Cc(1, 0), Note(60), Cc(7, 0), Cc(1, 10), Note(62)] {
+            send.send(event).unwrap();
+        }
+        assert_eq!(
+            recv.try_iter().collect::<Vec<_>>(),
+            [Note(60), Cc(1, 10), Note(62)]
+        );
+        assert_eq!(
+            recv.take_overflowed(),
+            Overflowed {
+                dropped: 1,
+                coalesced: 1
+            }
+        );
+        for event in [Cc(1, 0), Note(60), Cc(7, 0), Cc(1, 10)] {
+            send.send(event).unwrap();
+        }
+        assert_eq!(recv.recv(), Ok(Note(60)));
+        assert_eq!(recv.take_overflowed().coalesced, 1);
+
+        // a blocking sender waits its turn, and the others don't
+        let (send, recv) = bounded(1, Overflow::Block);
+        let ui = send.blocking();
+        send.send(Note(1)).unwrap();
+        send.send(Note(2)).unwrap();
+        let waiting = std::thread::spawn(move || ui.send(Note(3)));
+        assert_eq!(recv.recv(), Ok(Note(2)));
+        waiting.join().unwrap().unwrap();
+        assert_eq!(recv.recv(), Ok(Note(3)));
+        assert_eq!(recv.take_overflowed().dropped, 1);
+
+        drop(send);
+        assert_eq!(recv.recv(), Err(RecvError));
+        let (send, recv) = bounded(1, Overflow::Block);
+        drop(recv);
+        assert!(send.send(Note(1)).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+//! Randomizing and mutating parameters, for finding sounds by accident,
+//! with undo for when the accident is a bad one.
+
+use crate::filters::Rng;
+use crate::params::ParamInfo;
+
+/// How many changes can be undone.
+const UNDO_DEPTH: usize = 32;
+
+/// How far [`Explorer::mutate`] moves things unless told otherwise, as a
+/// fraction of their random range.
+pub const DEFAULT_MUTATION: f32 = 0.1;
+
+/// Whether `name` is in `group`: every parameter for no group, otherwise
+/// the one called that or the ones under it, so `ladder` takes in
+/// `ladder.cutoff`.
+pub fn in_group(group: Option<&str>, name: &str) -> bool {
+    match group {
+        None => true,
+        Some(group) => name
+            .strip_prefix(group)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('.')),
+    }
+}
+
+pub struct Explorer {
+    rng: Rng,
+    /// what each change replaced, latest last
+    undo: Vec<Vec<(String, f32)>>,
+}
+
+impl Explorer {
+    pub fn new(seed: u32) -> Self {
+        Self {
+            rng: Rng::with_seed(seed),
+            undo: Vec::new(),
+        }
+    }
+
+    /// Anywhere in 0..1.
+    fn unit(&mut self) -> f32 {
+        0.5 + 0.5 * self.rng.next_f32()
+    }
+
+    /// New values for the parameters in `group` that can be randomized,
+    /// from anywhere in their random ranges. `current` is what they are
+    /// now, to go back to.
+    pub fn randomize(
+        &mut self,
+        params: &[ParamInfo],
+        current: &[(String, f32)],
+        group: Option<&str>,
+    ) -> Vec<(String, f32)> {
+        self.change(params, current, group, |explorer, _, (min, max)| {
+            min + (max - min) * explorer.unit()
+        })
+    }
+
+    /// Nudges the parameters in `group` that can be randomized by up to
+    /// `amount` of their random ranges either way, staying inside them.
+    pub fn mutate(
+        &mut self,
+        params: &[ParamInfo],
+        current: &[(String, f32)],
+        group: Option<&str>,
+        amount: f32,
+    ) -> Vec<(String, f32)> {
+        self.change(params, current, group, |explorer, value, (min, max)| {
+            let nudge = amount * (max - min) * explorer.rng.next_f32();
+            (value + nudge).clamp(min.min(value), max.max(value))
+        })
+    }
+
+    fn change(
+        &mut self,
+        params: &[ParamInfo],
+        current: &[(String, f32)],
+        group: Option<&str>,
+        mut pick: impl FnMut(&mut Self, f32, (f32, f32)) -> f32,
+    ) -> Vec<(String, f32)> {
+        let mut before = Vec::new();
+        let mut after = Vec::new();
+        for info in params.iter().filter(|p| in_group(group, &p.name)) {
+            let (Some(range), Some(&(_, value))) = (
+                info.random,
+                current.iter().find(|(name, _)| *name == info.name),
+            ) else {
+                continue;
+            };
+            before.push((info.name.clone(), value));
+            after.push((info.name.clone(), pick(self, value, range)));
+        }
+        if !before.is_empty() {
+            if self.undo.len() == UNDO_DEPTH {
+                self.undo.remove(0);
+            }
+            self.undo.push(before);
+        }
+        after
+    }
+
+    /// What the latest change replaced, to set back.
+    pub fn undo(&mut self) -> Option<Vec<(String, f32)>> {
+        self.undo.pop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_explorer() {
+        assert!(in_group(Some("ladder"), "ladder.cutoff"));
+        assert!(!in_group(Some("ladder"), "ladderish"));
+        assert!(in_group(None, "volume"));
+
+        let params = [
+            ParamInfo::new("ladder.cutoff", 20., 20000.),
+            ParamInfo::new("ladder.resonance", 0., 1.).random_range(0., 0.8),
+            ParamInfo::new("volume", 0., 2.).not_random(),
+        ];
+        let current: Vec<_> = [
+            ("ladder.cutoff", 1000.),
+            ("ladder.resonance", 0.9),
+            ("volume", 1.),
+        ]
+        .map(|(name, value)| (name.to_string(), value))
+        .to_vec();
+        let mut explorer = Explorer::new(1);
+
+        let random = explorer.randomize(&params, &current, Some("ladder"));
+        assert_eq!(random.len(), 2);
+        assert!((0. ..=0.8).contains(&random[1].1));
+        // outside the range already, mutating doesn't make it any worse
+        let mutated = explorer.mutate(&params, &current, None, 0.1);
+        assert_eq!(mutated.len(), 2);
+        assert!((20. ..=3000.).contains(&mutated[0].1), "{mutated:?}");
+        assert!((0.72..=0.9).contains(&mutated[1].1), "{mutated:?}");
+
+        assert_eq!(explorer.undo().unwrap(), current[..2]);
+        assert_eq!(explorer.undo().unwrap(), current[..2]);
+        assert_eq!(explorer.undo(), None);
+        assert!(explorer
+            .randomize(&params, &current, Some("reverb"))
+            .is_empty());
+        assert_eq!(explorer.undo(), None);
+    }
+}
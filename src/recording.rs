@@ -0,0 +1,253 @@
+//! Recording audio to WAV files while it plays, the master output or any
+//! other point in the graph. The audio thread only copies each block into
+//! a queue, and a thread of its own writes it out.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::filters::SAMPLING_FREQ;
+use crate::spsc::{self, Consumer, Producer};
+
+/// Frames that can wait for the writer thread: two seconds.
+const QUEUE_LEN: usize = 2 * SAMPLING_FREQ;
+
+/// How often the writer thread looks for more.
+const POLL_PERIOD: Duration = Duration::from_millis(20);
+
+/// How long to wait for the audio thread to let go of the tap before
+/// ending the recording without it: it may have stopped, or the command
+/// taking the tap away may not have got through.
+const STOP_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Writes 32 bit float audio as it comes, filling in the lengths in the
+/// header at the end.
+pub struct WavWriter {
+    file: BufWriter<File>,
+    channels: u16,
+    frames: u32,
+    /// written at the end as the file's comment, if there is one
+    pub comment: Option<String>,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, channels: u16) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let rate = SAMPLING_FREQ as u32;
+        let frame_bytes = channels * 4;
+        file.write_all(b"RIFF\0\0\0\0WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        // IEEE float
+        file.write_all(&3u16.to_le_bytes())?;
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&rate.to_le_bytes())?;
+        file.write_all(&(rate * frame_bytes as u32).to_le_bytes())?;
+        file.write_all(&frame_bytes.to_le_bytes())?;
+        file.write_all(&32u16.to_le_bytes())?;
+        file.write_all(b"data\0\0\0\0")?;
+        Ok(Self {
+            file,
+            channels,
+            frames: 0,
+            comment: None,
+        })
+    }
+
+    /// Writes a sample for each channel.
+    pub fn write(&mut self, frame: &[f32]) -> io::Result<()> {
+        for sample in frame {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// Fills in the header, and the comment after the audio, returning how
+    /// many frames were written.
+    pub fn finish(mut self) -> io::Result<u32> {
+        let data = self.frames * self.channels as u32 * 4;
+        let mut info = Vec::new();
+        if let Some(comment) = &self.comment {
+            // nul terminated and padded to an even length, in a LIST chunk
+            let mut text = comment.as_bytes().to_vec();
+            text.push(0);
+            text.resize(text.len().next_multiple_of(2), 0);
+            info.extend(b"LIST");
+            info.extend((12 + text.len() as u32).to_le_bytes());
+            info.extend(b"INFOICMT");
+            info.extend((text.len() as u32).to_le_bytes());
+            info.extend(text);
+            self.file.write_all(&info)?;
+        }
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file
+            .write_all(&(36 + data + info.len() as u32).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&data.to_le_bytes())?;
+        self.file.flush()?;
+        Ok(self.frames)
+    }
+}
+
+/// The audio thread's end of a recording of `N` channels. Dropping it ends
+/// the recording.
+pub struct Tap<const N: usize = 2> {
+    frames: Producer<[f32; N]>,
+    dropped: Arc<AtomicUsize>,
+    done: Arc<AtomicBool>,
+}
+
+impl<const N: usize> Tap<N> {
+    fn push(&mut self, frame: [f32; N]) {
+        if self.frames.push(frame).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Tap<1> {
+    pub fn write(&mut self, samples: &[f32]) {
+        for &s in samples {
+            self.push([s]);
+        }
+    }
+}
+
+impl Tap<2> {
+    pub fn write(&mut self, left: &[f32], right: &[f32]) {
+        for (&l, &r) in left.iter().zip(right) {
+            self.push([l, r]);
+        }
+    }
+}
+
+impl<const N: usize> Drop for Tap<N> {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::Release);
+    }
+}
+
+impl<const N: usize> fmt::Debug for Tap<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Tap")
+    }
+}
+
+/// A recording in progress, on the control side.
+pub struct Recording {
+    pub path: PathBuf,
+    dropped: Arc<AtomicUsize>,
+    done: Arc<AtomicBool>,
+    writer: JoinHandle<io::Result<u32>>,
+}
+
+/// Starts writing to `path` whatever goes into the returned [`Tap`], with
+/// `comment` in the file.
+pub fn start<const N: usize>(
+    path: PathBuf,
+    comment: Option<String>,
+) -> io::Result<(Recording, Tap<N>)> {
+    let mut wav = WavWriter::create(&path, N as u16)?;
+    wav.comment = comment;
+    let (frames, mut queue): (_, Consumer<[f32; N]>) = spsc::channel(QUEUE_LEN);
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let done = done.clone();
+        std::thread::spawn(move || {
+            loop {
+                // checked first, so nothing pushed before the tap went
+                // away gets left behind
+                let finished = done.load(Ordering::Acquire);
+                while let Some(frame) = queue.pop() {
+                    wav.write(&frame)?;
+                }
+                if finished {
+                    break;
+                }
+                std::thread::sleep(POLL_PERIOD);
+            }
+            wav.finish()
+        })
+    };
+    let tap = Tap {
+        frames,
+        dropped: dropped.clone(),
+        done: done.clone(),
+    };
+    Ok((
+        Recording {
+            path,
+            dropped,
+            done,
+            writer,
+        },
+        tap,
+    ))
+}
+
+impl Recording {
+    /// Waits for everything to be written once the tap is gone, or
+    /// [`STOP_TIMEOUT`] if it doesn't go, and says how it went.
+    pub fn finish(self) -> String {
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        while !self.done.load(Ordering::Acquire) && Instant::now() < deadline {
+            std::thread::sleep(POLL_PERIOD);
+        }
+        self.done.store(true, Ordering::Release);
+        let path = self.path.display();
+        match self.writer.join().unwrap() {
+            Ok(frames) => {
+                let mut message = format!(
+                    "saved {:.1}s to {path}",
+                    frames as f32 / SAMPLING_FREQ as f32
+                );
+                let dropped = self.dropped.load(Ordering::Relaxed);
+                if dropped > 0 {
+                    message += &format!(", but missed {dropped} frames writing it");
+                }
+                message
+            }
+            Err(e) => format!("{path}: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recording() {
+        let path = std::env::temp_dir().join(format!("synthtoy-test-{}.wav", std::process::id()));
+        let (recording, mut tap) = start::<2>(path.clone(), Some("1:1 0:00".to_string())).unwrap();
+        let left: Vec<f32> = (0..1000).map(|i| i as f32 / 1000.).collect();
+        let right: Vec<f32> = left.iter().map(|s| -s).collect();
+        tap.write(&left[..600], &right[..600]);
+        tap.write(&left[600..], &right[600..]);
+        drop(tap);
+        assert!(recording.finish().starts_with("saved 0.0s"));
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(bytes.ends_with(b"ICMT\x0a\0\0\x001:1 0:00\0\0"));
+        let (header, data) = wav::read(&mut File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(header.channel_count, 2);
+        assert_eq!(header.sampling_rate, SAMPLING_FREQ as u32);
+        let samples = data.as_thirty_two_float().unwrap();
+        assert_eq!(samples.len(), 2000);
+        assert_eq!(samples[2 * 999], left[999]);
+        assert_eq!(samples[2 * 999 + 1], right[999]);
+
+        // the audio thread never lets go of this one
+        let (recording, mut tap) = start::<1>(path.clone(), None).unwrap();
+        tap.write(&left);
+        assert!(recording.finish().starts_with("saved 0.0s"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+//! Picking up changes to the patch file while playing, so a sound can be
+//! worked on without restarting. Changing only parameters just sets them;
+//! anything else means building the engine again and crossfading to it.
+//! A rebuild keeps the tempo, the modulation routing and any parameter the
+//! patch doesn't set, but notes and pedals start over.
+
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::patch::Patch;
+
+/// How often the file gets looked at.
+const POLL_PERIOD: Duration = Duration::from_millis(250);
+
+/// What to do about a patch that changed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reload {
+    /// Only parameters changed, to these.
+    Params(Vec<(String, f32)>),
+    /// The sources or effects changed, so it needs building again.
+    Rebuild(Patch),
+}
+
+impl Reload {
+    /// What it takes to get from `old` to `new`.
+    pub fn between(old: &Patch, new: &Patch) -> Option<Self> {
+        let same_nodes = old.nodes.len() == new.nodes.len()
+            && old
+                .nodes
+                .iter()
+                .zip(&new.nodes)
+                .all(|(a, b)| a.key == b.key && a.value == b.value);
+        if !same_nodes {
+            return Some(Reload::Rebuild(new.clone()));
+        }
+        let before = old.param_values();
+        let changed: Vec<_> = new
+            .param_values()
+            .into_iter()
+            .filter(|param| !before.contains(param))
+            .collect();
+        (!changed.is_empty()).then_some(Reload::Params(changed))
+    }
+}
+
+pub struct Watcher {
+    path: PathBuf,
+    patch: Patch,
+    modified: Option<SystemTime>,
+    next_check: Instant,
+}
+
+impl Watcher {
+    /// Watches `path`, which `patch` was loaded from.
+    pub fn new(path: PathBuf, patch: Patch) -> Self {
+        let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+        Self {
+            path,
+            patch,
+            modified,
+            next_check: Instant::now() + POLL_PERIOD,
+        }
+    }
+
+    pub fn next_deadline(&self) -> Instant {
+        self.next_check
+    }
+
+    /// Looks at the file if it's time to, returning what to do if it has
+    /// changed. A patch with mistakes in it is reported and otherwise left
+    /// alone, so the sound carries on while it gets fixed.
+    pub fn poll(&mut self, now: Instant) -> Option<Reload> {
+        if now < self.next_check {
+            return None;
+        }
+        self.next_check = now + POLL_PERIOD;
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified.is_none() || modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+
+        let path = self.path.display();
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) => {
+                println!("patch: {path}: {e}");
+                return None;
+            }
+        };
+        let errors = match Patch::read(&self.path, &text) {
+            Ok(patch) => match patch.validate() {
+                errors if errors.is_empty() => {
+                    let reload = Reload::between(&self.patch, &patch);
+                    self.patch = patch;
+                    return reload;
+                }
+                errors => errors,
+            },
+            Err(errors) => errors,
+        };
+        for error in errors {
+            println!("patch: {path}: {error}");
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload() {
+        let old = Patch::parse("ladder = 2000\n[params]\nladder.resonance = 0.5\n").unwrap();
+        let same =
+            Patch::parse("# tidied up\nladder = 2_000\n\n[params]\nladder.resonance = 0.5\n")
+                .unwrap();
+        assert_eq!(Reload::between(&old, &same), None);
+
+        let tweaked =
+            Patch::parse("ladder = 2000\n[params]\nladder.resonance = 0.7\nladder.drive = 2\n")
+                .unwrap();
+        assert_eq!(
+            Reload::between(&old, &tweaked),
+            Some(Reload::Params(vec![
+                ("ladder.resonance".to_string(), 0.7),
+                ("ladder.drive".to_string(), 2.)
+            ]))
+        );
+
+        let rebuilt = Patch::parse("ladder = 2000\nreverb = true\n").unwrap();
+        assert_eq!(
+            Reload::between(&old, &rebuilt),
+            Some(Reload::Rebuild(rebuilt.clone()))
+        );
+    }
+}
@@ -0,0 +1,383 @@
+//! Playing MIDI files through the engine faster than realtime, straight
+//! into WAV files.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::audio_thread::{self, AudioConfig, Engine};
+use crate::expression::EXPRESSION_CC;
+use crate::filters::{Filter, SAMPLING_FREQ};
+use crate::guard::{Guards, Reporter};
+use crate::midi::{self, MidiEvent, MidiEventInner};
+use crate::modulation::{Source, MOD_WHEEL_CC};
+use crate::params::Params;
+use crate::patch::Patch;
+use crate::smf;
+use crate::stereo::StereoFilter;
+
+/// Seconds to keep going after the last event, for releases and reverb
+/// tails.
+pub const DEFAULT_TAIL: f32 = 2.;
+
+/// Samples rendered at once, like the audio callback does.
+const BLOCK: usize = 256;
+
+/// Applies one MIDI event the way the audio thread would, minus strumming
+/// and aftertouch smoothing, which work in real time.
+fn apply<F: Filter + Params, O: StereoFilter + Params>(
+    engine: &mut Engine<F, O>,
+    config: &mut AudioConfig,
+    event: &MidiEventInner,
+) {
+    match *event {
+        MidiEventInner::Down { velocity: 0, note } | MidiEventInner::Up { note, .. } => {
+            engine.mono.synth.note_off(note);
+        }
+        MidiEventInner::Down { velocity, note } => {
+            match audio_thread::key_switch(config.key_switch_base, note) {
+                Some(articulation) => {
+                    engine.set_param("articulation", articulation.index() as f32);
+                }
+                None => {
+                    if let Some(freq) = config.tuning.freq(note) {
+                        engine.note_on(velocity as f32 / 127.);
+                        engine
+                            .mono
+                            .synth
+                            .note_on(Some(note), freq, velocity as f32 / 127.);
+                    }
+                }
+            }
+        }
+        MidiEventInner::PitchBend(bend) => engine
+            .mono
+            .synth
+            .set_bend(midi::pitch_bend_semitones(bend, config.bend_range)),
+        MidiEventInner::ControlChange { controller, value } => {
+            if controller == MOD_WHEEL_CC {
+                engine.set_source(Source::Wheel, value as f32 / 127.);
+            }
+            let pedal = (controller == EXPRESSION_CC).then(|| config.expression.position(value));
+            if let Some(position) = pedal {
+                engine.set_source(Source::Expression, position);
+            }
+            match (pedal, &config.expression.target) {
+                (Some(position), Some(target)) => {
+                    target.apply(position, engine);
+                }
+                _ => {
+                    config.cc_map.apply(controller, value, engine);
+                }
+            }
+        }
+        MidiEventInner::ChannelPressure(value) => {
+            engine.set_source(Source::Aftertouch, value as f32 / 127.);
+            if let Some(pressure) = &config.pressure {
+                pressure.target.apply(value as f32 / 127., engine);
+            }
+        }
+        MidiEventInner::KeyPressure { key, pressure } => {
+            engine
+                .mono
+                .synth
+                .set_key_pressure(key, pressure as f32 / 127.);
+        }
+        MidiEventInner::Tuning(ref retuning) => config.tuning.apply(retuning),
+        _ => {}
+    }
+    audio_thread::follow_keys(engine);
+}
+
+/// Plays `events`, stamped with how far into the song they are, through the engine
+/// `config` describes, and carries on for `tail` seconds after the last.
+/// Returns the left and right channels.
+pub fn render(mut config: AudioConfig, events: &[MidiEvent], tail: f32) -> (Vec<f32>, Vec<f32>) {
+    let report: Reporter = Arc::new(|e| println!("render: {e}"));
+    let mut engine = audio_thread::build_engine(&mut config, &mut Guards::new(report));
+    let at = |e: &MidiEvent| e.at.0 as usize;
+    let end = events.last().map_or(0, at) + (tail.max(0.) * SAMPLING_FREQ as f32).round() as usize;
+    let (mut left, mut right) = (vec![0.; end], vec![0.; end]);
+
+    let mut done = 0;
+    let mut events = events.iter().peekable();
+    while done < end {
+        while let Some(event) = events.next_if(|e| at(e) <= done) {
+            apply(&mut engine, &mut config, &event.inner);
+        }
+        let next = events.peek().map_or(end, |e| at(e));
+        let until = next.min(done + BLOCK).min(end);
+        engine.process_stereo(&mut left[done..until], &mut right[done..until]);
+        done = until;
+    }
+    (left, right)
+}
+
+/// Writes 32 bit float stereo.
+pub fn write_wav(path: &Path, left: &[f32], right: &[f32]) -> io::Result<()> {
+    let header = wav::Header::new(
+        wav::header::WAV_FORMAT_IEEE_FLOAT,
+        2,
+        SAMPLING_FREQ as u32,
+        32,
+    );
+    let interleaved = left.iter().zip(right).flat_map(|(&l, &r)| [l, r]).collect();
+    let mut writer = BufWriter::new(File::create(path)?);
+    wav::write(
+        header,
+        &wav::BitDepth::ThirtyTwoFloat(interleaved),
+        &mut writer,
+    )
+}
+
+/// A MIDI file to render, and where to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Job {
+    pub midi: PathBuf,
+    pub wav: PathBuf,
+}
+
+fn is_midi(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("mid") || ext.eq_ignore_ascii_case("midi"))
+}
+
+/// `<out>/<name>.wav` for `<name>.mid`.
+fn wav_for(midi: &Path, out: &Path) -> PathBuf {
+    out.join(midi.file_stem().unwrap_or_default())
+        .with_extension("wav")
+}
+
+/// Splits a manifest line into paths. A path with spaces in it goes in
+/// double quotes.
+fn manifest_paths(line: &str) -> Result<Vec<String>, String> {
+    let mut paths = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut path = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => path.push(c),
+                    None => return Err("unterminated quote".into()),
+                }
+            }
+            paths.push(path);
+        } else {
+            let mut path = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                path.push(c);
+            }
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+/// Finds the work to do: every MIDI file in `input` if it's a directory,
+/// otherwise `input` is a manifest with one `<file.mid> [<file.wav>]` per
+/// line. Blank lines and lines starting with `#` are ignored. MIDI paths
+/// are relative to the manifest and WAV paths to `out`, where the WAVs go
+/// when the manifest doesn't name one. Two jobs writing the same WAV is an
+/// error rather than one render quietly overwriting the other.
+pub fn find_jobs(input: &Path, out: &Path) -> io::Result<Vec<Job>> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut jobs = Vec::new();
+    if input.is_dir() {
+        let mut midis = fs::read_dir(input)?
+            .map(|entry| Ok(entry?.path()))
+            .filter(|path| path.as_ref().map_or(true, |p| is_midi(p)))
+            .collect::<io::Result<Vec<_>>>()?;
+        midis.sort();
+        jobs.extend(midis.into_iter().map(|midi| Job {
+            wav: wav_for(&midi, out),
+            midi,
+        }));
+    } else {
+        let base = input.parent().unwrap_or(Path::new(""));
+        for (n, line) in fs::read_to_string(input)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let at = |e| invalid(format!("{}:{}: {}", input.display(), n + 1, e));
+            let (midi, wav) = match &manifest_paths(line).map_err(at)?[..] {
+                [midi] => (base.join(midi), None),
+                [midi, wav] => (base.join(midi), Some(out.join(wav))),
+                _ => return Err(at("expected `<file.mid> [<file.wav>]`".into())),
+            };
+            let wav = wav.unwrap_or_else(|| wav_for(&midi, out));
+            jobs.push(Job { midi, wav });
+        }
+    }
+
+    let mut seen = HashMap::new();
+    for job in &jobs {
+        if let Some(other) = seen.insert(&job.wav, &job.midi) {
+            return Err(invalid(format!(
+                "{} and {} would both be rendered to {}",
+                other.display(),
+                job.midi.display(),
+                job.wav.display()
+            )));
+        }
+    }
+    Ok(jobs)
+}
+
+/// The engine `patch` describes, with the parameters it sets.
+fn patch_config(patch: &Patch) -> Result<AudioConfig, String> {
+    let mut config = patch.audio_config().map_err(|errors| {
+        errors
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("; ")
+    })?;
+    config.restore = patch.param_values();
+    Ok(config)
+}
+
+fn save(path: &Path, left: &[f32], right: &[f32]) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    write_wav(path, left, right).map_err(|e| format!("{}: {e}", path.display()))
+}
+
+/// Renders one MIDI file against `patch`.
+pub fn run_job(job: &Job, patch: &Patch, tail: f32) -> Result<(), String> {
+    let events = smf::load(&job.midi).map_err(|e| e.to_string())?.events;
+    let (left, right) = render(patch_config(patch)?, &events, tail);
+    save(&job.wav, &left, &right)
+}
+
+/// `<name>-voice<n>.wav` next to `<name>.wav`.
+fn wav_for_voice(wav: &Path, n: usize) -> PathBuf {
+    let stem = wav.file_stem().unwrap_or_default().to_string_lossy();
+    wav.with_file_name(format!("{stem}-voice{n}.wav"))
+}
+
+/// Renders one MIDI file once for each voice, with only that voice heard.
+/// Renders come out the same every time, so each voice plays what it
+/// played in the full mix. Returns where they went.
+pub fn run_voices(job: &Job, patch: &Patch, tail: f32) -> Result<Vec<PathBuf>, String> {
+    let events = smf::load(&job.midi).map_err(|e| e.to_string())?.events;
+    let voices = patch_config(patch)?.voices.len();
+    (1..=voices)
+        .map(|n| {
+            let mut config = patch_config(patch)?;
+            // after whatever the patch says
+            config.restore.push(("solo".to_string(), n as f32));
+            let (left, right) = render(config, &events, tail);
+            let path = wav_for_voice(&job.wav, n);
+            save(&path, &left, &right)?;
+            Ok(path)
+        })
+        .collect()
+}
+
+/// Renders every job against `patch` on `threads` worker threads. The
+/// results are in the same order as the jobs.
+pub fn render_batch(
+    jobs: &[Job],
+    patch: &Patch,
+    threads: usize,
+    tail: f32,
+) -> Vec<Result<(), String>> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(vec![Ok(()); jobs.len()]);
+    std::thread::scope(|scope| {
+        for _ in 0..threads.clamp(1, jobs.len().max(1)) {
+            scope.spawn(|| loop {
+                let idx = next.fetch_add(1, Ordering::Relaxed);
+                let Some(job) = jobs.get(idx) else {
+                    break;
+                };
+                let result = run_job(job, patch, tail);
+                match &result {
+                    Ok(()) => println!("{}", job.wav.display()),
+                    Err(e) => println!("{}: {e}", job.midi.display()),
+                }
+                results.lock().unwrap()[idx] = result;
+            });
+        }
+    });
+    results.into_inner().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::Stamp;
+    use std::time::Duration;
+
+    #[test]
+    fn test_render() {
+        let note = |us, velocity| MidiEvent {
+            at: Stamp::default() + Duration::from_micros(us),
+            channel: 0,
+            inner: MidiEventInner::Down { velocity, note: 69 },
+        };
+        let config = || {
+            Patch::parse("synth = \"fm\"")
+                .unwrap()
+                .audio_config()
+                .unwrap()
+        };
+        let events = [note(100_000, 100), note(300_000, 0)];
+        let (left, right) = render(config(), &events, 0.5);
+        // the same every time, so renders can be compared across changes
+        assert_eq!(render(config(), &events, 0.5).0, left);
+
+        assert_eq!(left.len(), SAMPLING_FREQ * 8 / 10);
+        assert_eq!(left, right);
+        let start = SAMPLING_FREQ / 10;
+        assert!(left[..start].iter().all(|&s| s == 0.));
+        assert!(left[start..start + 100].iter().any(|&s| s != 0.));
+        assert!(left[left.len() - 100..].iter().all(|&s| s.abs() < 1e-3));
+
+        assert_eq!(
+            wav_for(Path::new("songs/intro.mid"), Path::new("out")),
+            Path::new("out/intro.wav")
+        );
+        assert_eq!(
+            wav_for_voice(Path::new("out/intro.wav"), 2),
+            Path::new("out/intro-voice2.wav")
+        );
+    }
+
+    #[test]
+    fn test_manifest() {
+        assert_eq!(
+            manifest_paths(r#"a.mid  "my song.wav""#).unwrap(),
+            ["a.mid", "my song.wav"]
+        );
+        assert!(manifest_paths(r#""a.mid"#).is_err());
+
+        let dir = std::env::temp_dir().join(format!("synthtoy-batch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let manifest = dir.join("jobs.txt");
+        let out = Path::new("out");
+        fs::write(
+            &manifest,
+            "# intro\n\"old intro.mid\" first.wav\nverse.mid\n",
+        )
+        .unwrap();
+        let jobs = find_jobs(&manifest, out).unwrap();
+        assert_eq!(jobs[0].midi, dir.join("old intro.mid"));
+        assert_eq!(jobs[0].wav, out.join("first.wav"));
+        assert_eq!(jobs[1].wav, out.join("verse.wav"));
+
+        fs::write(&manifest, "verse.mid\nold/verse.mid\n").unwrap();
+        assert!(find_jobs(&manifest, out).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,377 @@
+//! Room simulation.
+
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+
+use crate::filters::{read_wav, resample, DelayLine, Filter, SAMPLING_FREQ};
+use crate::params::{ParamInfo, Params, Smoothed};
+
+// Freeverb's tunings, which are in samples at 44.1kHz
+const COMB_TUNINGS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNINGS: [usize; 4] = [556, 441, 341, 225];
+const INPUT_GAIN: f32 = 0.015;
+const WET_GAIN: f32 = 3.;
+
+fn scaled(tuning: usize) -> usize {
+    (tuning * SAMPLING_FREQ / 44100).max(1)
+}
+
+/// Feedback comb with a one-pole low-pass in the loop.
+struct Comb {
+    buf: Vec<f32>,
+    pos: usize,
+    filtered: f32,
+}
+
+impl Comb {
+    fn new(len: usize) -> Self {
+        Self {
+            buf: vec![0.; len],
+            pos: 0,
+            filtered: 0.,
+        }
+    }
+
+    fn tick(&mut self, x: f32, feedback: f32, damping: f32) -> f32 {
+        let out = self.buf[self.pos];
+        self.filtered = out + (self.filtered - out) * damping;
+        self.buf[self.pos] = x + self.filtered * feedback;
+        self.pos = (self.pos + 1) % self.buf.len();
+        out
+    }
+}
+
+/// Schroeder allpass, as approximated in Freeverb.
+struct Allpass {
+    buf: Vec<f32>,
+    pos: usize,
+}
+
+impl Allpass {
+    fn new(len: usize) -> Self {
+        Self {
+            buf: vec![0.; len],
+            pos: 0,
+        }
+    }
+
+    fn tick(&mut self, x: f32) -> f32 {
+        let delayed = self.buf[self.pos];
+        self.buf[self.pos] = x + delayed * 0.5;
+        self.pos = (self.pos + 1) % self.buf.len();
+        delayed - x
+    }
+}
+
+/// Jezar's Freeverb: eight parallel damped combs into four allpasses in
+/// series. `room_size` and `damping` are in 0..=1, and `mix` crossfades from
+/// the dry signal (0) to only the reverb (1).
+pub struct Reverb {
+    pub room_size: f32,
+    pub damping: f32,
+    pub mix: f32,
+
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+    smoothed_mix: Smoothed,
+}
+
+impl Reverb {
+    pub fn new(room_size: f32, damping: f32, mix: f32) -> Self {
+        Self {
+            room_size,
+            damping,
+            mix,
+            smoothed_mix: Smoothed::new(mix.clamp(0., 1.)),
+            combs: COMB_TUNINGS.iter().map(|&t| Comb::new(scaled(t))).collect(),
+            allpasses: ALLPASS_TUNINGS
+                .iter()
+                .map(|&t| Allpass::new(scaled(t)))
+                .collect(),
+        }
+    }
+}
+
+impl Default for Reverb {
+    fn default() -> Self {
+        Self::new(0.5, 0.5, 0.25)
+    }
+}
+
+impl Filter for Reverb {
+    fn process(&mut self, samples: &mut [f32]) {
+        let feedback = 0.7 + 0.28 * self.room_size.clamp(0., 1.);
+        let damping = 0.4 * self.damping.clamp(0., 1.);
+        self.smoothed_mix.set(self.mix.clamp(0., 1.));
+        for s in samples.iter_mut() {
+            let mix = self.smoothed_mix.next_value();
+            let input = *s * INPUT_GAIN;
+            let mut wet = 0.;
+            for comb in self.combs.iter_mut() {
+                wet += comb.tick(input, feedback, damping);
+            }
+            for allpass in self.allpasses.iter_mut() {
+                wet = allpass.tick(wet);
+            }
+            *s = *s * (1. - mix) + wet * WET_GAIN * mix;
+        }
+    }
+}
+
+impl Params for Reverb {
+    fn params(&self) -> Vec<ParamInfo> {
+        vec![
+            ParamInfo::new("room_size", 0., 1.),
+            ParamInfo::new("damping", 0., 1.),
+            ParamInfo::new("mix", 0., 1.),
+        ]
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        Some(match name {
+            "room_size" => self.room_size,
+            "damping" => self.damping,
+            "mix" => self.mix,
+            _ => return None,
+        })
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name {
+            "room_size" => self.room_size = value,
+            "damping" => self.damping = value,
+            "mix" => self.mix = value,
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Convolution block size, which is also the latency of the reverb.
+const BLOCK: usize = 256;
+
+/// Convolves against a recorded impulse response, using uniformly partitioned
+/// FFT convolution so that long responses stay cheap. `mix` crossfades from
+/// dry (0) to only the reverb (1); the dry signal is delayed to line up.
+pub struct ConvolutionReverb {
+    pub mix: f32,
+
+    fft: Arc<dyn Fft<f32>>,
+    ifft: Arc<dyn Fft<f32>>,
+    /// spectra of each `BLOCK` long piece of the impulse response
+    partitions: Vec<Vec<Complex<f32>>>,
+    /// spectra of the most recent input blocks, newest at `newest`
+    history: Vec<Vec<Complex<f32>>>,
+    newest: usize,
+    /// the previous and current input block
+    input: Vec<f32>,
+    output: Vec<f32>,
+    pos: usize,
+    dry: DelayLine,
+    wet: Vec<f32>,
+    scratch: Vec<Complex<f32>>,
+    smoothed_mix: Smoothed,
+}
+
+impl ConvolutionReverb {
+    pub fn new(ir: &[f32], mix: f32) -> Self {
+        let mut planner = FftPlanner::new();
+        let fft = planner.plan_fft_forward(2 * BLOCK);
+        let ifft = planner.plan_fft_inverse(2 * BLOCK);
+
+        let partitions: Vec<_> = ir
+            .chunks(BLOCK)
+            .map(|chunk| {
+                let mut spectrum = vec![Complex::default(); 2 * BLOCK];
+                for (c, &s) in spectrum.iter_mut().zip(chunk) {
+                    c.re = s;
+                }
+                fft.process(&mut spectrum);
+                spectrum
+            })
+            .collect();
+
+        Self {
+            mix,
+            history: vec![vec![Complex::default(); 2 * BLOCK]; partitions.len()],
+            partitions,
+            fft,
+            ifft,
+            newest: 0,
+            input: vec![0.; 2 * BLOCK],
+            output: vec![0.; BLOCK],
+            pos: 0,
+            dry: DelayLine::new(BLOCK + 1),
+            wet: Vec::new(),
+            scratch: vec![Complex::default(); 2 * BLOCK],
+            smoothed_mix: Smoothed::new(mix.clamp(0., 1.)),
+        }
+    }
+
+    /// Loads an impulse response from the first channel of a WAV file,
+    /// resampling it if need be and normalizing it to unit energy.
+    pub fn load(path: &Path, mix: f32) -> io::Result<Self> {
+        let (rate, mut ir) = read_wav(path)?;
+        if ir.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is empty", path.display()),
+            ));
+        }
+        // linear interpolation is crude, but an impulse response is mostly
+        // noise anyway
+        ir = resample(&ir, rate);
+        let energy = ir.iter().map(|s| s * s).sum::<f32>().sqrt();
+        if energy > 0. {
+            ir.iter_mut().for_each(|s| *s /= energy);
+        }
+        Ok(Self::new(&ir, mix))
+    }
+
+    fn process_block(&mut self) {
+        let n = self.partitions.len();
+        if n == 0 {
+            self.output.fill(0.);
+            return;
+        }
+        self.newest = (self.newest + 1) % n;
+        let spectrum = &mut self.history[self.newest];
+        for (c, &s) in spectrum.iter_mut().zip(&self.input) {
+            *c = Complex::new(s, 0.);
+        }
+        self.fft.process(spectrum);
+
+        self.scratch.fill(Complex::default());
+        for (age, partition) in self.partitions.iter().enumerate() {
+            let x = &self.history[(self.newest + n - age) % n];
+            for ((acc, x), h) in self.scratch.iter_mut().zip(x).zip(partition) {
+                *acc += x * h;
+            }
+        }
+        self.ifft.process(&mut self.scratch);
+
+        // overlap-save: the first half wrapped around, the second is good
+        let scale = 1. / (2 * BLOCK) as f32;
+        for (out, c) in self.output.iter_mut().zip(&self.scratch[BLOCK..]) {
+            *out = c.re * scale;
+        }
+        self.input.copy_within(BLOCK.., 0);
+    }
+}
+
+impl Filter for ConvolutionReverb {
+    fn process(&mut self, samples: &mut [f32]) {
+        let mut wet_buf = std::mem::take(&mut self.wet);
+        wet_buf.resize(samples.len(), 0.);
+        for (s, wet) in samples.iter().zip(wet_buf.iter_mut()) {
+            self.input[BLOCK + self.pos] = *s;
+            *wet = self.output[self.pos];
+            self.pos += 1;
+            if self.pos == BLOCK {
+                self.process_block();
+                self.pos = 0;
+            }
+        }
+
+        self.dry.process(samples);
+        self.smoothed_mix.set(self.mix.clamp(0., 1.));
+        for (s, wet) in samples.iter_mut().zip(&wet_buf) {
+            let mix = self.smoothed_mix.next_value();
+            *s = *s * (1. - mix) + wet * mix;
+        }
+        self.wet = wet_buf;
+    }
+
+    fn latency(&self) -> usize {
+        BLOCK
+    }
+}
+
+impl Params for ConvolutionReverb {
+    fn params(&self) -> Vec<ParamInfo> {
+        vec![ParamInfo::new("mix", 0., 1.)]
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name {
+            "mix" => Some(self.mix),
+            _ => None,
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name {
+            "mix" => self.mix = value,
+            _ => return false,
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn energy(samples: &[f32]) -> f32 {
+        samples.iter().map(|s| s * s).sum()
+    }
+
+    #[test]
+    fn test_reverb_tail() {
+        let impulse = |mut reverb: Reverb| {
+            let mut out = vec![0.; SAMPLING_FREQ * 2];
+            out[0] = 1.;
+            reverb.process(&mut out);
+            out
+        };
+
+        let dry = impulse(Reverb::new(0.5, 0.5, 0.));
+        assert_eq!(dry[0], 1.);
+        assert_eq!(energy(&dry[1..]), 0.);
+
+        let small = impulse(Reverb::new(0.2, 0.5, 1.));
+        let big = impulse(Reverb::new(0.9, 0.5, 1.));
+        let late = SAMPLING_FREQ / 2..SAMPLING_FREQ;
+        assert!(energy(&small[late.clone()]) > 0.);
+        assert!(energy(&big[late.clone()]) > 10. * energy(&small[late]));
+        // and it does die away
+        assert!(energy(&big[SAMPLING_FREQ..]) < energy(&big[..SAMPLING_FREQ]));
+    }
+
+    #[test]
+    fn test_convolution() {
+        // long enough to span a few partitions
+        let ir: Vec<f32> = (0..700).map(|i| ((i * 7919) % 13) as f32 - 6.).collect();
+        let input: Vec<f32> = (0..2000).map(|i| ((i * 104729) % 17) as f32 - 8.).collect();
+        let mut expected = vec![0.; input.len()];
+        for (n, out) in expected.iter_mut().enumerate() {
+            for (k, h) in ir.iter().enumerate().take(n + 1) {
+                *out += h * input[n - k];
+            }
+        }
+
+        let mut reverb = ConvolutionReverb::new(&ir, 1.);
+        assert_eq!(reverb.latency(), BLOCK);
+        let mut out = input.clone();
+        out.extend([0.; BLOCK]);
+        // odd sized blocks, to check the buffering
+        for chunk in out.chunks_mut(100) {
+            reverb.process(chunk);
+        }
+        for (got, want) in out[BLOCK..].iter().zip(&expected) {
+            assert!(
+                (got - want).abs() < 1e-2 * want.abs().max(1.),
+                "{got} != {want}"
+            );
+        }
+
+        let mut dry = ConvolutionReverb::new(&ir, 0.);
+        let mut out = input.clone();
+        dry.process(&mut out);
+        assert_eq!(out[BLOCK..], input[..input.len() - BLOCK]);
+    }
+}
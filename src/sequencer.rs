@@ -0,0 +1,529 @@
+//! A 16 step sequencer, for making patterns without any MIDI gear. It
+//! plays sixteenth notes in time with the session clock, and patterns come
+//! from a file or get typed in a step at a time on the computer keyboard.
+//! It holds any number of patterns, and plays either the one selected over
+//! and over or a song chaining them together.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::clock::{Clock, Stamp, TICKS_PER_BEAT};
+use crate::midi::MidiEventInner;
+use crate::note::Pitch;
+
+pub const STEPS: usize = 16;
+/// Sixteenth notes.
+const STEPS_PER_BEAT: u32 = 4;
+/// How much of its step a note is held for.
+const GATE: f64 = 0.5;
+const DEFAULT_VELOCITY: u8 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Step {
+    /// MIDI note
+    pub note: u8,
+    /// whether it plays at all
+    pub gate: bool,
+    pub velocity: u8,
+}
+
+impl Default for Step {
+    fn default() -> Self {
+        Self {
+            note: 60,
+            gate: false,
+            velocity: DEFAULT_VELOCITY,
+        }
+    }
+}
+
+/// Reads `-` as a rest, or a pitch like [`Pitch`] takes and an optional
+/// velocity out of 127, like "C3 110".
+impl FromStr for Step {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        let mut words = value.split_whitespace();
+        let (pitch, velocity) = match (words.next(), words.next(), words.next()) {
+            (None | Some("-"), None, None) => return Ok(Step::default()),
+            (Some(pitch), velocity, None) => (pitch, velocity),
+            _ => return Err(format!("don't know step {value:?}")),
+        };
+        let (note, _) = pitch.parse::<Pitch>()?.nearest();
+        let velocity = match velocity {
+            Some(v) => v
+                .parse::<u8>()
+                .ok()
+                .filter(|v| (1..=127).contains(v))
+                .ok_or_else(|| format!("velocity {v:?} isn't 1 to 127"))?,
+            None => DEFAULT_VELOCITY,
+        };
+        Ok(Step {
+            note: u8::try_from(note)
+                .ok()
+                .filter(|n| *n <= 127)
+                .ok_or_else(|| format!("{value:?} isn't a MIDI note"))?,
+            gate: true,
+            velocity,
+        })
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.gate {
+            write!(f, "{} {}", Pitch(self.note as f32), self.velocity)
+        } else {
+            f.write_str("-")
+        }
+    }
+}
+
+/// A step per line, and lines starting with `#` for comments. Steps not
+/// given rest.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Pattern {
+    pub steps: [Step; STEPS],
+}
+
+impl Pattern {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| text.parse())
+            .map_err(|e| format!("{}: {e}", path.display()))
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+    fn from_str(text: &str) -> Result<Self, String> {
+        let mut pattern = Pattern::default();
+        let lines = text
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+        for (n, (i, line)) in lines.enumerate() {
+            if n == STEPS {
+                return Err(format!("line {}: more than {STEPS} steps", i + 1));
+            }
+            pattern.steps[n] = line.parse().map_err(|e| format!("line {}: {e}", i + 1))?;
+        }
+        Ok(pattern)
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for step in &self.steps {
+            writeln!(f, "{step}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A pattern in a song, and how many times in a row it plays.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Part {
+    /// which pattern, counting from 0
+    pub pattern: usize,
+    pub repeats: u32,
+}
+
+/// Patterns chained one after another, written as
+/// `<pattern>[x<repeats>],...` with the patterns counting from 1, like
+/// "1x4, 2x2, 1". Once it's over it goes round again.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Song {
+    pub parts: Vec<Part>,
+}
+
+impl Song {
+    pub fn is_empty(&self) -> bool {
+        self.parts.is_empty()
+    }
+
+    /// How many steps it takes to play through once.
+    fn len(&self) -> usize {
+        self.parts.iter().map(|p| p.repeats as usize * STEPS).sum()
+    }
+
+    /// The pattern and step `step` steps in, or nothing if there's nothing
+    /// to play.
+    fn locate(&self, step: usize) -> Option<(usize, usize)> {
+        let len = self.len();
+        if len == 0 {
+            return None;
+        }
+        let mut at = step % len;
+        for part in &self.parts {
+            let steps = part.repeats as usize * STEPS;
+            if at < steps {
+                return Some((part.pattern, at % STEPS));
+            }
+            at -= steps;
+        }
+        None
+    }
+
+    /// Chains `pattern` on the end, or plays the last part once more if
+    /// it's the same pattern.
+    pub fn chain(&mut self, pattern: usize) {
+        match self.parts.last_mut() {
+            Some(last) if last.pattern == pattern => last.repeats += 1,
+            _ => self.parts.push(Part {
+                pattern,
+                repeats: 1,
+            }),
+        }
+    }
+}
+
+impl FromStr for Song {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        let parts = value
+            .split(',')
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+            .map(|part| {
+                let (pattern, repeats) = part.split_once('x').unwrap_or((part, "1"));
+                let number = |n: &str| n.trim().parse::<u32>().ok().filter(|&n| n > 0);
+                match (number(pattern), number(repeats)) {
+                    (Some(pattern), Some(repeats)) => Ok(Part {
+                        pattern: pattern as usize - 1,
+                        repeats,
+                    }),
+                    _ => Err(format!("expected <pattern>[x<repeats>], got {part:?}")),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Song { parts })
+    }
+}
+
+impl fmt::Display for Song {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, part) in self.parts.iter().enumerate() {
+            let comma = if i == 0 { "" } else { ", " };
+            write!(f, "{comma}{}x{}", part.pattern + 1, part.repeats)?;
+        }
+        Ok(())
+    }
+}
+
+/// What the keys for the sequencer do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Control {
+    PlayStop,
+    /// Starts or stops notes played going into the pattern.
+    StepEntry,
+    /// Puts a rest in the next step while entering steps.
+    Rest,
+    Save,
+    /// Picks the pattern to play and enter steps into, counting from 0.
+    Select(usize),
+    /// Switches between playing the song and the selected pattern.
+    SongMode,
+    /// Chains the selected pattern onto the end of the song.
+    Chain,
+    ClearSong,
+}
+
+pub struct Sequencer {
+    /// never empty
+    pub patterns: Vec<Pattern>,
+    /// the pattern played outside song mode, and that steps go into
+    pub selected: usize,
+    pub song: Song,
+    /// whether it plays `song` rather than the selected pattern
+    pub song_mode: bool,
+    clock: Clock,
+    /// when the step at `position` plays, while running
+    next: Option<Stamp>,
+    /// steps since it started
+    position: usize,
+    /// notes playing, and when to let them go
+    playing: Vec<(Stamp, u8)>,
+    /// the step entered notes go in, while entering them
+    pub entry: Option<usize>,
+}
+
+impl Sequencer {
+    /// Starts out on the first of `patterns`, with an empty one for each
+    /// that `song` plays and there isn't one for.
+    pub fn new(patterns: Vec<Pattern>, song: Song, clock: Clock) -> Self {
+        let mut this = Self {
+            patterns,
+            selected: 0,
+            song,
+            song_mode: false,
+            clock,
+            next: None,
+            position: 0,
+            playing: Vec::new(),
+            entry: None,
+        };
+        let last = this.song.parts.iter().map(|p| p.pattern).max();
+        this.grow(last.unwrap_or(0));
+        this
+    }
+
+    /// Makes sure there's a pattern `idx`, adding empty ones up to it.
+    fn grow(&mut self, idx: usize) {
+        if self.patterns.len() <= idx {
+            self.patterns.resize_with(idx + 1, Pattern::default);
+        }
+    }
+
+    /// Picks pattern `idx`, which starts out empty if it's new.
+    pub fn select(&mut self, idx: usize) {
+        self.grow(idx);
+        self.selected = idx;
+    }
+
+    pub fn pattern(&self) -> &Pattern {
+        &self.patterns[self.selected]
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.next.is_some()
+    }
+
+    fn step_len(&self) -> Duration {
+        self.clock.beat() / STEPS_PER_BEAT
+    }
+
+    /// Starts from the first step on the next sixteenth, or stops.
+    pub fn play_stop(&mut self, now: Stamp, out: &mut Vec<MidiEventInner>) {
+        if self.next.is_some() {
+            self.stop(out);
+        } else {
+            self.next = Some(self.clock.next(now, STEPS_PER_BEAT));
+            self.position = 0;
+        }
+    }
+
+    /// Stops, letting go of anything playing into `out`.
+    pub fn stop(&mut self, out: &mut Vec<MidiEventInner>) {
+        self.next = None;
+        for (_, note) in self.playing.drain(..) {
+            out.push(MidiEventInner::Up { velocity: 0, note });
+        }
+    }
+
+    /// Plays along with MIDI clock instead of on its own, given a tick
+    /// `position` ticks into the song.
+    pub fn follow(&mut self, position: u64, at: Stamp, out: &mut Vec<MidiEventInner>) {
+        self.next = None;
+        let per_step = TICKS_PER_BEAT / STEPS_PER_BEAT as u64;
+        if position.is_multiple_of(per_step) {
+            self.position = (position / per_step) as usize;
+            self.play_step(at, out);
+        }
+    }
+
+    /// The pattern and step to play at `position`.
+    fn locate(&self) -> Option<(usize, usize)> {
+        if self.song_mode {
+            self.song.locate(self.position)
+        } else {
+            Some((self.selected, self.position % STEPS))
+        }
+    }
+
+    fn play_step(&mut self, at: Stamp, out: &mut Vec<MidiEventInner>) {
+        let step = self
+            .locate()
+            .and_then(|(pattern, step)| Some(self.patterns.get(pattern)?.steps[step]))
+            .unwrap_or_default();
+        self.position += 1;
+        if step.gate {
+            out.push(MidiEventInner::Down {
+                velocity: step.velocity,
+                note: step.note,
+            });
+            self.playing
+                .push((at + self.step_len().mul_f64(GATE), step.note));
+        }
+    }
+
+    /// When [`Sequencer::poll`] next has something to do.
+    pub fn next_deadline(&self) -> Option<Stamp> {
+        self.playing
+            .iter()
+            .map(|&(at, _)| at)
+            .chain(self.next)
+            .min()
+    }
+
+    /// Collects the notes that start and end by `now` into `out`.
+    pub fn poll(&mut self, now: Stamp, out: &mut Vec<MidiEventInner>) {
+        // ends first, so a note played on the next step again starts over
+        self.playing.retain(|&(at, note)| {
+            let done = at <= now;
+            if done {
+                out.push(MidiEventInner::Up { velocity: 0, note });
+            }
+            !done
+        });
+        while let Some(at) = self.next.filter(|at| *at <= now) {
+            self.play_step(at, out);
+            // back onto the grid each step, so steps rounded to the sample
+            // don't add up to a drift
+            let halfway = at + self.step_len() / 2;
+            self.next = Some(self.clock.next(halfway, STEPS_PER_BEAT));
+        }
+    }
+
+    /// Puts `step` where step entry is up to and moves on to the next,
+    /// returning which it went in, or nothing if step entry is off.
+    pub fn enter(&mut self, step: Step) -> Option<usize> {
+        let at = self.entry?;
+        self.patterns[self.selected].steps[at] = step;
+        self.entry = Some((at + 1) % STEPS);
+        Some(at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_sequencer() {
+        let pattern: Pattern = "# a bar\nC3 110\n-\nEb3\n".parse().unwrap();
+        assert_eq!(
+            pattern.steps[..3],
+            [
+                Step {
+                    note: 48,
+                    gate: true,
+                    velocity: 110
+                },
+                Step::default(),
+                Step {
+                    note: 51,
+                    gate: true,
+                    velocity: DEFAULT_VELOCITY
+                },
+            ]
+        );
+        assert_eq!(pattern.to_string().parse(), Ok(pattern.clone()));
+        assert!("C3 0".parse::<Pattern>().is_err());
+        assert!("-\n".repeat(STEPS + 1).parse::<Pattern>().is_err());
+
+        // 120bpm makes a step 125ms
+        let start = Stamp::default();
+        let clock = Clock::new(Instant::now(), 120.);
+        let mut sequencer = Sequencer::new(vec![pattern], Song::default(), clock);
+        let mut out = Vec::new();
+        sequencer.play_stop(start + Duration::from_millis(10), &mut out);
+        assert_eq!(
+            sequencer.next_deadline(),
+            Some(start + Duration::from_millis(125))
+        );
+        sequencer.poll(start + Duration::from_millis(125), &mut out);
+        assert_eq!(
+            out,
+            [MidiEventInner::Down {
+                velocity: 110,
+                note: 48
+            }]
+        );
+        out.clear();
+        // the first note ends, the rest plays nothing and the third starts
+        sequencer.poll(start + Duration::from_millis(375), &mut out);
+        assert_eq!(
+            out,
+            [
+                MidiEventInner::Up {
+                    velocity: 0,
+                    note: 48
+                },
+                MidiEventInner::Down {
+                    velocity: DEFAULT_VELOCITY,
+                    note: 51
+                }
+            ]
+        );
+        out.clear();
+        sequencer.play_stop(start, &mut out);
+        assert!(!sequencer.is_running());
+        assert_eq!(
+            out,
+            [MidiEventInner::Up {
+                velocity: 0,
+                note: 51
+            }]
+        );
+
+        // on MIDI clock, a step is every 6 ticks
+        sequencer.follow(12, start, &mut out);
+        sequencer.follow(13, start, &mut out);
+        assert_eq!(
+            out[1..],
+            [MidiEventInner::Down {
+                velocity: DEFAULT_VELOCITY,
+                note: 51
+            }]
+        );
+
+        assert_eq!(sequencer.enter(Step::default()), None);
+        sequencer.entry = Some(STEPS - 1);
+        assert_eq!(sequencer.enter(Step::default()), Some(STEPS - 1));
+        assert_eq!(sequencer.entry, Some(0));
+    }
+
+    #[test]
+    fn test_song() {
+        let song: Song = "1x2, 3".parse().unwrap();
+        assert_eq!(song.to_string(), "1x2, 3x1");
+        assert_eq!(song.to_string().parse(), Ok(song.clone()));
+        assert!("0".parse::<Song>().is_err());
+        assert!("1x0".parse::<Song>().is_err());
+        assert_eq!("".parse(), Ok(Song::default()));
+
+        let mut chained = Song::default();
+        for pattern in [0, 0, 2] {
+            chained.chain(pattern);
+        }
+        assert_eq!(chained, song);
+
+        // a note on the first step of each pattern, which tells them apart
+        let patterns: Vec<Pattern> = ["C3", "D3"].iter().map(|p| p.parse().unwrap()).collect();
+        let clock = Clock::new(Instant::now(), 120.);
+        let mut sequencer = Sequencer::new(patterns, song, clock);
+        // the song plays a third pattern, which there wasn't
+        assert_eq!(sequencer.patterns.len(), 3);
+        sequencer.song_mode = true;
+        let mut out = Vec::new();
+        // a note each bar of the first, none on the empty third, and round
+        let notes = [0, 1, 2, 3].map(|bar| {
+            out.clear();
+            sequencer.follow(bar * STEPS as u64 * 6, Stamp::default(), &mut out);
+            out.len()
+        });
+        assert_eq!(notes, [1, 1, 0, 1]);
+
+        // outside song mode, the selected pattern over and over
+        sequencer.song_mode = false;
+        sequencer.select(1);
+        out.clear();
+        sequencer.follow(STEPS as u64 * 6, Stamp::default(), &mut out);
+        assert_eq!(
+            out,
+            [MidiEventInner::Down {
+                velocity: DEFAULT_VELOCITY,
+                note: 50
+            }]
+        );
+        sequencer.select(5);
+        assert_eq!(sequencer.patterns.len(), 6);
+        assert_eq!(sequencer.pattern(), &Pattern::default());
+    }
+}
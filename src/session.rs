@@ -0,0 +1,268 @@
+//! Saving what's playing every so often, and on the way out, so that after
+//! a crash or an accidental quit it can be picked up again with
+//! `--restore-last-session`.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::modulation::{LfoConfig, ModConfig};
+use crate::patch::{Entry, Patch};
+use crate::sequencer::{Pattern, Song};
+use crate::snapshot::Snapshots;
+
+pub const AUTOSAVE_PERIOD: Duration = Duration::from_secs(10);
+
+/// `$XDG_STATE_HOME/synthtoy/last-session`, falling back to
+/// `~/.local/state`.
+pub fn last_session_path() -> Option<PathBuf> {
+    let state = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(std::env::var_os("HOME")?).join(".local/state")))?;
+    Some(state.join("synthtoy").join("last-session"))
+}
+
+/// Everything that makes the sound, as it was last: the sources and
+/// effects with their parameters, the modulation routing, and the
+/// sequencer's patterns and the song chaining them. It's written as TOML,
+/// with the patch in a `[patch]` section the way a patch file has it, and
+/// the rest as strings written the way the command line and pattern files
+/// take them:
+///
+/// ```toml
+/// lfos = ["sine:0.5:ladder.cutoff=0.2"]
+/// mods = ["velocity:ladder.cutoff=0.3"]
+/// patterns = [["C3 110", "-", ...], ["Eb3", ...]]
+/// song = "1x4, 2x2"
+///
+/// [patch]
+/// version = 1
+/// ladder = 2000
+///
+/// [patch.params]
+/// "ladder.cutoff" = 1200
+/// ```
+///
+/// Parameter values are written out in full, so they come back bit for
+/// bit.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Session {
+    pub nodes: Vec<Entry>,
+    pub params: Vec<(String, f32)>,
+    pub lfos: Vec<LfoConfig>,
+    pub mods: Vec<ModConfig>,
+    pub patterns: Vec<Pattern>,
+    pub song: Song,
+}
+
+impl Session {
+    /// The sources, effects and parameters, as a patch to start from.
+    pub fn patch(&self) -> Patch {
+        Patch::from_state(self.nodes.clone(), &self.params)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut top: toml::Table = text.parse().map_err(|e: toml::de::Error| e.to_string())?;
+        let mut strings = |key: &str| -> Result<Vec<String>, String> {
+            match top.remove(key) {
+                Some(value) => value
+                    .try_into()
+                    .map_err(|_| format!("{key} isn't a list of strings")),
+                None => Ok(Vec::new()),
+            }
+        };
+        let lfos = strings("lfos")?
+            .iter()
+            .map(|lfo| lfo.parse())
+            .collect::<Result<_, String>>()
+            .map_err(|e| format!("lfos: {e}"))?;
+        let mods = strings("mods")?
+            .iter()
+            .map(|m| m.parse())
+            .collect::<Result<_, String>>()
+            .map_err(|e| format!("mods: {e}"))?;
+        let patterns: Vec<Vec<String>> = match top.remove("patterns") {
+            Some(value) => value
+                .try_into()
+                .map_err(|_| "patterns isn't a list of lists of strings".to_string())?,
+            None => Vec::new(),
+        };
+        let patterns = patterns
+            .iter()
+            .enumerate()
+            .map(|(i, steps)| {
+                steps
+                    .join("\n")
+                    .parse()
+                    .map_err(|e| format!("pattern {}: {e}", i + 1))
+            })
+            .collect::<Result<_, String>>()?;
+        let song = match top.remove("song") {
+            Some(toml::Value::String(song)) => song.parse().map_err(|e| format!("song: {e}"))?,
+            Some(_) => return Err("song isn't a string".to_string()),
+            None => Song::default(),
+        };
+        let patch = match top.remove("patch") {
+            Some(toml::Value::Table(patch)) => {
+                Patch::parse(&patch.to_string()).map_err(|errors| {
+                    let errors: Vec<_> = errors.iter().map(|e| e.message.clone()).collect();
+                    format!("patch: {}", errors.join("; "))
+                })?
+            }
+            Some(_) => return Err("patch isn't a section".to_string()),
+            None => Patch::default(),
+        };
+        if let Some(key) = top.keys().next() {
+            return Err(format!("don't know {key:?}"));
+        }
+        Ok(Self {
+            params: patch.param_values(),
+            nodes: patch.nodes,
+            lfos,
+            mods,
+            patterns,
+            song,
+        })
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Writes the session next to `path` and renames it into place, so a
+    /// crash halfway through can't leave a truncated one behind. The file
+    /// next to it is this process's own, so another synthtoy saving at the
+    /// same time can't write into it too.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+        fs::write(&tmp, self.to_string())?;
+        fs::rename(tmp, path)
+    }
+}
+
+impl fmt::Display for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let strings = |items: Vec<String>| {
+            toml::Value::Array(items.into_iter().map(toml::Value::String).collect())
+        };
+        let mut top = toml::Table::new();
+        top.insert(
+            "lfos".to_string(),
+            strings(self.lfos.iter().map(ToString::to_string).collect()),
+        );
+        top.insert(
+            "mods".to_string(),
+            strings(self.mods.iter().map(ToString::to_string).collect()),
+        );
+        let patterns = self
+            .patterns
+            .iter()
+            .map(|pattern| strings(pattern.steps.iter().map(ToString::to_string).collect()));
+        top.insert(
+            "patterns".to_string(),
+            toml::Value::Array(patterns.collect()),
+        );
+        top.insert(
+            "song".to_string(),
+            toml::Value::String(self.song.to_string()),
+        );
+        let patch: toml::Table = self.patch().to_string().parse().map_err(|_| fmt::Error)?;
+        top.insert("patch".to_string(), toml::Value::Table(patch));
+        f.write_str(&toml::to_string(&top).map_err(|_| fmt::Error)?)
+    }
+}
+
+/// Saves sessions to a file on a thread of its own, one at a time, each
+/// with the parameters from a snapshot taken just before it's written.
+pub struct Saver {
+    send: mpsc::Sender<Session>,
+    thread: JoinHandle<()>,
+}
+
+impl Saver {
+    pub fn spawn(path: PathBuf, snapshots: Arc<Snapshots>) -> Self {
+        let (send, recv) = mpsc::channel::<Session>();
+        let thread = std::thread::spawn(move || {
+            for mut session in recv {
+                let Some(snapshot) = snapshots.take(Duration::from_millis(500)) else {
+                    println!("session: timed out waiting for a snapshot, not saved");
+                    continue;
+                };
+                session.params = snapshot.params;
+                if let Err(e) = session.save(&path) {
+                    println!("saving session to {}: {e}", path.display());
+                }
+            }
+        });
+        Self { send, thread }
+    }
+
+    /// Saves `session`, with the parameters as they are by then.
+    pub fn save(&self, session: Session) {
+        // only gone if it panicked, which it will have said
+        let _ = self.send.send(session);
+    }
+
+    /// Waits for everything asked for so far to be saved.
+    pub fn finish(self) {
+        drop(self.send);
+        let _ = self.thread.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch::Value;
+
+    #[test]
+    fn test_session_round_trip() {
+        let mut pattern = Pattern::default();
+        pattern.steps[2] = "Eb3 90".parse().unwrap();
+        let session = Session {
+            nodes: vec![
+                Entry::new("ladder", Value::Number(2000.)),
+                Entry::new("chain", Value::String("ladder, +delay:1/8".to_string())),
+            ],
+            params: vec![
+                ("volume".to_string(), 0.1 + 0.2),
+                ("delay.time".to_string(), 1. / 3.),
+                ("ladder.cutoff".to_string(), 1e-7),
+                ("voices".to_string(), 8.),
+            ],
+            lfos: vec![
+                "sine:0.5:ladder.cutoff=0.2,pan.position=-0.5"
+                    .parse()
+                    .unwrap(),
+                "sh:1/8.:volume=0.1".parse().unwrap(),
+            ],
+            mods: vec!["velocity:ladder.cutoff=0.3".parse().unwrap()],
+            patterns: vec![pattern, Pattern::default()],
+            song: "1x4, 2".parse().unwrap(),
+        };
+        let parsed = Session::parse(&session.to_string()).unwrap();
+        for ((_, a), (_, b)) in session.params.iter().zip(&parsed.params) {
+            assert_eq!(a.to_bits(), b.to_bits());
+        }
+        // entries read from a file know their lines
+        let lines: Vec<_> = parsed.nodes.iter().map(|e| (&e.key, &e.value)).collect();
+        let expected: Vec<_> = session.nodes.iter().map(|e| (&e.key, &e.value)).collect();
+        assert_eq!(lines, expected);
+        assert_eq!(parsed.lfos, session.lfos);
+        assert_eq!(parsed.mods, session.mods);
+        assert_eq!(parsed.patterns, session.patterns);
+        assert_eq!(parsed.song, session.song);
+        assert!(Session::parse("volume 1").is_err());
+        assert!(Session::parse("lfos = [\"sine:fast:volume=1\"]").is_err());
+        assert!(Session::parse("tempo = 120").is_err());
+    }
+}
@@ -0,0 +1,212 @@
+//! Test signals to put through the chain alongside the voices, for
+//! measuring what the effects do and checking that outputs are wired up.
+
+use std::f64::consts::TAU;
+
+use crate::filters::{Filter, Rng, SAMPLING_FREQ};
+use crate::params::{ParamInfo, Params};
+
+/// A signal as written on the command line: `sweep:<from>-<to>:<secs>s` for
+/// a sine sweeping up (or down) between two frequencies in Hz over and over,
+/// `white` or `pink` noise, `impulse:<hz>` for a train of single sample
+/// clicks, or `dc:<level>`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Signal {
+    Sweep { from: f32, to: f32, secs: f32 },
+    White,
+    Pink,
+    Impulse(f32),
+    Dc(f32),
+}
+
+impl std::str::FromStr for Signal {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        let (kind, args) = value.split_once(':').unwrap_or((value, ""));
+        let number = |v: &str, what: &str| {
+            v.trim()
+                .parse::<f32>()
+                .ok()
+                .filter(|n| n.is_finite())
+                .ok_or_else(|| format!("bad {what} {v:?} in {value:?}"))
+        };
+        let hz = |v: &str| {
+            number(v, "frequency").and_then(|hz| {
+                if hz > 0. && hz < SAMPLING_FREQ as f32 / 2. {
+                    Ok(hz)
+                } else {
+                    Err(format!("{hz}Hz is outside 0..{}", SAMPLING_FREQ / 2))
+                }
+            })
+        };
+        Ok(match kind {
+            "sweep" => {
+                let err = || format!("expected sweep:<from>-<to>:<secs>s, got {value:?}");
+                let (range, secs) = args.split_once(':').ok_or_else(err)?;
+                let (from, to) = range.split_once('-').ok_or_else(err)?;
+                let secs = number(secs.strip_suffix('s').unwrap_or(secs), "length")?;
+                // shorter than a sample, it'd never get anywhere
+                if secs * (SAMPLING_FREQ as f32) < 1. {
+                    return Err(format!("a sweep can't take {secs}s"));
+                }
+                Signal::Sweep {
+                    from: hz(from)?,
+                    to: hz(to)?,
+                    secs,
+                }
+            }
+            "white" => Signal::White,
+            "pink" => Signal::Pink,
+            "impulse" => Signal::Impulse(hz(args)?),
+            "dc" => Signal::Dc(number(args, "level")?.clamp(-1., 1.)),
+            _ => return Err(format!("unknown signal {value:?}")),
+        })
+    }
+}
+
+pub struct Siggen {
+    pub signal: Signal,
+    /// How loud, apart from DC, which is always its own level.
+    pub level: f32,
+    /// samples since the start, or the start of the sweep
+    elapsed: u64,
+    /// in cycles, kept in 0..1
+    phase: f64,
+    rng: Rng,
+    /// state of the filters that make white noise pink
+    pink: [f32; 7],
+}
+
+impl Siggen {
+    pub fn new(signal: Signal) -> Self {
+        Self {
+            signal,
+            level: 0.5,
+            elapsed: 0,
+            phase: 0.,
+            rng: Rng::default(),
+            pink: [0.; 7],
+        }
+    }
+
+    fn next(&mut self) -> f32 {
+        let rate = SAMPLING_FREQ as f64;
+        let sample = match self.signal {
+            Signal::Sweep { from, to, secs } => {
+                let length = ((secs as f64 * rate) as u64).max(1);
+                if self.elapsed >= length {
+                    self.elapsed = 0;
+                }
+                // exponential, so every octave gets the same time
+                let t = self.elapsed as f64 / length as f64;
+                let freq = from as f64 * (to as f64 / from as f64).powf(t);
+                let out = (self.phase * TAU).sin() as f32;
+                self.phase = (self.phase + freq / rate).fract();
+                out * self.level
+            }
+            Signal::White => self.rng.next_f32() * self.level,
+            Signal::Pink => {
+                // Paul Kellet's filter, good to within a dB or so
+                let white = self.rng.next_f32();
+                let b = &mut self.pink;
+                b[0] = 0.99886 * b[0] + white * 0.0555179;
+                b[1] = 0.99332 * b[1] + white * 0.0750759;
+                b[2] = 0.96900 * b[2] + white * 0.153_852;
+                b[3] = 0.86650 * b[3] + white * 0.3104856;
+                b[4] = 0.55000 * b[4] + white * 0.5329522;
+                b[5] = -0.7616 * b[5] - white * 0.0168980;
+                let pink = b.iter().sum::<f32>() + white * 0.5362;
+                b[6] = white * 0.115926;
+                // about the same loudness as the white noise
+                pink * 0.11 * self.level
+            }
+            Signal::Impulse(hz) => {
+                let period = (rate / hz as f64).round().max(1.) as u64;
+                if self.elapsed.is_multiple_of(period) {
+                    self.level
+                } else {
+                    0.
+                }
+            }
+            Signal::Dc(level) => level,
+        };
+        self.elapsed += 1;
+        sample
+    }
+}
+
+impl Filter for Siggen {
+    fn process(&mut self, samples: &mut [f32]) {
+        for s in samples.iter_mut() {
+            *s += self.next();
+        }
+    }
+}
+
+impl Params for Siggen {
+    fn params(&self) -> Vec<ParamInfo> {
+        vec![ParamInfo::new("level", 0., 1.).not_random()]
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name {
+            "level" => Some(self.level),
+            _ => None,
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name {
+            "level" => self.level = value.clamp(0., 1.),
+            _ => return false,
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_siggen() {
+        assert_eq!(
+            "sweep:20-20000:10s".parse(),
+            Ok(Signal::Sweep {
+                from: 20.,
+                to: 20000.,
+                secs: 10.
+            })
+        );
+        assert_eq!("impulse:10".parse(), Ok(Signal::Impulse(10.)));
+        assert!("sweep:20-30000:10s".parse::<Signal>().is_err());
+        assert!("sweep:20-200".parse::<Signal>().is_err());
+        assert!("sweep:20-200:0.00001s".parse::<Signal>().is_err());
+        assert!("square".parse::<Signal>().is_err());
+
+        let render = |signal: &str, len: usize| {
+            let mut siggen = Siggen::new(signal.parse().unwrap());
+            siggen.level = 1.;
+            let mut out = vec![0.; len];
+            siggen.process(&mut out);
+            out
+        };
+        // a second of a sweep that doesn't go anywhere is a second of 100Hz
+        let sine = render("sweep:100-100:1s", SAMPLING_FREQ);
+        let crossings = sine
+            .windows(2)
+            .filter(|w| (w[0] < 0.) != (w[1] < 0.))
+            .count();
+        assert!((199..=201).contains(&crossings), "{crossings}");
+
+        let clicks = render("impulse:100", SAMPLING_FREQ);
+        let at: Vec<_> = (0..clicks.len()).filter(|&i| clicks[i] != 0.).collect();
+        assert_eq!(at.len(), 100);
+        assert_eq!(at[1] - at[0], 441);
+
+        assert!(render("dc:0.25", 64).iter().all(|&s| s == 0.25));
+        let pink = render("pink", SAMPLING_FREQ);
+        assert!(pink.iter().all(|s| s.abs() < 1.));
+        assert!(pink.iter().any(|s| s.abs() > 0.1));
+    }
+}
@@ -0,0 +1,36 @@
+//! Quitting cleanly on Ctrl-C or SIGTERM, for when there's no window to
+//! close.
+
+use std::ffi::c_int;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+const SIGINT: c_int = 2;
+const SIGTERM: c_int = 15;
+
+static RECEIVED: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+}
+
+extern "C" fn handle(_signum: c_int) {
+    // all a handler can safely do
+    RECEIVED.store(true, Ordering::SeqCst);
+}
+
+/// Catches SIGINT and SIGTERM from now on, instead of dying on them.
+pub fn install() {
+    // SAFETY: the handler only touches an atomic
+    unsafe {
+        signal(SIGINT, handle);
+        signal(SIGTERM, handle);
+    }
+}
+
+/// Blocks until one of the signals arrives.
+pub fn wait() {
+    while !RECEIVED.load(Ordering::SeqCst) {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
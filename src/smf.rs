@@ -0,0 +1,474 @@
+//! Reading and writing Standard MIDI Files.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::audio_thread::AudioEvent;
+use crate::clock::{Clock, EngineClock, Stamp, BEATS_PER_BAR};
+use crate::midi::{parse_midi, MidiEvent};
+use crate::queue;
+
+/// Tempo until the file says otherwise: 120bpm.
+const DEFAULT_TEMPO: u32 = 500_000;
+
+/// Ticks per beat in files we write, which at the default tempo makes a
+/// tick 100µs.
+const WRITE_DIVISION: u16 = 5000;
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let out = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| format!("truncated at byte {}", self.pos))?;
+        self.pos += len;
+        Ok(out)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    /// A variable length quantity: seven bits a byte, high bit set on all
+    /// but the last.
+    fn vlq(&mut self) -> Result<u32, String> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.byte()?;
+            value = (value << 7) | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(format!(
+            "variable length number too long at byte {}",
+            self.pos
+        ))
+    }
+
+    fn chunk(&mut self, kind: &[u8; 4]) -> Result<Reader<'a>, String> {
+        let at = self.pos;
+        if self.bytes(4)? != kind {
+            return Err(format!(
+                "expected a {} chunk at byte {at}",
+                String::from_utf8_lossy(kind)
+            ));
+        }
+        let len = self.u32()? as usize;
+        Ok(Reader {
+            data: self.bytes(len)?,
+            pos: 0,
+        })
+    }
+}
+
+enum TrackEvent {
+    /// microseconds per quarter note from here on
+    Tempo(u32),
+    /// a time signature: beats to the bar, and the note that gets a beat as
+    /// a power of two
+    Meter(u8, u8),
+    Midi(MidiEvent),
+}
+
+/// A change in how a song keeps time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeChange {
+    /// beats per minute
+    Tempo(f32),
+    /// Beats to the bar from a bar line here on. Beats are quarter notes,
+    /// so 6/8 has three, and ones that don't come out whole are rounded.
+    Meter(u64),
+}
+
+/// What a file has to play, and the tempo map and time signatures to play
+/// it to, all stamped with how far into the song they are.
+#[derive(Clone, Debug, Default)]
+pub struct MidiFile {
+    pub events: Vec<MidiEvent>,
+    /// in order, starting with 4/4 at 120bpm until the file says otherwise
+    pub time: Vec<(Stamp, TimeChange)>,
+}
+
+/// Everything on one track, with its time in ticks.
+fn read_track(mut track: Reader) -> Result<Vec<(u64, TrackEvent)>, String> {
+    let mut out = Vec::new();
+    let mut tick = 0u64;
+    let mut running = None;
+    while track.pos < track.data.len() {
+        tick += track.vlq()? as u64;
+        let mut status = track.byte()?;
+        match status {
+            0xff => {
+                let kind = track.byte()?;
+                let len = track.vlq()? as usize;
+                let data = track.bytes(len)?;
+                match (kind, data) {
+                    (0x51, &[a, b, c]) => {
+                        out.push((tick, TrackEvent::Tempo(u32::from_be_bytes([0, a, b, c]))))
+                    }
+                    (0x58, &[beats, note, ..]) => out.push((tick, TrackEvent::Meter(beats, note))),
+                    // end of track
+                    (0x2f, _) => break,
+                    _ => {}
+                }
+                continue;
+            }
+            0xf0 | 0xf7 => {
+                let len = track.vlq()? as usize;
+                let data = track.bytes(len)?;
+                // a whole SysEx message, rather than a piece of one
+                if status == 0xf0 {
+                    let msg = [&[0xf0], data].concat();
+                    if let Some(event) = parse_midi(Stamp::default(), &msg) {
+                        out.push((tick, TrackEvent::Midi(event)));
+                    }
+                }
+                continue;
+            }
+            _ => {}
+        }
+        let mut first = None;
+        if status & 0x80 == 0 {
+            // running status: this is the first data byte
+            first = Some(status);
+            status = running.ok_or("data byte with no status before it")?;
+        } else {
+            running = Some(status);
+        }
+        let len = match status >> 4 {
+            0xc | 0xd => 1,
+            _ => 2,
+        };
+        let mut msg = [status, 0, 0];
+        for (i, byte) in msg[1..=len].iter_mut().enumerate() {
+            *byte = match (i, first) {
+                (0, Some(first)) => first,
+                _ => track.byte()?,
+            };
+        }
+        // program changes don't mean anything to us
+        if status >> 4 != 0xc {
+            if let Some(event) = parse_midi(Stamp::default(), &msg[..=len]) {
+                out.push((tick, TrackEvent::Midi(event)));
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Reads a format 0 or 1 file, merging its tracks and following its tempo
+/// map. Events come back in order, stamped with how far into the song they
+/// are.
+pub fn parse(data: &[u8]) -> Result<MidiFile, String> {
+    let mut file = Reader { data, pos: 0 };
+    let mut header = file.chunk(b"MThd")?;
+    let format = header.u16()?;
+    let tracks = header.u16()?;
+    let division = header.u16()?;
+    if format > 1 {
+        return Err(format!("format {format} files aren't supported"));
+    }
+
+    let mut events = Vec::new();
+    for _ in 0..tracks {
+        events.extend(read_track(file.chunk(b"MTrk")?)?);
+    }
+    // stable, so simultaneous events keep their order within a track, and
+    // tempo changes from the first track come before notes on later ones
+    events.sort_by_key(|&(tick, _)| tick);
+
+    let (mut us, mut last_tick, mut tempo) = (0f64, 0u64, DEFAULT_TEMPO);
+    let us_per_tick = |tempo: u32| {
+        if division & 0x8000 == 0 {
+            tempo as f64 / division.max(1) as f64
+        } else {
+            // SMPTE: frames per second, negated, and ticks per frame
+            let fps = -((division >> 8) as u8 as i8) as f64;
+            let per_frame = (division & 0xff) as f64;
+            1e6 / (fps * per_frame).max(1.)
+        }
+    };
+    let mut out = MidiFile {
+        events: Vec::new(),
+        time: vec![
+            (
+                Stamp::default(),
+                TimeChange::Tempo(60e6 / DEFAULT_TEMPO as f32),
+            ),
+            (Stamp::default(), TimeChange::Meter(BEATS_PER_BAR)),
+        ],
+    };
+    for (tick, event) in events {
+        us += (tick - last_tick) as f64 * us_per_tick(tempo);
+        last_tick = tick;
+        let at = Stamp::default() + Duration::from_secs_f64(us / 1e6);
+        match event {
+            TrackEvent::Tempo(t) => {
+                tempo = t.max(1);
+                out.time.push((at, TimeChange::Tempo(60e6 / tempo as f32)));
+            }
+            TrackEvent::Meter(beats, note) => {
+                let quarters = beats as f32 * 4. / 2f32.powi(note.min(6) as i32);
+                let meter = (quarters.round() as u64).max(1);
+                out.time.push((at, TimeChange::Meter(meter)));
+            }
+            TrackEvent::Midi(mut event) => {
+                event.at = at;
+                out.events.push(event);
+            }
+        }
+    }
+    Ok(out)
+}
+
+pub fn load(path: &Path) -> io::Result<MidiFile> {
+    parse(&fs::read(path)?).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {e}", path.display()),
+        )
+    })
+}
+
+fn write_vlq(out: &mut Vec<u8>, mut value: u32) {
+    let mut groups = vec![(value & 0x7f) as u8];
+    value >>= 7;
+    while value > 0 {
+        groups.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.extend(groups.iter().rev());
+}
+
+/// Writes `events`, stamped with how far into the song they are, as a
+/// format 0 file at `bpm`, with `marker` at the start if there is one.
+pub fn write(events: &[MidiEvent], bpm: f32, marker: Option<&str>) -> Vec<u8> {
+    let mut events = events.to_vec();
+    events.sort_by_key(|e| e.at);
+    let tempo = if bpm > 0. {
+        (60e6 / bpm as f64).round().clamp(1., 0xff_ffff as f64) as u32
+    } else {
+        DEFAULT_TEMPO
+    };
+    let us_per_tick = tempo as f64 / WRITE_DIVISION as f64;
+
+    let mut track = vec![0, 0xff, 0x51, 3];
+    track.extend(&tempo.to_be_bytes()[1..]);
+    if let Some(marker) = marker {
+        track.extend([0, 0xff, 0x06]);
+        write_vlq(&mut track, marker.len() as u32);
+        track.extend(marker.as_bytes());
+    }
+    let mut last = 0;
+    for event in &events {
+        let tick = (event.at.as_secs_f64() * 1e6 / us_per_tick).round() as u64;
+        write_vlq(&mut track, (tick - last) as u32);
+        last = tick;
+        let bytes = event.to_bytes();
+        match bytes.split_first() {
+            // SysEx is written with its length after the F0
+            Some((&0xf0, rest)) => {
+                track.push(0xf0);
+                write_vlq(&mut track, rest.len() as u32);
+                track.extend(rest);
+            }
+            _ => track.extend(bytes),
+        }
+    }
+    track.extend([0, 0xff, 0x2f, 0]);
+
+    let mut out = b"MThd\0\0\0\x06\0\0\0\x01".to_vec();
+    out.extend(WRITE_DIVISION.to_be_bytes());
+    out.extend(b"MTrk");
+    out.extend((track.len() as u32).to_be_bytes());
+    out.extend(track);
+    out
+}
+
+/// Collects MIDI as it's played, to save as a file.
+pub struct Recorder {
+    pub path: PathBuf,
+    pub events: Vec<MidiEvent>,
+    start: Option<Stamp>,
+    /// for the tempo, and to mark where in the session the first event was
+    clock: Clock,
+}
+
+impl Recorder {
+    pub fn new(path: PathBuf, clock: Clock) -> Self {
+        Self {
+            path,
+            events: Vec::new(),
+            start: None,
+            clock,
+        }
+    }
+
+    /// Times count from the first event, so the wait before it isn't kept.
+    pub fn record(&mut self, mut event: MidiEvent) {
+        let start = *self.start.get_or_insert(event.at);
+        event.at = Stamp(event.at.samples_since(start));
+        self.events.push(event);
+    }
+
+    /// Writes everything so far. Saving again later writes over it with
+    /// what has been added since as well.
+    pub fn save(&self) -> io::Result<()> {
+        let marker = self.start.map(|start| self.clock.at(start).to_string());
+        fs::write(
+            &self.path,
+            write(&self.events, self.clock.bpm(), marker.as_deref()),
+        )
+    }
+}
+
+/// Sends `song` to the audio thread as it comes due, as if it were being
+/// played live from now on `clock`, with its tempo and time signatures
+/// ahead of anything played at the same time. Returns once it's all been
+/// sent, or when the audio thread has gone away.
+pub fn play(song: &MidiFile, send: &queue::Sender<AudioEvent>, clock: EngineClock) {
+    let start = clock.now();
+    let later = |at: Stamp| Stamp(start.0 + at.0);
+    let mut due: Vec<(Stamp, AudioEvent)> = song
+        .time
+        .iter()
+        .map(|&(at, change)| (later(at), AudioEvent::Time(change, later(at))))
+        .chain(song.events.iter().map(|event| {
+            let at = later(event.at);
+            (
+                at,
+                AudioEvent::Midi(MidiEvent {
+                    at,
+                    ..event.clone()
+                }),
+            )
+        }))
+        .collect();
+    // stable, so the time comes first
+    due.sort_by_key(|&(at, _)| at);
+    for (at, event) in due {
+        std::thread::sleep(clock.instant(at).saturating_duration_since(Instant::now()));
+        if send.send(event).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::MidiEventInner;
+
+    #[test]
+    fn test_smf() {
+        let mut file = b"MThd\0\0\0\x06\0\x01\0\x02\x01\xe0".to_vec();
+        // tempo track: 3/4 at 60bpm from the start, 120bpm after a beat
+        let tempo = b"\0\xff\x58\x04\x03\x02\x18\x08\0\xff\x51\x03\x0f\x42\x40\x83\x60\xff\x51\x03\x07\xa1\x20\0\xff\x2f\0";
+        file.extend(b"MTrk\0\0\0\x1b");
+        file.extend(tempo);
+        // a program change, then a note on at beat 1 and off at beat 2
+        // through running status with velocity 0
+        let notes = b"\0\xc0\x05\x83\x60\x90\x45\x64\x83\x60\x45\0\0\xff\x2f\0";
+        file.extend(b"MTrk\0\0\0\x10");
+        file.extend(notes);
+
+        let song = parse(&file).unwrap();
+        let start = Stamp::default();
+        assert_eq!(
+            song.time,
+            [
+                (start, TimeChange::Tempo(120.)),
+                (start, TimeChange::Meter(4)),
+                (start, TimeChange::Meter(3)),
+                (start, TimeChange::Tempo(60.)),
+                (Stamp(44_100), TimeChange::Tempo(120.)),
+            ]
+        );
+        let events = song.events;
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].at, Stamp(44_100));
+        assert!(matches!(
+            events[0].inner,
+            MidiEventInner::Down {
+                note: 0x45,
+                velocity: 100
+            }
+        ));
+        assert_eq!(events[1].at, Stamp(66_150));
+        assert!(matches!(
+            events[1].inner,
+            MidiEventInner::Down { velocity: 0, .. }
+        ));
+
+        assert!(parse(&file[..30]).is_err());
+        assert!(parse(b"RIFF").is_err());
+
+        let (send, recv) = queue::bounded(16, queue::Overflow::Block);
+        let mut quick = MidiFile {
+            events: events.clone(),
+            time: vec![(start + Duration::from_millis(5), TimeChange::Meter(3))],
+        };
+        quick.events[1].at = start + Duration::from_millis(5);
+        quick.events[0].at = start;
+        play(&quick, &send, EngineClock::new(Instant::now()));
+        let at: Vec<(bool, Stamp)> = recv
+            .try_iter()
+            .map(|event| match event {
+                AudioEvent::Midi(event) => (false, event.at),
+                AudioEvent::Time(_, at) => (true, at),
+                other => panic!("{other:?}"),
+            })
+            .collect();
+        let later = at[0].1 + Duration::from_millis(5);
+        assert_eq!(at[1..], [(true, later), (false, later)]);
+
+        let t0 = Stamp::default() + Duration::from_secs(1);
+        let mut recorder = Recorder::new(PathBuf::new(), Clock::new(Instant::now(), 120.));
+        let at = |event: &MidiEvent, at| MidiEvent {
+            at,
+            ..event.clone()
+        };
+        recorder.record(at(&events[0], t0));
+        recorder.record(at(&events[1], t0 + Duration::from_micros(123_456_700)));
+        let bend = MidiEvent {
+            at: t0 + Duration::from_millis(1),
+            channel: 3,
+            inner: MidiEventInner::PitchBend(0x1234),
+        };
+        recorder.record(bend);
+        let written = write(&recorder.events, 120., Some("1:1 0:00"));
+        assert!(written.windows(11).any(|w| w == b"\xff\x06\x081:1 0:00"));
+        let back = parse(&written).unwrap().events;
+        let times: Vec<Stamp> = back.iter().map(|e| e.at).collect();
+        assert_eq!(
+            times,
+            [
+                start,
+                start + Duration::from_millis(1),
+                start + Duration::from_micros(123_456_700)
+            ]
+        );
+        assert_eq!(back[1].channel, 3);
+        assert!(matches!(back[1].inner, MidiEventInner::PitchBend(0x1234)));
+        assert!(matches!(
+            back[2].inner,
+            MidiEventInner::Down { velocity: 0, .. }
+        ));
+    }
+}
@@ -0,0 +1,125 @@
+//! Consistent copies of the engine state (graph topology and parameter
+//! values) that can be taken while audio is running. The audio callback
+//! produces them itself between blocks, so they never see a half-applied
+//! change, and it never waits on whoever asked for one.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::params::Params;
+use crate::stereo::StereoFilter;
+
+/// One node of the processing graph.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Node {
+    pub name: String,
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    pub fn leaf(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            children: Vec::new(),
+        }
+    }
+
+    pub fn with_children(name: impl Into<String>, children: Vec<Node>) -> Self {
+        Self {
+            name: name.into(),
+            children,
+        }
+    }
+
+    /// Name of a type without its module path or generics.
+    pub fn type_name<T: ?Sized>() -> String {
+        let name = std::any::type_name::<T>();
+        let name = name.split('<').next().unwrap_or(name);
+        name.rsplit("::").next().unwrap_or(name).to_string()
+    }
+
+    fn fmt_indented(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        writeln!(f, "{:indent$}{}", "", self.name, indent = depth * 2)?;
+        for child in &self.children {
+            child.fmt_indented(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub graph: Node,
+    pub params: Vec<(String, f32)>,
+}
+
+impl Snapshot {
+    pub fn of<T: StereoFilter + Params>(engine: &T) -> Self {
+        let params = engine
+            .params()
+            .into_iter()
+            .filter_map(|p| {
+                let value = engine.get_param(&p.name)?;
+                Some((p.name, value))
+            })
+            .collect();
+        Self {
+            graph: engine.describe(),
+            params,
+        }
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.graph)?;
+        for (name, value) in &self.params {
+            writeln!(f, "{name} = {value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Hands snapshots from the audio callback to anyone who asks for them.
+#[derive(Default)]
+pub struct Snapshots {
+    requested: AtomicBool,
+    slot: Mutex<Option<Snapshot>>,
+}
+
+impl Snapshots {
+    /// Called by the audio callback after each block. Never blocks.
+    pub fn publish_if_requested(&self, take: impl FnOnce() -> Snapshot) {
+        if !self.requested.load(Ordering::Acquire) {
+            return;
+        }
+        if let Ok(mut slot) = self.slot.try_lock() {
+            *slot = Some(take());
+            self.requested.store(false, Ordering::Release);
+        }
+    }
+
+    /// Asks for a fresh snapshot and waits up to `timeout` for the audio
+    /// callback to produce it.
+    pub fn take(&self, timeout: Duration) -> Option<Snapshot> {
+        self.slot.lock().unwrap().take();
+        self.requested.store(true, Ordering::Release);
+
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if let Some(snapshot) = self.slot.lock().unwrap().take() {
+                return Some(snapshot);
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        None
+    }
+}
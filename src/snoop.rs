@@ -0,0 +1,170 @@
+//! Taps that can go anywhere in the graph and write whatever passes through
+//! them to WAV files, for seeing what each stage is doing. Every tap is
+//! registered under a name of its own, so they can all be started and
+//! saved at once.
+
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::filters::Filter;
+use crate::recording::{self, Recording, Tap};
+
+static REGISTRY: Mutex<Vec<Weak<Port>>> = Mutex::new(Vec::new());
+
+struct Port {
+    name: String,
+    capture: Mutex<Option<(Recording, Tap<1>)>>,
+}
+
+/// Captures whatever passes through it to a mono WAV file, between
+/// [`Snoop::start`] and [`Snoop::stop`]. The samples go through a queue to a
+/// writer thread, so captures can run as long as they like; anything the
+/// writer can't keep up with is dropped and counted.
+pub struct Snoop {
+    port: Arc<Port>,
+}
+
+impl Snoop {
+    /// Registers a tap called `name`, or `name-2` and so on if that's
+    /// taken.
+    pub fn new(name: &str) -> Snoop {
+        let mut registry = REGISTRY.lock().unwrap();
+        registry.retain(|port| port.strong_count() > 0);
+        let taken = |name: &str| {
+            registry
+                .iter()
+                .filter_map(Weak::upgrade)
+                .any(|port| port.name == name)
+        };
+        let mut unique = name.to_string();
+        for n in 2.. {
+            if !taken(&unique) {
+                break;
+            }
+            unique = format!("{name}-{n}");
+        }
+        let port = Arc::new(Port {
+            name: unique,
+            capture: Mutex::new(None),
+        });
+        registry.push(Arc::downgrade(&port));
+        Snoop { port }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.port.name
+    }
+
+    pub fn is_capturing(&self) -> bool {
+        self.port.capture.lock().unwrap().is_some()
+    }
+
+    /// Starts capturing to `path`, saving whatever capture was going
+    /// first.
+    pub fn start(&self, path: PathBuf) -> io::Result<()> {
+        if let Some(message) = self.stop() {
+            println!("snoop {}: {message}", self.name());
+        }
+        *self.port.capture.lock().unwrap() = Some(recording::start(path, None)?);
+        Ok(())
+    }
+
+    /// Stops capturing, saying how it went. This waits for the writer, so
+    /// it doesn't belong on the audio thread.
+    pub fn stop(&self) -> Option<String> {
+        let (recording, tap) = self.port.capture.lock().unwrap().take()?;
+        drop(tap);
+        Some(recording.finish())
+    }
+}
+
+impl Filter for Snoop {
+    fn process(&mut self, samples: &mut [f32]) {
+        // only held for long by the other side while starting or stopping,
+        // when missing a block doesn't matter
+        if let Ok(mut capture) = self.port.capture.try_lock() {
+            if let Some((_, tap)) = &mut *capture {
+                tap.write(samples);
+            }
+        }
+    }
+}
+
+/// Every tap that's still around.
+fn registered() -> Vec<Arc<Port>> {
+    REGISTRY
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect()
+}
+
+pub fn any_capturing() -> bool {
+    registered()
+        .iter()
+        .any(|port| port.capture.lock().unwrap().is_some())
+}
+
+/// Starts every tap capturing to `snoop-<name>-<stamp>.wav`, saying how
+/// each went.
+pub fn start_all(stamp: u64) -> Vec<String> {
+    registered()
+        .into_iter()
+        .map(|port| {
+            let name = &port.name;
+            let snoop = Snoop { port: port.clone() };
+            let path = PathBuf::from(format!("snoop-{name}-{stamp}.wav"));
+            match snoop.start(path.clone()) {
+                Ok(()) => format!("{name}: to {}", path.display()),
+                Err(e) => format!("{name}: {e}"),
+            }
+        })
+        .collect()
+}
+
+/// Saves every capture that's going, saying how each went.
+pub fn save_all() -> Vec<String> {
+    registered()
+        .into_iter()
+        .filter_map(|port| {
+            let snoop = Snoop { port };
+            let message = snoop.stop()?;
+            Some(format!("{}: {message}", snoop.name()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters::{read_wav, SAMPLING_FREQ};
+
+    #[test]
+    fn test_snoop() {
+        let mut snoop = Snoop::new("test-snoop");
+        let other = Snoop::new("test-snoop");
+        assert_eq!(other.name(), "test-snoop-2");
+        drop(other);
+        assert_eq!(Snoop::new("test-snoop").name(), "test-snoop-2");
+
+        // nothing is kept until it's started
+        snoop.process(&mut [1.; 64]);
+        assert_eq!(snoop.stop(), None);
+
+        let path = std::env::temp_dir().join(format!("synthtoy-snoop-{}.wav", std::process::id()));
+        snoop.start(path.clone()).unwrap();
+        assert!(any_capturing());
+        let mut block: Vec<f32> = (0..256).map(|i| i as f32).collect();
+        snoop.process(&mut block[..100]);
+        snoop.process(&mut block[100..]);
+        assert!(snoop.stop().unwrap().starts_with("saved"));
+        assert!(!snoop.is_capturing());
+
+        let (rate, samples) = read_wav(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(rate, SAMPLING_FREQ as u32);
+        assert_eq!(samples, block);
+    }
+}
@@ -0,0 +1,506 @@
+//! The sound sources the voice manager can be filled with, and picking one
+//! at runtime.
+
+use std::f32::consts::TAU;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::eks::EksString;
+use crate::filters::{
+    read_wav, voice_seed, Adsr, Exciter, Filter, Rng, StringSynth, Svf, SvfMode, SAMPLING_FREQ,
+};
+use crate::note::Pitch;
+use crate::params::{nested, ParamInfo, Params};
+use crate::voice::{DynVoice, Voice};
+use crate::wavetable::{MipMap, PositionEnvelope, Wave, WaveTable};
+
+/// What voices are seeded with unless told otherwise.
+pub const DEFAULT_SEED: u32 = 1;
+
+/// Source selection as written on the command line: `string`, `eks`,
+/// `wavetable[:<wave>,...]`, `fm`, `sampler:<file.wav>` or `noise`.
+#[derive(Clone, Debug)]
+pub enum SynthKind {
+    String,
+    /// the string with the Extended Karplus-Strong filters
+    Eks,
+    /// a sine unless it says which, or the waves to morph between, with
+    /// how to move through them over each note if not by hand
+    Wavetable {
+        waves: Vec<Wave>,
+        position: Option<PositionEnvelope>,
+    },
+    Fm,
+    Sampler(PathBuf),
+    Noise,
+}
+
+impl std::str::FromStr for SynthKind {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        Ok(match value {
+            "string" => SynthKind::String,
+            "eks" => SynthKind::Eks,
+            "wavetable" => SynthKind::Wavetable {
+                waves: vec![Wave::default()],
+                position: None,
+            },
+            "fm" => SynthKind::Fm,
+            "noise" => SynthKind::Noise,
+            "sampler" => return Err("sampler needs a file, as sampler:<file.wav>".to_string()),
+            _ => match value.split_once(':') {
+                Some(("sampler", path)) => SynthKind::Sampler(PathBuf::from(path)),
+                Some(("wavetable", waves)) => SynthKind::Wavetable {
+                    waves: waves
+                        .split(',')
+                        .map(|wave| wave.trim().parse())
+                        .collect::<Result<_, _>>()?,
+                    position: None,
+                },
+                _ => return Err(format!("unknown synth {value:?}")),
+            },
+        })
+    }
+}
+
+impl SynthKind {
+    /// Moves a wavetable through its waves along `env` over each note.
+    pub fn with_position(self, env: PositionEnvelope) -> Result<Self, String> {
+        match self {
+            SynthKind::Wavetable { waves, .. } => Ok(SynthKind::Wavetable {
+                waves,
+                position: Some(env),
+            }),
+            _ => Err("only a wavetable has a position to move".to_string()),
+        }
+    }
+
+    /// Makes `count` voices of this kind. `exciter` is what strings get
+    /// plucked with and is ignored by the rest. Each voice gets its own
+    /// noise from `seed`, so notes played together don't come out alike.
+    pub fn build_voices(
+        &self,
+        count: usize,
+        exciter: &dyn Exciter,
+        seed: u32,
+    ) -> io::Result<Vec<Box<dyn DynVoice>>> {
+        let sample = match self {
+            SynthKind::Sampler(path) => {
+                let (rate, samples) = read_wav(path)?;
+                Some((rate, Arc::new(samples)))
+            }
+            _ => None,
+        };
+        let tables: Option<Arc<[MipMap]>> = match self {
+            SynthKind::Wavetable { waves, .. } => Some(waves.iter().map(MipMap::new).collect()),
+            _ => None,
+        };
+
+        (0..count)
+            .map(|i| -> io::Result<Box<dyn DynVoice>> {
+                let seed = voice_seed(seed, i);
+                Ok(match self {
+                    SynthKind::String => {
+                        let mut string = StringSynth::new(500);
+                        string.set_exciter(exciter, seed);
+                        string.scatter_drift(seed);
+                        Box::new(string)
+                    }
+                    SynthKind::Eks => {
+                        let mut string = EksString::default();
+                        string.exciter = exciter.boxed_clone();
+                        string.exciter.reseed(seed);
+                        Box::new(string)
+                    }
+                    SynthKind::Wavetable { position, .. } => {
+                        let mut voice = WaveTable::new(tables.clone().unwrap());
+                        voice.position_env = position.clone();
+                        Box::new(voice)
+                    }
+                    SynthKind::Fm => Box::<FmVoice>::default(),
+                    SynthKind::Sampler(_) => {
+                        let (rate, samples) = sample.clone().unwrap();
+                        Box::new(SamplerVoice::new(samples, rate))
+                    }
+                    SynthKind::Noise => Box::new(NoiseVoice {
+                        rng: Rng::with_seed(seed),
+                        ..NoiseVoice::default()
+                    }),
+                })
+            })
+            .collect()
+    }
+}
+
+fn bent(freq: f32, semitones: f32) -> f32 {
+    freq * 2f32.powf(semitones / 12.)
+}
+
+/// Two operator FM: a sine modulator phase modulating a sine carrier. The
+/// modulation index follows the envelope, so notes get duller as they fade.
+pub struct FmVoice {
+    /// modulator frequency relative to the carrier
+    pub ratio: f32,
+    pub index: f32,
+    pub env: Adsr,
+
+    note_freq: f32,
+    bend: f32,
+    velocity: f32,
+    /// how much per-note expression scales the index by
+    expression: f32,
+    carrier_phase: f32,
+    mod_phase: f32,
+}
+
+impl Default for FmVoice {
+    fn default() -> Self {
+        Self {
+            ratio: 1.,
+            index: 2.,
+            env: Adsr::default(),
+            note_freq: 440.,
+            bend: 0.,
+            velocity: 1.,
+            expression: 1.,
+            carrier_phase: 0.,
+            mod_phase: 0.,
+        }
+    }
+}
+
+impl Voice for FmVoice {
+    fn note_on(&mut self, freq: f32, velocity: f32) {
+        self.note_freq = freq;
+        self.velocity = velocity;
+        self.carrier_phase = 0.;
+        self.mod_phase = 0.;
+        self.env.note_on();
+    }
+
+    fn note_off(&mut self) {
+        self.env.note_off();
+    }
+
+    fn set_bend(&mut self, semitones: f32) {
+        self.bend = semitones;
+    }
+
+    fn is_active(&self) -> bool {
+        !self.env.is_idle()
+    }
+
+    /// Both brighten it: the timbre from none to twice the index, and
+    /// pressure adding up to as much again.
+    fn set_expression(&mut self, pressure: f32, timbre: f32) {
+        self.expression = 2. * timbre + pressure;
+    }
+}
+
+impl Filter for FmVoice {
+    fn process(&mut self, samples: &mut [f32]) {
+        let inc = bent(self.note_freq, self.bend) / SAMPLING_FREQ as f32;
+        let index = self.index * self.expression;
+        for s in samples.iter_mut() {
+            let level = self.env.next_level();
+            let modulator = (TAU * self.mod_phase).sin();
+            *s = (TAU * self.carrier_phase + index * level * modulator).sin()
+                * level
+                * self.velocity;
+            self.carrier_phase = (self.carrier_phase + inc).fract();
+            self.mod_phase = (self.mod_phase + inc * self.ratio).fract();
+        }
+    }
+}
+
+impl Params for FmVoice {
+    fn params(&self) -> Vec<ParamInfo> {
+        let mut out = vec![
+            ParamInfo::new("ratio", 0.25, 8.),
+            ParamInfo::new("index", 0., 10.),
+        ];
+        out.extend(nested("env", &self.env));
+        out
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name {
+            "ratio" => Some(self.ratio),
+            "index" => Some(self.index),
+            _ => self.env.get_param(name.strip_prefix("env.")?),
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name {
+            "ratio" => self.ratio = value,
+            "index" => self.index = value,
+            _ => {
+                return name
+                    .strip_prefix("env.")
+                    .is_some_and(|rest| self.env.set_param(rest, value))
+            }
+        }
+        true
+    }
+}
+
+/// Plays a recording, repitched so that `root` (a MIDI note) plays it back
+/// as recorded.
+pub struct SamplerVoice {
+    pub root: f32,
+    pub env: Adsr,
+
+    samples: Arc<Vec<f32>>,
+    /// recording's sample rate over ours
+    rate_ratio: f32,
+    note_freq: f32,
+    bend: f32,
+    velocity: f32,
+    pos: f32,
+}
+
+impl SamplerVoice {
+    pub fn new(samples: Arc<Vec<f32>>, sample_rate: u32) -> Self {
+        Self {
+            root: 60.,
+            env: Adsr::default(),
+            pos: samples.len() as f32,
+            samples,
+            rate_ratio: sample_rate as f32 / SAMPLING_FREQ as f32,
+            note_freq: 440.,
+            bend: 0.,
+            velocity: 1.,
+        }
+    }
+
+    fn finished(&self) -> bool {
+        self.pos + 1. >= self.samples.len() as f32
+    }
+}
+
+impl Voice for SamplerVoice {
+    fn note_on(&mut self, freq: f32, velocity: f32) {
+        self.note_freq = freq;
+        self.velocity = velocity;
+        self.pos = 0.;
+        self.env.note_on();
+    }
+
+    fn note_off(&mut self) {
+        self.env.note_off();
+    }
+
+    fn set_bend(&mut self, semitones: f32) {
+        self.bend = semitones;
+    }
+
+    fn is_active(&self) -> bool {
+        !self.env.is_idle() && !self.finished()
+    }
+}
+
+impl Filter for SamplerVoice {
+    fn process(&mut self, samples: &mut [f32]) {
+        let step = self.rate_ratio * bent(self.note_freq, self.bend) / Pitch(self.root).freq();
+        for s in samples.iter_mut() {
+            let level = self.env.next_level();
+            if self.finished() {
+                *s = 0.;
+                continue;
+            }
+            let idx = self.pos as usize;
+            let frac = self.pos - idx as f32;
+            let (a, b) = (self.samples[idx], self.samples[idx + 1]);
+            *s = (a + (b - a) * frac) * level * self.velocity;
+            self.pos += step;
+        }
+    }
+}
+
+impl Params for SamplerVoice {
+    fn params(&self) -> Vec<ParamInfo> {
+        let mut out = vec![ParamInfo::new("root", 0., 127.).not_random()];
+        out.extend(nested("env", &self.env));
+        out
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name {
+            "root" => Some(self.root),
+            _ => self.env.get_param(name.strip_prefix("env.")?),
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name {
+            "root" => self.root = value,
+            _ => {
+                return name
+                    .strip_prefix("env.")
+                    .is_some_and(|rest| self.env.set_param(rest, value))
+            }
+        }
+        true
+    }
+}
+
+/// White noise through a band-pass that follows the keyboard, so it's
+/// vaguely pitched.
+pub struct NoiseVoice {
+    pub filter: Svf,
+    pub env: Adsr,
+
+    rng: Rng,
+    note_freq: f32,
+    bend: f32,
+    velocity: f32,
+}
+
+impl Default for NoiseVoice {
+    fn default() -> Self {
+        let mut filter = Svf::new(SvfMode::BandPass, 440., 8.);
+        filter.key_tracking = 1.;
+        Self {
+            filter,
+            env: Adsr::default(),
+            rng: Rng::default(),
+            note_freq: 440.,
+            bend: 0.,
+            velocity: 1.,
+        }
+    }
+}
+
+impl Voice for NoiseVoice {
+    fn note_on(&mut self, freq: f32, velocity: f32) {
+        self.note_freq = freq;
+        self.velocity = velocity;
+        self.filter.set_note(bent(freq, self.bend));
+        self.env.note_on();
+    }
+
+    fn note_off(&mut self) {
+        self.env.note_off();
+    }
+
+    fn set_bend(&mut self, semitones: f32) {
+        self.bend = semitones;
+        self.filter.set_note(bent(self.note_freq, semitones));
+    }
+
+    fn is_active(&self) -> bool {
+        !self.env.is_idle()
+    }
+}
+
+impl Filter for NoiseVoice {
+    fn process(&mut self, samples: &mut [f32]) {
+        for s in samples.iter_mut() {
+            *s = self.rng.next_f32();
+        }
+        self.filter.process(samples);
+        for s in samples.iter_mut() {
+            *s *= self.env.next_level() * self.velocity;
+        }
+    }
+}
+
+impl Params for NoiseVoice {
+    fn params(&self) -> Vec<ParamInfo> {
+        let mut out = nested("filter", &self.filter);
+        out.extend(nested("env", &self.env));
+        out
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name.split_once('.')? {
+            ("filter", rest) => self.filter.get_param(rest),
+            ("env", rest) => self.env.get_param(rest),
+            _ => None,
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name.split_once('.') {
+            Some(("filter", rest)) => self.filter.set_param(rest, value),
+            Some(("env", rest)) => self.env.set_param(rest, value),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sources_play() {
+        let exciter = crate::filters::Noise::default();
+        for kind in [
+            "string",
+            "eks",
+            "fm",
+            "noise",
+            "wavetable",
+            "wavetable:triangle",
+            "wavetable:square",
+            "wavetable:sine,square",
+        ] {
+            let kind: SynthKind = kind.parse().unwrap();
+            let mut voices = kind.build_voices(2, &exciter, DEFAULT_SEED).unwrap();
+            let voice = &mut voices[0];
+            assert!(!voice.is_active());
+            voice.note_on(440., 1.);
+            let mut out = vec![0.; 4096];
+            voice.process(&mut out);
+            assert!(out.iter().any(|s| s.abs() > 0.01), "{kind:?} is silent");
+            assert!(out.iter().all(|s| s.abs() <= 1.5), "{kind:?} is too loud");
+            voice.note_off();
+            let mut out = vec![0.; SAMPLING_FREQ];
+            voice.process(&mut out);
+            assert!(!voice.is_active(), "{kind:?} never stops");
+        }
+        assert!("sampler".parse::<SynthKind>().is_err());
+        assert!("wavetable:saw".parse::<SynthKind>().is_err());
+    }
+
+    #[test]
+    fn test_voice_seeds() {
+        let exciter = crate::filters::Noise::default();
+        let play = |kind: &str, seed| {
+            let kind: SynthKind = kind.parse().unwrap();
+            let mut voices = kind.build_voices(2, &exciter, seed).unwrap();
+            voices
+                .iter_mut()
+                .map(|voice| {
+                    voice.note_on(440., 1.);
+                    let mut out = vec![0.; 256];
+                    voice.process(&mut out);
+                    out
+                })
+                .collect::<Vec<_>>()
+        };
+        for kind in ["string", "eks", "noise"] {
+            // voices on the same note don't make the same noise
+            let first = play(kind, DEFAULT_SEED);
+            assert_ne!(first[0], first[1], "{kind}");
+            // but do again from the same seed, and not from another
+            assert_eq!(play(kind, DEFAULT_SEED), first, "{kind}");
+            assert_ne!(play(kind, 7)[0], first[0], "{kind}");
+        }
+    }
+
+    #[test]
+    fn test_sampler_pitch() {
+        let samples: Vec<f32> = (0..1000).map(|i| i as f32).collect();
+        let mut voice = SamplerVoice::new(Arc::new(samples), SAMPLING_FREQ as u32);
+        // an octave above the root plays back twice as fast
+        voice.note_on(Pitch(72.).freq(), 1.);
+        voice.env.attack = 0.;
+        let mut out = [0.; 4];
+        voice.process(&mut out);
+        assert!((out[3] - 6.).abs() < 0.01, "{out:?}");
+    }
+}
@@ -0,0 +1,156 @@
+//! A fixed size single producer, single consumer queue for handing things
+//! to the audio callback. Neither end ever blocks or allocates once it's
+//! made, so the callback can drain it without risking a glitch.
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+struct Shared<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    /// Count of items ever popped. Only the consumer writes it.
+    head: AtomicUsize,
+    /// Count of items ever pushed. Only the producer writes it.
+    tail: AtomicUsize,
+}
+
+// SAFETY: a slot is only touched by the producer while it is outside
+// head..tail and only by the consumer while it is inside, and the counters
+// hand slots over with release/acquire ordering.
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    fn slot(&self, idx: usize) -> *mut MaybeUninit<T> {
+        // the length is a power of two, so this stays right when the
+        // counters wrap around
+        self.slots[idx & (self.slots.len() - 1)].get()
+    }
+}
+
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        let (head, tail) = (*self.head.get_mut(), *self.tail.get_mut());
+        let mut idx = head;
+        while idx != tail {
+            // SAFETY: everything between head and tail was pushed and not
+            // popped, and nobody else can see it any more
+            unsafe { (*self.slot(idx)).assume_init_drop() };
+            idx = idx.wrapping_add(1);
+        }
+    }
+}
+
+pub struct Producer<T>(Arc<Shared<T>>);
+
+pub struct Consumer<T>(Arc<Shared<T>>);
+
+/// Makes a queue with room for at least `capacity` items.
+pub fn channel<T: Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let slots = (0..capacity.max(1).next_power_of_two())
+        .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+        .collect();
+    let shared = Arc::new(Shared {
+        slots,
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+    (Producer(shared.clone()), Consumer(shared))
+}
+
+impl<T> Producer<T> {
+    /// Hands `value` back if the queue is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let shared = &*self.0;
+        let tail = shared.tail.load(Ordering::Relaxed);
+        let head = shared.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) == shared.slots.len() {
+            return Err(value);
+        }
+        // SAFETY: the slot is outside head..tail, so the consumer is done
+        // with it, and we're the only producer
+        unsafe { (*shared.slot(tail)).write(value) };
+        shared.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+}
+
+impl<T> Consumer<T> {
+    /// The item [`Consumer::pop`] would return, left in the queue.
+    pub fn peek(&self) -> Option<&T> {
+        let shared = &*self.0;
+        let head = shared.head.load(Ordering::Relaxed);
+        let tail = shared.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        // SAFETY: as for pop, and it can't be popped while borrowed
+        Some(unsafe { (*shared.slot(head)).assume_init_ref() })
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        let shared = &*self.0;
+        let head = shared.head.load(Ordering::Relaxed);
+        let tail = shared.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        // SAFETY: the slot is inside head..tail, so the producer has
+        // finished writing it, and we're the only consumer
+        let value = unsafe { (*shared.slot(head)).assume_init_read() };
+        shared.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spsc() {
+        let (mut tx, mut rx) = channel(3);
+        for i in 0..4 {
+            tx.push(i).unwrap();
+        }
+        assert_eq!(tx.push(4), Err(4));
+        assert_eq!(rx.peek(), Some(&0));
+        assert_eq!(rx.pop(), Some(0));
+        tx.push(4).unwrap();
+        assert_eq!(
+            (1..5).map(|_| rx.pop().unwrap()).collect::<Vec<_>>(),
+            [1, 2, 3, 4]
+        );
+        assert_eq!(rx.pop(), None);
+
+        // whatever is left over gets dropped along with the queue
+        let counted = Arc::new(());
+        let (mut tx, rx) = channel(8);
+        tx.push(counted.clone()).unwrap();
+        tx.push(counted.clone()).unwrap();
+        drop((tx, rx));
+        assert_eq!(Arc::strong_count(&counted), 1);
+
+        let (mut tx, mut rx) = channel(16);
+        let producer = std::thread::spawn(move || {
+            for i in 0..10_000u32 {
+                let mut item = i;
+                while let Err(back) = tx.push(item) {
+                    item = back;
+                    std::thread::yield_now();
+                }
+            }
+        });
+        let mut expected = 0;
+        while expected < 10_000 {
+            match rx.pop() {
+                Some(i) => {
+                    assert_eq!(i, expected);
+                    expected += 1;
+                }
+                None => std::thread::yield_now(),
+            }
+        }
+        producer.join().unwrap();
+    }
+}
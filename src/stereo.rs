@@ -0,0 +1,690 @@
+//! Two channel processing.
+//!
+//! The voices and most effects are mono [`Filter`]s. The engine's output
+//! goes through a [`Stereo`] stage after them, which copies the mono signal
+//! to both sides and hands the pair to [`StereoFilter`]s from there on.
+//! Mono filters can go after the split too by wrapping them in
+//! [`DualMono`].
+
+use crate::filters::{chain_of, DelayLine, Filter, Named, NoopFilter, DRY_FADE, SAMPLING_FREQ};
+use crate::params::{ParamInfo, Params, Smoothed};
+use crate::snapshot::Node;
+
+pub trait StereoFilter: 'static + Send {
+    /// `left` and `right` are always the same length.
+    fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]);
+
+    /// As for [`Filter::latency`].
+    fn latency(&self) -> usize {
+        0
+    }
+
+    /// As for [`Filter::describe`].
+    fn describe(&self) -> Node {
+        Node::leaf(Node::type_name::<Self>())
+    }
+}
+
+impl<F: StereoFilter + ?Sized> StereoFilter for Box<F> {
+    fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) {
+        (**self).process_stereo(left, right);
+    }
+
+    fn latency(&self) -> usize {
+        (**self).latency()
+    }
+
+    fn describe(&self) -> Node {
+        (**self).describe()
+    }
+}
+
+impl StereoFilter for NoopFilter {
+    fn process_stereo(&mut self, _left: &mut [f32], _right: &mut [f32]) {}
+}
+
+impl<F: StereoFilter> StereoFilter for Option<F> {
+    fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) {
+        if let Some(f) = self {
+            f.process_stereo(left, right);
+        }
+    }
+
+    fn latency(&self) -> usize {
+        self.as_ref().map_or(0, F::latency)
+    }
+
+    fn describe(&self) -> Node {
+        self.as_ref()
+            .map_or_else(|| Filter::describe(&NoopFilter), F::describe)
+    }
+}
+
+impl<F: StereoFilter> StereoFilter for Named<F> {
+    fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) {
+        self.inner.process_stereo(left, right);
+    }
+
+    fn latency(&self) -> usize {
+        self.inner.latency()
+    }
+
+    fn describe(&self) -> Node {
+        self.inner.describe()
+    }
+}
+
+/// Runs `T` and then `H`, like [`crate::filters::Chain`].
+pub struct StereoChain<H: StereoFilter, T: StereoFilter>(pub H, pub T);
+
+impl<H: StereoFilter, T: StereoFilter> StereoFilter for StereoChain<H, T> {
+    fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) {
+        self.1.process_stereo(left, right);
+        self.0.process_stereo(left, right);
+    }
+
+    fn latency(&self) -> usize {
+        self.0.latency() + self.1.latency()
+    }
+
+    fn describe(&self) -> Node {
+        chain_of([self.1.describe(), self.0.describe()])
+    }
+}
+
+impl<H: StereoFilter + Params, T: StereoFilter + Params> Params for StereoChain<H, T> {
+    fn params(&self) -> Vec<ParamInfo> {
+        let mut out = self.1.params();
+        out.extend(self.0.params());
+        out
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        self.1.get_param(name).or_else(|| self.0.get_param(name))
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        let found = self.1.set_param(name, value);
+        self.0.set_param(name, value) || found
+    }
+}
+
+/// A mono filter after the split, with a copy of its own for each side.
+/// Parameters go to both.
+pub struct DualMono<F: Filter> {
+    pub left: F,
+    pub right: F,
+}
+
+impl<F: Filter> DualMono<F> {
+    pub fn new(left: F, right: F) -> Self {
+        Self { left, right }
+    }
+}
+
+impl<F: Filter> StereoFilter for DualMono<F> {
+    fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) {
+        self.left.process(left);
+        self.right.process(right);
+    }
+
+    fn latency(&self) -> usize {
+        self.left.latency()
+    }
+
+    fn describe(&self) -> Node {
+        Node::with_children("DualMono", vec![self.left.describe()])
+    }
+}
+
+impl<F: Filter + Params> Params for DualMono<F> {
+    fn params(&self) -> Vec<ParamInfo> {
+        self.left.params()
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        self.left.get_param(name)
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        let found = self.left.set_param(name, value);
+        self.right.set_param(name, value) || found
+    }
+}
+
+/// A source followed by a stereo chain, built up with
+/// [`Stereo::chain`] the way [`crate::filters::SynthBuilder`] builds a
+/// [`crate::filters::Synth`]. The source is mono unless it has a
+/// [`Filter::process_wide`] of its own.
+pub struct Stereo<M: Filter, O: StereoFilter = NoopFilter> {
+    pub mono: M,
+    pub output: O,
+}
+
+impl<M: Filter> Stereo<M, NoopFilter> {
+    pub fn new(mono: M) -> Self {
+        Self {
+            mono,
+            output: NoopFilter,
+        }
+    }
+}
+
+impl<M: Filter, O: StereoFilter> Stereo<M, O> {
+    pub fn chain<F: StereoFilter>(self, filter: F) -> Stereo<M, StereoChain<F, O>> {
+        Stereo {
+            mono: self.mono,
+            output: StereoChain(filter, self.output),
+        }
+    }
+
+    /// Puts what's been chained after the split so far behind a `dry`
+    /// parameter, like a [`crate::filters::Rack`]'s.
+    pub fn dry_switch(self) -> Stereo<M, Dry<O>> {
+        Stereo {
+            mono: self.mono,
+            output: Dry::new(self.output),
+        }
+    }
+}
+
+impl<M: Filter, O: StereoFilter> StereoFilter for Stereo<M, O> {
+    fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) {
+        self.mono.process_wide(left, right);
+        self.output.process_stereo(left, right);
+    }
+
+    fn latency(&self) -> usize {
+        self.mono.latency() + self.output.latency()
+    }
+
+    fn describe(&self) -> Node {
+        Node::with_children(
+            "Stereo",
+            vec![self.mono.describe(), chain_of([self.output.describe()])],
+        )
+    }
+}
+
+impl<M: Filter + Params, O: StereoFilter + Params> Params for Stereo<M, O> {
+    fn params(&self) -> Vec<ParamInfo> {
+        let mut out = self.mono.params();
+        // one both sides have is set on both at once, so it's listed once
+        let output = self.output.params();
+        out.extend(
+            output
+                .into_iter()
+                .filter(|p| !out.iter().any(|o| o.name == p.name))
+                .collect::<Vec<_>>(),
+        );
+        out
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        self.mono
+            .get_param(name)
+            .or_else(|| self.output.get_param(name))
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        let found = self.mono.set_param(name, value);
+        self.output.set_param(name, value) || found
+    }
+}
+
+/// Fades what it wraps over to what went in, delayed to line up, on its
+/// `dry` parameter, the same as a [`crate::filters::Rack`] does. Like the
+/// rack's, it stays out of snapshots.
+pub struct Dry<O: StereoFilter> {
+    pub inner: O,
+    /// 0 for the effects, 1 for only the dry signal
+    pub dry: f32,
+    mix: Smoothed,
+    lines: [DelayLine; 2],
+    bufs: [Vec<f32>; 2],
+}
+
+impl<O: StereoFilter> Dry<O> {
+    pub fn new(inner: O) -> Self {
+        Self {
+            inner,
+            dry: 0.,
+            mix: Smoothed::new(0.),
+            lines: [DelayLine::new(1), DelayLine::new(1)],
+            bufs: [Vec::new(), Vec::new()],
+        }
+    }
+}
+
+impl<O: StereoFilter> StereoFilter for Dry<O> {
+    fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) {
+        let [mut dry_left, mut dry_right] = std::mem::take(&mut self.bufs);
+        let latency = self.inner.latency();
+        for ((line, dry), samples) in self
+            .lines
+            .iter_mut()
+            .zip([&mut dry_left, &mut dry_right])
+            .zip([&*left, &*right])
+        {
+            dry.clear();
+            dry.extend_from_slice(samples);
+            if line.len() != latency + 1 {
+                line.set_len(latency + 1);
+            }
+            line.process(dry);
+        }
+
+        self.inner.process_stereo(left, right);
+
+        self.mix.set_over(self.dry, DRY_FADE);
+        if self.mix.value() > 0. || self.mix.is_ramping() {
+            for (((l, r), dl), dr) in left
+                .iter_mut()
+                .zip(right.iter_mut())
+                .zip(&dry_left)
+                .zip(&dry_right)
+            {
+                let mix = self.mix.next_value();
+                *l += (dl - *l) * mix;
+                *r += (dr - *r) * mix;
+            }
+        }
+        self.bufs = [dry_left, dry_right];
+    }
+
+    fn latency(&self) -> usize {
+        self.inner.latency()
+    }
+
+    fn describe(&self) -> Node {
+        self.inner.describe()
+    }
+}
+
+impl<O: StereoFilter + Params> Params for Dry<O> {
+    fn params(&self) -> Vec<ParamInfo> {
+        let mut out = vec![ParamInfo::new("dry", 0., 1.).not_random()];
+        out.extend(self.inner.params());
+        out
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        self.inner.get_param(name)
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        if name == "dry" {
+            self.dry = value.clamp(0., 1.);
+            return true;
+        }
+        self.inner.set_param(name, value)
+    }
+}
+
+/// Longest delay a [`Haas`] can put on either channel.
+pub const MAX_HAAS_MS: f32 = 30.;
+
+/// Precedence effect widener: the same signal on both sides, with one a few
+/// milliseconds late. Below about 30ms the ear hears one sound, pulled
+/// towards the earlier side and a lot wider than it was.
+pub struct Haas {
+    pub left_ms: f32,
+    pub right_ms: f32,
+
+    left: DelayLine,
+    right: DelayLine,
+}
+
+impl Haas {
+    pub fn new(left_ms: f32, right_ms: f32) -> Self {
+        let len = (MAX_HAAS_MS / 1000. * SAMPLING_FREQ as f32) as usize + 1;
+        let mut this = Self {
+            left_ms,
+            right_ms,
+            left: DelayLine::new(len),
+            right: DelayLine::new(len),
+        };
+        this.update();
+        this
+    }
+
+    /// Resizes the delays after changing `left_ms` or `right_ms`.
+    pub fn update(&mut self) {
+        let samples = |ms: f32| {
+            (ms.clamp(0., MAX_HAAS_MS) / 1000. * SAMPLING_FREQ as f32).round() as usize + 1
+        };
+        self.left.set_len(samples(self.left_ms));
+        self.right.set_len(samples(self.right_ms));
+    }
+}
+
+impl StereoFilter for Haas {
+    fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) {
+        self.left.process(left);
+        self.right.process(right);
+    }
+}
+
+impl Default for Haas {
+    fn default() -> Self {
+        Self::new(0., 15.)
+    }
+}
+
+impl Params for Haas {
+    fn params(&self) -> Vec<ParamInfo> {
+        vec![
+            ParamInfo::new("left", 0., MAX_HAAS_MS),
+            ParamInfo::new("right", 0., MAX_HAAS_MS),
+        ]
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        Some(match name {
+            "left" => self.left_ms,
+            "right" => self.right_ms,
+            _ => return None,
+        })
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name {
+            "left" => self.left_ms = value,
+            "right" => self.right_ms = value,
+            _ => return false,
+        }
+        self.update();
+        true
+    }
+}
+
+/// Constant power pan: `position` runs from -1 (hard left) to 1 (hard
+/// right), and moving it keeps the total power the same. Centred, both
+/// sides come through unchanged.
+pub struct Panner {
+    pub position: f32,
+
+    left: Smoothed,
+    right: Smoothed,
+}
+
+impl Panner {
+    pub fn new(position: f32) -> Self {
+        let (left, right) = Self::gains(position);
+        Self {
+            position,
+            left: Smoothed::new(left),
+            right: Smoothed::new(right),
+        }
+    }
+
+    fn gains(position: f32) -> (f32, f32) {
+        let angle = (position.clamp(-1., 1.) + 1.) * std::f32::consts::FRAC_PI_4;
+        let (sin, cos) = angle.sin_cos();
+        (
+            cos * std::f32::consts::SQRT_2,
+            sin * std::f32::consts::SQRT_2,
+        )
+    }
+}
+
+impl Default for Panner {
+    fn default() -> Self {
+        Self::new(0.)
+    }
+}
+
+impl StereoFilter for Panner {
+    fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) {
+        let (l, r) = Self::gains(self.position);
+        self.left.set(l);
+        self.right.set(r);
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            *l *= self.left.next_value();
+            *r *= self.right.next_value();
+        }
+    }
+}
+
+impl Params for Panner {
+    fn params(&self) -> Vec<ParamInfo> {
+        vec![ParamInfo::new("position", -1., 1.)]
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name {
+            "position" => Some(self.position),
+            _ => None,
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name {
+            "position" => self.position = value,
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Fold-down loss, in dB, above which a [`Width`] says it'll sound hollow in
+/// mono.
+const HOLLOW_DB: f32 = 3.;
+
+/// Mid/side width: 0 folds everything to mono, 1 leaves it alone, and above
+/// that the difference between the sides is turned up. There has to be
+/// some difference to begin with, from a [`Haas`] say. It keeps track of
+/// how well what comes out survives being folded down to mono, which the
+/// graph shows.
+pub struct Width {
+    pub width: f32,
+
+    smoothed: Smoothed,
+    /// [`fold_down_loss`] of the last few blocks with anything in them
+    loss: f32,
+}
+
+impl Width {
+    pub fn new(width: f32) -> Self {
+        Self {
+            width,
+            smoothed: Smoothed::new(width),
+            loss: 0.,
+        }
+    }
+
+    /// How much quieter the output has lately been folded down to mono, in
+    /// dB.
+    pub fn loss(&self) -> f32 {
+        self.loss
+    }
+}
+
+impl Default for Width {
+    fn default() -> Self {
+        Self::new(1.)
+    }
+}
+
+impl StereoFilter for Width {
+    fn process_stereo(&mut self, left: &mut [f32], right: &mut [f32]) {
+        self.smoothed.set(self.width.max(0.));
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            let mid = (*l + *r) / 2.;
+            let side = (*l - *r) / 2. * self.smoothed.next_value();
+            (*l, *r) = (mid + side, mid - side);
+        }
+        // silence says nothing either way
+        if left.iter().chain(right.iter()).any(|&s| s != 0.) {
+            self.loss += (fold_down_loss(left, right) - self.loss) / 8.;
+        }
+    }
+
+    fn describe(&self) -> Node {
+        let hollow = if self.loss > HOLLOW_DB {
+            ", hollow"
+        } else {
+            ""
+        };
+        Node::leaf(format!("Width ({:.1} dB down in mono{hollow})", self.loss))
+    }
+}
+
+impl Params for Width {
+    fn params(&self) -> Vec<ParamInfo> {
+        vec![ParamInfo::new("width", 0., 2.)]
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name {
+            "width" => Some(self.width),
+            _ => None,
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name {
+            "width" => self.width = value,
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// How much quieter, in dB, a stereo signal gets when folded down to mono
+/// as (L + R) / 2, compared to the two channels on their own. Widening by
+/// delay comb filters the fold-down, so anything much above a few dB is
+/// going to sound hollow on a mono speaker.
+pub fn fold_down_loss(left: &[f32], right: &[f32]) -> f32 {
+    let energy = |it: &mut dyn Iterator<Item = f32>| it.map(|s| s * s).sum::<f32>();
+    let stereo = (energy(&mut left.iter().copied()) + energy(&mut right.iter().copied())) / 2.;
+    let mono = energy(&mut left.iter().zip(right).map(|(l, r)| (l + r) / 2.));
+    if stereo <= 0. {
+        return 0.;
+    }
+    -10. * (mono.max(1e-12) / stereo).log10()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::TAU;
+
+    #[test]
+    fn test_haas() {
+        let mut haas = Haas::new(0., 10.);
+        let mut input = vec![0.; 1000];
+        input[0] = 1.;
+        let (mut left, mut right) = (input.clone(), input.clone());
+        haas.process_stereo(&mut left, &mut right);
+        assert_eq!(left[0], 1.);
+        assert_eq!(right[SAMPLING_FREQ / 100], 1.);
+
+        // a delay of half a period cancels in mono
+        let tone = |i: usize| (TAU * 50. * i as f32 / SAMPLING_FREQ as f32).sin();
+        let input: Vec<f32> = (0..SAMPLING_FREQ).map(tone).collect();
+        let (mut left, mut right) = (input.clone(), input.clone());
+        haas.process_stereo(&mut left, &mut right);
+        assert!(fold_down_loss(&left[1000..], &right[1000..]) > 20.);
+        assert!(fold_down_loss(&input, &input).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_panner_width() {
+        let run = |filter: &mut dyn StereoFilter, left: f32, right: f32| {
+            let (mut l, mut r) = ([left; 1000], [right; 1000]);
+            filter.process_stereo(&mut l, &mut r);
+            (l[999], r[999])
+        };
+        let close = |(a, b): (f32, f32), (c, d): (f32, f32)| {
+            assert!((a - c).abs() < 1e-4 && (b - d).abs() < 1e-4, "{a}, {b}");
+        };
+
+        let mut pan = Panner::default();
+        close(run(&mut pan, 1., 1.), (1., 1.));
+        pan.position = -1.;
+        close(run(&mut pan, 1., 1.), (2f32.sqrt(), 0.));
+        // the power stays put along the way
+        pan.position = 0.5;
+        let (l, r) = run(&mut pan, 1., 1.);
+        assert!((l * l + r * r - 2.).abs() < 1e-4);
+
+        let mut width = Width::new(0.);
+        close(run(&mut width, 1., 0.), (0.5, 0.5));
+        width.width = 2.;
+        close(run(&mut width, 1., 0.), (1.5, -0.5));
+
+        // the same on both sides folds down as it is, and opposites cancel
+        let mut width = Width::default();
+        run(&mut width, 1., 1.);
+        assert!(width.loss() < 1e-3);
+        assert!(!width.describe().name.contains("hollow"));
+        for _ in 0..50 {
+            run(&mut width, 1., -1.);
+        }
+        assert!(width.loss() > HOLLOW_DB);
+        assert!(width.describe().name.contains("hollow"));
+    }
+
+    /// Counts up from 1, one per sample.
+    struct Ramp(f32);
+
+    impl Filter for Ramp {
+        fn process(&mut self, samples: &mut [f32]) {
+            for s in samples.iter_mut() {
+                self.0 += 1.;
+                *s = self.0;
+            }
+        }
+    }
+
+    /// A one sample delay.
+    struct Late(DelayLine);
+
+    impl Filter for Late {
+        fn process(&mut self, samples: &mut [f32]) {
+            self.0.process(samples);
+        }
+    }
+
+    macro_rules! no_params {
+        ($($t:ty),*) => {$(
+            impl Params for $t {
+                fn params(&self) -> Vec<ParamInfo> {
+                    Vec::new()
+                }
+
+                fn get_param(&self, _name: &str) -> Option<f32> {
+                    None
+                }
+
+                fn set_param(&mut self, _name: &str, _value: f32) -> bool {
+                    false
+                }
+            }
+        )*};
+    }
+
+    no_params!(Ramp, Late);
+
+    #[test]
+    fn test_stereo_chain() {
+        let mut stereo = Stereo::new(Ramp(0.))
+            .chain(Named::new("haas", Haas::new(0., 0.)))
+            .chain(DualMono::new(
+                Late(DelayLine::new(2)),
+                Late(DelayLine::new(2)),
+            ));
+        assert!(stereo.set_param("haas.right", 1000. / SAMPLING_FREQ as f32));
+        assert_eq!(
+            stereo.get_param("haas.right"),
+            Some(1000. / SAMPLING_FREQ as f32)
+        );
+
+        let (mut left, mut right) = ([0.; 4], [0.; 4]);
+        stereo.process_stereo(&mut left, &mut right);
+        assert_eq!(left, [0., 1., 2., 3.]);
+        assert_eq!(right, [0., 0., 1., 2.]);
+        assert_eq!(stereo.describe().children[1].children.len(), 2);
+    }
+}
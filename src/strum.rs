@@ -0,0 +1,219 @@
+//! Spreads chords out in time like a strummed guitar. Note-ons that arrive
+//! close together are collected into a chord, which is then replayed one note
+//! at a time in pitch order.
+
+use std::time::Duration;
+
+use crate::clock::Stamp;
+use crate::filters::Rng;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StrumDirection {
+    /// Lowest note first.
+    Up,
+    /// Highest note first.
+    Down,
+    /// Up then down, switching every chord.
+    Alternate,
+}
+
+impl std::str::FromStr for StrumDirection {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        Ok(match value {
+            "up" => StrumDirection::Up,
+            "down" => StrumDirection::Down,
+            "alternate" => StrumDirection::Alternate,
+            _ => return Err(format!("unknown strum direction {value:?}")),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StrumConfig {
+    /// Time from the first to the last note of a chord.
+    pub time: Duration,
+    pub direction: StrumDirection,
+    /// Random timing jitter, as a fraction of the gap between notes.
+    pub humanize: f32,
+    /// Notes arriving within this long of the first one form a chord.
+    pub window: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StrumEvent {
+    On { note: u8, velocity: u8 },
+    Off { note: u8 },
+}
+
+struct Pending {
+    at: Stamp,
+    note: u8,
+    velocity: u8,
+    /// released before it even got played
+    released: bool,
+}
+
+pub struct Strummer {
+    config: StrumConfig,
+    chord_started: Option<Stamp>,
+    /// notes of the chord currently being collected; `at` is unused
+    chord: Vec<Pending>,
+    scheduled: Vec<Pending>,
+    next_up: bool,
+    rng: Rng,
+}
+
+impl Strummer {
+    pub fn new(config: StrumConfig) -> Self {
+        Self {
+            config,
+            chord_started: None,
+            chord: Vec::new(),
+            scheduled: Vec::new(),
+            next_up: true,
+            rng: Rng::default(),
+        }
+    }
+
+    pub fn note_on(&mut self, now: Stamp, note: u8, velocity: u8) {
+        self.chord_started.get_or_insert(now);
+        self.chord.push(Pending {
+            at: now,
+            note,
+            velocity,
+            released: false,
+        });
+    }
+
+    /// Returns true if the note hasn't been played yet, in which case it
+    /// will be released right after it is.
+    pub fn note_off(&mut self, note: u8) -> bool {
+        let pending = self
+            .chord
+            .iter_mut()
+            .chain(self.scheduled.iter_mut())
+            .find(|p| p.note == note && !p.released);
+        match pending {
+            Some(p) => {
+                p.released = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// When [`Strummer::poll`] next has something to do.
+    pub fn next_deadline(&self) -> Option<Stamp> {
+        let chord_done = self.chord_started.map(|t| t + self.config.window);
+        self.scheduled.iter().map(|p| p.at).chain(chord_done).min()
+    }
+
+    fn schedule_chord(&mut self, start: Stamp) {
+        let mut chord = std::mem::take(&mut self.chord);
+        chord.sort_by_key(|p| p.note);
+        let up = match self.config.direction {
+            StrumDirection::Up => true,
+            StrumDirection::Down => false,
+            StrumDirection::Alternate => {
+                self.next_up = !self.next_up;
+                !self.next_up
+            }
+        };
+        if !up {
+            chord.reverse();
+        }
+
+        let step = self.config.time / (chord.len().max(2) - 1) as u32;
+        for (i, mut p) in chord.into_iter().enumerate() {
+            let jitter = self.rng.next_f32() * self.config.humanize * step.as_secs_f32() / 2.;
+            let offset = step * i as u32;
+            let offset = if jitter >= 0. {
+                offset + Duration::from_secs_f32(jitter)
+            } else {
+                offset.saturating_sub(Duration::from_secs_f32(-jitter))
+            };
+            p.at = start + offset;
+            self.scheduled.push(p);
+        }
+    }
+
+    /// Collects the events that are due at `now` into `out`.
+    pub fn poll(&mut self, now: Stamp, out: &mut Vec<StrumEvent>) {
+        if let Some(started) = self.chord_started {
+            if started + self.config.window <= now {
+                self.chord_started = None;
+                self.schedule_chord(now);
+            }
+        }
+
+        self.scheduled.sort_by_key(|p| p.at);
+        let due = self.scheduled.partition_point(|p| p.at <= now);
+        for p in self.scheduled.drain(..due) {
+            out.push(StrumEvent::On {
+                note: p.note,
+                velocity: p.velocity,
+            });
+            if p.released {
+                out.push(StrumEvent::Off { note: p.note });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strum_order() {
+        let mut strummer = Strummer::new(StrumConfig {
+            time: Duration::from_millis(100),
+            direction: StrumDirection::Down,
+            humanize: 0.,
+            window: Duration::from_millis(10),
+        });
+        let t0 = Stamp::default();
+        let ms = |n| t0 + Duration::from_millis(n);
+        strummer.note_on(t0, 60, 100);
+        strummer.note_on(ms(2), 67, 100);
+        strummer.note_on(ms(4), 64, 100);
+        assert!(strummer.note_off(60));
+
+        let mut out = Vec::new();
+        strummer.poll(ms(5), &mut out);
+        assert!(out.is_empty());
+        assert_eq!(strummer.next_deadline(), Some(ms(10)));
+
+        strummer.poll(ms(10), &mut out);
+        assert_eq!(
+            out,
+            [StrumEvent::On {
+                note: 67,
+                velocity: 100
+            }]
+        );
+        out.clear();
+        strummer.poll(ms(60), &mut out);
+        assert_eq!(
+            out,
+            [StrumEvent::On {
+                note: 64,
+                velocity: 100
+            }]
+        );
+        out.clear();
+        strummer.poll(ms(110), &mut out);
+        assert_eq!(
+            out,
+            [
+                StrumEvent::On {
+                    note: 60,
+                    velocity: 100
+                },
+                StrumEvent::Off { note: 60 }
+            ]
+        );
+        assert_eq!(strummer.next_deadline(), None);
+    }
+}
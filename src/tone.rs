@@ -0,0 +1,161 @@
+//! The bass, mid and treble knobs every patch ends with, for changing how
+//! the whole thing sounds without going into the patch itself.
+
+use crate::filters::{Biquad, Filter};
+use crate::params::{ParamInfo, Params, Smoothed};
+
+const BASS: f32 = 120.;
+const MID: f32 = 800.;
+const TREBLE: f32 = 4000.;
+/// How far each band goes either way, in dB.
+const RANGE: f32 = 15.;
+/// How often the bands' coefficients follow a gain on its way somewhere,
+/// in samples.
+const STEP: usize = 32;
+
+/// Three bands of boost or cut, all flat to start with.
+pub struct ToneStack {
+    /// dB
+    pub bass: f32,
+    /// dB
+    pub mid: f32,
+    /// dB
+    pub treble: f32,
+
+    bands: [Biquad; 3],
+    gains: [Smoothed; 3],
+}
+
+impl Default for ToneStack {
+    fn default() -> Self {
+        let q = std::f32::consts::FRAC_1_SQRT_2;
+        Self {
+            bass: 0.,
+            mid: 0.,
+            treble: 0.,
+            bands: [
+                Biquad::low_shelf(BASS, q, 0.),
+                Biquad::peaking(MID, 0.7, 0.),
+                Biquad::high_shelf(TREBLE, q, 0.),
+            ],
+            gains: [Smoothed::new(0.); 3],
+        }
+    }
+}
+
+impl Filter for ToneStack {
+    fn process(&mut self, samples: &mut [f32]) {
+        let targets = [self.bass, self.mid, self.treble];
+        for ((band, gain), target) in self.bands.iter_mut().zip(&mut self.gains).zip(targets) {
+            gain.set(target.clamp(-RANGE, RANGE));
+            // a flat band passes everything through as it is. Its state
+            // would stay at nothing if it ran, so starting again from
+            // nothing carries on where it left off
+            if !gain.is_ramping() && gain.value() == 0. {
+                band.clear();
+                continue;
+            }
+            // a step at a time is smooth enough, and saves working out
+            // the coefficients every sample
+            for chunk in samples.chunks_mut(STEP) {
+                let mut next = gain.value();
+                for _ in 0..chunk.len() {
+                    next = gain.next_value();
+                }
+                if band.gain != next {
+                    band.gain = next;
+                    band.update();
+                }
+                band.process(chunk);
+            }
+        }
+    }
+}
+
+impl Params for ToneStack {
+    fn params(&self) -> Vec<ParamInfo> {
+        ["bass", "mid", "treble"]
+            .map(|name| ParamInfo::new(name, -RANGE, RANGE).random_range(-6., 6.))
+            .to_vec()
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        Some(match name {
+            "bass" => self.bass,
+            "mid" => self.mid,
+            "treble" => self.treble,
+            _ => return None,
+        })
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name {
+            "bass" => self.bass = value,
+            "mid" => self.mid = value,
+            "treble" => self.treble = value,
+            _ => return false,
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters::SAMPLING_FREQ;
+    use crate::params::RAMP_SAMPLES;
+
+    fn level(tone: &mut ToneStack, freq: f32) -> f32 {
+        let mut samples: Vec<f32> = (0..SAMPLING_FREQ / 2)
+            .map(|i| (std::f32::consts::TAU * freq * i as f32 / SAMPLING_FREQ as f32).sin())
+            .collect();
+        tone.process(&mut samples);
+        // past where the filters settle
+        let tail = &samples[SAMPLING_FREQ / 4..];
+        let rms = (tail.iter().map(|s| s * s).sum::<f32>() / tail.len() as f32).sqrt();
+        20. * (rms * std::f32::consts::SQRT_2).log10()
+    }
+
+    #[test]
+    fn test_tone_stack() {
+        let mut tone = ToneStack::default();
+        assert!(level(&mut tone, 50.).abs() < 0.1);
+
+        assert!(tone.set_param("bass", 12.));
+        assert!(!tone.set_param("presence", 1.));
+        assert!((level(&mut tone, 50.) - 12.).abs() < 0.5);
+        let mut tone = ToneStack {
+            treble: -12.,
+            ..ToneStack::default()
+        };
+        assert!((level(&mut tone, 12000.) + 12.).abs() < 1.);
+        assert!(level(&mut tone, 100.).abs() < 0.5);
+        assert_eq!(tone.get_param("treble"), Some(-12.));
+    }
+
+    #[test]
+    fn test_tone_stack_glides() {
+        let mut tone = ToneStack::default();
+        let mut samples = vec![1.; 256];
+        tone.process(&mut samples);
+        assert_eq!(samples, [1.; 256]);
+
+        // a turned knob gets there over a few ms instead of all at once
+        tone.set_param("bass", 12.);
+        tone.process(&mut samples[..STEP]);
+        assert!(tone.bands[0].gain > 0. && tone.bands[0].gain < 1.);
+        for _ in 0..RAMP_SAMPLES as usize / 256 + 1 {
+            tone.process(&mut samples);
+        }
+        assert_eq!(tone.bands[0].gain, 12.);
+
+        // and once it's flat again, it's out of the way entirely
+        tone.set_param("bass", 0.);
+        for _ in 0..SAMPLING_FREQ / 256 {
+            tone.process(&mut samples);
+        }
+        let mut samples = vec![1.; 256];
+        tone.process(&mut samples);
+        assert_eq!(samples, [1.; 256]);
+    }
+}
@@ -0,0 +1,461 @@
+//! Tunings other than twelve-tone equal temperament, from Scala scale
+//! (.scl) and keyboard mapping (.kbm) files, and from MIDI Tuning Standard
+//! SysEx messages while playing.
+
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::note::{Pitch, A4};
+
+/// The lines of a Scala file that aren't comments.
+fn lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines().filter(|line| !line.starts_with('!'))
+}
+
+/// A scale, as the pitches of its degrees above the first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scale {
+    pub description: String,
+    /// Cents above the first degree of each of the others, ending with the
+    /// period the scale repeats at, usually an octave.
+    pub cents: Vec<f64>,
+}
+
+/// Reads a Scala .scl file: a description, the number of notes, then each
+/// note as cents if it has a decimal point and a ratio like `3/2` or `2`
+/// otherwise.
+impl FromStr for Scale {
+    type Err = String;
+    fn from_str(text: &str) -> Result<Self, String> {
+        let mut lines = lines(text);
+        let description = lines.next().ok_or("empty scale")?.trim().to_string();
+        let count = lines
+            .next()
+            .and_then(|line| line.split_whitespace().next()?.parse::<usize>().ok())
+            .ok_or("expected the number of notes after the description")?;
+        let cents = lines
+            .take(count)
+            .map(|line| {
+                let value = line.split_whitespace().next().unwrap_or("");
+                let err = || format!("bad pitch {value:?}");
+                let cents = if value.contains('.') {
+                    value.parse::<f64>().map_err(|_| err())?
+                } else {
+                    let (num, den) = value.split_once('/').unwrap_or((value, "1"));
+                    let ratio = num.parse::<f64>().map_err(|_| err())?
+                        / den.parse::<f64>().map_err(|_| err())?;
+                    if !(ratio.is_finite() && ratio > 0.) {
+                        return Err(err());
+                    }
+                    1200. * ratio.log2()
+                };
+                Ok(cents)
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        if cents.len() != count {
+            return Err(format!("expected {count} notes, found {}", cents.len()));
+        }
+        if count == 0 {
+            return Err("a scale needs at least one note".to_string());
+        }
+        Ok(Scale { description, cents })
+    }
+}
+
+impl Scale {
+    /// Cents above the first degree of any degree, counting on through the
+    /// periods either way.
+    fn degree_cents(&self, degree: i32) -> f64 {
+        let len = self.cents.len() as i32;
+        let period = self.cents[self.cents.len() - 1];
+        let (periods, within) = (degree.div_euclid(len), degree.rem_euclid(len));
+        let cents = if within == 0 {
+            0.
+        } else {
+            self.cents[within as usize - 1]
+        };
+        periods as f64 * period + cents
+    }
+}
+
+/// Which keys play which degrees of a scale, and what one of them is tuned
+/// to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keymap {
+    pub first: u8,
+    pub last: u8,
+    /// The key that plays the first degree.
+    pub middle: u8,
+    /// The key tuned to `freq`.
+    pub reference: u8,
+    pub freq: f64,
+    /// The degree the mapping repeats at. Ignored for a linear mapping.
+    pub octave_degree: i32,
+    /// The degree each key plays from `middle` on, repeating, with `None`
+    /// for keys that don't play. Empty maps every key to the next degree.
+    pub mapping: Vec<Option<i32>>,
+}
+
+/// Middle C plays the first degree and is tuned as it is in equal
+/// temperament, like Scala does without a keyboard mapping.
+impl Default for Keymap {
+    fn default() -> Self {
+        Self {
+            first: 0,
+            last: 127,
+            middle: 60,
+            reference: 60,
+            freq: Pitch(60.).freq() as f64,
+            octave_degree: 0,
+            mapping: Vec::new(),
+        }
+    }
+}
+
+/// Reads a Scala .kbm file: the size of the mapping, the first and last
+/// keys to tune, the middle key, the reference key and its frequency, the
+/// degree of the formal octave, then the degree for each key in the
+/// mapping, or `x` for keys that don't play.
+impl FromStr for Keymap {
+    type Err = String;
+    fn from_str(text: &str) -> Result<Self, String> {
+        let mut values = lines(text).map(|line| line.split_whitespace().next().unwrap_or(""));
+        let mut next = |what: &str| {
+            values
+                .next()
+                .ok_or_else(|| format!("expected {what}, found the end"))
+        };
+        fn parse<T: FromStr>(value: &str, what: &str) -> Result<T, String> {
+            value.parse().map_err(|_| format!("bad {what} {value:?}"))
+        }
+        let key = |value: &str, what: &str| {
+            parse::<u8>(value, what).and_then(|key| {
+                (key < 128)
+                    .then_some(key)
+                    .ok_or_else(|| format!("{what} {key} isn't a MIDI note"))
+            })
+        };
+        let size: usize = parse(next("the size of the mapping")?, "mapping size")?;
+        let first = key(next("the first key")?, "first key")?;
+        let last = key(next("the last key")?, "last key")?;
+        let middle = key(next("the middle key")?, "middle key")?;
+        let reference = key(next("the reference key")?, "reference key")?;
+        let freq: f64 = parse(next("the reference frequency")?, "frequency")?;
+        if !(freq.is_finite() && freq > 0.) {
+            return Err(format!("bad frequency {freq}"));
+        }
+        let octave_degree = parse(next("the octave degree")?, "octave degree")?;
+        let mapping = (0..size)
+            .map(|i| match next(&format!("{size} keys in the mapping"))? {
+                "x" | "X" => Ok(None),
+                degree => parse(degree, &format!("degree for key {i}")).map(Some),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Keymap {
+            first,
+            last,
+            middle,
+            reference,
+            freq,
+            octave_degree,
+            mapping,
+        })
+    }
+}
+
+impl Keymap {
+    /// The degree `key` plays, if it plays at all.
+    fn degree(&self, key: u8) -> Option<i32> {
+        let offset = key as i32 - self.middle as i32;
+        if self.mapping.is_empty() {
+            return Some(offset);
+        }
+        let len = self.mapping.len() as i32;
+        let degree = self.mapping[offset.rem_euclid(len) as usize]?;
+        Some(offset.div_euclid(len) * self.octave_degree + degree)
+    }
+}
+
+/// The frequency of every MIDI note.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tuning {
+    freqs: Vec<Option<f32>>,
+    /// what every note is multiplied by, for `--a4` and `--transpose`
+    shift: f32,
+}
+
+/// Twelve-tone equal temperament at A440.
+impl Default for Tuning {
+    fn default() -> Self {
+        Self {
+            freqs: (0..128)
+                .map(|note| Some(Pitch(note as f32).freq()))
+                .collect(),
+            shift: 1.,
+        }
+    }
+}
+
+impl Tuning {
+    pub fn new(scale: &Scale, keymap: &Keymap) -> Result<Self, String> {
+        let reference = keymap
+            .degree(keymap.reference)
+            .ok_or("the reference key isn't mapped to anything")?;
+        let reference = scale.degree_cents(reference);
+        let freqs = (0..128)
+            .map(|key| {
+                if key < keymap.first || key > keymap.last {
+                    return None;
+                }
+                let cents = scale.degree_cents(keymap.degree(key)?) - reference;
+                Some((keymap.freq * 2f64.powf(cents / 1200.)) as f32)
+            })
+            .collect();
+        Ok(Self { freqs, shift: 1. })
+    }
+
+    /// Loads a scale, and the keyboard mapping for it if there is one.
+    pub fn load(scale: &Path, keymap: Option<&Path>) -> Result<Self, String> {
+        let read =
+            |path: &Path| fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()));
+        let scale: Scale = read(scale)?
+            .parse()
+            .map_err(|e| format!("{}: {e}", scale.display()))?;
+        let keymap = match keymap {
+            Some(path) => read(path)?
+                .parse()
+                .map_err(|e| format!("{}: {e}", path.display()))?,
+            None => Keymap::default(),
+        };
+        Tuning::new(&scale, &keymap)
+    }
+
+    /// Moves every note by as much as A4 moves from 440Hz to `a4`, then
+    /// by `transpose` semitones, which needn't be whole ones.
+    pub fn set_reference(&mut self, a4: f32, transpose: f32) -> Result<(), String> {
+        if !(a4.is_finite() && a4 > 0.) {
+            return Err(format!("A4 has to be above 0Hz, not {a4}"));
+        }
+        if !transpose.is_finite() {
+            return Err(format!("can't transpose by {transpose} semitones"));
+        }
+        self.shift = a4 / A4 * 2f32.powf(transpose / 12.);
+        Ok(())
+    }
+
+    /// Retunes the notes a MIDI Tuning Standard message changed.
+    pub fn apply(&mut self, retuning: &Retuning) {
+        for &(note, pitch) in &retuning.0 {
+            if let Some(freq) = self.freqs.get_mut(note as usize) {
+                *freq = Some(Pitch(pitch).freq());
+            }
+        }
+    }
+
+    /// What `note` is tuned to, or `None` if it doesn't play.
+    pub fn freq(&self, note: u8) -> Option<f32> {
+        Some((*self.freqs.get(note as usize)?)? * self.shift)
+    }
+
+    /// Moves a frequency from equal temperament into this tuning, keeping
+    /// how far off the nearest note it was, for the computer keyboard and
+    /// anything else that plays by frequency.
+    pub fn retune(&self, freq: f32) -> Option<f32> {
+        let (note, cents) = Pitch::from_freq(freq).nearest();
+        let tuned = self.freq(note.clamp(0, 127) as u8)?;
+        Some(tuned * 2f32.powf(cents / 1200.))
+    }
+}
+
+/// Notes retuned by a MIDI Tuning Standard message, each to a pitch as a
+/// fractional MIDI note.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Retuning(pub Vec<(u8, f32)>);
+
+/// The three bytes MTS gives a pitch in: the semitone, then 14 bits of the
+/// way to the next.
+const NO_CHANGE: [u8; 3] = [0x7f; 3];
+
+fn mts_pitch(data: &[u8]) -> Option<f32> {
+    match *data {
+        [0x7f, 0x7f, 0x7f] => None,
+        [semitone, msb, lsb] => {
+            Some(semitone as f32 + ((msb as u16) << 7 | lsb as u16) as f32 / 16384.)
+        }
+        _ => None,
+    }
+}
+
+fn mts_bytes(pitch: f32) -> [u8; 3] {
+    let pitch = pitch.clamp(0., 127.);
+    let semitone = pitch.floor();
+    let fraction = (((pitch - semitone) * 16384.).round() as u16).min(16383);
+    [
+        semitone as u8,
+        (fraction >> 7) as u8,
+        (fraction & 0x7f) as u8,
+    ]
+}
+
+impl Retuning {
+    /// Reads a MIDI Tuning Standard SysEx message, from the `F0` on: a bulk
+    /// dump, single note changes, or an octave tuning, realtime or not.
+    /// Which device and tuning program it's for are ignored, so it always
+    /// changes what's playing.
+    pub fn from_sysex(msg: &[u8]) -> Option<Self> {
+        let body = msg.strip_prefix(&[0xf0])?;
+        let body = body.strip_suffix(&[0xf7]).unwrap_or(body);
+        let (&[realtime, _device, 0x08, format], rest) = body.split_first_chunk()? else {
+            return None;
+        };
+        if realtime != 0x7e && realtime != 0x7f {
+            return None;
+        }
+        // the bank and program, and for dumps the name, come first
+        let bulk = |skip: usize| {
+            rest.get(skip..skip + 128 * 3).map(|data| {
+                data.chunks_exact(3)
+                    .enumerate()
+                    .filter_map(|(note, data)| Some((note as u8, mts_pitch(data)?)))
+                    .collect()
+            })
+        };
+        let single = |skip: usize| {
+            let count = *rest.get(skip)? as usize;
+            rest.get(skip + 1..skip + 1 + 4 * count).map(|data| {
+                data.chunks_exact(4)
+                    .filter_map(|data| Some((data[0].min(127), mts_pitch(&data[1..])?)))
+                    .collect()
+            })
+        };
+        // the cents each pitch class is off equal temperament, after three
+        // bytes saying which channels it's for
+        let octave = |cents: Vec<f32>| {
+            (0..128u8)
+                .map(|note| (note, note as f32 + cents[note as usize % 12] / 100.))
+                .collect()
+        };
+        let notes = match format {
+            0x01 => bulk(17)?,
+            0x04 => bulk(18)?,
+            0x02 => single(1)?,
+            0x07 => single(2)?,
+            0x08 => octave(rest.get(3..15)?.iter().map(|&c| c as f32 - 64.).collect()),
+            0x09 => octave(
+                rest.get(3..27)?
+                    .chunks_exact(2)
+                    .map(|c| ((c[0] as u16) << 7 | c[1] as u16) as f32 / 8192. * 100. - 100.)
+                    .collect(),
+            ),
+            _ => return None,
+        };
+        Some(Retuning(notes))
+    }
+
+    /// Writes it out as a bulk dump to tuning program 0, which
+    /// [`Retuning::from_sysex`] reads back.
+    pub fn to_sysex(&self) -> Vec<u8> {
+        let mut pitches = [NO_CHANGE; 128];
+        for &(note, pitch) in &self.0 {
+            pitches[note as usize & 127] = mts_bytes(pitch);
+        }
+        let mut msg = vec![0xf0, 0x7e, 0x7f, 0x08, 0x01, 0];
+        msg.extend(b"synthtoy        ");
+        msg.extend(pitches.iter().flatten());
+        let checksum = msg[1..].iter().fold(0, |sum, b| sum ^ b) & 0x7f;
+        msg.extend([checksum, 0xf7]);
+        msg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tuning() {
+        let just: Scale = "! just.scl\n\
+            ! a comment\n\
+            Just major\n\
+            7\n\
+            9/8\n5/4\n4/3\n3/2\n5/3\n15/8\n2\n"
+            .parse()
+            .unwrap();
+        assert_eq!(just.cents.len(), 7);
+
+        // the white keys play the scale, the black ones nothing, and A4 is
+        // 440Hz
+        let keymap: Keymap = "12\n0\n127\n60\n69\n440.0\n7\n\
+            0\nx\n1\nx\n2\n3\nx\n4\nx\n5\nx\n6\n"
+            .parse()
+            .unwrap();
+        let tuning = Tuning::new(&just, &keymap).unwrap();
+        let c4 = 440. * 3. / 5.;
+        assert!((tuning.freq(60).unwrap() - c4).abs() < 0.001);
+        assert!((tuning.freq(67).unwrap() - c4 * 1.5).abs() < 0.001);
+        assert!((tuning.freq(72).unwrap() - c4 * 2.).abs() < 0.001);
+        assert_eq!(tuning.freq(61), None);
+        assert!((tuning.freq(57).unwrap() - c4 * 5. / 6.).abs() < 0.001);
+
+        // cents, and no keymap
+        let edo: Scale = "5 equal\n5\n240.\n480.\n720.\n960.\n1200.\n"
+            .parse()
+            .unwrap();
+        let tuning = Tuning::new(&edo, &Keymap::default()).unwrap();
+        let ratio = tuning.freq(61).unwrap() / tuning.freq(60).unwrap();
+        assert!((ratio - 2f32.powf(0.2)).abs() < 0.0001);
+        assert!((tuning.freq(60).unwrap() - 261.6256).abs() < 0.001);
+
+        assert!("bad\n2\n3/2\n".parse::<Scale>().is_err());
+        assert!("bad\n1\n-3/2\n".parse::<Scale>().is_err());
+
+        let mut equal = Tuning::default();
+        assert!((equal.retune(450.).unwrap() - 450.).abs() < 0.01);
+        equal.set_reference(432., 0.5).unwrap();
+        let quarter_up = 2f32.powf(0.5 / 12.);
+        assert!((equal.freq(69).unwrap() - 432. * quarter_up).abs() < 0.01);
+        assert!((equal.retune(440.).unwrap() - 432. * quarter_up).abs() < 0.01);
+        assert!(equal.set_reference(0., 0.).is_err());
+    }
+
+    #[test]
+    fn test_mts() {
+        // realtime single note changes: A4 a quarter tone up, and C4 to
+        // whatever it was
+        let msg = [
+            0xf0, 0x7f, 0x7f, 0x08, 0x02, 0, 2, 69, 69, 0x40, 0, 60, 0x7f, 0x7f, 0x7f, 0xf7,
+        ];
+        let event = crate::midi::parse_midi(Default::default(), &msg).unwrap();
+        let crate::midi::MidiEventInner::Tuning(retuning) = &event.inner else {
+            panic!("{event:?}")
+        };
+        assert_eq!(retuning.0, [(69, 69.5)]);
+        let mut tuning = Tuning::default();
+        tuning.apply(retuning);
+        assert!((tuning.freq(69).unwrap() - Pitch(69.5).freq()).abs() < 0.001);
+        assert!((tuning.freq(60).unwrap() - Pitch(60.).freq()).abs() < 0.001);
+
+        // written out as a bulk dump and read back
+        assert_eq!(
+            Retuning::from_sysex(&retuning.to_sysex()),
+            Some(retuning.clone())
+        );
+        assert_eq!(event.to_bytes().len(), 408);
+
+        // an octave tuning with E 14 cents flat
+        let mut msg = vec![0xf0, 0x7e, 0x7f, 0x08, 0x08, 0x03, 0x7f, 0x7f];
+        msg.extend([64; 12]);
+        msg[8 + 4] = 64 - 14;
+        msg.push(0xf7);
+        let retuning = Retuning::from_sysex(&msg).unwrap();
+        assert_eq!(retuning.0.len(), 128);
+        assert_eq!(retuning.0[64], (64, 63.86));
+        assert_eq!(retuning.0[65], (65, 65.));
+
+        assert_eq!(
+            Retuning::from_sysex(&[0xf0, 0x7e, 0x7f, 0x06, 0x01, 0xf7]),
+            None
+        );
+    }
+}
@@ -0,0 +1,910 @@
+use crate::filters::{Bus, Filter};
+use crate::lfo::{Lfo, LfoSync};
+use crate::params::{ParamInfo, Params};
+use crate::snapshot::Node;
+use crate::snoop::Snoop;
+use crate::stereo::{Panner, StereoFilter};
+
+/// A single playable voice, which the [`VoiceManager`] allocates notes to.
+pub trait Voice: Filter {
+    /// `velocity` is in 0..=1.
+    fn note_on(&mut self, freq: f32, velocity: f32);
+    fn note_off(&mut self);
+    fn set_bend(&mut self, semitones: f32);
+    /// Whether the voice is still making sound.
+    fn is_active(&self) -> bool;
+    /// Per-note pressure and timbre from an MPE controller, both in 0..=1,
+    /// with the timbre centered on 0.5. Voices with nothing to do with them
+    /// can leave them be.
+    fn set_expression(&mut self, _pressure: f32, _timbre: f32) {}
+}
+
+impl<V: Voice + ?Sized> Voice for Box<V> {
+    fn note_on(&mut self, freq: f32, velocity: f32) {
+        (**self).note_on(freq, velocity);
+    }
+
+    fn note_off(&mut self) {
+        (**self).note_off();
+    }
+
+    fn set_bend(&mut self, semitones: f32) {
+        (**self).set_bend(semitones);
+    }
+
+    fn is_active(&self) -> bool {
+        (**self).is_active()
+    }
+
+    fn set_expression(&mut self, pressure: f32, timbre: f32) {
+        (**self).set_expression(pressure, timbre);
+    }
+}
+
+/// A voice whose type is only known at runtime.
+pub trait DynVoice: Voice + Params {}
+
+impl<V: Voice + Params> DynVoice for V {}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Slot {
+    /// The MIDI note holding the voice down, if any. Console notes don't
+    /// have one and just ring out.
+    note: Option<u8>,
+    /// Allocation order, for stealing the oldest voice.
+    started: u64,
+    /// The note's key is up, but the latch is holding it.
+    latched: bool,
+    /// The note's key is up, but the sustain pedal is holding it.
+    sustained: bool,
+    /// The MIDI note the voice was last started with, which it may still be
+    /// ringing with after `note` is cleared.
+    played: Option<u8>,
+    /// The MPE member channel the note came in on, whose expression it
+    /// follows even after it's released.
+    channel: Option<u8>,
+    /// Polyphonic aftertouch on the note's key, 0..=1.
+    touch: f32,
+    /// The vibrato's share of the bend, in semitones.
+    vibrato: f32,
+}
+
+/// What an MPE member channel can change about the note playing on it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PerNote {
+    /// In semitones, on top of the bend for every note.
+    Bend(f32),
+    /// 0..=1
+    Pressure(f32),
+    /// 0..=1, centered on 0.5
+    Timbre(f32),
+}
+
+/// What pressing harder on a key that's down (polyphonic aftertouch) does
+/// to the voice playing it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyPressure {
+    /// Adds to its pressure, the way an MPE controller's would.
+    #[default]
+    Pressure,
+    /// Brightens it, taking its timbre from the middle up to the top.
+    Timbre,
+    Off,
+}
+
+impl KeyPressure {
+    pub const ALL: [KeyPressure; 3] =
+        [KeyPressure::Pressure, KeyPressure::Timbre, KeyPressure::Off];
+
+    pub fn index(self) -> usize {
+        Self::ALL.iter().position(|&k| k == self).unwrap_or(0)
+    }
+}
+
+/// `pressure`, `timbre` or `off`.
+impl std::str::FromStr for KeyPressure {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        Ok(match value {
+            "pressure" => KeyPressure::Pressure,
+            "timbre" => KeyPressure::Timbre,
+            "off" => KeyPressure::Off,
+            _ => return Err(format!("unknown key pressure target {value:?}")),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Channel {
+    bend: f32,
+    pressure: f32,
+    timbre: f32,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self {
+            bend: 0.,
+            pressure: 0.,
+            timbre: 0.5,
+        }
+    }
+}
+
+/// How often the vibrato's LFO is read, in samples, with the bend gliding
+/// from one reading to the next.
+const VIBRATO_PERIOD: usize = 32;
+/// Vibrato rate to start with, in Hz.
+const VIBRATO_RATE: f32 = 5.5;
+
+/// Polyphony: spreads notes over a fixed set of voices and mixes them.
+///
+/// With the latch on, notes keep sounding after their keys come up, until
+/// they're played again, the latch is turned off, or they're all released.
+/// The latch is also the "latch" parameter, so it can go on a CC.
+///
+/// The sustain pedal holds notes the same way, but only while it's down,
+/// and lifting it lets go of them. It's the "sustain" parameter, on CC64
+/// unless mapped somewhere else.
+///
+/// With "reuse" on, a note played again while it's still ringing goes back
+/// to the voice it's ringing on, the way a real string gets plucked again,
+/// rather than starting a fresh one on top of it.
+///
+/// Each voice has a vibrato of its own, starting with its note, as deep as
+/// "vibrato.depth" says, which the mod wheel (CC1) is on to start with.
+///
+/// Notes from MPE member channels are kept apart from the rest, and each
+/// follows the bend, pressure and timbre of the channel it came in on.
+/// Other notes can still be played into with polyphonic aftertouch, which
+/// goes where the "key_pressure" parameter says.
+///
+/// Each voice also has a place in the stereo field of its own, from
+/// "voice1.pan" on, -1 for the left and 1 for the right, panned with the
+/// same constant power law as [`Panner`].
+///
+/// For seeing what the voices are up to one at a time, "solo" leaves out
+/// all but the voice it's set to, counting from 1, and with taps each voice
+/// gets a [`Snoop`] of its own, "voice1" and so on.
+///
+/// Voices playing notes at or below the key note also go into the
+/// [`Bus`] it's been given, for a sidechain to duck the rest under.
+pub struct VoiceManager<V: Voice> {
+    pub voices: Vec<V>,
+    latch: bool,
+    sustain: bool,
+    reuse: bool,
+    key_pressure: KeyPressure,
+    /// in semitones each way
+    vibrato_depth: f32,
+    vibrato: Vec<Lfo>,
+    /// the only voice to hear, if it's only one
+    solo: Option<usize>,
+    /// one for each voice, if they're tapped
+    taps: Vec<Snoop>,
+    /// where voices playing this note or lower go, besides the mix
+    key: Option<(u8, Bus)>,
+    /// one for each voice
+    pans: Vec<Panner>,
+    slots: Vec<Slot>,
+    counter: u64,
+    scratch: Vec<f32>,
+    /// the right side of each voice, played wide, and of the mix, played in
+    /// mono
+    scratch_right: Vec<f32>,
+    mix_right: Vec<f32>,
+    /// the bend for every note
+    bend: f32,
+    channels: [Channel; 16],
+}
+
+impl<V: Voice> VoiceManager<V> {
+    pub fn new(voices: Vec<V>) -> Self {
+        assert!(!voices.is_empty(), "need at least one voice");
+        Self {
+            slots: vec![Slot::default(); voices.len()],
+            vibrato: (0..voices.len())
+                .map(|_| Lfo::new(VIBRATO_RATE).with_sync(LfoSync::Retrigger { phase: 0. }))
+                .collect(),
+            pans: (0..voices.len()).map(|_| Panner::default()).collect(),
+            voices,
+            latch: false,
+            sustain: false,
+            reuse: false,
+            key_pressure: KeyPressure::default(),
+            vibrato_depth: 0.,
+            solo: None,
+            taps: Vec::new(),
+            key: None,
+            counter: 0,
+            scratch: Vec::new(),
+            scratch_right: Vec::new(),
+            mix_right: Vec::new(),
+            bend: 0.,
+            channels: [Channel::default(); 16],
+        }
+    }
+
+    /// Picks a voice for a new note: a silent one if there is one, then the
+    /// oldest released one, then the oldest overall.
+    fn allocate(&self) -> usize {
+        let oldest = |pred: &dyn Fn(usize) -> bool| {
+            (0..self.voices.len())
+                .filter(|&i| pred(i))
+                .min_by_key(|&i| self.slots[i].started)
+        };
+        oldest(&|i| !self.voices[i].is_active())
+            .or_else(|| oldest(&|i| self.slots[i].note.is_none()))
+            .or_else(|| oldest(&|_| true))
+            .unwrap()
+    }
+
+    /// The voice most recently started on `note`, if it's still sounding.
+    fn ringing(&self, note: u8) -> Option<usize> {
+        (0..self.voices.len())
+            .filter(|&i| self.slots[i].played == Some(note) && self.voices[i].is_active())
+            .max_by_key(|&i| self.slots[i].started)
+    }
+
+    /// Starts a note, returning the index of the voice playing it.
+    pub fn note_on(&mut self, note: Option<u8>, freq: f32, velocity: f32) -> usize {
+        self.start(None, note, freq, velocity)
+    }
+
+    /// Starts a note from an MPE member channel, returning the index of the
+    /// voice playing it.
+    pub fn channel_note_on(&mut self, channel: u8, note: u8, freq: f32, velocity: f32) -> usize {
+        self.start(Some(channel), Some(note), freq, velocity)
+    }
+
+    fn start(&mut self, channel: Option<u8>, note: Option<u8>, freq: f32, velocity: f32) -> usize {
+        // a held note played again starts over rather than doubling up
+        self.release(|slot| {
+            note.is_some()
+                && (slot.latched || slot.sustained)
+                && slot.note == note
+                && slot.channel == channel
+        });
+        let idx = match note.filter(|_| self.reuse && channel.is_none()) {
+            Some(note) => self.ringing(note).unwrap_or_else(|| self.allocate()),
+            None => self.allocate(),
+        };
+        self.counter += 1;
+        let previous = self.slots[idx];
+        self.slots[idx] = Slot {
+            note,
+            started: self.counter,
+            latched: false,
+            sustained: false,
+            played: note,
+            channel,
+            touch: 0.,
+            vibrato: 0.,
+        };
+        self.vibrato[idx].note_on();
+        // set up before the note starts, so it starts in tune
+        if channel.is_some()
+            || previous.channel.is_some()
+            || previous.touch != 0.
+            || previous.vibrato != 0.
+        {
+            self.express(idx);
+        }
+        self.voices[idx].note_on(freq, velocity);
+        idx
+    }
+
+    /// Brings a voice's bend and expression up to date with its channel.
+    fn express(&mut self, idx: usize) {
+        let slot = self.slots[idx];
+        let mut channel = match slot.channel {
+            Some(channel) => self.channels[channel as usize & 15],
+            None => Channel::default(),
+        };
+        match self.key_pressure {
+            KeyPressure::Pressure => channel.pressure = (channel.pressure + slot.touch).min(1.),
+            KeyPressure::Timbre => channel.timbre = (channel.timbre + slot.touch / 2.).min(1.),
+            KeyPressure::Off => {}
+        }
+        let voice = &mut self.voices[idx];
+        voice.set_bend(self.bend + channel.bend + slot.vibrato);
+        voice.set_expression(channel.pressure, channel.timbre);
+    }
+
+    pub fn note_off(&mut self, note: u8) {
+        self.stop(None, note);
+    }
+
+    pub fn channel_note_off(&mut self, channel: u8, note: u8) {
+        self.stop(Some(channel), note);
+    }
+
+    fn stop(&mut self, channel: Option<u8>, note: u8) {
+        let playing = |slot: &Slot| slot.note == Some(note) && slot.channel == channel;
+        if self.latch {
+            for slot in self.slots.iter_mut() {
+                slot.latched |= playing(slot);
+            }
+        } else if self.sustain {
+            for slot in self.slots.iter_mut() {
+                slot.sustained |= playing(slot);
+            }
+        } else {
+            self.release(playing);
+        }
+    }
+
+    /// Changes the expression of the notes from an MPE member channel, and
+    /// of any played on it from now on.
+    pub fn set_per_note(&mut self, channel: u8, change: PerNote) {
+        let state = &mut self.channels[channel as usize & 15];
+        match change {
+            PerNote::Bend(semitones) => state.bend = semitones,
+            PerNote::Pressure(pressure) => state.pressure = pressure,
+            PerNote::Timbre(timbre) => state.timbre = timbre,
+        }
+        for idx in 0..self.voices.len() {
+            if self.slots[idx].channel == Some(channel) {
+                self.express(idx);
+            }
+        }
+    }
+
+    /// Polyphonic aftertouch, 0..=1, on the key of every note playing
+    /// `note` that didn't come from an MPE member channel.
+    pub fn set_key_pressure(&mut self, note: u8, pressure: f32) {
+        for idx in 0..self.voices.len() {
+            let slot = &mut self.slots[idx];
+            if slot.note == Some(note) && slot.channel.is_none() {
+                slot.touch = pressure.clamp(0., 1.);
+                self.express(idx);
+            }
+        }
+    }
+
+    /// Whether any notes are held, by their keys, the latch or the sustain
+    /// pedal, for the modulation matrix's envelope.
+    pub fn holding(&self) -> bool {
+        self.slots.iter().any(|slot| slot.note.is_some())
+    }
+
+    /// How hard the hardest pressed key that's down is pressed, for the
+    /// modulation matrix.
+    pub fn key_pressure(&self) -> f32 {
+        self.slots
+            .iter()
+            .filter(|slot| slot.note.is_some() && slot.channel.is_none())
+            .map(|slot| slot.touch)
+            .fold(0., f32::max)
+    }
+
+    /// Moves where aftertouch goes, taking what's there already with it.
+    pub fn set_key_pressure_target(&mut self, target: KeyPressure) {
+        self.key_pressure = target;
+        for idx in 0..self.voices.len() {
+            if self.slots[idx].touch != 0. {
+                self.express(idx);
+            }
+        }
+    }
+
+    fn release(&mut self, pred: impl Fn(&Slot) -> bool) {
+        for (slot, voice) in self.slots.iter_mut().zip(self.voices.iter_mut()) {
+            if pred(slot) {
+                slot.note = None;
+                slot.latched = false;
+                slot.sustained = false;
+                voice.note_off();
+            }
+        }
+    }
+
+    /// Releases every note, held down or not.
+    pub fn release_all(&mut self) {
+        self.release(|_| true);
+    }
+
+    pub fn latch(&self) -> bool {
+        self.latch
+    }
+
+    /// Turning the latch off releases the notes it was holding.
+    pub fn set_latch(&mut self, latch: bool) {
+        self.latch = latch;
+        if !latch {
+            self.release(|slot| slot.latched);
+        }
+    }
+
+    pub fn sustain(&self) -> bool {
+        self.sustain
+    }
+
+    /// Lifting the pedal releases the notes it was holding.
+    pub fn set_sustain(&mut self, sustain: bool) {
+        self.sustain = sustain;
+        if !sustain {
+            self.release(|slot| slot.sustained);
+        }
+    }
+
+    pub fn reuse(&self) -> bool {
+        self.reuse
+    }
+
+    pub fn set_reuse(&mut self, reuse: bool) {
+        self.reuse = reuse;
+    }
+
+    pub fn set_bend(&mut self, semitones: f32) {
+        self.bend = semitones;
+        for idx in 0..self.voices.len() {
+            self.retune(idx);
+        }
+    }
+
+    /// Brings a voice's bend up to date, leaving its expression be.
+    fn retune(&mut self, idx: usize) {
+        let slot = &self.slots[idx];
+        let channel = slot
+            .channel
+            .map_or(0., |c| self.channels[c as usize & 15].bend);
+        self.voices[idx].set_bend(self.bend + channel + slot.vibrato);
+    }
+
+    /// Vibrato depth in semitones each way, up to half of one.
+    pub fn set_vibrato(&mut self, semitones: f32) {
+        self.vibrato_depth = semitones.clamp(0., 0.5);
+    }
+
+    /// Leaves out every voice but the one at `idx`, or none.
+    pub fn set_solo(&mut self, idx: Option<usize>) {
+        self.solo = idx.filter(|&idx| idx < self.voices.len());
+    }
+
+    /// Gives each voice a tap of its own, to capture along with the rest.
+    pub fn tap_voices(&mut self) {
+        self.taps = (1..=self.voices.len())
+            .map(|n| Snoop::new(&format!("voice{n}")))
+            .collect();
+    }
+
+    /// Routes voices playing `below` or lower into `bus` as well as the mix.
+    pub fn set_key_bus(&mut self, below: u8, bus: Bus) {
+        self.key = Some((below, bus));
+    }
+
+    /// Plays a voice into `left` and `right` a sample at a time, gliding
+    /// its vibrato from where it was to where the LFO has got to by the end,
+    /// so the pitch doesn't move in steps.
+    fn vibrate(&mut self, idx: usize, left: &mut [f32], right: &mut [f32]) {
+        let from = self.slots[idx].vibrato;
+        let to = self.vibrato_depth * self.vibrato[idx].next_block(left.len());
+        let len = left.len() as f32;
+        for (i, (l, r)) in left.iter_mut().zip(right.iter_mut()).enumerate() {
+            self.slots[idx].vibrato = from + (to - from) * (i + 1) as f32 / len;
+            self.retune(idx);
+            self.voices[idx].process_wide(std::slice::from_mut(l), std::slice::from_mut(r));
+        }
+    }
+}
+
+impl<V: Voice> Filter for VoiceManager<V> {
+    fn process(&mut self, samples: &mut [f32]) {
+        let mut right = std::mem::take(&mut self.mix_right);
+        right.resize(samples.len(), 0.);
+        self.process_wide(samples, &mut right);
+        for (s, r) in samples.iter_mut().zip(&right) {
+            *s = (*s + r) / 2.;
+        }
+        self.mix_right = right;
+    }
+
+    fn process_wide(&mut self, left: &mut [f32], right: &mut [f32]) {
+        self.scratch.resize(left.len(), 0.);
+        self.scratch_right.resize(left.len(), 0.);
+        left.fill(0.);
+        right.fill(0.);
+        if let Some((_, bus)) = &self.key {
+            bus.start(left.len());
+        }
+        for idx in 0..self.voices.len() {
+            // the vibrato goes on while there's any, and once more to glide
+            // back when the wheel comes down, but only on voices sounding
+            let vibrato = self.vibrato_depth != 0. || self.slots[idx].vibrato != 0.;
+            if vibrato && self.voices[idx].is_active() {
+                let mut scratch = std::mem::take(&mut self.scratch);
+                let mut scratch_right = std::mem::take(&mut self.scratch_right);
+                for (l, r) in scratch
+                    .chunks_mut(VIBRATO_PERIOD)
+                    .zip(scratch_right.chunks_mut(VIBRATO_PERIOD))
+                {
+                    self.vibrate(idx, l, r);
+                }
+                self.scratch = scratch;
+                self.scratch_right = scratch_right;
+            } else {
+                if self.slots[idx].vibrato != 0. {
+                    // gone quiet partway through, so its next note starts
+                    // in tune
+                    self.slots[idx].vibrato = 0.;
+                    self.retune(idx);
+                }
+                self.voices[idx].process_wide(&mut self.scratch, &mut self.scratch_right);
+            }
+            // the left side, which is all of it for a mono voice
+            if let Some(tap) = self.taps.get_mut(idx) {
+                tap.process(&mut self.scratch);
+            }
+            if let Some((below, bus)) = &self.key {
+                if self.slots[idx].played.is_some_and(|note| note <= *below) {
+                    bus.add(&self.scratch);
+                }
+            }
+            self.pans[idx].process_stereo(&mut self.scratch, &mut self.scratch_right);
+            // left out after it's played, so it's where it would be when
+            // it's heard again
+            if self.solo.is_some_and(|solo| solo != idx) {
+                continue;
+            }
+            for (out, s) in left.iter_mut().zip(self.scratch.iter()) {
+                *out += s;
+            }
+            for (out, s) in right.iter_mut().zip(self.scratch_right.iter()) {
+                *out += s;
+            }
+        }
+    }
+
+    fn describe(&self) -> Node {
+        Node::with_children(
+            format!("VoiceManager ({} voices)", self.voices.len()),
+            vec![self.voices[0].describe()],
+        )
+    }
+}
+
+/// Parameters are shared by every voice.
+/// The index of the voice "voice<n>.pan" places, counting from 1.
+fn voice_pan(name: &str) -> Option<usize> {
+    let n = name.strip_prefix("voice")?.strip_suffix(".pan")?;
+    n.parse::<usize>().ok()?.checked_sub(1)
+}
+
+impl<V: Voice + Params> Params for VoiceManager<V> {
+    fn params(&self) -> Vec<ParamInfo> {
+        let mut out = vec![
+            ParamInfo::new("latch", 0., 1.).not_random(),
+            ParamInfo::new("sustain", 0., 1.).not_random(),
+            ParamInfo::new("reuse", 0., 1.).not_random(),
+            ParamInfo::new("key_pressure", 0., (KeyPressure::ALL.len() - 1) as f32).not_random(),
+            ParamInfo::new("vibrato.depth", 0., 0.5).not_random(),
+            ParamInfo::new("vibrato.rate", 1., 12.),
+            ParamInfo::new("solo", 0., self.voices.len() as f32).not_random(),
+        ];
+        out.extend(
+            (1..=self.voices.len()).map(|n| ParamInfo::new(&format!("voice{n}.pan"), -1., 1.)),
+        );
+        out.extend(self.voices[0].params());
+        out
+    }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        match name {
+            "latch" => Some(self.latch as u8 as f32),
+            "sustain" => Some(self.sustain as u8 as f32),
+            "reuse" => Some(self.reuse as u8 as f32),
+            "key_pressure" => Some(self.key_pressure.index() as f32),
+            "vibrato.depth" => Some(self.vibrato_depth),
+            "vibrato.rate" => Some(self.vibrato[0].rate),
+            "solo" => Some(self.solo.map_or(0., |idx| (idx + 1) as f32)),
+            _ => match voice_pan(name) {
+                Some(idx) => self.pans.get(idx).map(|pan| pan.position),
+                None => self.voices[0].get_param(name),
+            },
+        }
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        match name {
+            "latch" => {
+                self.set_latch(value >= 0.5);
+                return true;
+            }
+            "sustain" => {
+                self.set_sustain(value >= 0.5);
+                return true;
+            }
+            "reuse" => {
+                self.set_reuse(value >= 0.5);
+                return true;
+            }
+            "key_pressure" => {
+                let index = (value.round().max(0.) as usize).min(KeyPressure::ALL.len() - 1);
+                self.set_key_pressure_target(KeyPressure::ALL[index]);
+                return true;
+            }
+            "vibrato.depth" => {
+                self.set_vibrato(value);
+                return true;
+            }
+            "vibrato.rate" => {
+                for lfo in self.vibrato.iter_mut() {
+                    lfo.rate = value.max(0.);
+                }
+                return true;
+            }
+            "solo" => {
+                let voice = value.round().max(0.) as usize;
+                self.set_solo(voice.checked_sub(1));
+                return true;
+            }
+            _ => {}
+        }
+        if let Some(pan) = voice_pan(name).and_then(|idx| self.pans.get_mut(idx)) {
+            pan.position = value.clamp(-1., 1.);
+            return true;
+        }
+        let mut found = false;
+        for voice in self.voices.iter_mut() {
+            found |= voice.set_param(name, value);
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters::AdsrStage;
+    use crate::sources::FmVoice;
+
+    #[test]
+    fn test_latch() {
+        let mut voices = VoiceManager::new((0..4).map(|_| FmVoice::default()).collect());
+        let mut out = vec![0.; 64];
+        voices.set_latch(true);
+        let held = voices.note_on(Some(60), 440., 1.);
+        voices.note_off(60);
+        voices.process(&mut out);
+        assert!(voices.voices[held].env.stage() != AdsrStage::Release);
+
+        // playing it again restarts it, leaving the old voice to ring out
+        let again = voices.note_on(Some(60), 440., 1.);
+        assert_ne!(held, again);
+        assert!(voices.voices[held].env.stage() == AdsrStage::Release);
+
+        voices.note_off(60);
+        assert!(voices.set_param("latch", 0.));
+        assert!(voices.voices[again].env.stage() == AdsrStage::Release);
+    }
+
+    #[test]
+    fn test_sustain() {
+        let mut voices = VoiceManager::new((0..4).map(|_| FmVoice::default()).collect());
+        let before = voices.note_on(Some(60), 440., 1.);
+        assert!(voices.set_param("sustain", 1.));
+        let during = voices.note_on(Some(64), 554., 1.);
+        voices.note_off(60);
+        voices.note_off(64);
+        // what was down when it went down, and what was played while it was
+        for idx in [before, during] {
+            assert!(voices.voices[idx].env.stage() != AdsrStage::Release);
+        }
+        assert!(voices.holding());
+
+        // the latch still holds what it has when the pedal lifts
+        voices.set_latch(true);
+        let latched = voices.note_on(Some(67), 659., 1.);
+        voices.note_off(67);
+        voices.set_sustain(false);
+        for idx in [before, during] {
+            assert!(voices.voices[idx].env.stage() == AdsrStage::Release);
+        }
+        assert!(voices.voices[latched].env.stage() != AdsrStage::Release);
+
+        // a key held through the pedal lifting keeps playing
+        voices.set_latch(false);
+        let held = voices.note_on(Some(72), 880., 1.);
+        voices.set_sustain(true);
+        voices.set_sustain(false);
+        assert!(voices.voices[held].env.stage() != AdsrStage::Release);
+        voices.note_off(72);
+        assert!(!voices.holding());
+    }
+
+    #[test]
+    fn test_voice_reuse() {
+        let mut voices = VoiceManager::new((0..4).map(|_| FmVoice::default()).collect());
+        let first = voices.note_on(Some(60), 440., 1.);
+        voices.note_off(60);
+        // without reuse, a free voice is better than one still ringing
+        let second = voices.note_on(Some(60), 440., 1.);
+        assert_ne!(first, second);
+        voices.note_off(60);
+
+        assert!(voices.set_param("reuse", 1.));
+        assert_eq!(voices.note_on(Some(60), 440., 1.), second);
+        assert!(voices.voices[second].env.stage() == AdsrStage::Attack);
+        // even while it's still held
+        assert_eq!(voices.note_on(Some(60), 440., 1.), second);
+        assert_ne!(voices.note_on(Some(62), 494., 1.), second);
+        // computer keyboard notes have nothing to match on
+        assert_ne!(voices.note_on(None, 440., 1.), second);
+    }
+
+    /// Remembers what it's told.
+    #[derive(Default)]
+    struct Probe {
+        bend: f32,
+        pressure: f32,
+        timbre: f32,
+        held: bool,
+        /// the bend each sample was played with
+        played: Vec<f32>,
+    }
+
+    impl Filter for Probe {
+        fn process(&mut self, samples: &mut [f32]) {
+            self.played.extend(samples.iter().map(|_| self.bend));
+        }
+    }
+
+    impl Voice for Probe {
+        fn note_on(&mut self, _freq: f32, _velocity: f32) {
+            self.held = true;
+        }
+
+        fn note_off(&mut self) {
+            self.held = false;
+        }
+
+        fn set_bend(&mut self, semitones: f32) {
+            self.bend = semitones;
+        }
+
+        fn is_active(&self) -> bool {
+            self.held
+        }
+
+        fn set_expression(&mut self, pressure: f32, timbre: f32) {
+            self.pressure = pressure;
+            self.timbre = timbre;
+        }
+    }
+
+    #[test]
+    fn test_per_note() {
+        let mut voices = VoiceManager::new((0..4).map(|_| Probe::default()).collect());
+        // bent before the note starts, the way MPE controllers do it
+        voices.set_per_note(2, PerNote::Bend(1.));
+        let a = voices.channel_note_on(2, 60, 262., 1.);
+        let b = voices.channel_note_on(3, 60, 262., 1.);
+        let plain = voices.note_on(Some(60), 262., 1.);
+        assert_eq!(voices.voices[a].bend, 1.);
+
+        voices.set_per_note(3, PerNote::Pressure(0.5));
+        voices.set_bend(2.);
+        assert_eq!(voices.voices[a].bend, 3.);
+        assert_eq!(voices.voices[b].pressure, 0.5);
+        assert_eq!(voices.voices[a].pressure, 0.);
+        assert_eq!(voices.voices[plain].bend, 2.);
+
+        // the same note on another channel is another note
+        voices.channel_note_off(2, 60);
+        assert!(!voices.voices[a].held);
+        assert!(voices.voices[b].held && voices.voices[plain].held);
+        voices.note_off(60);
+        assert!(voices.voices[b].held && !voices.voices[plain].held);
+    }
+
+    #[test]
+    fn test_key_pressure() {
+        let mut voices = VoiceManager::new((0..4).map(|_| Probe::default()).collect());
+        let pressed = voices.note_on(Some(60), 262., 1.);
+        let other = voices.note_on(Some(64), 330., 1.);
+        let member = voices.channel_note_on(2, 60, 262., 1.);
+        voices.set_key_pressure(60, 0.5);
+        assert_eq!(voices.voices[pressed].pressure, 0.5);
+        assert_eq!(voices.voices[other].pressure, 0.);
+        assert_eq!(voices.voices[member].pressure, 0.);
+        assert_eq!(voices.key_pressure(), 0.5);
+
+        voices.set_key_pressure_target(KeyPressure::Timbre);
+        assert_eq!(voices.voices[pressed].pressure, 0.);
+        assert_eq!(voices.voices[pressed].timbre, 0.75);
+
+        // a voice picked up again starts with none
+        voices.release_all();
+        assert_eq!(voices.key_pressure(), 0.);
+        for note in [60, 62, 65, 67] {
+            voices.note_on(Some(note), 262., 1.);
+        }
+        assert_eq!(voices.voices[pressed].timbre, 0.5);
+        assert_eq!("timbre".parse(), Ok(KeyPressure::Timbre));
+        assert!("brightness".parse::<KeyPressure>().is_err());
+    }
+
+    #[test]
+    fn test_vibrato() {
+        let mut voices = VoiceManager::new((0..2).map(|_| Probe::default()).collect());
+        voices.set_bend(1.);
+        let idx = voices.note_on(Some(60), 262., 1.);
+        voices.set_vibrato(0.5);
+        let mut block = vec![0.; 256];
+        let (mut low, mut high) = (f32::MAX, f32::MIN);
+        // a cycle and a bit at 5.5Hz
+        for _ in 0..40 {
+            voices.process(&mut block);
+            low = low.min(voices.voices[idx].bend);
+            high = high.max(voices.voices[idx].bend);
+        }
+        assert!(low < 0.6 && high > 1.4, "{low}..{high}");
+        assert!(low >= 0.5 && high <= 1.5);
+        // a sample at a time, not in steps
+        let played = &voices.voices[idx].played;
+        assert_eq!(played.len(), 40 * 256);
+        let jump = played
+            .windows(2)
+            .map(|w| (w[1] - w[0]).abs())
+            .fold(0., f32::max);
+        assert!(jump < 0.001, "{jump}");
+        // and not at all on the voice that isn't playing
+        let idle = 1 - idx;
+        assert_eq!(voices.voices[idle].bend, 1.);
+
+        // put back where it was once the wheel comes down
+        voices.set_vibrato(0.);
+        voices.process(&mut block);
+        assert_eq!(voices.voices[idx].bend, 1.);
+    }
+
+    #[test]
+    fn test_solo() {
+        let mut voices = VoiceManager::new((0..3).map(|_| FmVoice::default()).collect());
+        let playing = voices.note_on(Some(60), 262., 1.);
+        let silent = (0..3).find(|&idx| idx != playing).unwrap();
+        let heard = |voices: &mut VoiceManager<FmVoice>, solo: usize| {
+            assert!(voices.set_param("solo", solo as f32));
+            let mut block = vec![0.; 256];
+            voices.process(&mut block);
+            block.iter().any(|&s| s != 0.)
+        };
+        assert!(!heard(&mut voices, silent + 1));
+        assert!(heard(&mut voices, playing + 1));
+        assert!(heard(&mut voices, 0));
+        assert_eq!(voices.get_param("solo"), Some(0.));
+
+        voices.tap_voices();
+        assert_eq!(voices.taps[2].name(), "voice3");
+    }
+
+    #[test]
+    fn test_voice_pan() {
+        let mut voices = VoiceManager::new((0..2).map(|_| FmVoice::default()).collect());
+        let left = voices.note_on(Some(60), 262., 1.);
+        let right = voices.note_on(Some(67), 392., 1.);
+        assert!(voices.set_param(&format!("voice{}.pan", left + 1), -1.));
+        assert!(voices.set_param(&format!("voice{}.pan", right + 1), 5.));
+        assert_eq!(
+            voices.get_param(&format!("voice{}.pan", right + 1)),
+            Some(1.)
+        );
+        assert!(!voices.set_param("voice3.pan", 0.));
+
+        let wide = |voices: &mut VoiceManager<FmVoice>| {
+            let (mut l, mut r) = (vec![0.; 2048], vec![0.; 2048]);
+            voices.process_wide(&mut l, &mut r);
+            (l, r)
+        };
+        // past the pans gliding over
+        wide(&mut voices);
+        let (l, r) = wide(&mut voices);
+        assert!(l.iter().any(|&s| s != 0.) && r.iter().any(|&s| s != 0.));
+        // each side only has its own voice in it
+        voices.set_solo(Some(left));
+        let (l, r) = wide(&mut voices);
+        assert!(l.iter().any(|&s| s != 0.));
+        assert!(r.iter().all(|&s| s.abs() < 1e-6), "{:?}", &r[..4]);
+    }
+}
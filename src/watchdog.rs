@@ -0,0 +1,165 @@
+//! Noticing when the audio callback keeps taking longer than the audio it
+//! renders lasts, which is heard as dropouts, and doing something about it.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::event_log::{EventLog, Field};
+use crate::filters::SAMPLING_FREQ;
+use crate::guard::{EngineError, Load, Reporter};
+
+/// Blocks in a row over budget before anything is done, so one slow block
+/// (a page fault, the OS getting in the way) doesn't count.
+const STRIKES: u32 = 8;
+
+pub struct Watchdog {
+    loads: Vec<(String, Arc<Load>)>,
+    /// whether to bypass the most expensive node, or just say so
+    shed: bool,
+    report: Reporter,
+    overruns: u32,
+    /// where every block over budget gets written down, not just the
+    /// strikes that get reported
+    pub log: Option<EventLog>,
+}
+
+impl Watchdog {
+    /// Watches the nodes in `loads`, which with `shed` can be taken out.
+    pub fn new(loads: Vec<(String, Arc<Load>)>, shed: bool, report: Reporter) -> Self {
+        Self {
+            loads,
+            shed,
+            report,
+            overruns: 0,
+            log: None,
+        }
+    }
+
+    /// The node taking longest that is still running.
+    fn heaviest(&self) -> Option<&(String, Arc<Load>)> {
+        self.loads
+            .iter()
+            .filter(|(_, load)| !load.is_shed())
+            .max_by_key(|(_, load)| load.nanos())
+    }
+
+    /// Takes how long rendering `frames` took. Reporting allocates, but by
+    /// then the callback is already glitching.
+    pub fn check(&mut self, frames: usize, took: Duration) {
+        let budget = Duration::from_secs_f64(frames as f64 / SAMPLING_FREQ as f64);
+        if took <= budget {
+            self.overruns = 0;
+            return;
+        }
+        self.overruns += 1;
+        if let Some(log) = &self.log {
+            log.log(
+                log.now(),
+                "overrun",
+                &[
+                    ("took_us", Field::Number(took.as_micros() as f32)),
+                    ("budget_us", Field::Number(budget.as_micros() as f32)),
+                    ("in_a_row", Field::Number(self.overruns as f32)),
+                ],
+            );
+        }
+        if self.overruns < STRIKES {
+            return;
+        }
+        self.overruns = 0;
+
+        let over = format!(
+            "{STRIKES} blocks in a row took longer than they last, the last {}µs for {}µs",
+            took.as_micros(),
+            budget.as_micros()
+        );
+        let error = match self.heaviest() {
+            Some((name, load)) if self.shed => {
+                load.shed();
+                EngineError {
+                    node: name.clone(),
+                    message: format!(
+                        "{over}, and it was the slowest at {}µs",
+                        load.nanos() / 1000
+                    ),
+                    bypassed: true,
+                }
+            }
+            Some((name, load)) => EngineError {
+                node: "engine".to_string(),
+                message: format!("{over}; {name} is the slowest at {}µs", load.nanos() / 1000),
+                bypassed: false,
+            },
+            None => EngineError {
+                node: "engine".to_string(),
+                message: over,
+                bypassed: false,
+            },
+        };
+        (self.report)(error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::clock::EngineClock;
+    use crate::filters::Filter;
+    use crate::guard::Guards;
+
+    struct Slow(Duration);
+
+    impl Filter for Slow {
+        fn process(&mut self, _samples: &mut [f32]) {
+            std::thread::sleep(self.0);
+        }
+    }
+
+    #[test]
+    fn test_watchdog() {
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let report: Reporter = {
+            let errors = errors.clone();
+            Arc::new(move |e| errors.lock().unwrap().push(e))
+        };
+        let mut guards = Guards::new(report.clone());
+        let mut fast = guards.guard("fast", Slow(Duration::ZERO));
+        let mut slow = guards.guard("slow", Slow(Duration::from_millis(2)));
+        let mut samples = [0.; 64];
+        fast.process(&mut samples);
+        slow.process(&mut samples);
+
+        let mut watchdog = Watchdog::new(guards.loads, true, report);
+        let path =
+            std::env::temp_dir().join(format!("synthtoy-watchdog-{}.jsonl", std::process::id()));
+        watchdog.log = Some(EventLog::create(&path, EngineClock::new(Instant::now())).unwrap());
+        let block = Duration::from_millis(2);
+        for _ in 0..STRIKES - 1 {
+            watchdog.check(64, block);
+        }
+        // a block on time starts the count over
+        watchdog.check(64, Duration::ZERO);
+        for _ in 0..STRIKES - 1 {
+            watchdog.check(64, block);
+        }
+        assert!(errors.lock().unwrap().is_empty());
+
+        watchdog.check(64, block);
+        let errors = errors.lock().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].node, "slow");
+        assert!(errors[0].bypassed);
+        assert!(slow.is_bypassed());
+        assert!(!fast.is_bypassed());
+
+        // every block over budget is in the log, strike or not
+        drop(watchdog);
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let overruns = text.lines().filter(|l| l.contains("\"overrun\"")).count();
+        assert_eq!(overruns, 2 * STRIKES as usize - 1);
+    }
+}
@@ -1,22 +1,59 @@
+use std::sync::Arc;
+
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+
+use crate::filters::{Adsr, Filter, SAMPLING_FREQ};
+use crate::params::{nested, ParamInfo, Params};
+use crate::voice::Voice;
+
 const PERIOD_SAMPLE_SIZE: usize = 4096;
 
 pub type WaveLookupTable = [f32; PERIOD_SAMPLE_SIZE];
 
 pub trait WavetableSource {
     fn sample(&self, index: usize) -> f32;
+
+    /// How many entries make up a period.
+    fn period(&self) -> usize {
+        PERIOD_SAMPLE_SIZE
+    }
+
+    /// The wave at `phase` through its period, read between entries.
+    fn at_phase(&self, phase: f32) -> f32 {
+        let pos = phase.rem_euclid(1.) * self.period() as f32;
+        let index = pos as usize;
+        let frac = pos - index as f32;
+        let (a, b) = (self.sample(index), self.sample(index + 1));
+        a + (b - a) * frac
+    }
 }
+/// A table of any length, read a period at a time.
+impl WavetableSource for [f32] {
+    fn sample(&self, index: usize) -> f32 {
+        self[index % self.len()]
+    }
+
+    fn period(&self) -> usize {
+        self.len()
+    }
+}
+
+#[allow(clippy::excessive_precision, clippy::approx_constant)]
 static SIN_VALUES: WaveLookupTable = include!("../include/sin_table.txt");
+#[allow(clippy::excessive_precision, clippy::approx_constant)]
 static TRIANGLE_VALUES: WaveLookupTable = include!("../include/triangle_table.txt");
 
-struct SquareWave;
+/// High for the first half, the way the sine is.
+pub struct SquareWave;
 
 impl WavetableSource for SquareWave {
     // period = 4096 steps = 2 pi
     fn sample(&self, index: usize) -> f32 {
         if index % PERIOD_SAMPLE_SIZE < (PERIOD_SAMPLE_SIZE / 2) {
-            -1.
-        } else {
             1.
+        } else {
+            -1.
         }
     }
 }
@@ -24,7 +61,7 @@ impl WavetableSource for SquareWave {
 macro_rules! impl_lookup {
     ($(($name:ident, $table:ident)),* $(,)*) => {
         $(
-            struct $name;
+            pub struct $name;
 
             impl WavetableSource for $name {
                 fn sample(&self, index: usize) -> f32 {
@@ -40,13 +77,470 @@ impl_lookup! {
     (SineWave, SIN_VALUES)
 }
 
-pub struct WaveTable<Wave: WavetableSource>(Wave);
+/// One of the tables, picked at runtime.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Wave {
+    #[default]
+    Sine,
+    Triangle,
+    Square,
+}
+
+/// `sine`, `triangle` or `square`.
+impl std::str::FromStr for Wave {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        Ok(match value {
+            "sine" => Wave::Sine,
+            "triangle" => Wave::Triangle,
+            "square" => Wave::Square,
+            _ => {
+                return Err(format!(
+                    "unknown wave {value:?}, expected sine, triangle or square"
+                ))
+            }
+        })
+    }
+}
+
+impl WavetableSource for Wave {
+    fn sample(&self, index: usize) -> f32 {
+        match self {
+            Wave::Sine => SineWave.sample(index),
+            Wave::Triangle => TriangleWave.sample(index),
+            Wave::Square => SquareWave.sample(index),
+        }
+    }
+}
+
+/// Band-limited copies of a wave an octave apart, each with half the
+/// harmonics of the one before, so there's one for every pitch with nothing
+/// above Nyquist to alias back down.
+pub struct MipMap {
+    /// every harmonic the table can hold first, down to just the
+    /// fundamental
+    levels: Vec<Vec<f32>>,
+}
+
+impl MipMap {
+    pub fn new(wave: &impl WavetableSource) -> Self {
+        let mut planner = FftPlanner::new();
+        let fft = planner.plan_fft_forward(PERIOD_SAMPLE_SIZE);
+        let ifft = planner.plan_fft_inverse(PERIOD_SAMPLE_SIZE);
+        let mut spectrum: Vec<_> = (0..PERIOD_SAMPLE_SIZE)
+            .map(|i| Complex::new(wave.sample(i), 0.))
+            .collect();
+        fft.process(&mut spectrum);
+
+        let mut levels = Vec::new();
+        let mut harmonics = PERIOD_SAMPLE_SIZE / 2;
+        while harmonics > 0 {
+            let mut level = spectrum.clone();
+            // each harmonic is in a bin from either end
+            for (bin, c) in level.iter_mut().enumerate() {
+                if bin.min(PERIOD_SAMPLE_SIZE - bin) > harmonics {
+                    *c = Complex::default();
+                }
+            }
+            ifft.process(&mut level);
+            let scale = 1. / PERIOD_SAMPLE_SIZE as f32;
+            levels.push(level.iter().map(|c| c.re * scale).collect());
+            harmonics /= 2;
+        }
+        Self { levels }
+    }
+
+    /// The wave at `phase` through its period, from the copies `level`
+    /// picks.
+    pub fn at_phase(&self, level: MipLevel, phase: f32) -> f32 {
+        let safe = self.levels[level.index].at_phase(phase);
+        if level.fuller == 0. {
+            return safe;
+        }
+        let fuller = self.levels[level.index - 1].at_phase(phase);
+        safe + (fuller - safe) * level.fuller
+    }
+}
+
+/// Which copies in a [`MipMap`] to play at a frequency. Rather than jump
+/// from one to the next as the pitch crosses an octave, which a bend or
+/// vibrato would make heard, it fades between the fullest copy with no
+/// harmonics above Nyquist and the next fuller one, all the way into the
+/// fuller one where its top harmonic reaches Nyquist.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MipLevel {
+    index: usize,
+    /// how much of the next fuller copy to mix in
+    fuller: f32,
+}
+
+impl MipLevel {
+    pub fn new(freq: f32) -> Self {
+        let fit = SAMPLING_FREQ as f32 / 2. / freq.max(1.);
+        let last = (PERIOD_SAMPLE_SIZE / 2).ilog2() as f32;
+        // fractional, 0 for the fullest copy
+        let level = ((PERIOD_SAMPLE_SIZE / 2) as f32 / fit)
+            .log2()
+            .clamp(0., last);
+        let index = level.ceil();
+        Self {
+            index: index as usize,
+            fuller: index - level,
+        }
+    }
+}
+
+/// An oscillator reading through a table once a period at the note's
+/// frequency, gated by its envelope. It plays the band-limited copies of the
+/// wave that suit the pitch, as [`MipLevel`] picks them.
+///
+/// Given more than one wave it morphs between them: `position` runs from
+/// the first at 0 to the last at 1, crossfading the two either side of it.
+/// A position envelope takes over from `position` for each note it plays.
+pub struct WaveTable {
+    pub env: Adsr,
+    pub position: f32,
+    pub position_env: Option<PositionEnvelope>,
+
+    /// the frames to morph between, in order
+    tables: Arc<[MipMap]>,
+    note_freq: f32,
+    bend: f32,
+    velocity: f32,
+    /// 0..1 through the period
+    phase: f32,
+}
+
+impl WaveTable {
+    pub fn new(tables: Arc<[MipMap]>) -> Self {
+        Self {
+            env: Adsr::default(),
+            position: 0.,
+            position_env: None,
+            tables,
+            note_freq: 440.,
+            bend: 0.,
+            velocity: 1.,
+            phase: 0.,
+        }
+    }
+}
+
+impl Voice for WaveTable {
+    fn note_on(&mut self, freq: f32, velocity: f32) {
+        self.note_freq = freq;
+        self.velocity = velocity;
+        self.phase = 0.;
+        self.env.note_on();
+        if let Some(env) = &mut self.position_env {
+            env.note_on();
+        }
+    }
+
+    fn note_off(&mut self) {
+        self.env.note_off();
+    }
+
+    fn set_bend(&mut self, semitones: f32) {
+        self.bend = semitones;
+    }
+
+    fn is_active(&self) -> bool {
+        !self.env.is_idle()
+    }
+}
+
+impl Filter for WaveTable {
+    fn process(&mut self, samples: &mut [f32]) {
+        let freq = self.note_freq * 2f32.powf(self.bend / 12.);
+        let inc = freq / SAMPLING_FREQ as f32;
+        let level = MipLevel::new(freq);
+        let last = self.tables.len() - 1;
+        for s in samples.iter_mut() {
+            let position = match &mut self.position_env {
+                Some(env) => env.next_position(),
+                None => self.position,
+            };
+            let frame = position.clamp(0., 1.) * last as f32;
+            let i = (frame as usize).min(last);
+            let wave = self.tables[i].at_phase(level, self.phase);
+            let wave = match self.tables.get(i + 1) {
+                Some(next) => {
+                    let mix = frame - i as f32;
+                    wave + (next.at_phase(level, self.phase) - wave) * mix
+                }
+                None => wave,
+            };
+            *s = wave * self.env.next_level() * self.velocity;
+            self.phase = (self.phase + inc).fract();
+        }
+    }
+}
 
-impl<W: WavetableSource> WaveTable<W> {
-    pub fn new(w: W) -> Self {
-        Self(w)
+impl Params for WaveTable {
+    fn params(&self) -> Vec<ParamInfo> {
+        let mut params = vec![ParamInfo::new("position", 0., 1.)];
+        params.extend(nested("env", &self.env));
+        params
     }
+
+    fn get_param(&self, name: &str) -> Option<f32> {
+        if name == "position" {
+            return Some(self.position);
+        }
+        self.env.get_param(name.strip_prefix("env.")?)
+    }
+
+    fn set_param(&mut self, name: &str, value: f32) -> bool {
+        if name == "position" {
+            self.position = value;
+            return true;
+        }
+        name.strip_prefix("env.")
+            .is_some_and(|rest| self.env.set_param(rest, value))
+    }
+}
+
+/// How long a looping [`PositionEnvelope`] spends crossfading from where
+/// it ends back to where it starts, at most.
+const LOOP_FADE_SECS: f32 = 0.05;
+
+/// A breakpoint curve for the table position over a note's lifetime, for
+/// morphing between frames: `(seconds since note on, position)` pairs in
+/// time order, joined by straight lines and holding the last position.
+///
+/// A looping one starts over after its last breakpoint instead, fading
+/// into its first position over the end of each time round so the wave
+/// doesn't jump at the seam.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PositionEnvelope {
+    points: Vec<(f32, f32)>,
+    looping: bool,
+    /// seconds since note on
+    time: f32,
 }
 
-// todo implement
-// impl<W: WavetableSource> Filter for WaveTable<W> {}
+impl PositionEnvelope {
+    pub fn new(mut points: Vec<(f32, f32)>, looping: bool) -> Self {
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Self {
+            points,
+            looping,
+            time: 0.,
+        }
+    }
+
+    pub fn note_on(&mut self) {
+        self.time = 0.;
+    }
+
+    pub fn at(&self, time: f32) -> f32 {
+        let end = self.points.last().map_or(0., |&(t, _)| t);
+        if !self.looping || end <= 0. {
+            return self.curve(time);
+        }
+        let time = time % end;
+        let fade = LOOP_FADE_SECS.min(end / 2.);
+        let into_fade = time - (end - fade);
+        let pos = self.curve(time);
+        if into_fade > 0. {
+            pos + (self.curve(0.) - pos) * into_fade / fade
+        } else {
+            pos
+        }
+    }
+
+    fn curve(&self, time: f32) -> f32 {
+        let i = self.points.partition_point(|&(t, _)| t <= time);
+        match (i.checked_sub(1), self.points.get(i)) {
+            (None, Some(&(_, pos))) => pos,
+            (Some(prev), None) => self.points[prev].1,
+            (Some(prev), Some(&(t1, p1))) => {
+                let (t0, p0) = self.points[prev];
+                p0 + (p1 - p0) * (time - t0) / (t1 - t0)
+            }
+            (None, None) => 0.,
+        }
+    }
+
+    /// The position for the next sample.
+    pub fn next_position(&mut self) -> f32 {
+        let pos = self.at(self.time);
+        self.time += 1. / crate::filters::SAMPLING_FREQ as f32;
+        pos
+    }
+}
+
+/// Breakpoints as "<secs>:<position>,...", e.g. "0:0,0.5:1,2:0.25", and
+/// ",loop" on the end to go round again.
+impl std::str::FromStr for PositionEnvelope {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        let (value, looping) = match value.strip_suffix("loop") {
+            Some(rest) => (rest.trim_end().trim_end_matches(',').trim_end(), true),
+            None => (value, false),
+        };
+        let points = value
+            .split(',')
+            .map(|point| {
+                let (t, pos) = point
+                    .split_once(':')
+                    .ok_or_else(|| format!("expected <secs>:<position>, got {point:?}"))?;
+                let num = |s: &str| {
+                    s.trim()
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|n| n.is_finite())
+                        .ok_or_else(|| format!("bad number {s:?}"))
+                };
+                let t = num(t)?;
+                if t < 0. {
+                    return Err(format!("{t}s is before the note starts"));
+                }
+                Ok((t, num(pos)?))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Self::new(points, looping))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::{PI, TAU};
+
+    #[test]
+    fn test_position_envelope() {
+        let env: PositionEnvelope = "0.5:1, 0:0, 2:0.25".parse().unwrap();
+        assert_eq!(env.at(0.), 0.);
+        assert_eq!(env.at(0.25), 0.5);
+        assert_eq!(env.at(1.25), 0.625);
+        assert_eq!(env.at(10.), 0.25);
+        assert!("0:0,1".parse::<PositionEnvelope>().is_err());
+        assert!("-1:0".parse::<PositionEnvelope>().is_err());
+
+        // round again every second, fading back to 0 over the last 50ms
+        let looped: PositionEnvelope = "0:0, 1:1, loop".parse().unwrap();
+        assert_eq!(looped.at(0.5), 0.5);
+        assert_eq!(looped.at(1.5), 0.5);
+        assert!((looped.at(0.975) - 0.4875).abs() < 1e-4);
+        assert!(looped.at(0.9999) < 0.01);
+    }
+
+    #[test]
+    fn test_wavetable_morph() {
+        let tables: Arc<[MipMap]> =
+            Arc::new([MipMap::new(&Wave::Sine), MipMap::new(&Wave::Square)]);
+        let mut voice = WaveTable::new(tables);
+        voice.env = Adsr::new(0., 0., 1., 0.01);
+        // halfway, a quarter of the way through the period is halfway
+        // between the sine's 1 and the band-limited square's near 1
+        let mut out = [0.; 100];
+        voice.set_param("position", 0.5);
+        voice.note_on(441., 1.);
+        voice.process(&mut out);
+        assert!((out[25] - 1.).abs() < 0.1, "{}", out[25]);
+        // an eighth of the way the sine is down at 0.71, the square isn't
+        voice.set_param("position", 0.);
+        voice.process(&mut out);
+        let sine = out[12];
+        voice.set_param("position", 1.);
+        voice.process(&mut out);
+        assert!(out[12] - sine > 0.2, "{sine} {}", out[12]);
+
+        // the envelope takes over, starting over with each note
+        voice.position_env = Some("0:0, 0.01:1".parse().unwrap());
+        voice.note_on(441., 1.);
+        voice.process(&mut out);
+        assert!((out[12] - sine).abs() < 0.01);
+        let mut later = [0.; 1000];
+        voice.process(&mut later);
+        assert!(later[912] - sine > 0.2);
+    }
+
+    #[test]
+    fn test_at_phase() {
+        assert!((SineWave.at_phase(0.25) - 1.).abs() < 1e-6);
+        assert!((SineWave.at_phase(0.3) - (0.3 * std::f32::consts::TAU).sin()).abs() < 1e-5);
+        assert!(TriangleWave.at_phase(1.5).abs() < 1e-6);
+        assert_eq!(SquareWave.at_phase(0.1), 1.);
+        assert_eq!(SquareWave.at_phase(-0.1), -1.);
+    }
+
+    #[test]
+    fn test_wavetable_voice() {
+        assert_eq!("square".parse(), Ok(Wave::Square));
+        assert!("saw".parse::<Wave>().is_err());
+
+        // 441Hz makes a period of 100 samples
+        let mut voice = WaveTable::new(Arc::new([MipMap::new(&Wave::Sine)]));
+        voice.env = Adsr::new(0., 0., 1., 0.01);
+        voice.note_on(441., 0.5);
+        let mut out = [0.; 200];
+        voice.process(&mut out);
+        assert!((out[25] - 0.5).abs() < 1e-3, "{}", out[25]);
+        assert!((out[175] + 0.5).abs() < 1e-3, "{}", out[175]);
+
+        // an octave up goes round twice as fast
+        voice.set_bend(12.);
+        voice.process(&mut out);
+        assert!((out[12] - 0.5 * (TAU * 0.24).sin()).abs() < 1e-3);
+
+        voice.note_off();
+        let mut tail = [0.; 1000];
+        voice.process(&mut tail);
+        assert!(!voice.is_active());
+        assert!(tail[500..].iter().all(|&s| s == 0.));
+    }
+
+    #[test]
+    fn test_mipmap() {
+        let square = MipMap::new(&Wave::Square);
+        // how much of harmonic `n` there is in a table
+        let harmonic = |table: &[f32], n: usize| {
+            let len = table.len() as f32;
+            let sum: f32 = table
+                .iter()
+                .enumerate()
+                .map(|(i, s)| s * (TAU * n as f32 * i as f32 / len).sin())
+                .sum();
+            2. * sum / len
+        };
+        // a period of what plays at `freq`
+        let table = |freq| -> Vec<f32> {
+            let level = MipLevel::new(freq);
+            (0..PERIOD_SAMPLE_SIZE)
+                .map(|i| square.at_phase(level, i as f32 / PERIOD_SAMPLE_SIZE as f32))
+                .collect()
+        };
+        let full = table(10.);
+        assert!((harmonic(&full, 101) - 4. / (PI * 101.)).abs() < 1e-3);
+        // 344.5Hz has room for 64 harmonics, so that's all it gets
+        let freq_64 = SAMPLING_FREQ as f32 / 2. / 64.;
+        let at_64 = table(freq_64);
+        assert!((harmonic(&at_64, 63) - 4. / (PI * 63.)).abs() < 1e-3);
+        assert!(harmonic(&at_64, 65).abs() < 1e-4);
+        // 441Hz has room for 50: the 32 harmonic copy, with some of the 64
+        let level = MipLevel::new(441.);
+        assert_eq!(level.index, 6);
+        let table_441 = table(441.);
+        assert!((harmonic(&table_441, 31) - 4. / (PI * 31.)).abs() < 1e-3);
+        let top = harmonic(&table_441, 33) / (4. / (PI * 33.));
+        assert!((top - level.fuller).abs() < 1e-2, "{top}");
+        // just past it the 32 harmonic copy fades in, rather than jumping in
+        let above = table(freq_64 * 1.001);
+        assert!(above.iter().zip(&at_64).all(|(a, b)| (a - b).abs() < 0.01));
+        // above the highest, just the fundamental
+        let top = table(15_000.);
+        assert!((harmonic(&top, 1) - 4. / PI).abs() < 1e-3);
+        assert!(harmonic(&top, 3).abs() < 1e-4);
+
+        let sine = MipMap::new(&Wave::Sine);
+        let level = MipLevel::new(1000.);
+        assert!((sine.at_phase(level, 0.3) - SineWave.at_phase(0.3)).abs() < 1e-4);
+        // a table of its own length
+        let short = [0., 1., 0., -1.];
+        assert_eq!(short[..].at_phase(0.25), 1.);
+        assert_eq!(short[..].at_phase(0.875), -0.5);
+    }
+}
@@ -0,0 +1,102 @@
+//! Window functions, for tapering the ends of FIR filters and analysis
+//! frames.
+
+use std::f32::consts::TAU;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Window {
+    /// No tapering at all.
+    Rectangular,
+    Hann,
+    Hamming,
+    Blackman,
+    /// Trades main lobe width for sidelobe level with `beta`: 0 is
+    /// rectangular, around 5 is similar to Hamming, around 8.6 to Blackman.
+    Kaiser(f32),
+}
+
+/// `rect`, `hann`, `hamming`, `blackman`, or `kaiser[:<beta>]` with beta
+/// defaulting to 8.6.
+impl std::str::FromStr for Window {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, String> {
+        Ok(match value {
+            "rect" | "rectangular" => Window::Rectangular,
+            "hann" => Window::Hann,
+            "hamming" => Window::Hamming,
+            "blackman" => Window::Blackman,
+            "kaiser" => Window::Kaiser(8.6),
+            _ => match value.strip_prefix("kaiser:") {
+                Some(beta) => Window::Kaiser(
+                    beta.parse()
+                        .map_err(|_| format!("bad kaiser beta {beta:?}"))?,
+                ),
+                None => return Err(format!("unknown window {value:?}")),
+            },
+        })
+    }
+}
+
+/// Zeroth order modified Bessel function of the first kind, by its series.
+fn bessel_i0(x: f32) -> f32 {
+    let mut sum = 1.;
+    let mut term = 1.;
+    let half = x / 2.;
+    for k in 1..50 {
+        term *= (half / k as f32).powi(2);
+        sum += term;
+        if term < sum * 1e-9 {
+            break;
+        }
+    }
+    sum
+}
+
+impl Window {
+    /// The window's value at `n` of `len` points, symmetric so that the first
+    /// and last are the ends.
+    pub fn value(self, n: usize, len: usize) -> f32 {
+        if len <= 1 {
+            return 1.;
+        }
+        let x = n as f32 / (len - 1) as f32;
+        match self {
+            Window::Rectangular => 1.,
+            Window::Hann => 0.5 - 0.5 * (TAU * x).cos(),
+            Window::Hamming => 0.54 - 0.46 * (TAU * x).cos(),
+            Window::Blackman => 0.42 - 0.5 * (TAU * x).cos() + 0.08 * (2. * TAU * x).cos(),
+            Window::Kaiser(beta) => {
+                let r = 2. * x - 1.;
+                bessel_i0(beta * (1. - r * r).max(0.).sqrt()) / bessel_i0(beta)
+            }
+        }
+    }
+
+    /// Multiplies `samples` by the window.
+    pub fn apply(self, samples: &mut [f32]) {
+        let len = samples.len();
+        for (n, s) in samples.iter_mut().enumerate() {
+            *s *= self.value(n, len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_windows() {
+        for window in ["hann", "hamming", "blackman", "kaiser", "kaiser:5"] {
+            let window: Window = window.parse().unwrap();
+            let mid = window.value(50, 101);
+            assert!((mid - 1.).abs() < 1e-5, "{window:?} peaks at {mid}");
+            assert!(window.value(0, 101) < 0.1, "{window:?}");
+            assert!((window.value(10, 101) - window.value(90, 101)).abs() < 1e-5);
+        }
+        assert_eq!(Window::Hann.value(0, 101), 0.);
+        assert!((Window::Hamming.value(0, 101) - 0.08).abs() < 1e-6);
+        assert_eq!(Window::Kaiser(0.).value(0, 101), 1.);
+        assert!("kaiser:x".parse::<Window>().is_err());
+    }
+}